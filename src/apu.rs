@@ -1,32 +1,140 @@
 use serde::Serialize;
 use serde::Deserialize;
 
+use apu::pulse::Pulse;
+use apu::triangle::Triangle;
+use apu::noise::Noise;
+use apu::dmc::Dmc;
+use apu::frame_counter::FrameCounter;
+use apu::frame_counter::FrameClock;
+use apu::resampler::Resampler;
+use apu::filter::FilterChain;
+use apu::mixer::Mixer;
+use apu::mixer::Channel;
+use apu::scope::Scope;
+use cpu::IrqLine;
+use cpu::IrqSource;
+use rom::Region;
+
+pub mod envelope;
+pub mod length_counter;
+pub mod pulse;
+pub mod triangle;
+pub mod noise;
+pub mod dmc;
+pub mod frame_counter;
+pub mod mixer;
+pub mod rate_control;
+pub mod resampler;
+pub mod filter;
+pub mod scope;
+
+// CPU clocks, the APU is clocked alongside the CPU
+const NTSC_CPU_CLOCK: f64 = 1_789_773.0;
+const PAL_CPU_CLOCK: f64 = 1_662_607.0;
+pub const SAMPLE_RATES: [u32; 3] = [44_100, 48_000, 96_000];
+
+#[derive(Debug, Fail)]
+pub enum ApuError {
+    #[fail(display = "Unsupported sample rate {}Hz", _0)]
+    SampleRate(u32),
+}
+
+// Everything that decides what the APU plays next, the output settings are
+// left as they are on load
+#[derive(Serialize, Deserialize)]
+pub struct ApuState {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    odd_cycle: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Apu {
-    pulse1: u8,
-    pulse2: u8,
-    triangle: u8,
-    noise: u8,
-    dmc: u8,
-    control: u8,
-    status: u8,
-    frame_counter: u8,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    pub mixer: Mixer,
+    frame_counter: FrameCounter,
+    // The pulse timers run at half the CPU clock, on odd cycles
+    odd_cycle: bool,
+    region: Region,
+    sample_rate: u32,
+    resampler: Resampler,
+    filter_chain: FilterChain,
+    // Filters the sound like the console's audio out does. Without it the
+    // output has a DC offset and sounds harsher
+    pub filters: bool,
+    // Multiplies the number of samples made, for keeping the frontend's
+    // audio queue filled, see RateControl
+    pub rate_ratio: f64,
+    // Whatever the cartridge's sound chip is putting out
+    expansion: f32,
+    #[serde(skip, default = "Scope::new")]
+    scope: Scope,
 }
 
 impl Apu {
     pub fn new() -> Apu {
         Apu {
-            pulse1: 0,
-            pulse2: 0,
-            triangle: 0,
-            noise: 0,
-            dmc: 0,
-            control: 0,
-            status: 0,
-            frame_counter: 0,
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            mixer: Mixer::new(),
+            frame_counter: FrameCounter::new(),
+            odd_cycle: false,
+            region: Region::NTSC,
+            sample_rate: SAMPLE_RATES[0],
+            resampler: Resampler::new(NTSC_CPU_CLOCK, SAMPLE_RATES[0]),
+            filter_chain: FilterChain::new(SAMPLE_RATES[0]),
+            filters: true,
+            rate_ratio: 1.0,
+            expansion: 0.0,
+            scope: Scope::new(),
         }
     }
 
+    // Reset silences every channel, like writing 0 to $4015
+    pub fn reset(&mut self) {
+        self.store(0x15, 0);
+        self.frame_counter.reset();
+    }
+
+    pub fn get_state(&self) -> ApuState {
+        ApuState {
+            pulse1: self.pulse1,
+            pulse2: self.pulse2,
+            triangle: self.triangle,
+            noise: self.noise,
+            dmc: self.dmc,
+            frame_counter: self.frame_counter,
+            odd_cycle: self.odd_cycle,
+        }
+    }
+
+    pub fn set_state(&mut self, apu_state: ApuState) {
+        self.pulse1 = apu_state.pulse1;
+        self.pulse2 = apu_state.pulse2;
+        self.triangle = apu_state.triangle;
+        self.noise = apu_state.noise;
+        self.dmc = apu_state.dmc;
+        self.frame_counter = apu_state.frame_counter;
+        self.odd_cycle = apu_state.odd_cycle;
+    }
+
+    // Address of a sample byte the DMC asked for that hasn't arrived yet
+    pub fn pending_fetch(&self) -> Option<u16> {
+        self.dmc.pending_fetch()
+    }
+
     pub fn load(&mut self, addr: u16) -> u8 {
         match addr {
             0x15 => self.read_status(),
@@ -36,13 +144,164 @@ impl Apu {
             ),
         }
     }
-    pub fn store(&self, _addr: u16, _val: u8) {
-        // unimplemented!("Storing to APU not supported");
-        //println!("Warning! Storing not implemented for APU");
+
+    pub fn store(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x00...0x03 => self.pulse1.store(addr, val),
+            0x04...0x07 => self.pulse2.store(addr - 4, val),
+            0x08...0x0B => self.triangle.store(addr - 8, val),
+            0x0C...0x0F => self.noise.store(addr - 0x0C, val),
+            0x10...0x13 => self.dmc.store(addr - 0x10, val),
+            // ---D NT21, disabling a channel also silences it straight away
+            0x15 => {
+                self.pulse1.set_enabled(val & 0x01 != 0);
+                self.pulse2.set_enabled(val & 0x02 != 0);
+                self.triangle.set_enabled(val & 0x04 != 0);
+                self.noise.set_enabled(val & 0x08 != 0);
+                self.dmc.set_enabled(val & 0x10 != 0);
+            }
+            0x17 => self.frame_counter.store(val, !self.odd_cycle),
+            _ => (),
+        }
+    }
+
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc.fill(val);
+    }
+
+    // Set every cycle from the cartridge, mixed in with the next sample
+    pub fn set_expansion(&mut self, level: f32) {
+        self.expansion = level;
+    }
+
+    // Advances the APU by one CPU cycle. Returns the address of a sample
+    // byte when the DMC wants one fetched
+    pub fn tick(&mut self, irq: &mut IrqLine) -> Option<u16> {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        let fetch = self.dmc.clock_timer();
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.clock_frame_sequence();
+
+        let sample = self.mix();
+        if self.resampler.push(sample, self.rate_ratio) {
+            let levels = self.channel_levels();
+            self.scope.record(levels);
+        }
+
+        irq.set(IrqSource::FrameCounter, self.frame_counter.irq);
+        irq.set(IrqSource::Dmc, self.dmc.irq);
+        fetch
+    }
+
+    fn clock_frame_sequence(&mut self) {
+        let clock = match self.frame_counter.clock() {
+            Some(clock) => clock,
+            None => return,
+        };
+        self.pulse1.clock_quarter();
+        self.pulse2.clock_quarter();
+        self.triangle.clock_quarter();
+        self.noise.clock_quarter();
+        if clock == FrameClock::Half {
+            self.pulse1.clock_half();
+            self.pulse2.clock_half();
+            self.triangle.clock_half();
+            self.noise.clock_half();
+        }
+    }
+
+    fn mix(&self) -> f32 {
+        self.mixer.mix(
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+            self.expansion,
+        )
+    }
+
+    fn channel_levels(&self) -> [f32; 6] {
+        [
+            self.pulse1.output() as f32 / 15.0,
+            self.pulse2.output() as f32 / 15.0,
+            self.triangle.output() as f32 / 15.0,
+            self.noise.output() as f32 / 15.0,
+            self.dmc.output() as f32 / 127.0,
+            self.expansion,
+        ]
+    }
+
+    // The last SCOPE_LENGTH samples of one channel at sample_rate(), oldest
+    // first, for oscilloscope views
+    pub fn scope(&self, channel: Channel) -> Vec<f32> {
+        self.scope.samples(channel)
+    }
+
+    // One of SAMPLE_RATES. Samples made at the old rate and not taken yet
+    // are dropped
+    pub fn set_sample_rate(&mut self, rate: u32) -> Result<(), ApuError> {
+        if !SAMPLE_RATES.contains(&rate) {
+            return Err(ApuError::SampleRate(rate));
+        }
+        self.sample_rate = rate;
+        self.resampler = Resampler::new(self.cpu_clock(), rate);
+        self.filter_chain = FilterChain::new(rate);
+        Ok(())
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // PAL consoles clock the APU slower and divide it down differently, so
+    // the same register writes play at the same pitch and tempo
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.noise.region = region;
+        self.dmc.region = region;
+        self.frame_counter.region = region;
+        self.resampler = Resampler::new(self.cpu_clock(), self.sample_rate);
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    fn cpu_clock(&self) -> f64 {
+        match self.region {
+            Region::NTSC => NTSC_CPU_CLOCK,
+            Region::PAL => PAL_CPU_CLOCK,
+        }
+    }
+
+    // Samples at sample_rate(), mono, made since this was last called
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        let mut samples = self.resampler.take_samples();
+        if self.filters {
+            for sample in samples.iter_mut() {
+                *sample = self.filter_chain.apply(*sample);
+            }
+        }
+        samples
     }
 
+    // IF-D NT21, the IRQ flags and which channels are still playing. Reading
+    // acknowledges the frame IRQ but not the DMC's
     fn read_status(&mut self) -> u8 {
-        //TODO: this is a placeholder
-        self.status
+        let status = (self.dmc.irq as u8) << 7
+            | (self.frame_counter.irq as u8) << 6
+            | (self.dmc.active() as u8) << 4
+            | (self.noise.active() as u8) << 3
+            | (self.triangle.active() as u8) << 2
+            | (self.pulse2.active() as u8) << 1
+            | self.pulse1.active() as u8;
+        self.frame_counter.irq = false;
+        status
     }
 }
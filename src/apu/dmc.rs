@@ -0,0 +1,183 @@
+use serde::Serialize;
+use serde::Deserialize;
+use rom::Region;
+
+// Output rates the 4 bit index in $4010 picks, in CPU cycles per bit. PAL's
+// are shorter to make up for its slower CPU clock
+const NTSC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+// Delta modulation channel, $4010-$4013. Plays 1 bit delta encoded samples
+// that it fetches from PRG a byte at a time with DMA, each bit moving the
+// output level up or down by 2
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct Dmc {
+    pub region: Region,
+    irq_enabled: bool,
+    looping: bool,
+    timer: u16,
+    period: u16,
+    // 7 bit output level
+    level: u8,
+    // Where the sample starts and how many bytes it is, from $4012/$4013
+    sample_addr: u16,
+    sample_len: u16,
+    // Memory reader
+    addr: u16,
+    bytes_left: u16,
+    buffer: Option<u8>,
+    // A DMA has been asked for and hasn't filled the buffer yet
+    fetching: bool,
+    // Output unit
+    shift: u8,
+    bits_left: u8,
+    silence: bool,
+    // Set when a sample without looping runs out, while IRQs are enabled
+    pub irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            region: Region::NTSC,
+            irq_enabled: false,
+            looping: false,
+            timer: 0,
+            period: NTSC_RATES[0],
+            level: 0,
+            sample_addr: 0xC000,
+            sample_len: 1,
+            addr: 0xC000,
+            bytes_left: 0,
+            buffer: None,
+            fetching: false,
+            shift: 0,
+            bits_left: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    // reg is the register's offset from $4010
+    pub fn store(&mut self, reg: u16, val: u8) {
+        match reg {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = val & 0x40 != 0;
+                let rates = match self.region {
+                    Region::NTSC => &NTSC_RATES,
+                    Region::PAL => &PAL_RATES,
+                };
+                self.period = rates[(val & 0x0F) as usize];
+            }
+            // Direct load of the output level, games use it to play PCM
+            1 => self.level = val & 0x7F,
+            // AAAA AAAA, $C000 + A * 64
+            2 => self.sample_addr = 0xC000 | (val as u16) << 6,
+            // LLLL LLLL, L * 16 + 1 bytes
+            3 => self.sample_len = (val as u16) << 4 | 1,
+            _ => panic!("The DMC only has 4 registers"),
+        }
+    }
+
+    // Bit 4 of $4015. Enabling starts the sample over only if the last one
+    // finished, disabling lets the byte in the buffer finish playing
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_left = 0;
+        } else if self.bytes_left == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_left > 0
+    }
+
+    pub fn pending_fetch(&self) -> Option<u16> {
+        if self.fetching {
+            Some(self.addr)
+        } else {
+            None
+        }
+    }
+
+    fn restart(&mut self) {
+        self.addr = self.sample_addr;
+        self.bytes_left = self.sample_len;
+    }
+
+    // Every CPU cycle. Returns the address of the next sample byte when the
+    // buffer needs filling, for the DMA unit to fetch
+    pub fn clock_timer(&mut self) -> Option<u16> {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            self.clock_output();
+        } else {
+            self.timer -= 1;
+        }
+        if self.buffer.is_none() && self.bytes_left > 0 && !self.fetching {
+            self.fetching = true;
+            Some(self.addr)
+        } else {
+            None
+        }
+    }
+
+    fn clock_output(&mut self) {
+        if !self.silence {
+            // The level stays put rather than wrapping at either end
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_left -= 1;
+        if self.bits_left == 0 {
+            self.bits_left = 8;
+            match self.buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // The byte the DMA fetched
+    pub fn fill(&mut self, val: u8) {
+        self.fetching = false;
+        if self.bytes_left == 0 {
+            return;
+        }
+        self.buffer = Some(val);
+        // The address wraps around to $8000, not $0000
+        self.addr = self.addr.checked_add(1).unwrap_or(0x8000);
+        self.bytes_left -= 1;
+        if self.bytes_left == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
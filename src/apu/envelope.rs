@@ -0,0 +1,67 @@
+use serde::Serialize;
+use serde::Deserialize;
+
+// Either a constant volume, or one that decays from 15 to 0 over time and
+// optionally loops. Shared by the pulse and noise channels
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct Envelope {
+    start: bool,
+    divider: u8,
+    decay: u8,
+    looping: bool,
+    constant: bool,
+    // Constant volume, or the divider's period
+    volume: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            start: false,
+            divider: 0,
+            decay: 0,
+            looping: false,
+            constant: false,
+            volume: 0,
+        }
+    }
+
+    // The low 6 bits of the channel's first register: --LC VVVV
+    pub fn write(&mut self, val: u8) {
+        self.looping = val & 0x20 != 0;
+        self.constant = val & 0x10 != 0;
+        self.volume = val & 0x0F;
+    }
+
+    // Writing the channel's length register starts the decay over on the
+    // next quarter frame
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    // Quarter frame
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use serde::Serialize;
+use serde::Deserialize;
+use std::f32::consts::PI;
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+enum Kind {
+    HighPass,
+    LowPass,
+}
+
+// A first order RC filter, like the ones the capacitors and resistors
+// between the APU and the audio out make
+#[derive(Serialize, Deserialize, Copy, Clone)]
+struct Filter {
+    kind: Kind,
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Filter {
+    fn new(kind: Kind, cutoff: f32, sample_rate: u32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        let alpha = match kind {
+            Kind::HighPass => rc / (rc + dt),
+            Kind::LowPass => dt / (rc + dt),
+        };
+        Filter {
+            kind: kind,
+            alpha: alpha,
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+
+    fn apply(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            Kind::HighPass => {
+                self.alpha * (self.prev_out + input - self.prev_in)
+            }
+            Kind::LowPass => {
+                self.prev_out + self.alpha * (input - self.prev_out)
+            }
+        };
+        self.prev_in = input;
+        self.prev_out = output;
+        output
+    }
+}
+
+// What a front loading NES does to the sound on its way out: two high pass
+// filters that take out the DC offset and some of the bass, and a low pass
+// that takes the edge off the square waves
+#[derive(Serialize, Deserialize)]
+pub struct FilterChain {
+    filters: [Filter; 3],
+}
+
+impl FilterChain {
+    pub fn new(sample_rate: u32) -> FilterChain {
+        FilterChain {
+            filters: [
+                Filter::new(Kind::HighPass, 90.0, sample_rate),
+                Filter::new(Kind::HighPass, 440.0, sample_rate),
+                Filter::new(Kind::LowPass, 14_000.0, sample_rate),
+            ],
+        }
+    }
+
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        self.filters
+            .iter_mut()
+            .fold(sample, |sample, filter| filter.apply(sample))
+    }
+}
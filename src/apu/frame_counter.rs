@@ -0,0 +1,137 @@
+use serde::Serialize;
+use serde::Deserialize;
+use rom::Region;
+
+struct Sequence {
+    // CPU cycles after $4017 takes effect that each step lands on. The 4
+    // step sequence is the first 4 of these. Quarter frames clock envelopes
+    // and the triangle's linear counter, half frames also clock length
+    // counters and sweeps
+    steps: [u32; 5],
+    // The frame IRQ flag is set on the 3 cycles around the last step of the
+    // 4 step sequence, the last of which also starts the sequence over
+    four_step_irq: [u32; 3],
+    four_step_length: u32,
+    five_step_length: u32,
+}
+
+const NTSC: Sequence = Sequence {
+    steps: [7457, 14913, 22371, 29829, 37281],
+    four_step_irq: [29828, 29829, 29830],
+    four_step_length: 29830,
+    five_step_length: 37282,
+};
+
+// The PAL APU divides down a slower CPU clock less, so frames still come
+// out at 50Hz
+const PAL: Sequence = Sequence {
+    steps: [8313, 16627, 24939, 33253, 41565],
+    four_step_irq: [33252, 33253, 33254],
+    four_step_length: 33254,
+    five_step_length: 41566,
+};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FrameClock {
+    Quarter,
+    // Half frames are also quarter frames
+    Half,
+}
+
+// The frame counter, $4017. Clocks the channels' envelopes, sweeps and
+// counters at around 240Hz, and in 4 step mode raises an IRQ at 60Hz
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct FrameCounter {
+    pub region: Region,
+    five_step: bool,
+    irq_inhibit: bool,
+    cycle: u32,
+    // A write to $4017 takes 3 or 4 cycles to restart the sequence
+    pending: Option<(u8, u8)>,
+    last_write: u8,
+    pub irq: bool,
+}
+
+impl FrameCounter {
+    pub fn new() -> FrameCounter {
+        let mut counter = FrameCounter {
+            region: Region::NTSC,
+            five_step: false,
+            irq_inhibit: false,
+            cycle: 0,
+            pending: None,
+            last_write: 0,
+            irq: false,
+        };
+        counter.apply(0);
+        counter
+    }
+
+    // Reset doesn't change the mode, it writes the last value again
+    pub fn reset(&mut self) {
+        let val = self.last_write;
+        self.irq = false;
+        self.apply(val);
+    }
+
+    // MI-- ----. apu_cycle is whether the write landed on a cycle that also
+    // clocked the pulse timers, which decides the delay
+    pub fn store(&mut self, val: u8, apu_cycle: bool) {
+        self.last_write = val;
+        self.irq_inhibit = val & 0x40 != 0;
+        if self.irq_inhibit {
+            self.irq = false;
+        }
+        let delay = if apu_cycle { 3 } else { 4 };
+        self.pending = Some((delay, val));
+    }
+
+    fn apply(&mut self, val: u8) {
+        self.five_step = val & 0x80 != 0;
+        self.irq_inhibit = val & 0x40 != 0;
+        self.cycle = 0;
+    }
+
+    // Every CPU cycle
+    pub fn clock(&mut self) -> Option<FrameClock> {
+        if let Some((delay, val)) = self.pending {
+            if delay == 1 {
+                self.pending = None;
+                self.apply(val);
+                // Switching to 5 step mode clocks everything straight away
+                if self.five_step {
+                    return Some(FrameClock::Half);
+                }
+                return None;
+            }
+            self.pending = Some((delay - 1, val));
+        }
+
+        let sequence = match self.region {
+            Region::NTSC => &NTSC,
+            Region::PAL => &PAL,
+        };
+        self.cycle += 1;
+        if !self.five_step
+            && !self.irq_inhibit
+            && sequence.four_step_irq.contains(&self.cycle)
+        {
+            self.irq = true;
+        }
+        let (steps, length) = if self.five_step {
+            (&sequence.steps[..], sequence.five_step_length)
+        } else {
+            (&sequence.steps[..4], sequence.four_step_length)
+        };
+        if self.cycle == length {
+            self.cycle = 0;
+        }
+        match steps.iter().position(|&step| step == self.cycle) {
+            // The 4th step of the 5 step sequence does nothing
+            Some(3) if self.five_step => None,
+            Some(step) if step % 2 == 1 || step == 4 => Some(FrameClock::Half),
+            Some(_) => Some(FrameClock::Quarter),
+            None => None,
+        }
+    }
+}
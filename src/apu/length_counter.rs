@@ -0,0 +1,55 @@
+use serde::Serialize;
+use serde::Deserialize;
+
+// Lengths the 5 bit index written to a channel's length register picks, in
+// half frames
+const LENGTHS: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24,
+    18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Silences a channel once it has played for the time it was given. Every
+// channel but the DMC has one
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct LengthCounter {
+    count: u8,
+    // Stops counting, which is also the envelope's loop flag
+    pub halt: bool,
+    // From $4015. While a channel is disabled it's held at 0
+    enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn new() -> LengthCounter {
+        LengthCounter {
+            count: 0,
+            halt: false,
+            enabled: false,
+        }
+    }
+
+    // The top 5 bits of the channel's length register
+    pub fn load(&mut self, val: u8) {
+        if self.enabled {
+            self.count = LENGTHS[(val >> 3) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.count = 0;
+        }
+    }
+
+    // Half frame
+    pub fn clock(&mut self) {
+        if !self.halt && self.count > 0 {
+            self.count -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.count > 0
+    }
+}
@@ -0,0 +1,102 @@
+use serde::Serialize;
+use serde::Deserialize;
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+    // Sound chips on the cartridge, only the VRC7's with the vrc7-audio
+    // feature so far
+    Expansion,
+}
+
+pub const CHANNELS: [Channel; 6] = [
+    Channel::Pulse1,
+    Channel::Pulse2,
+    Channel::Triangle,
+    Channel::Noise,
+    Channel::Dmc,
+    Channel::Expansion,
+];
+
+// Mixes the channels, leaving out any that have been muted to pick out or
+// debug the others. Muting happens here rather than in the channels, so the
+// game still sees them playing
+#[derive(Serialize, Deserialize)]
+pub struct Mixer {
+    muted: [bool; 6],
+}
+
+impl Mixer {
+    pub fn new() -> Mixer {
+        Mixer { muted: [false; 6] }
+    }
+
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel as usize] = muted;
+    }
+
+    pub fn is_muted(&self, channel: Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    // Mutes every other channel, or unmutes them all if this was already the
+    // only one playing
+    pub fn solo(&mut self, channel: Channel) {
+        let soloed = CHANNELS
+            .iter()
+            .all(|&other| self.is_muted(other) == (other != channel));
+        for &other in CHANNELS.iter() {
+            self.set_muted(other, !soloed && other != channel);
+        }
+    }
+
+    fn level(&self, channel: Channel, output: u8) -> f32 {
+        if self.is_muted(channel) {
+            0.0
+        } else {
+            output as f32
+        }
+    }
+
+    // The NES mixes its channels with resistor networks rather than adding
+    // them, so a channel is quieter the more the others in its group are
+    // putting out. These are the approximations of both groups' curves from
+    // the NESdev wiki, they come to 0-1 with everything at full volume
+    pub fn mix(
+        &self,
+        pulse1: u8,
+        pulse2: u8,
+        triangle: u8,
+        noise: u8,
+        dmc: u8,
+        expansion: f32,
+    ) -> f32 {
+        let pulses = self.level(Channel::Pulse1, pulse1)
+            + self.level(Channel::Pulse2, pulse2);
+        let pulse_out = if pulses == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulses + 100.0)
+        };
+        let tnd = self.level(Channel::Triangle, triangle) / 8227.0
+            + self.level(Channel::Noise, noise) / 12241.0
+            + self.level(Channel::Dmc, dmc) / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        // Cartridge audio goes around the console's mixer, straight onto
+        // the output
+        let expansion = if self.is_muted(Channel::Expansion) {
+            0.0
+        } else {
+            expansion
+        };
+        pulse_out + tnd_out + expansion
+    }
+}
@@ -0,0 +1,106 @@
+use serde::Serialize;
+use serde::Deserialize;
+use apu::envelope::Envelope;
+use apu::length_counter::LengthCounter;
+use rom::Region;
+
+// Timer periods the 4 bit index in $400E picks, in CPU cycles. PAL's are
+// shorter to make up for its slower CPU clock
+const NTSC_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+// Pseudo-random noise channel, $400C-$400F
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct Noise {
+    pub region: Region,
+    // 15 bit linear feedback shift register, its low bit gates the output
+    shift: u16,
+    // Feeds back bit 6 instead of bit 1, which repeats every 93 steps and
+    // sounds metallic rather than hissy
+    short_mode: bool,
+    timer: u16,
+    period: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            region: Region::NTSC,
+            shift: 1,
+            short_mode: false,
+            timer: 0,
+            period: NTSC_PERIODS[0],
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    // reg is the register's offset from $400C
+    pub fn store(&mut self, reg: u16, val: u8) {
+        match reg {
+            // --LC VVVV
+            0 => {
+                self.length.halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            }
+            1 => (),
+            // M--- PPPP
+            2 => {
+                self.short_mode = val & 0x80 != 0;
+                let periods = match self.region {
+                    Region::NTSC => &NTSC_PERIODS,
+                    Region::PAL => &PAL_PERIODS,
+                };
+                self.period = periods[(val & 0x0F) as usize];
+            }
+            // LLLL L---
+            3 => {
+                self.length.load(val);
+                self.envelope.restart();
+            }
+            _ => panic!("The noise channel only has 4 registers"),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn active(&self) -> bool {
+        self.length.active()
+    }
+
+    // Every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ self.shift >> tap) & 1;
+            self.shift = self.shift >> 1 | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
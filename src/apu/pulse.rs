@@ -0,0 +1,160 @@
+use serde::Serialize;
+use serde::Deserialize;
+use apu::envelope::Envelope;
+use apu::length_counter::LengthCounter;
+
+// The 4 duty cycles, 12.5%, 25%, 50% and 25% inverted. The sequencer steps
+// through them backwards
+const DUTIES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// Square wave channel, $4000-$4003 and $4004-$4007
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct Pulse {
+    // Pulse 1 negates its sweep with one's complement, so it goes one lower
+    // than pulse 2 would
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    timer: u16,
+    period: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep: Sweep,
+}
+
+// Bends the pitch by adding or subtracting a shifted copy of the period
+// every few half frames
+#[derive(Serialize, Deserialize, Copy, Clone)]
+struct Sweep {
+    enabled: bool,
+    divider_period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement: ones_complement,
+            duty: 0,
+            step: 0,
+            timer: 0,
+            period: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            sweep: Sweep {
+                enabled: false,
+                divider_period: 0,
+                negate: false,
+                shift: 0,
+                divider: 0,
+                reload: false,
+            },
+        }
+    }
+
+    // reg is the register's offset from the channel's first
+    pub fn store(&mut self, reg: u16, val: u8) {
+        match reg {
+            // DDLC VVVV
+            0 => {
+                self.duty = val >> 6;
+                self.length.halt = val & 0x20 != 0;
+                self.envelope.write(val);
+            }
+            // EPPP NSSS
+            1 => {
+                self.sweep.enabled = val & 0x80 != 0;
+                self.sweep.divider_period = (val >> 4) & 7;
+                self.sweep.negate = val & 0x08 != 0;
+                self.sweep.shift = val & 7;
+                self.sweep.reload = true;
+            }
+            2 => self.period = self.period & 0x700 | val as u16,
+            // LLLL LTTT, which also restarts the waveform and envelope
+            3 => {
+                self.period = self.period & 0xFF | (val as u16 & 7) << 8;
+                self.length.load(val);
+                self.step = 0;
+                self.envelope.restart();
+            }
+            _ => panic!("Pulse channels only have 4 registers"),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn active(&self) -> bool {
+        self.length.active()
+    }
+
+    // Every APU cycle, which is every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 7) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_half(&mut self) {
+        self.length.clock();
+        let target = self.sweep_target();
+        if self.sweep.divider == 0
+            && self.sweep.enabled
+            && self.sweep.shift > 0
+            && !self.sweep_mutes(target)
+        {
+            self.period = target;
+        }
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.divider_period;
+            self.sweep.reload = false;
+        } else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    // Where the sweep would take the period. This is worked out all the time,
+    // not just when the sweep is enabled
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep.shift;
+        if self.sweep.negate {
+            let extra = self.ones_complement as u16;
+            self.period.saturating_sub(change + extra)
+        } else {
+            self.period + change
+        }
+    }
+
+    // Periods under 8 are too high to hear, and a target past 11 bits would
+    // overflow the timer, both silence the channel
+    fn sweep_mutes(&self, target: u16) -> bool {
+        self.period < 8 || target > 0x7FF
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active()
+            || self.sweep_mutes(self.sweep_target())
+            || DUTIES[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use serde::Serialize;
+use serde::Deserialize;
+
+// How far the sample rate can be pulled either way. Half a percent is well
+// under what anyone can hear as a change in pitch
+const MAX_DELTA: f64 = 0.005;
+// Below this a single late frame empties the queue, above it the delay
+// between pressing a button and hearing it gets noticeable
+pub const MIN_LATENCY_MS: u32 = 30;
+pub const MAX_LATENCY_MS: u32 = 100;
+
+// What to play when the queue is about to run dry, e.g. because a frame took
+// too long
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Underrun {
+    // Let it run dry, which is a short gap in the sound
+    Silence,
+    // Queue the newest frame's sound a second time, which is usually less
+    // noticeable than a gap
+    Repeat,
+}
+
+// Counters for diagnosing choppy sound
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AudioStats {
+    // Times the queue was found empty
+    pub underruns: u32,
+    // Samples queued again to cover for a late frame
+    pub repeated: u32,
+    // Samples thrown away because the queue was already too full
+    pub dropped: u32,
+}
+
+// The emulator runs off the display's refresh rate and the sound card off
+// its own clock, and the two never quite agree. Left alone the audio queue
+// slowly empties and crackles, or fills up and lags behind the picture.
+// This nudges how many samples the APU makes to keep the queue around a
+// target fill level, and decides what to queue when that isn't enough
+pub struct RateControl {
+    sample_rate: u32,
+    // Samples the frontend aims to keep queued
+    target: u32,
+    queued: u32,
+    pub underrun: Underrun,
+    stats: AudioStats,
+}
+
+impl RateControl {
+    // The target is kept between MIN_LATENCY_MS and MAX_LATENCY_MS
+    pub fn new(sample_rate: u32, target_ms: u32) -> RateControl {
+        let target_ms = target_ms.max(MIN_LATENCY_MS).min(MAX_LATENCY_MS);
+        RateControl {
+            sample_rate: sample_rate,
+            target: sample_rate * target_ms / 1000,
+            queued: 0,
+            underrun: Underrun::Silence,
+            stats: AudioStats {
+                underruns: 0,
+                repeated: 0,
+                dropped: 0,
+            },
+        }
+    }
+
+    // Takes the number of samples still waiting to be played and a frame's
+    // worth of new ones, and gives back the samples to queue. Past twice the
+    // target they're dropped, so the sound can't fall behind the picture
+    pub fn feed(&mut self, queued: u32, samples: Vec<f32>) -> Vec<f32> {
+        self.queued = queued;
+        if queued == 0 {
+            self.stats.underruns += 1;
+        }
+        let len = samples.len() as u32;
+        if queued > self.target * 2 {
+            self.stats.dropped += len;
+            Vec::new()
+        } else if queued < len && self.underrun == Underrun::Repeat {
+            self.stats.repeated += len;
+            let mut padded = samples.clone();
+            padded.extend(samples);
+            padded
+        } else {
+            samples
+        }
+    }
+
+    // The ratio to make samples at as of the last feed, above 1 when the
+    // queue is running low
+    pub fn ratio(&self) -> f64 {
+        let target = self.target as f64;
+        let error = (target - self.queued as f64) / target;
+        1.0 + MAX_DELTA * error.max(-1.0).min(1.0)
+    }
+
+    // How long a sample made now waits before it's heard, as of the last
+    // feed
+    pub fn latency_ms(&self) -> f64 {
+        self.queued as f64 * 1000.0 / self.sample_rate as f64
+    }
+
+    pub fn stats(&self) -> AudioStats {
+        self.stats
+    }
+}
@@ -0,0 +1,55 @@
+use serde::Serialize;
+use serde::Deserialize;
+use std::mem;
+
+// Turns the APU's output, one value per CPU cycle, into samples at the
+// output rate. Each sample is the average of the APU output over the time it
+// covers, with the CPU cycle that straddles two samples split between them.
+// Averaging filters out most of what's too high to be represented at the
+// output rate, which picking out single values would alias into audible
+// noise
+#[derive(Serialize, Deserialize)]
+pub struct Resampler {
+    // Output samples per input value
+    step: f64,
+    // How far into the current output sample the input has got, 0-1
+    phase: f64,
+    sum: f32,
+    #[serde(skip)]
+    samples: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(input_rate: f64, output_rate: u32) -> Resampler {
+        Resampler {
+            step: output_rate as f64 / input_rate,
+            phase: 0.0,
+            sum: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    // ratio stretches the output rate slightly, see RateControl. Returns
+    // whether this finished a sample
+    pub fn push(&mut self, input: f32, ratio: f64) -> bool {
+        let step = self.step * ratio;
+        self.phase += step;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+            // The part of this input past the end of the sample goes to the
+            // next one
+            let after = (self.phase / step) as f32;
+            self.sum += input * (1.0 - after);
+            self.samples.push(self.sum * step as f32);
+            self.sum = input * after;
+            true
+        } else {
+            self.sum += input;
+            false
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        mem::replace(&mut self.samples, Vec::new())
+    }
+}
@@ -0,0 +1,38 @@
+use apu::mixer::Channel;
+use apu::mixer::CHANNELS;
+
+// Samples kept per channel, a little over 2 frames at 48000Hz
+pub const SCOPE_LENGTH: usize = 2048;
+
+// The last SCOPE_LENGTH samples of each channel on its own, for drawing
+// oscilloscope views. Channels are recorded before muting and mixing, each
+// scaled to 0-1
+pub struct Scope {
+    buffers: Vec<Vec<f32>>,
+    // Where the next sample goes, which is also the oldest one
+    pos: usize,
+}
+
+impl Scope {
+    pub fn new() -> Scope {
+        Scope {
+            buffers: vec![vec![0.0; SCOPE_LENGTH]; CHANNELS.len()],
+            pos: 0,
+        }
+    }
+
+    pub fn record(&mut self, levels: [f32; 6]) {
+        for (buffer, &level) in self.buffers.iter_mut().zip(levels.iter()) {
+            buffer[self.pos] = level;
+        }
+        self.pos = (self.pos + 1) % SCOPE_LENGTH;
+    }
+
+    // Oldest sample first
+    pub fn samples(&self, channel: Channel) -> Vec<f32> {
+        let buffer = &self.buffers[channel as usize];
+        let mut samples = buffer[self.pos..].to_vec();
+        samples.extend_from_slice(&buffer[..self.pos]);
+        samples
+    }
+}
@@ -0,0 +1,105 @@
+use serde::Serialize;
+use serde::Deserialize;
+use apu::length_counter::LengthCounter;
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6,
+    7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// Triangle wave channel, $4008-$400B. It has no volume control, only a
+// second, finer grained, counter that can silence it
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct Triangle {
+    step: u8,
+    timer: u16,
+    period: u16,
+    length: LengthCounter,
+    linear: u8,
+    linear_period: u8,
+    linear_reload: bool,
+    // Keeps reloading the linear counter, which is also the length counter's
+    // halt flag
+    control: bool,
+}
+
+impl Triangle {
+    pub fn new() -> Triangle {
+        Triangle {
+            step: 0,
+            timer: 0,
+            period: 0,
+            length: LengthCounter::new(),
+            linear: 0,
+            linear_period: 0,
+            linear_reload: false,
+            control: false,
+        }
+    }
+
+    // reg is the register's offset from $4008
+    pub fn store(&mut self, reg: u16, val: u8) {
+        match reg {
+            // CRRR RRRR
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_period = val & 0x7F;
+            }
+            1 => (),
+            2 => self.period = self.period & 0x700 | val as u16,
+            // LLLL LTTT
+            3 => {
+                self.period = self.period & 0xFF | (val as u16 & 7) << 8;
+                self.length.load(val);
+                self.linear_reload = true;
+            }
+            _ => panic!("The triangle channel only has 4 registers"),
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.set_enabled(enabled);
+    }
+
+    pub fn active(&self) -> bool {
+        self.length.active()
+    }
+
+    // Every CPU cycle, unlike the pulse channels
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            // Periods of 0 and 1 step at over 50kHz. The hardware does that
+            // and the result averages out to a flat line at 7.5, which games
+            // use to silence the triangle, but sampling it gives loud
+            // aliasing. Holding the step instead is inaudible
+            if self.linear > 0 && self.length.active() && self.period >= 2 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_quarter(&mut self) {
+        if self.linear_reload {
+            self.linear = self.linear_period;
+        } else if self.linear > 0 {
+            self.linear -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half(&mut self) {
+        self.length.clock();
+    }
+
+    // Silencing the triangle stops it where it is rather than dropping it to
+    // 0, so this is whatever step it stopped on
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
@@ -0,0 +1,45 @@
+extern crate failure;
+extern crate nes_emu;
+
+use failure::Error;
+use nes_emu::blargg::run_test_rom;
+use nes_emu::blargg::Outcome;
+use nes_emu::rom::load_rom;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process;
+
+// Enough for the longest instr_test-v5 ROMs with plenty to spare
+const MAX_FRAMES: usize = 60 * 60;
+
+fn run(path: &str) -> Result<bool, Error> {
+    let mut raw_bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut raw_bytes)?;
+    let report = run_test_rom(load_rom(&raw_bytes)?, MAX_FRAMES)?;
+    let text = report.text.trim();
+    match report.outcome {
+        Outcome::Passed => println!("PASS {}", path),
+        Outcome::Failed(code) => println!("FAIL {} ({}): {}", path, code, text),
+        Outcome::TimedOut => println!("TIMEOUT {}: {}", path, text),
+    }
+    Ok(report.outcome == Outcome::Passed)
+}
+
+// Runs every ROM given on the command line and exits with an error if any of
+// them didn't pass, e.g. `blargg nes_test_roms/instr_test-v5/rom_singles/*.nes`
+fn main() {
+    let mut all_passed = true;
+    for path in env::args().skip(1) {
+        match run(&path) {
+            Ok(passed) => all_passed &= passed,
+            Err(e) => {
+                println!("ERROR {}: {}", path, e);
+                all_passed = false;
+            }
+        }
+    }
+    if !all_passed {
+        process::exit(1);
+    }
+}
@@ -6,25 +6,47 @@ extern crate sdl2;
 #[macro_use]
 extern crate failure;
 use std::path::Path;
+use std::path::PathBuf;
 use failure::Error;
+use sdl2::audio::AudioQueue;
+use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::keyboard::Mod;
 use sdl2::render::TextureAccess;
 use sdl2::pixels::PixelFormatEnum;
 
 use std::collections::HashMap;
 use nes_emu::config::ButtonLayout;
 use nes_emu::config::Config;
+use nes_emu::config::WatchRom;
 use nes_emu::controller::Button;
+use nes_emu::ppu::palette::PaletteChoice;
 use nes_emu::rom::load_rom;
+use nes_emu::mapper::supported_boards;
 use nes_emu::NesEmulator;
+use nes_emu::apu::rate_control::RateControl;
+use nes_emu::apu::mixer::Channel;
+use nes_emu::apu::mixer::CHANNELS;
+use nes_emu::profiler::Profiler;
+use nes_emu::ppu::Timing;
+use nes_emu::trace;
+use nes_emu::trace::Tracer;
+use std::fs;
 use std::fs::File;
 use std::io::Read;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::thread;
 
 use std::env;
 
-const SCREEN_WIDTH: usize = 256;
-const SCREEN_HEIGHT: usize = 240;
+const PROFILE_HOTSPOTS: usize = 50;
+// How often battery saves are written out while playing, in frames
+const SAVE_INTERVAL: u64 = 60 * 5;
+// How often the ROM file is checked for changes with watch_rom, in frames
+const WATCH_INTERVAL: u64 = 30;
 
 fn get_save_state_name<'a>(rom_path: &'a Path) -> Result<&'a str, Error> {
     if let Some(os_stem) = rom_path.file_stem() {
@@ -45,17 +67,33 @@ fn get_save_state_name<'a>(rom_path: &'a Path) -> Result<&'a str, Error> {
 
 fn main() -> Result<(), Error> {
     env_logger::init();
+    if env::args().any(|a| a == "--mappers") {
+        for board in supported_boards() {
+            println!("{:>3} {}", board.mapper, board.name);
+        }
+        return Ok(());
+    }
     if let Some(str_path) = env::args().nth(1) {
         let rom_path = Path::new(&str_path);
         if !rom_path.is_file() {
             bail!("Given path is not a file");
         } else {
             let save_state_name = get_save_state_name(rom_path)?;
+            if let Some(log_path) =
+                env::args().skip_while(|a| a != "--verify-nestest").nth(1)
+            {
+                return verify_nestest(rom_path, Path::new(&log_path));
+            }
+            let trace_path = env::args().skip_while(|a| a != "--trace").nth(1);
+            let profile_path =
+                env::args().skip_while(|a| a != "--profile").nth(1);
             start_emulator(
                 rom_path
                     .to_str()
                     .expect("Checked for this in get_save_state_name"),
                 save_state_name,
+                trace_path,
+                profile_path,
             )
         }
     } else {
@@ -68,7 +106,14 @@ struct NesFrontEnd {
     pause: bool,
     ctrl0: HashMap<Keycode, Button>,
     ctrl1: HashMap<Keycode, Button>,
-    save_name: String,
+    state_name: String,
+    // Where battery RAM, or a self-flashing board's PRG, goes between runs
+    save_path: PathBuf,
+    // What's in that file as of the last read or write
+    saved: Option<Vec<u8>>,
+    rom_path: PathBuf,
+    watch_rom: WatchRom,
+    rom_modified: Option<SystemTime>,
 }
 
 enum EventRes {
@@ -91,11 +136,36 @@ impl NesFrontEnd {
                 self.switch_pause();
                 None
             }
+            Event::KeyDown {
+                keycode: Some(Keycode::F7),
+                ..
+            } => {
+                self.nes.step_into();
+                self.pause = false;
+                None
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F8),
+                ..
+            } => {
+                self.nes.step_over();
+                self.pause = false;
+                None
+            }
+            Event::KeyDown {
+                keycode: Some(Keycode::F9),
+                ..
+            } => {
+                self.nes.step_out();
+                self.pause = false;
+                None
+            }
             Event::KeyDown {
                 keycode: Some(Keycode::R),
                 ..
             } => {
                 self.nes.reset();
+                self.pause = false;
                 None
             }
             Event::KeyDown {
@@ -118,6 +188,25 @@ impl NesFrontEnd {
                 };
                 Some(EventRes::StateRes(state_res))
             }
+            Event::KeyDown {
+                keycode: Some(key),
+                keymod,
+                ..
+            } if channel_key(key).is_some() => {
+                let channel = channel_key(key).expect("Checked in the guard");
+                let mixer = &mut self.nes.cpu.mmu.apu.mixer;
+                if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                    mixer.solo(channel);
+                } else {
+                    let muted = mixer.is_muted(channel);
+                    mixer.set_muted(channel, !muted);
+                }
+                let playing: Vec<_> = CHANNELS
+                    .iter()
+                    .filter(|&&channel| !mixer.is_muted(channel))
+                    .collect();
+                Some(EventRes::StateRes(format!("Playing {:?}", playing)))
+            }
             Event::KeyDown {
                 keycode: Some(key), ..
             } => {
@@ -150,28 +239,95 @@ impl NesFrontEnd {
 
     fn switch_pause(&mut self) {
         self.pause = !self.pause;
+        if !self.pause {
+            self.nes.resume();
+        }
     }
 
     fn save_state(&mut self) -> Result<String, Error> {
-        let mut file = File::create(&self.save_name)?;
+        let mut file = File::create(&self.state_name)?;
         self.nes.get_state().save(&mut file)?;
-        Ok(format!("Successfully saved state: {}", &self.save_name))
+        Ok(format!("Successfully saved state: {}", &self.state_name))
+    }
+
+    fn read_save_data(&mut self) {
+        let mut cartridge = self.nes.cpu.mmu.cartridge.borrow_mut();
+        if let Ok(data) = fs::read(&self.save_path) {
+            cartridge.load_save_data(&data);
+        }
+        self.saved = cartridge.save_data();
+    }
+
+    // Only once the game has changed something, so nothing gets left behind
+    // for games that never save
+    fn write_save_data(&mut self) -> Result<(), Error> {
+        let data = self.nes.cpu.mmu.cartridge.borrow().save_data();
+        match data {
+            Some(ref bytes) if data != self.saved => {
+                fs::write(&self.save_path, bytes)?;
+            }
+            _ => return Ok(()),
+        }
+        self.saved = data;
+        Ok(())
+    }
+
+    // Picks up a new build of the ROM, if the file has changed since the
+    // last look
+    fn reload_rom(&mut self) -> Result<Option<String>, Error> {
+        let modified = fs::metadata(&self.rom_path)?.modified()?;
+        if self.rom_modified == Some(modified) {
+            return Ok(None);
+        }
+        self.rom_modified = Some(modified);
+        let rom = load_rom(&fs::read(&self.rom_path)?)?;
+        let prg = self.watch_rom == WatchRom::ChrAndPrg;
+        self.nes.reload_rom(rom, prg)?;
+        Ok(Some(format!("Reloaded {}", self.rom_path.display())))
     }
 
     fn load_state(&mut self) -> Result<String, Error> {
-        let mut file = File::open(&self.save_name)?;
+        let mut file = File::open(&self.state_name)?;
         let state = nes_emu::state::State::load(&mut file)?;
-        self.nes.load_state(state);
+        self.nes.load_state(state)?;
         Ok("Loaded state successfully".to_string())
     }
 }
 
-fn start_emulator(path_in: &str, rom_stem: &str) -> Result<(), Error> {
+// 1-6 mute and unmute each APU channel, with shift they solo it
+fn channel_key(key: Keycode) -> Option<Channel> {
+    match key {
+        Keycode::Num1 => Some(Channel::Pulse1),
+        Keycode::Num2 => Some(Channel::Pulse2),
+        Keycode::Num3 => Some(Channel::Triangle),
+        Keycode::Num4 => Some(Channel::Noise),
+        Keycode::Num5 => Some(Channel::Dmc),
+        Keycode::Num6 => Some(Channel::Expansion),
+        _ => None,
+    }
+}
+
+// Headless check of the CPU against nestest.log, no window is opened
+fn verify_nestest(rom_path: &Path, log_path: &Path) -> Result<(), Error> {
+    let mut raw_bytes = Vec::new();
+    File::open(rom_path)?.read_to_end(&mut raw_bytes)?;
+    let mut nes = NesEmulator::new(load_rom(&raw_bytes)?);
+    let log = fs::read_to_string(log_path)?;
+    let matched = trace::verify_nestest(&mut nes.cpu, &log)?;
+    println!("All {} lines of {} match", matched, log_path.display());
+    Ok(())
+}
+
+fn start_emulator(
+    path_in: &str,
+    rom_stem: &str,
+    trace_path: Option<String>,
+    profile_path: Option<String>,
+) -> Result<(), Error> {
     let config = Config::load_config("./config.toml".to_string())?;
 
-    let screen_height = SCREEN_HEIGHT as u32
-        - config.overscan.bottom as u32
-        - config.overscan.top as u32;
+    let screen_width = config.overscan.width() as u32;
+    let screen_height = config.overscan.height() as u32;
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -179,7 +335,7 @@ fn start_emulator(path_in: &str, rom_stem: &str) -> Result<(), Error> {
     let window = video_subsystem
         .window(
             "Res",
-            (SCREEN_WIDTH * config.pixel_scale) as u32,
+            screen_width * config.pixel_scale as u32,
             screen_height * config.pixel_scale as u32,
         )
         .position_centered()
@@ -198,43 +354,174 @@ fn start_emulator(path_in: &str, rom_stem: &str) -> Result<(), Error> {
         .create_texture(
             PixelFormatEnum::RGB24,
             TextureAccess::Streaming,
-            SCREEN_WIDTH as u32,
+            screen_width,
             screen_height,
         )
         .unwrap();
 
     let mut event_pump = sdl_context.event_pump().unwrap();
 
+    let sample_rate = config.sample_rate;
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let audio_spec = AudioSpecDesired {
+        freq: Some(sample_rate as i32),
+        channels: Some(1),
+        samples: None,
+    };
+    let audio: AudioQueue<f32> =
+        audio_subsystem.open_queue(None, &audio_spec).unwrap();
+    audio.resume();
+    let mut rate_control =
+        RateControl::new(sample_rate, config.audio_latency_ms);
+    rate_control.underrun = config.underrun;
+
     let mut raw_bytes = Vec::new();
     let mut raw_rom = File::open(path_in)?;
     raw_rom.read_to_end(&mut raw_bytes)?;
 
     let rom = load_rom(&raw_bytes)?;
 
+    let save_dir = match config.save_dir {
+        Some(ref dir) => {
+            fs::create_dir_all(dir)?;
+            PathBuf::from(dir)
+        }
+        None => match Path::new(path_in).parent() {
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::new(),
+        },
+    };
+
     let mut nes_frontend = NesFrontEnd {
-        nes: NesEmulator::new(rom),
+        nes: NesEmulator::with_power_on(rom, config.power_on),
         pause: false,
         ctrl0: ButtonLayout::make_ctrl_map(&config.ctrl1_layout)?,
         ctrl1: ButtonLayout::make_ctrl_map(&config.ctrl2_layout)?,
-        save_name: rom_stem.to_string() + ".sav",
+        state_name: rom_stem.to_string() + ".state",
+        save_path: save_dir.join(rom_stem.to_string() + ".sav"),
+        saved: None,
+        rom_path: PathBuf::from(path_in),
+        watch_rom: config.watch_rom,
+        rom_modified: fs::metadata(path_in)?.modified().ok(),
     };
+    nes_frontend.read_save_data();
+
+    // There are only 64 sprites, and fewer than 8 would hide sprites the
+    // hardware shows
+    nes_frontend.nes.cpu.mmu.ppu.sprite_limit =
+        config.sprites_per_scanline.max(8).min(64);
+    nes_frontend.nes.cpu.mmu.ppu.oam_decay = config.oam_decay;
+    nes_frontend.nes.cpu.mmu.ppu.warm_up = config.ppu_warm_up;
+    nes_frontend.nes.cpu.mmu.apu.set_sample_rate(sample_rate)?;
+    nes_frontend.nes.cpu.mmu.apu.filters = config.audio_filters;
+    // A .pal file given on the command line wins over the config
+    let palette = match env::args().skip_while(|a| a != "--palette").nth(1) {
+        Some(path) => PaletteChoice::File(path),
+        None => config.palette.clone(),
+    };
+    nes_frontend.nes.cpu.mmu.ppu.set_colors(palette.load()?);
+
+    if let Some(path) = trace_path {
+        nes_frontend.nes.cpu.tracer = Some(Tracer::to_file(Path::new(&path))?);
+    }
+    // Any number of label files can be given, e.g. one .nl file per bank
+    let mut args = env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--symbols" {
+            if let Some(path) = args.next() {
+                nes_frontend.nes.cpu.mmu.debugger.symbols.load(Path::new(&path))?;
+            }
+        }
+    }
+    if profile_path.is_some() {
+        nes_frontend.nes.cpu.profiler = Some(Profiler::new());
+    }
+
+    // vsync keeps NTSC at the right speed on most screens, the 50Hz timings
+    // are held back here instead
+    let timing = nes_frontend.nes.power.timing;
+    let pace = timing != Timing::Ntsc;
+    let frame_time = Duration::from_secs_f64(1.0 / timing.frames_per_second());
+    let mut next_frame = Instant::now();
 
     loop {
         if !nes_frontend.pause {
-            let framebuffer = nes_frontend.nes.next_frame();
-            texture
-                .update(
-                    None,
-                    &framebuffer[config.overscan.top as usize * 3 * SCREEN_WIDTH
-                        ..(SCREEN_WIDTH * SCREEN_HEIGHT
-                            - config.overscan.bottom as usize)
-                            * 3],
-                    SCREEN_WIDTH * 3,
-                )
-                .unwrap();
-            canvas.clear();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
+            match nes_frontend.nes.next_frame() {
+                Ok(framebuffer) => {
+                    texture
+                        .update(
+                            None,
+                            &config.overscan.crop(framebuffer),
+                            screen_width as usize * 3,
+                        )
+                        .unwrap();
+                    canvas.clear();
+                    canvas.copy(&texture, None, None).unwrap();
+                    canvas.present();
+                    if pace {
+                        next_frame += frame_time;
+                        let now = Instant::now();
+                        if next_frame > now {
+                            thread::sleep(next_frame - now);
+                        } else {
+                            // Fell behind, e.g. after being paused
+                            next_frame = now;
+                        }
+                    }
+                    let apu = &mut nes_frontend.nes.cpu.mmu.apu;
+                    let samples = apu.take_samples();
+                    let queued = audio.size() / 4;
+                    audio.queue(&rate_control.feed(queued, samples));
+                    apu.rate_ratio = rate_control.ratio();
+                    if nes_frontend.nes.cpu.mmu.frames % SAVE_INTERVAL == 0 {
+                        if let Err(e) = nes_frontend.write_save_data() {
+                            warn!("Couldn't write save: {}", e);
+                        }
+                    }
+                    let frames = nes_frontend.nes.cpu.mmu.frames;
+                    if nes_frontend.watch_rom != WatchRom::Off
+                        && frames % WATCH_INTERVAL == 0
+                    {
+                        match nes_frontend.reload_rom() {
+                            Ok(Some(r)) => println!("{}", r),
+                            Ok(None) => (),
+                            Err(e) => println!("Couldn't reload ROM: {}", e),
+                        }
+                    }
+                    // Once a second, the sound's latency in the title bar
+                    if nes_frontend.nes.cpu.mmu.frames % 60 == 0 {
+                        let title = format!(
+                            "Res - audio {:.0}ms, {} underruns",
+                            rate_control.latency_ms(),
+                            rate_control.stats().underruns
+                        );
+                        canvas.window_mut().set_title(&title).unwrap();
+                    }
+                    if let Some(b) = nes_frontend.nes.cpu.mmu.debugger.hit() {
+                        let cpu = &nes_frontend.nes.cpu;
+                        println!(
+                            "Stopped on {:?} at {:04X} {}, press P to resume",
+                            b,
+                            cpu.regs.pc.get_addr(),
+                            trace::disassemble(
+                                &cpu.mmu,
+                                cpu.regs.pc.get_addr(),
+                                cpu.regs.x,
+                                cpu.regs.y
+                            )
+                        );
+                        nes_frontend.pause = true;
+                    }
+                }
+                // A jammed CPU keeps the rest of the system running, anything
+                // else leaves the CPU in a bad state so pause until a reset
+                Err(e) => {
+                    println!("{}, press R to reset", e);
+                    if !nes_frontend.nes.is_jammed() {
+                        nes_frontend.pause = true;
+                    }
+                }
+            }
         }
 
         for event in event_pump.poll_iter() {
@@ -243,7 +530,15 @@ fn start_emulator(path_in: &str, rom_stem: &str) -> Result<(), Error> {
                     EventRes::StateRes(r) => {
                         println!("{}", r)
                     }
-                    EventRes::Quit => return Ok(()),
+                    EventRes::Quit => {
+                        nes_frontend.write_save_data()?;
+                        if let (Some(path), Some(profiler)) =
+                            (profile_path, nes_frontend.nes.cpu.profiler.take())
+                        {
+                            fs::write(path, profiler.report(PROFILE_HOTSPOTS))?;
+                        }
+                        return Ok(());
+                    }
                 }
             }
         }
@@ -0,0 +1,92 @@
+use failure::Error;
+use rom::Rom;
+use NesEmulator;
+#[cfg(feature = "cached-core")]
+use cpu::block_cache::BlockCache;
+
+// blargg's test ROMs report through PRG RAM: a status byte at $6000, a
+// signature at $6001-$6003 once that status is valid, and a zero terminated
+// message from $6004
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const TEXT: u16 = 0x6004;
+const MAGIC: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+
+// The ROM wants reset pressed no sooner than 100ms after asking
+const RESET_DELAY_FRAMES: usize = 7;
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    // Result code the ROM finished with
+    Failed(u8),
+    TimedOut,
+}
+
+pub struct TestReport {
+    pub outcome: Outcome,
+    pub text: String,
+    pub frames: usize,
+}
+
+// Runs a test ROM headlessly until it reports a result or max_frames pass,
+// pressing reset whenever the ROM asks for it
+pub fn run_test_rom(rom: Rom, max_frames: usize) -> Result<TestReport, Error> {
+    let mut nes = NesEmulator::new(rom);
+    #[cfg(feature = "cached-core")]
+    {
+        nes.cpu.block_cache = Some(BlockCache::new());
+    }
+    let mut reset_at = None;
+    for frame in 0..max_frames {
+        nes.next_frame()?;
+        if !has_signature(&nes) {
+            continue;
+        }
+        match nes.cpu.mmu.peek(STATUS) {
+            RUNNING => (),
+            NEEDS_RESET => match reset_at {
+                Some(at) if frame >= at => {
+                    nes.reset();
+                    reset_at = None;
+                }
+                Some(_) => (),
+                None => reset_at = Some(frame + RESET_DELAY_FRAMES),
+            },
+            code => {
+                return Ok(TestReport {
+                    outcome: if code == 0 {
+                        Outcome::Passed
+                    } else {
+                        Outcome::Failed(code)
+                    },
+                    text: read_text(&nes),
+                    frames: frame + 1,
+                });
+            }
+        }
+    }
+    Ok(TestReport {
+        outcome: Outcome::TimedOut,
+        text: read_text(&nes),
+        frames: max_frames,
+    })
+}
+
+fn has_signature(nes: &NesEmulator) -> bool {
+    MAGIC
+        .iter()
+        .enumerate()
+        .all(|(i, &b)| nes.cpu.mmu.peek(SIGNATURE + i as u16) == b)
+}
+
+fn read_text(nes: &NesEmulator) -> String {
+    (TEXT..0x8000)
+        .map(|addr| nes.cpu.mmu.peek(addr))
+        .take_while(|&b| b != 0)
+        .map(|b| b as char)
+        .collect()
+}
@@ -0,0 +1,189 @@
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use mapper::Mapper;
+use mapper::Nametable;
+use mapper::new_mapper;
+use mapper::describe_board;
+
+#[derive(Debug, Fail)]
+pub enum ReloadError {
+    #[fail(display = "Reloaded ROM is for mapper {}, not {}", _0, _1)]
+    OtherMapper(u8, u8),
+    #[fail(display = "Reloaded {} is {} bytes, not {}", _0, _1, _2)]
+    OtherSize(&'static str, usize, usize),
+}
+
+// What's plugged into the slot, the ROM and RAM chips along with the board
+// that decides which parts of them the CPU and PPU see
+pub struct Cartridge {
+    pub rom: Rom,
+    pub mapper: Box<dyn Mapper>,
+}
+
+impl Cartridge {
+    pub fn from_rom(mut rom: Rom) -> Cartridge {
+        rom.fill_prg_ram();
+        let mapper = match new_mapper(&rom) {
+            Some(mapper) => mapper,
+            None => panic!(
+                "{} not implemented",
+                describe_board(rom.header.mapper)
+            ),
+        };
+        if let Some(size) = mapper.chr_ram_size() {
+            rom.chr_ram.resize(size, 0);
+        }
+        if let Some(size) = mapper.prg_ram_size() {
+            rom.prg_ram.resize(size, 0);
+        }
+        rom.load_trainer();
+        Cartridge {
+            rom: rom,
+            mapper: mapper,
+        }
+    }
+
+    // Swaps in the CHR ROM from a new build of the same ROM, and the PRG ROM
+    // too if prg is set, without touching the board or any RAM so the game
+    // carries on with the new data. Games with CHR RAM copy their tiles out
+    // of PRG, so only reloading that does anything for them
+    pub fn reload(&mut self, rom: Rom, prg: bool) -> Result<(), Error> {
+        if rom.header.mapper != self.rom.header.mapper {
+            return Err(Error::from(ReloadError::OtherMapper(
+                rom.header.mapper,
+                self.rom.header.mapper,
+            )));
+        }
+        check_size("CHR ROM", &rom.chr_rom, &self.rom.chr_rom)?;
+        if prg {
+            check_size("PRG ROM", &rom.prg_rom, &self.rom.prg_rom)?;
+            self.rom.prg_rom = rom.prg_rom;
+        }
+        self.rom.chr_rom = rom.chr_rom;
+        Ok(())
+    }
+
+    // Smaller RAM is mirrored across the whole 8KB, and without any the
+    // board gets the access
+    fn fixed_prg_ram(&self, addr: u16) -> Option<usize> {
+        let len = self.rom.prg_ram.len();
+        match addr {
+            0x6000...0x7FFF if !self.mapper.controls_prg_ram() && len != 0 => {
+                Some((addr as usize - 0x6000) % len)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn ld_prg(&self, addr: u16) -> u8 {
+        if let Some(offset) = self.fixed_prg_ram(addr) {
+            return self.rom.prg_ram[offset];
+        }
+        self.mapper.ld_prg(addr, &self.rom)
+    }
+
+    pub fn store_prg(&mut self, addr: u16, val: u8) {
+        if let Some(offset) = self.fixed_prg_ram(addr) {
+            self.rom.prg_ram[offset] = val;
+            return;
+        }
+        let val = match self.mapper.prg_offset(addr) {
+            Some(offset) if self.mapper.bus_conflicts() => {
+                val & self.rom.prg_rom[offset]
+            }
+            _ => val,
+        };
+        self.mapper.store_prg(addr, val, &mut self.rom);
+    }
+
+    pub fn read_prg(&mut self, addr: u16) -> u8 {
+        if let Some(offset) = self.fixed_prg_ram(addr) {
+            return self.rom.prg_ram[offset];
+        }
+        self.mapper.read_prg(addr, &self.rom)
+    }
+
+    pub fn prg_offset(&self, addr: u16) -> Option<usize> {
+        self.mapper.prg_offset(addr)
+    }
+
+    pub fn ld_chr(&self, addr: u16) -> u8 {
+        self.mapper.ld_chr(addr, &self.rom)
+    }
+
+    pub fn store_chr(&mut self, addr: u16, val: u8) {
+        self.mapper.store_chr(addr, val, &mut self.rom);
+    }
+
+    pub fn get_mirroring(&self) -> ScreenMode {
+        self.mapper.get_mirroring()
+    }
+
+    // What backs the nametable at a PPU address in $2000-$2FFF
+    pub fn nametable(&self, addr: u16) -> Nametable {
+        self.mapper.nametable((addr >> 10) & 3)
+    }
+
+    pub fn ld_chr_nametable(&self, bank: usize, addr: u16) -> u8 {
+        let chr_rom = &self.rom.chr_rom;
+        chr_rom[(bank * 0x400 + (addr as usize & 0x3FF)) % chr_rom.len()]
+    }
+
+    pub fn switches_chr(&self, addr: u16) -> bool {
+        self.mapper.switches_chr(addr)
+    }
+
+    pub fn a12_rise(&mut self) {
+        self.mapper.a12_rise();
+    }
+
+    pub fn pattern_fetch(&mut self, addr: u16) -> bool {
+        self.mapper.pattern_fetch(addr)
+    }
+
+    pub fn clock(&mut self) {
+        self.mapper.clock();
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.irq_pending()
+    }
+
+    pub fn audio_output(&self) -> f32 {
+        self.mapper.audio_output()
+    }
+
+    pub fn reset(&mut self) {
+        self.mapper.reset();
+    }
+
+    pub fn save_data(&self) -> Option<Vec<u8>> {
+        self.mapper.save_data(&self.rom)
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.mapper.load_save_data(data, &mut self.rom);
+    }
+
+    pub fn save_mapper(&self) -> Vec<u8> {
+        self.mapper.save_state()
+    }
+
+    pub fn load_mapper(&mut self, state: &[u8]) -> Result<(), Error> {
+        self.mapper.load_state(state)
+    }
+}
+
+// Banks are worked out from the chip sizes, so those can't change
+fn check_size(
+    chip: &'static str,
+    new: &[u8],
+    old: &[u8],
+) -> Result<(), ReloadError> {
+    if new.len() == old.len() {
+        Ok(())
+    } else {
+        Err(ReloadError::OtherSize(chip, new.len(), old.len()))
+    }
+}
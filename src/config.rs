@@ -7,19 +7,132 @@ use std::io::Read;
 use controller::Button;
 use failure::Error;
 use sdl2::keyboard::Keycode;
+use PowerOn;
+use ppu::palette::PaletteChoice;
+use apu::SAMPLE_RATES;
+use apu::rate_control::Underrun;
+use ppu::SCREEN_WIDTH;
+use ppu::SCREEN_HEIGHT;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub pixel_scale: usize,
+    // 8 is what the hardware draws, more removes flicker in some games
+    #[serde(default = "default_sprites_per_scanline")]
+    pub sprites_per_scanline: usize,
+    // Lets OAM decay while rendering is off, like it does on hardware
+    #[serde(default)]
+    pub oam_decay: bool,
+    // Ignores some PPU register writes right after power on, like hardware
+    #[serde(default)]
+    pub ppu_warm_up: bool,
+    // 44100, 48000 or 96000
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: u32,
+    // Filters the sound like a real console, off gives the raw APU output
+    #[serde(default = "default_audio_filters")]
+    pub audio_filters: bool,
+    // How much sound to keep queued, 30-100ms
+    #[serde(default = "default_audio_latency_ms")]
+    pub audio_latency_ms: u32,
+    // What to play when the sound is about to run out
+    #[serde(default = "default_underrun")]
+    pub underrun: Underrun,
+    // One of the built in palettes or a .pal file
+    #[serde(default = "default_palette")]
+    pub palette: PaletteChoice,
+    // Where battery saves go, next to the ROM if not set
+    #[serde(default)]
+    pub save_dir: Option<String>,
+    // Reloads the ROM when the file changes, for seeing edits while playing
+    #[serde(default = "default_watch_rom")]
+    pub watch_rom: WatchRom,
     pub ctrl1_layout: ButtonLayout,
     pub ctrl2_layout: ButtonLayout,
     pub overscan: Overscan,
+    // Older config files don't have this section
+    #[serde(default = "PowerOn::new")]
+    pub power_on: PowerOn,
 }
 
+fn default_sprites_per_scanline() -> usize {
+    8
+}
+
+fn default_sample_rate() -> u32 {
+    SAMPLE_RATES[0]
+}
+
+fn default_audio_filters() -> bool {
+    true
+}
+
+fn default_audio_latency_ms() -> u32 {
+    50
+}
+
+fn default_underrun() -> Underrun {
+    Underrun::Silence
+}
+
+fn default_palette() -> PaletteChoice {
+    PaletteChoice::Classic
+}
+
+fn default_watch_rom() -> WatchRom {
+    WatchRom::Off
+}
+
+// What's taken from the ROM file when it changes
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum WatchRom {
+    Off,
+    Chr,
+    // The game keeps running on the new code from wherever it was, which
+    // only goes well for small changes
+    ChrAndPrg,
+}
+
+// Edges bigger than this would leave too little of the picture to be useful
+const MAX_OVERSCAN: u8 = 64;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Overscan {
     pub top: u8,
     pub bottom: u8,
+    // Older config files only crop the top and bottom
+    #[serde(default)]
+    pub left: u8,
+    #[serde(default)]
+    pub right: u8,
+}
+
+impl Overscan {
+    // Size of the picture that's left after cropping, which is also what the
+    // window's aspect ratio follows
+    pub fn width(&self) -> usize {
+        SCREEN_WIDTH - self.edge(self.left) - self.edge(self.right)
+    }
+
+    pub fn height(&self) -> usize {
+        SCREEN_HEIGHT - self.edge(self.top) - self.edge(self.bottom)
+    }
+
+    // Cuts the edges off a full RGB24 frame
+    pub fn crop(&self, frame: &[u8]) -> Vec<u8> {
+        let top = self.edge(self.top);
+        let left = self.edge(self.left);
+        let mut cropped = Vec::with_capacity(self.width() * self.height() * 3);
+        for row in frame.chunks(SCREEN_WIDTH * 3).skip(top).take(self.height())
+        {
+            cropped.extend_from_slice(&row[left * 3..][..self.width() * 3]);
+        }
+        cropped
+    }
+
+    fn edge(&self, pixels: u8) -> usize {
+        pixels.min(MAX_OVERSCAN) as usize
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -120,13 +233,29 @@ impl Config {
             select: "B".to_string(),
         };
 
-        let overscan = Overscan { top: 8, bottom: 8 };
+        let overscan = Overscan {
+            top: 8,
+            bottom: 8,
+            left: 0,
+            right: 0,
+        };
 
         Config {
             pixel_scale: 3,
+            sprites_per_scanline: default_sprites_per_scanline(),
+            oam_decay: false,
+            ppu_warm_up: false,
+            sample_rate: default_sample_rate(),
+            audio_filters: default_audio_filters(),
+            audio_latency_ms: default_audio_latency_ms(),
+            underrun: default_underrun(),
+            palette: default_palette(),
+            save_dir: None,
+            watch_rom: default_watch_rom(),
             ctrl1_layout: layout1,
             ctrl2_layout: layout2,
             overscan: overscan,
+            power_on: PowerOn::new(),
         }
     }
 
@@ -4,6 +4,16 @@ use cpu_const::*;
 use std::fmt;
 use mmu::Mmu;
 use log::Level;
+use trace::Tracer;
+use profiler::Profiler;
+use debugger::CallFrame;
+use debugger::CallKind;
+use debugger::CallStack;
+#[cfg(feature = "cached-core")]
+use self::block_cache::BlockCache;
+
+#[cfg(feature = "cached-core")]
+pub mod block_cache;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Registers {
@@ -30,16 +40,6 @@ impl fmt::Debug for Registers {
     }
 }
 
-impl Registers {
-    fn reset(&mut self, address: u16) {
-        self.acc = 0;
-        self.x = 0;
-        self.y = 0;
-        self.pc.set_addr(address);
-        self.sp = 0xFD;
-        self.flags = Flags(0b00100100);
-    }
-}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ProgramCounter(u16);
@@ -85,15 +85,86 @@ bitfield! {
     pub as_byte, set_byte:      7, 0;
 }
 
+// Everything that can pull the IRQ line low. The line is level triggered, so
+// it stays asserted until every source has been acknowledged
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum IrqSource {
+    FrameCounter = 0b0000_0001,
+    Dmc = 0b0000_0010,
+    Mapper = 0b0000_0100,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct IrqLine(u8);
+
+impl IrqLine {
+    pub fn new() -> IrqLine {
+        IrqLine(0)
+    }
+
+    pub fn assert(&mut self, source: IrqSource) {
+        self.0 |= source as u8;
+    }
+
+    pub fn release(&mut self, source: IrqSource) {
+        self.0 &= !(source as u8);
+    }
+
+    // For sources that keep their own flag and just mirror it onto the line
+    pub fn set(&mut self, source: IrqSource, asserted: bool) {
+        if asserted {
+            self.assert(source);
+        } else {
+            self.release(source);
+        }
+    }
+
+    pub fn is_asserted_by(&self, source: IrqSource) -> bool {
+        self.0 & source as u8 != 0
+    }
+
+    pub fn asserted(&self) -> bool {
+        self.0 != 0
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum CpuError {
+    #[fail(display = "CPU jammed by opcode {:02X} at {:04X}", _0, _1)]
+    Jammed(u8, u16),
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum CpuState {
+    Running,
+    // A KIL/JAM opcode locks up the 6502 until it is reset. The rest of the
+    // system keeps running, so the frontend can still draw and reset
+    Jammed(u8),
+}
+
 pub struct Cpu {
     pub regs: Registers,
     pub cycle_count: u16,
     pub mmu: Mmu,
-    cc: usize,
+    pub state: CpuState,
+    pub tracer: Option<Tracer>,
+    pub profiler: Option<Profiler>,
+    // JSRs and interrupts that haven't returned yet, used by the debugger to
+    // show where execution came from and to step over and out of subroutines
+    pub call_stack: CallStack,
+    // Experimental, runs whole basic blocks decoded ahead of time when set
+    #[cfg(feature = "cached-core")]
+    pub block_cache: Option<BlockCache>,
+    // Interrupts seen during the last poll, serviced before the next opcode
+    nmi_pending: bool,
+    irq_pending: bool,
 }
 
-#[derive(Clone)]
+#[derive(Copy, Clone)]
 pub enum Mode {
+    // Implied and accumulator ops, which never address memory
+    Imp,
     Imm,
     ZP,
     ZPX,
@@ -113,7 +184,6 @@ impl Cpu {
     pub fn new(mmu: Mmu) -> Cpu {
         let mut cpu = Cpu {
             cycle_count: 0,
-            cc: 0,
             regs: Registers {
                 acc: 0,
                 x: 0,
@@ -123,30 +193,101 @@ impl Cpu {
                 flags: Flags(0b00100100),
             },
             mmu: mmu,
+            state: CpuState::Running,
+            tracer: None,
+            profiler: None,
+            call_stack: CallStack::new(),
+            #[cfg(feature = "cached-core")]
+            block_cache: None,
+            nmi_pending: false,
+            irq_pending: false,
         };
-        cpu.regs.pc.set_addr(cpu.mmu.ld16(RESET_VEC));
+        cpu.power_on();
         cpu
     }
 
+    // Registers start cleared with SP at 0, which the reset sequence then
+    // decrements to the familiar 0xFD
+    pub fn power_on(&mut self) {
+        self.mmu.cycles = 0;
+        self.mmu.frames = 0;
+        self.regs.acc = 0;
+        self.regs.x = 0;
+        self.regs.y = 0;
+        self.regs.sp = 0;
+        self.regs.flags = Flags(0b00100100);
+        self.reset_sequence();
+    }
+
+    // A soft reset leaves A, X, Y and the other flags alone and just runs the
+    // reset sequence, so SP ends up 3 lower than it was
     pub fn reset(&mut self) {
+        self.reset_sequence();
+    }
+
+    // Reset is the interrupt sequence with its stack writes turned into
+    // reads, taking the same 7 cycles before fetching the reset vector
+    fn reset_sequence(&mut self) {
         self.cycle_count = 0;
-        self.cc = 0;
+        self.state = CpuState::Running;
+        self.call_stack.clear();
+        #[cfg(feature = "cached-core")]
+        {
+            if let Some(ref mut cache) = self.block_cache {
+                cache.clear();
+            }
+        }
+        self.nmi_pending = false;
+        self.irq_pending = false;
+        let pc = self.regs.pc.get_addr();
+        self.dummy_ld8(pc);
+        self.dummy_ld8(pc);
+        for _ in 0..3 {
+            let addr = self.regs.sp as u16 | 0x100;
+            self.dummy_ld8(addr);
+            self.regs.sp = self.regs.sp.wrapping_sub(1);
+        }
+        self.regs.flags.set_itr(true);
         let addr = self.mmu.ld16(RESET_VEC);
-        self.regs.reset(addr);
+        self.regs.pc.set_addr(addr);
     }
 
+    // Indexed reads that cross a page first read from the address before
+    // the carry into the high byte is fixed up, costing the extra cycle
     fn check_pb(&mut self, base: u16, base_offset: u16) {
         if (base & 0xFF00) != (base_offset & 0xFF00) {
             self.incr_cc();
+            self.dummy_ld8(Cpu::uncarried(base, base_offset));
         }
     }
 
+    // The address the 6502 puts on the bus before it has carried the index
+    // into the high byte
+    fn uncarried(base: u16, base_offset: u16) -> u16 {
+        (base & 0xFF00) | (base_offset & 0x00FF)
+    }
+
+    // Dummy accesses still go out on the bus, so anything with read side
+    // effects (PPUSTATUS, PPUDATA, controller ports) still sees them
+    fn dummy_ld8(&mut self, addr: u16) {
+        let _ = self.mmu.ld8(addr);
+    }
+
+    // Read-modify-write instructions write the unmodified value back before
+    // the real result, which mappers and PPU registers can observe
+    fn rmw_ld8(&mut self, addr: u16) -> u8 {
+        let val = self.mmu.ld8(addr);
+        self.store(addr, val);
+        val
+    }
+
     fn incr_cc(&mut self) {
         self.cycle_count += 1;
     }
 
     fn address_mem(&mut self, mode: Mode) -> u16 {
         match mode {
+            Mode::Imp => unreachable!("Implied ops have no operand"),
             Mode::Imm => {
                 let tmp = self.regs.pc.get_addr();
                 self.regs.pc.add_unsigned(1);
@@ -155,16 +296,18 @@ impl Cpu {
             Mode::ZP => self.ld8_pc_up() as u16,
             Mode::ZPX => {
                 let tmp = self.ld8_pc_up();
+                self.dummy_ld8(tmp as u16);
                 tmp.wrapping_add(self.regs.x) as u16
             }
             Mode::ZPY => {
                 let tmp = self.ld8_pc_up();
+                self.dummy_ld8(tmp as u16);
                 tmp.wrapping_add(self.regs.y) as u16
             }
             Mode::Abs => self.ld16_pc_up(),
             Mode::AbsX => {
                 let base = self.ld16_pc_up();
-                let tmp = base + self.regs.x as u16;
+                let tmp = base.wrapping_add(self.regs.x as u16);
                 self.check_pb(base, tmp);
                 tmp
             }
@@ -176,12 +319,14 @@ impl Cpu {
             }
             Mode::NoPBAbsX => {
                 let base = self.ld16_pc_up();
-                let tmp = base + self.regs.x as u16;
+                let tmp = base.wrapping_add(self.regs.x as u16);
+                self.dummy_ld8(Cpu::uncarried(base, tmp));
                 tmp
             }
             Mode::NoPBAbsY => {
                 let base = self.ld16_pc_up();
                 let tmp = base.wrapping_add(self.regs.y as u16);
+                self.dummy_ld8(Cpu::uncarried(base, tmp));
                 tmp
             }
             Mode::JmpIndir => {
@@ -196,6 +341,7 @@ impl Cpu {
             }
             Mode::IndX => {
                 let tmp = self.ld8_pc_up();
+                self.dummy_ld8(tmp as u16);
                 let base_address = tmp.wrapping_add(self.regs.x) as u16;
                 if base_address == 0xFF {
                     (self.mmu.ld8(0) as u16) << 8
@@ -223,16 +369,59 @@ impl Cpu {
                     self.mmu.ld16(base as u16)
                 };
                 let addr = tmp.wrapping_add(self.regs.y as u16);
+                self.dummy_ld8(Cpu::uncarried(tmp, addr));
                 addr
             }
         }
     }
 
     pub fn proc_nmi(&mut self) {
-        let flags = self.regs.flags;
+        self.interrupt(NMI_VEC);
+    }
+
+    pub fn proc_irq(&mut self) {
+        self.interrupt(IRQ_VEC);
+    }
+
+    // Hardware interrupts push the flags with the break bit cleared so the
+    // handler can tell them apart from BRK
+    fn interrupt(&mut self, vector: u16) {
+        self.cycle_count += 7;
+        let pc = self.regs.pc.get_addr();
+        self.dummy_ld8(pc);
+        self.dummy_ld8(pc);
+        let flags = (self.regs.flags.as_byte() & !0b10000) | 0b100000;
+        self.push_interrupt(flags, vector);
+    }
+
+    // Shared tail of BRK, IRQ and NMI. The vector isn't picked until after
+    // the flags are pushed, so an NMI that shows up by then hijacks the
+    // sequence and BRK/IRQ end up in the NMI handler instead (with the break
+    // bit still telling a BRK apart)
+    fn push_interrupt(&mut self, flags: u8, vector: u16) {
+        let ret = self.regs.pc.get_addr();
         self.push_pc();
-        self.push(flags.as_byte());
-        self.regs.pc.set_addr(self.mmu.ld16(NMI_VEC));
+        self.push(flags);
+        self.regs.flags.set_itr(true);
+        let vector = if vector != NMI_VEC && self.mmu.poll_nmi() {
+            self.nmi_pending = false;
+            NMI_VEC
+        } else {
+            vector
+        };
+        self.regs.pc.set_addr(self.mmu.ld16(vector));
+        let (kind, caller) = match (flags & 0b10000 != 0, vector) {
+            (true, _) => (CallKind::Brk, ret.wrapping_sub(2)),
+            (false, NMI_VEC) => (CallKind::Nmi, ret),
+            (false, _) => (CallKind::Irq, ret),
+        };
+        self.call_stack.push(CallFrame {
+            kind,
+            caller,
+            target: self.regs.pc.get_addr(),
+            ret,
+            sp: self.regs.sp,
+        });
     }
 
     fn read_op(&mut self, mode: Mode) -> u8 {
@@ -240,20 +429,10 @@ impl Cpu {
         self.mmu.ld8(addr)
     }
 
-    fn write_dma(&mut self, high_nyb: u8) {
-        self.cycle_count += 513 + (self.cycle_count % 2);
-        let page_num = (high_nyb as u16) << 8;
-        for address in page_num..=page_num + 0xFF {
-            let tmp = self.mmu.ld8(address);
-            self.mmu.store(OAM_DATA, tmp);
-        }
-    }
-
     fn store(&mut self, addr: u16, val: u8) {
+        self.mmu.store(addr, val);
         if addr == DMA_ADDR {
-            self.write_dma(val);
-        } else {
-            self.mmu.store(addr, val);
+            self.cycle_count += self.mmu.oam_dma(val);
         }
     }
 
@@ -318,7 +497,7 @@ impl Cpu {
         self.set_zero_neg(val);
     }
 
-    fn ror_acc(&mut self) {
+    fn ror_acc(&mut self, _: Mode) {
         let (tmp, n_flag) =
             Cpu::get_ror(self.regs.flags.carry(), self.regs.acc);
         self.regs.flags.set_carry(n_flag);
@@ -329,7 +508,7 @@ impl Cpu {
     fn ror_addr(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
         let (tmp, n_flag) =
-            Cpu::get_ror(self.regs.flags.carry(), self.mmu.ld8(addr));
+            Cpu::get_ror(self.regs.flags.carry(), self.rmw_ld8(addr));
         self.regs.flags.set_carry(n_flag);
         self.set_zero_neg(tmp);
         self.store(addr, tmp);
@@ -339,7 +518,7 @@ impl Cpu {
         ((val >> 1) | ((carry_flag as u8) << 7), (val & 0b01) != 0)
     }
 
-    fn rol_acc(&mut self) {
+    fn rol_acc(&mut self, _: Mode) {
         let (tmp, n_flag) =
             Cpu::get_rol(self.regs.flags.carry(), self.regs.acc);
         self.regs.flags.set_carry(n_flag);
@@ -350,7 +529,7 @@ impl Cpu {
     fn rol_addr(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
         let (tmp, n_flag) =
-            Cpu::get_rol(self.regs.flags.carry(), self.mmu.ld8(addr));
+            Cpu::get_rol(self.regs.flags.carry(), self.rmw_ld8(addr));
         self.regs.flags.set_carry(n_flag);
         self.set_zero_neg(tmp);
         self.store(addr, tmp);
@@ -360,7 +539,7 @@ impl Cpu {
         ((val << 1) | (carry_flag as u8), (val & 0x80) != 0)
     }
 
-    fn asl_acc(&mut self) {
+    fn asl_acc(&mut self, _: Mode) {
         let acc = self.regs.acc;
         self.regs.flags.set_carry((acc >> 7) != 0);
         let tmp = acc << 1;
@@ -370,14 +549,14 @@ impl Cpu {
 
     fn asl_addr(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
-        let val = self.mmu.ld8(addr);
+        let val = self.rmw_ld8(addr);
         self.regs.flags.set_carry((val >> 7) != 0);
         let tmp = val << 1;
         self.set_zero_neg(tmp);
         self.store(addr, tmp);
     }
 
-    fn lsr_acc(&mut self) {
+    fn lsr_acc(&mut self, _: Mode) {
         let acc = self.regs.acc;
         self.regs.flags.set_carry((acc & 0b01) != 0);
         let tmp = acc >> 1;
@@ -387,7 +566,7 @@ impl Cpu {
 
     fn lsr_addr(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
-        let val = self.mmu.ld8(addr);
+        let val = self.rmw_ld8(addr);
         self.regs.flags.set_carry((val & 0b01) != 0);
         let tmp = val >> 1;
         self.set_zero_neg(tmp);
@@ -415,8 +594,8 @@ impl Cpu {
         self.set_zero_neg(tmp as u8);
     }
 
-    fn generic_branch(&mut self, flag: bool) {
-        let val = self.ld8_pc_up() as i8;
+    fn generic_branch(&mut self, mode: Mode, flag: bool) {
+        let val = self.read_op(mode) as i8;
         if flag {
             let addr = self.regs.pc.get_addr();
             self.regs.pc.add_signed(val);
@@ -436,14 +615,14 @@ impl Cpu {
 
     fn dec(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
-        let val: u8 = self.mmu.ld8(addr).wrapping_sub(1);
+        let val: u8 = self.rmw_ld8(addr).wrapping_sub(1);
         self.set_zero_neg(val);
         self.store(addr, val);
     }
 
     fn inc(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
-        let val: u8 = self.mmu.ld8(addr).wrapping_add(1);
+        let val: u8 = self.rmw_ld8(addr).wrapping_add(1);
         self.set_zero_neg(val);
         self.store(addr, val);
     }
@@ -466,6 +645,81 @@ impl Cpu {
         self.store(addr, tmp);
     }
 
+    fn bvs(&mut self, mode: Mode) {
+        let flag = self.regs.flags.overflow();
+        self.generic_branch(mode, flag);
+    }
+
+    fn bvc(&mut self, mode: Mode) {
+        let flag = !self.regs.flags.overflow();
+        self.generic_branch(mode, flag);
+    }
+
+    fn bmi(&mut self, mode: Mode) {
+        let flag = self.regs.flags.neg();
+        self.generic_branch(mode, flag);
+    }
+
+    fn bpl(&mut self, mode: Mode) {
+        let flag = !self.regs.flags.neg();
+        self.generic_branch(mode, flag);
+    }
+
+    fn bne(&mut self, mode: Mode) {
+        let flag = !self.regs.flags.zero();
+        self.generic_branch(mode, flag);
+    }
+
+    fn beq(&mut self, mode: Mode) {
+        let flag = self.regs.flags.zero();
+        self.generic_branch(mode, flag);
+    }
+
+    fn bcs(&mut self, mode: Mode) {
+        let flag = self.regs.flags.carry();
+        self.generic_branch(mode, flag);
+    }
+
+    fn bcc(&mut self, mode: Mode) {
+        let flag = !self.regs.flags.carry();
+        self.generic_branch(mode, flag);
+    }
+
+    fn jsr(&mut self, mode: Mode) {
+        let addr = self.address_mem(mode);
+        let ret = self.regs.pc.get_addr();
+        self.regs.pc.add_signed(-1);
+        self.push_pc();
+        self.regs.pc.set_addr(addr);
+        self.call_stack.push(CallFrame {
+            kind: CallKind::Jsr,
+            caller: ret.wrapping_sub(3),
+            target: addr,
+            ret,
+            sp: self.regs.sp,
+        });
+    }
+
+    fn rts(&mut self, _: Mode) {
+        let sp = self.regs.sp;
+        self.pull_pc();
+        self.regs.pc.add_unsigned(1);
+        self.call_stack.ret(sp, self.regs.pc.get_addr());
+    }
+
+    fn rti(&mut self, _: Mode) {
+        let sp = self.regs.sp;
+        self.pull_status();
+        self.pull_pc();
+        self.call_stack.ret(sp, self.regs.pc.get_addr());
+    }
+
+    fn brk(&mut self, _: Mode) {
+        let _ = self.ld8_pc_up();
+        let flags = self.regs.flags.as_byte() | 0b110000;
+        self.push_interrupt(flags, IRQ_VEC);
+    }
+
     fn jmp(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
         self.regs.pc.set_addr(addr);
@@ -504,8 +758,8 @@ impl Cpu {
         self.set_zero_neg(val);
     }
 
-    fn axs(&mut self) {
-        let val = self.read_op(Mode::Imm);
+    fn axs(&mut self, mode: Mode) {
+        let val = self.read_op(mode);
         let tmp = self.regs.x & self.regs.acc;
         let res = tmp.wrapping_sub(val);
         self.regs.flags.set_carry(tmp >= val);
@@ -516,7 +770,7 @@ impl Cpu {
     //TODO this is dec followed by cmp, refactor this to use those functions
     fn dcp(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
-        let val: u8 = self.mmu.ld8(addr).wrapping_sub(1);
+        let val: u8 = self.rmw_ld8(addr).wrapping_sub(1);
         self.set_zero_neg(val);
         self.store(addr, val);
         let tmp = self.regs.acc as i16 - val as i16;
@@ -527,7 +781,7 @@ impl Cpu {
     //TODO This one can also probably be refactored
     fn isc(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
-        let val: u8 = self.mmu.ld8(addr).wrapping_add(1);
+        let val: u8 = self.rmw_ld8(addr).wrapping_add(1);
         self.set_zero_neg(val);
         self.store(addr, val);
         self.adc_val(val ^ 0xFF);
@@ -536,7 +790,7 @@ impl Cpu {
     //TODO same as this one
     fn slo(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
-        let val = self.mmu.ld8(addr);
+        let val = self.rmw_ld8(addr);
         self.regs.flags.set_carry((val >> 7) != 0);
         let tmp = val << 1;
         self.store(addr, tmp);
@@ -549,7 +803,7 @@ impl Cpu {
     fn rla(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
         let (tmp, n_flag) =
-            Cpu::get_rol(self.regs.flags.carry(), self.mmu.ld8(addr));
+            Cpu::get_rol(self.regs.flags.carry(), self.rmw_ld8(addr));
         self.regs.flags.set_carry(n_flag);
         self.store(addr, tmp);
 
@@ -560,7 +814,7 @@ impl Cpu {
 
     fn sre(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
-        let val = self.mmu.ld8(addr);
+        let val = self.rmw_ld8(addr);
         self.regs.flags.set_carry((val & 0b01) != 0);
         let tmp = val >> 1;
         self.store(addr, tmp);
@@ -573,7 +827,7 @@ impl Cpu {
     fn rra(&mut self, mode: Mode) {
         let addr = self.address_mem(mode);
         let (tmp, n_flag) =
-            Cpu::get_ror(self.regs.flags.carry(), self.mmu.ld8(addr));
+            Cpu::get_ror(self.regs.flags.carry(), self.rmw_ld8(addr));
         self.regs.flags.set_carry(n_flag);
         self.set_zero_neg(tmp);
         self.store(addr, tmp);
@@ -589,33 +843,177 @@ impl Cpu {
         self.regs.acc = tmp;
     }
 
-    fn atx(&mut self) {
-        self.lda(Mode::Imm);
-        self.tax();
+    fn atx(&mut self, mode: Mode) {
+        self.lda(mode);
+        self.tax(mode);
     }
 
-    fn tax(&mut self) {
+    // Unofficial NOPs that still read their operand
+    fn ign(&mut self, mode: Mode) {
+        let _ = self.read_op(mode);
+    }
+
+    fn tax(&mut self, _: Mode) {
         let acc = self.regs.acc;
         self.regs.x = acc;
         self.set_zero_neg(acc);
     }
 
-    fn sya(&mut self, mode: Mode) {
-        let mut addr = self.address_mem(mode);
-        if (addr & 0xFF00) != (addr - self.regs.x as u16) & 0xFF00 {
-            addr &= (self.regs.y as u16) << 8;
-        }
-        let tmp = self.regs.y & ((addr >> 8) as u8 + 1);
-        self.store(addr, tmp);
+    // SHY, SHX, AHX and TAS AND the stored value with the high byte of the
+    // base address plus one. If indexing crosses a page, that same value
+    // ends up as the high byte of the address written to
+    fn store_and_high(&mut self, mode: Mode, index: u8, val: u8) {
+        let addr = self.address_mem(mode);
+        let base = addr.wrapping_sub(index as u16);
+        let val = val & ((base >> 8) as u8).wrapping_add(1);
+        let addr = if (base & 0xFF00) != (addr & 0xFF00) {
+            ((val as u16) << 8) | (addr & 0xFF)
+        } else {
+            addr
+        };
+        self.store(addr, val);
     }
 
-    fn sxa(&mut self, mode: Mode) {
-        let mut addr = self.address_mem(mode);
-        if (addr & 0xFF00) != (addr - self.regs.y as u16) & 0xFF00 {
-            addr &= (self.regs.x as u16) << 8;
-        }
-        let tmp = self.regs.x & ((addr >> 8) as u8 + 1);
-        self.store(addr, tmp);
+    fn shy(&mut self, mode: Mode) {
+        let (x, y) = (self.regs.x, self.regs.y);
+        self.store_and_high(mode, x, y);
+    }
+
+    fn shx(&mut self, mode: Mode) {
+        let (x, y) = (self.regs.x, self.regs.y);
+        self.store_and_high(mode, y, x);
+    }
+
+    fn ahx(&mut self, mode: Mode) {
+        let val = self.regs.acc & self.regs.x;
+        let y = self.regs.y;
+        self.store_and_high(mode, y, val);
+    }
+
+    fn tas(&mut self, mode: Mode) {
+        self.regs.sp = self.regs.acc & self.regs.x;
+        let (sp, y) = (self.regs.sp, self.regs.y);
+        self.store_and_high(mode, y, sp);
+    }
+
+    fn las(&mut self, mode: Mode) {
+        let val = self.read_op(mode) & self.regs.sp;
+        self.regs.acc = val;
+        self.regs.x = val;
+        self.regs.sp = val;
+        self.set_zero_neg(val);
+    }
+
+    // XAA is unstable on real hardware, the magic constant varies between
+    // chips. 0xEE is what most emulators (and test ROMs) settle on
+    fn xaa(&mut self, mode: Mode) {
+        let val = self.read_op(mode);
+        let tmp = (self.regs.acc | 0xEE) & self.regs.x & val;
+        self.set_zero_neg(tmp);
+        self.regs.acc = tmp;
+    }
+
+    fn sed(&mut self, _: Mode) {
+        self.regs.flags.set_dec(true);
+    }
+
+    fn clc(&mut self, _: Mode) {
+        self.regs.flags.set_carry(false);
+    }
+
+    fn sec(&mut self, _: Mode) {
+        self.regs.flags.set_carry(true);
+    }
+
+    fn cli(&mut self, _: Mode) {
+        self.regs.flags.set_itr(false);
+    }
+
+    fn sei(&mut self, _: Mode) {
+        self.regs.flags.set_itr(true);
+    }
+
+    fn clv(&mut self, _: Mode) {
+        self.regs.flags.set_overflow(false);
+    }
+
+    fn cld(&mut self, _: Mode) {
+        self.regs.flags.set_dec(false);
+    }
+
+    fn nop(&mut self, _: Mode) {}
+
+    fn txa(&mut self, _: Mode) {
+        let x = self.regs.x;
+        self.regs.acc = x;
+        self.set_zero_neg(x);
+    }
+
+    fn tay(&mut self, _: Mode) {
+        let acc = self.regs.acc;
+        self.regs.y = acc;
+        self.set_zero_neg(acc);
+    }
+
+    fn tya(&mut self, _: Mode) {
+        let y = self.regs.y;
+        self.regs.acc = y;
+        self.set_zero_neg(y);
+    }
+
+    fn dex(&mut self, _: Mode) {
+        let x = self.regs.x.wrapping_sub(1);
+        self.regs.x = x;
+        self.set_zero_neg(x);
+    }
+
+    fn inx(&mut self, _: Mode) {
+        let x = self.regs.x.wrapping_add(1);
+        self.regs.x = x;
+        self.set_zero_neg(x);
+    }
+
+    fn dey(&mut self, _: Mode) {
+        let y = self.regs.y.wrapping_sub(1);
+        self.regs.y = y;
+        self.set_zero_neg(y);
+    }
+
+    fn iny(&mut self, _: Mode) {
+        let y = self.regs.y.wrapping_add(1);
+        self.regs.y = y;
+        self.set_zero_neg(y);
+    }
+
+    fn tsx(&mut self, _: Mode) {
+        let sp = self.regs.sp;
+        self.regs.x = sp;
+        self.set_zero_neg(sp);
+    }
+
+    fn txs(&mut self, _: Mode) {
+        let x = self.regs.x;
+        self.regs.sp = x;
+    }
+
+    fn pha(&mut self, _: Mode) {
+        let acc = self.regs.acc;
+        self.push(acc);
+    }
+
+    fn pla(&mut self, _: Mode) {
+        let acc = self.pop();
+        self.regs.acc = acc;
+        self.set_zero_neg(acc);
+    }
+
+    fn php(&mut self, _: Mode) {
+        let flags = self.regs.flags.as_byte() | 0b10000;
+        self.push(flags);
+    }
+
+    fn plp(&mut self, _: Mode) {
+        self.pull_status();
     }
 
     fn push(&mut self, val: u8) {
@@ -654,19 +1052,111 @@ impl Cpu {
         self.regs.flags.set_zero(val == 0);
     }
 
-    pub fn step(&mut self) -> u16 {
-        let byte = self.ld8_pc_up();
-        self.cycle_count += CYCLES[byte as usize] as u16;
-        self.execute_op(byte);
+    // Errors are reported once, from the step that hit them. A jammed CPU
+    // keeps clocking the rest of the system afterwards so frames still come
+    // out until the frontend resets it
+    pub fn step(&mut self) -> Result<u16, CpuError> {
+        #[cfg(feature = "cached-core")]
+        {
+            if let Some(mut cache) = self.block_cache.take() {
+                let res = self.step_block(&mut cache);
+                self.block_cache = Some(cache);
+                return res;
+            }
+        }
+        self.step_op()
+    }
+
+    fn step_op(&mut self) -> Result<u16, CpuError> {
+        if let CpuState::Jammed(_) = self.state {
+            self.mmu.tick();
+            return Ok(1);
+        }
+        let pc = self.regs.pc.get_addr();
+        if !self.nmi_pending
+            && !self.irq_pending
+            && self.mmu.debugger.break_on_exec(pc, self.call_stack.depth())
+        {
+            return Ok(0);
+        }
+        let start = self.mmu.cycles;
+        // The interrupt sequence itself doesn't poll, so the first
+        // instruction of a handler always runs
+        let mut res = Ok(());
+        let poll = if self.nmi_pending {
+            self.nmi_pending = false;
+            self.proc_nmi();
+            None
+        } else if self.irq_pending {
+            self.irq_pending = false;
+            self.proc_irq();
+            None
+        } else {
+            self.trace();
+            let byte = self.ld8_pc_up();
+            self.cycle_count += DECODE[byte as usize].cycles as u16;
+            let itr = self.regs.flags.itr();
+            let prg_offset = match self.profiler {
+                Some(_) => self.mmu.cartridge.borrow().prg_offset(pc),
+                None => None,
+            };
+            res = self.execute_op(byte);
+            Some((byte, itr, prg_offset))
+        };
+        let tmp = self.finish_cycles(start);
+        if let Some((op, itr, prg_offset)) = poll {
+            self.poll_interrupts(op, itr);
+            if let Some(ref mut profiler) = self.profiler {
+                profiler.record(op, pc, prg_offset, tmp);
+            }
+        }
+        res.map(|_| tmp)
+    }
+
+    // Internal cycles don't touch the bus, so clock the rest of the system
+    // for whatever the instruction didn't spend on loads/stores. DMC DMA
+    // stalls come on top of the instruction's own cycles
+    fn finish_cycles(&mut self, start: u64) -> u16 {
+        let stalled = self.mmu.take_stalled();
+        let elapsed = (self.mmu.cycles - start) as u16 - stalled;
+        for _ in elapsed..self.cycle_count {
+            self.mmu.tick();
+        }
+        self.cycle_count += stalled;
         let tmp = self.cycle_count;
         if log_enabled!(Level::Debug) {
-            debug!("{:?} CYC:{}", self.regs.clone(), self.cc);
-            self.cc += tmp as usize;
+            debug!("{:?} CYC:{}", self.regs.clone(), start);
         }
         self.cycle_count = 0;
         tmp
     }
 
+    fn trace(&mut self) {
+        if let Some(mut tracer) = self.tracer.take() {
+            match tracer.trace(self) {
+                Ok(()) => self.tracer = Some(tracer),
+                Err(e) => warn!("Disabling trace, failed to write: {}", e),
+            }
+        }
+    }
+
+    // CLI, SEI and PLP change the I flag after the poll on their last cycle,
+    // so an IRQ they enable (or mask) only takes effect one instruction late.
+    // RTI restores the flags before polling, so it takes effect immediately
+    fn poll_interrupts(&mut self, op: u8, itr_before: bool) {
+        let itr = match op {
+            // Like the hardware interrupt sequence, BRK never polls, so an
+            // NMI that was too late to hijack it waits for the next opcode
+            BRK => return,
+            CLI | SEI | PLP => itr_before,
+            _ => self.regs.flags.itr(),
+        };
+        if self.mmu.poll_nmi() {
+            self.nmi_pending = true;
+        }
+        self.irq_pending = self.mmu.poll_irq() && !itr;
+    }
+
     fn ld8_pc_up(&mut self) -> u8 {
         let ram_ptr = self.regs.pc.get_addr();
         self.regs.pc.add_unsigned(1);
@@ -679,314 +1169,313 @@ impl Cpu {
         self.mmu.ld16(ram_ptr)
     }
 
-    pub fn execute_op(&mut self, op: u8) {
-        match op {
-            INC_ABSX => self.inc(Mode::AbsX),
-            INC_ZPX => self.inc(Mode::ZPX),
-            INC_ABS => self.inc(Mode::Abs),
-            INC_ZP => self.inc(Mode::ZP),
-            SBC_IMM | 0xEB => self.sbc(Mode::Imm),
-            SBC_ABSX => self.sbc(Mode::AbsX),
-            SBC_ABSY => self.sbc(Mode::AbsY),
-            SBC_ABS => self.sbc(Mode::Abs),
-            SBC_INDY => self.sbc(Mode::IndY),
-            SBC_INDX => self.sbc(Mode::IndX),
-            SBC_ZPX => self.sbc(Mode::ZPX),
-            SBC_ZP => self.sbc(Mode::ZP),
-            CPX_IMM => self.cpx(Mode::Imm),
-            CPX_ABS => self.cpx(Mode::Abs),
-            CPX_ZP => self.cpx(Mode::ZP),
-            LDX_IMM => self.ldx(Mode::Imm),
-            LDX_ZPY => self.ldx(Mode::ZPY),
-            LDX_ABS => self.ldx(Mode::Abs),
-            LDX_ABSY => self.ldx(Mode::AbsY),
-            LDX_ZP => self.ldx(Mode::ZP),
-            DEC_ZPX => self.dec(Mode::ZPX),
-            DEC_ABS => self.dec(Mode::Abs),
-            DEC_ABSX => self.dec(Mode::AbsX),
-            DEC_ZP => self.dec(Mode::ZP),
-            CMP_IMM => self.cmp(Mode::Imm),
-            CMP_ABSX => self.cmp(Mode::AbsX),
-            CMP_ABSY => self.cmp(Mode::AbsY),
-            CMP_ZPX => self.cmp(Mode::ZPX),
-            CMP_INDY => self.cmp(Mode::IndY),
-            CMP_ABS => self.cmp(Mode::Abs),
-            CMP_ZP => self.cmp(Mode::ZP),
-            CMP_INDX => self.cmp(Mode::IndX),
-            CPY_IMM => self.cpy(Mode::Imm),
-            CPY_ZP => self.cpy(Mode::ZP),
-            CPY_ABS => self.cpy(Mode::Abs),
-            LDA_IMM => self.lda(Mode::Imm),
-            LDA_ABSX => self.lda(Mode::AbsX),
-            LDA_ABSY => self.lda(Mode::AbsY),
-            LDA_ZPX => self.lda(Mode::ZPX),
-            LDA_INDY => self.lda(Mode::IndY),
-            LDA_ABS => self.lda(Mode::Abs),
-            LDA_ZP => self.lda(Mode::ZP),
-            LDA_INDX => self.lda(Mode::IndX),
-            LDY_IMM => self.ldy(Mode::Imm),
-            LDY_ZPX => self.ldy(Mode::ZPX),
-            LDY_ABS => self.ldy(Mode::Abs),
-            LDY_ABSX => self.ldy(Mode::AbsX),
-            LDY_ZP => self.ldy(Mode::ZP),
-            STA_ABSX => self.sta(Mode::NoPBAbsX),
-            STA_ABSY => self.sta(Mode::NoPBAbsY),
-            STA_ZPX => self.sta(Mode::ZPX),
-            STA_INDY => self.sta(Mode::NoPBIndY),
-            STA_ABS => self.sta(Mode::Abs),
-            STA_ZP => self.sta(Mode::ZP),
-            STA_INDX => self.sta(Mode::IndX),
-            STX_ABS => self.stx(Mode::Abs),
-            STX_ZP => self.stx(Mode::ZP),
-            STX_ZPY => self.stx(Mode::ZPY),
-            STY_ABS => self.sty(Mode::Abs),
-            STY_ZP => self.sty(Mode::ZP),
-            STY_ZPX => self.sty(Mode::ZPX),
-            ADC_IMM => self.adc(Mode::Imm),
-            ADC_ABSX => self.adc(Mode::AbsX),
-            ADC_ABSY => self.adc(Mode::AbsY),
-            ADC_ZPX => self.adc(Mode::ZPX),
-            ADC_INDY => self.adc(Mode::IndY),
-            ADC_ABS => self.adc(Mode::Abs),
-            ADC_ZP => self.adc(Mode::ZP),
-            ADC_INDX => self.adc(Mode::IndX),
-            ROR_ABSX => self.ror_addr(Mode::NoPBAbsX),
-            ROR_ZPX => self.ror_addr(Mode::ZPX),
-            ROR_ZP => self.ror_addr(Mode::ZP),
-            ROR_ABS => self.ror_addr(Mode::Abs),
-            ROR_ACC => self.ror_acc(),
-            EOR_IMM => self.eor(Mode::Imm),
-            EOR_ABSX => self.eor(Mode::AbsX),
-            EOR_ABSY => self.eor(Mode::AbsY),
-            EOR_ZPX => self.eor(Mode::ZPX),
-            EOR_INDY => self.eor(Mode::IndY),
-            EOR_ABS => self.eor(Mode::Abs),
-            EOR_ZP => self.eor(Mode::ZP),
-            EOR_INDX => self.eor(Mode::IndX),
-            LSR_ABSX => self.lsr_addr(Mode::AbsX),
-            LSR_ZPX => self.lsr_addr(Mode::ZPX),
-            LSR_ABS => self.lsr_addr(Mode::Abs),
-            LSR_ZP => self.lsr_addr(Mode::ZP),
-            LSR_ACC => self.lsr_acc(),
-            JMP_ABS => self.jmp(Mode::Abs),
-            ROL_ABS => self.rol_addr(Mode::Abs),
-            ROL_ABSX => self.rol_addr(Mode::NoPBAbsX),
-            ROL_ZPX => self.rol_addr(Mode::ZPX),
-            ROL_ZP => self.rol_addr(Mode::ZP),
-            ROL_ACC => self.rol_acc(),
-            AND_IMM => self.and(Mode::Imm),
-            AND_ZP => self.and(Mode::ZP),
-            AND_ABSX => self.and(Mode::AbsX),
-            AND_ABSY => self.and(Mode::AbsY),
-            AND_INDY => self.and(Mode::IndY),
-            AND_ABS => self.and(Mode::Abs),
-            AND_INDX => self.and(Mode::IndX),
-            AND_ZPX => self.and(Mode::ZPX),
-            BIT_ABS => self.bit(Mode::Abs),
-            BIT_ZP => self.bit(Mode::ZP),
-            ORA_IMM => self.ora(Mode::Imm),
-            ORA_ABSX => self.ora(Mode::AbsX),
-            ORA_ABSY => self.ora(Mode::AbsY),
-            ORA_ZPX => self.ora(Mode::ZPX),
-            ORA_INDY => self.ora(Mode::IndY),
-            ORA_ABS => self.ora(Mode::Abs),
-            ORA_ZP => self.ora(Mode::ZP),
-            ORA_INDX => self.ora(Mode::IndX),
-            ASL_ABSX => self.asl_addr(Mode::NoPBAbsX),
-            ASL_ABS => self.asl_addr(Mode::Abs),
-            ASL_ZP => self.asl_addr(Mode::ZP),
-            ASL_ZPX => self.asl_addr(Mode::ZPX),
-            ASL_ACC => self.asl_acc(),
-            0x0B | 0x2B => self.aac(Mode::Imm),
-            0x87 => self.aax(Mode::ZP),
-            0x97 => self.aax(Mode::ZPY),
-            0x83 => self.aax(Mode::IndX),
-            0x8F => self.aax(Mode::Abs),
-            0x6B => self.arr(Mode::Imm),
-            0xA7 => self.lax(Mode::ZP),
-            0xB7 => self.lax(Mode::ZPY),
-            0xAF => self.lax(Mode::Abs),
-            0xBF => self.lax(Mode::AbsY),
-            0xA3 => self.lax(Mode::IndX),
-            0xB3 => self.lax(Mode::IndY),
-            0xC7 => self.dcp(Mode::ZP),
-            0xD7 => self.dcp(Mode::ZPX),
-            0xCF => self.dcp(Mode::Abs),
-            0xDF => self.dcp(Mode::NoPBAbsX),
-            0xDB => self.dcp(Mode::NoPBAbsY),
-            0xC3 => self.dcp(Mode::IndX),
-            0xD3 => self.dcp(Mode::NoPBIndY),
-            0xE7 => self.isc(Mode::ZP),
-            0xF7 => self.isc(Mode::ZPX),
-            0xEF => self.isc(Mode::Abs),
-            0xFF => self.isc(Mode::NoPBAbsX),
-            0xFB => self.isc(Mode::NoPBAbsY),
-            0xE3 => self.isc(Mode::IndX),
-            0xF3 => self.isc(Mode::NoPBIndY),
-            0x07 => self.slo(Mode::ZP),
-            0x17 => self.slo(Mode::ZPX),
-            0x0F => self.slo(Mode::Abs),
-            0x1F => self.slo(Mode::NoPBAbsX),
-            0x1B => self.slo(Mode::NoPBAbsY),
-            0x03 => self.slo(Mode::IndX),
-            0x13 => self.slo(Mode::NoPBIndY),
-            0x27 => self.rla(Mode::ZP),
-            0x37 => self.rla(Mode::ZPX),
-            0x2F => self.rla(Mode::Abs),
-            0x3F => self.rla(Mode::NoPBAbsX),
-            0x3B => self.rla(Mode::NoPBAbsY),
-            0x23 => self.rla(Mode::IndX),
-            0x33 => self.rla(Mode::NoPBIndY),
-            0x47 => self.sre(Mode::ZP),
-            0x57 => self.sre(Mode::ZPX),
-            0x4F => self.sre(Mode::Abs),
-            0x5F => self.sre(Mode::NoPBAbsX),
-            0x5B => self.sre(Mode::NoPBAbsY),
-            0x53 => self.sre(Mode::NoPBIndY),
-            0x43 => self.sre(Mode::IndX),
-            0x67 => self.rra(Mode::ZP),
-            0x77 => self.rra(Mode::ZPX),
-            0x6F => self.rra(Mode::Abs),
-            0x7F => self.rra(Mode::NoPBAbsX),
-            0x7B => self.rra(Mode::NoPBAbsY),
-            0x63 => self.rra(Mode::IndX),
-            0x73 => self.rra(Mode::NoPBIndY),
-            0x4B => self.alr(Mode::Imm),
-            0x9C => self.sya(Mode::NoPBAbsX),
-            0x9E => self.sxa(Mode::NoPBAbsY),
-            0xAB => self.atx(),
-            0xCB => self.axs(),
-            RTS => {
-                self.pull_pc();
-                self.regs.pc.add_unsigned(1);
-            }
-            RTI => {
-                self.pull_status();
-                self.pull_pc();
-            }
-            SED => self.regs.flags.set_dec(true),
-            CLC => self.regs.flags.set_carry(false),
-            SEC => self.regs.flags.set_carry(true),
-            CLI => self.regs.flags.set_itr(false),
-            SEI => self.regs.flags.set_itr(true),
-            CLV => self.regs.flags.set_overflow(false),
-            CLD => self.regs.flags.set_dec(false),
-            NOP | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => (),
-            // DOP: Double NOP
-            0x14 | 0x34 | 0x44 | 0x54 | 0x64 | 0x74 | 0x80 | 0x82 | 0x89
-            | 0xC2 | 0xD4 | 0xE2 | 0xF4 | 0x04 => {
-                self.regs.pc.add_signed(1);
-            }
-            // TOP: Triple NOP
-            0x0C => self.regs.pc.add_signed(2),
-            0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {
-                let _ = self.address_mem(Mode::AbsX);
-            }
-            BRK => {
-                self.regs.pc.add_signed(1);
-                self.push_pc();
-                self.push(self.regs.flags.as_byte() | 0b10000);
-                self.regs.flags.set_itr(true);
-                self.regs.pc.set_addr(self.mmu.ld16(IRQ_VEC));
-            }
-            TAX => self.tax(),
-            TXA => {
-                let x = self.regs.x;
-                self.regs.acc = x;
-                self.set_zero_neg(x);
-            }
-            TAY => {
-                let acc = self.regs.acc;
-                self.regs.y = acc;
-                self.set_zero_neg(acc);
-            }
-            TYA => {
-                let y = self.regs.y;
-                self.regs.acc = y;
-                self.set_zero_neg(y);
-            }
-            DEX => {
-                let x = self.regs.x.wrapping_sub(1);
-                self.regs.x = x;
-                self.set_zero_neg(x);
-            }
-            INX => {
-                let x = self.regs.x.wrapping_add(1);
-                self.regs.x = x;
-                self.set_zero_neg(x);
-            }
-            DEY => {
-                let y = self.regs.y.wrapping_sub(1);
-                self.regs.y = y;
-                self.set_zero_neg(y);
-            }
-            INY => {
-                let y = self.regs.y.wrapping_add(1);
-                self.regs.y = y;
-                self.set_zero_neg(y);
-            }
-            TSX => {
-                let sp = self.regs.sp;
-                self.regs.x = sp;
-                self.set_zero_neg(sp);
-            }
-            TXS => {
-                let x = self.regs.x;
-                self.regs.sp = x;
-            }
-            PHA => {
-                let acc = self.regs.acc;
-                self.push(acc);
-            }
-            PLA => {
-                let acc = self.pop();
-                self.regs.acc = acc;
-                self.set_zero_neg(acc);
-            }
-            PHP => {
-                self.push(self.regs.flags.as_byte() | 0b10000);
-            }
-            PLP => self.pull_status(),
-            BVS => {
-                let flag = self.regs.flags.overflow();
-                self.generic_branch(flag);
-            }
-            BVC => {
-                let flag = !self.regs.flags.overflow();
-                self.generic_branch(flag);
-            }
-            BMI => {
-                let flag = self.regs.flags.neg();
-                self.generic_branch(flag);
-            }
-            BPL => {
-                let flag = !self.regs.flags.neg();
-                self.generic_branch(flag);
-            }
-            BNE => {
-                let flag = !self.regs.flags.zero();
-                self.generic_branch(flag);
-            }
-            BEQ => {
-                let flag = self.regs.flags.zero();
-                self.generic_branch(flag);
-            }
-            BCS => {
-                let flag = self.regs.flags.carry();
-                self.generic_branch(flag);
-            }
-            BCC => {
-                let flag = !self.regs.flags.carry();
-                self.generic_branch(flag);
-            }
-            JSR => {
-                let addr = self.address_mem(Mode::Abs);
+    pub fn execute_op(&mut self, op: u8) -> Result<(), CpuError> {
+        self.execute_instr(op, &DECODE[op as usize])
+    }
+
+    fn execute_instr(&mut self, op: u8, instr: &Instr) -> Result<(), CpuError> {
+        match instr.exec {
+            Exec::Op(handler) => handler(self, instr.mode),
+            // KIL: Locks up the CPU with PC left on the opcode
+            Exec::Kil => {
                 self.regs.pc.add_signed(-1);
-                self.push_pc();
-                self.regs.pc.set_addr(addr);
+                self.state = CpuState::Jammed(op);
+                let pc = self.regs.pc.get_addr();
+                return Err(CpuError::Jammed(op, pc));
             }
-            JMP_IND => self.jmp(Mode::JmpIndir),
-            _ => panic!("Unsupported op {:X} {:?}", op, self.regs),
         }
+        Ok(())
     }
 }
+
+#[derive(Copy, Clone)]
+enum Exec {
+    Op(fn(&mut Cpu, Mode)),
+    Kil,
+}
+
+// One entry of the opcode decode table. The cycle count is the base cost,
+// page crossings, taken branches and DMA add to it as the op runs
+#[derive(Copy, Clone)]
+struct Instr {
+    exec: Exec,
+    mode: Mode,
+    cycles: u8,
+}
+
+impl Instr {
+    const fn op(handler: fn(&mut Cpu, Mode), mode: Mode, cycles: u8) -> Instr {
+        Instr {
+            exec: Exec::Op(handler),
+            mode,
+            cycles,
+        }
+    }
+
+    const fn kil(cycles: u8) -> Instr {
+        Instr {
+            exec: Exec::Kil,
+            mode: Mode::Imp,
+            cycles,
+        }
+    }
+}
+
+static DECODE: [Instr; 256] = [
+    Instr::op(Cpu::brk, Mode::Imp, 7), // 00
+    Instr::op(Cpu::ora, Mode::IndX, 6), // 01
+    Instr::kil(2), // 02
+    Instr::op(Cpu::slo, Mode::IndX, 8), // 03
+    Instr::op(Cpu::ign, Mode::ZP, 3), // 04
+    Instr::op(Cpu::ora, Mode::ZP, 3), // 05
+    Instr::op(Cpu::asl_addr, Mode::ZP, 5), // 06
+    Instr::op(Cpu::slo, Mode::ZP, 5), // 07
+    Instr::op(Cpu::php, Mode::Imp, 3), // 08
+    Instr::op(Cpu::ora, Mode::Imm, 2), // 09
+    Instr::op(Cpu::asl_acc, Mode::Imp, 2), // 0A
+    Instr::op(Cpu::aac, Mode::Imm, 2), // 0B
+    Instr::op(Cpu::ign, Mode::Abs, 4), // 0C
+    Instr::op(Cpu::ora, Mode::Abs, 4), // 0D
+    Instr::op(Cpu::asl_addr, Mode::Abs, 6), // 0E
+    Instr::op(Cpu::slo, Mode::Abs, 6), // 0F
+    Instr::op(Cpu::bpl, Mode::Imm, 2), // 10
+    Instr::op(Cpu::ora, Mode::IndY, 5), // 11
+    Instr::kil(2), // 12
+    Instr::op(Cpu::slo, Mode::NoPBIndY, 8), // 13
+    Instr::op(Cpu::ign, Mode::ZPX, 4), // 14
+    Instr::op(Cpu::ora, Mode::ZPX, 4), // 15
+    Instr::op(Cpu::asl_addr, Mode::ZPX, 6), // 16
+    Instr::op(Cpu::slo, Mode::ZPX, 6), // 17
+    Instr::op(Cpu::clc, Mode::Imp, 2), // 18
+    Instr::op(Cpu::ora, Mode::AbsY, 4), // 19
+    Instr::op(Cpu::nop, Mode::Imp, 2), // 1A
+    Instr::op(Cpu::slo, Mode::NoPBAbsY, 7), // 1B
+    Instr::op(Cpu::ign, Mode::AbsX, 4), // 1C
+    Instr::op(Cpu::ora, Mode::AbsX, 4), // 1D
+    Instr::op(Cpu::asl_addr, Mode::NoPBAbsX, 7), // 1E
+    Instr::op(Cpu::slo, Mode::NoPBAbsX, 7), // 1F
+    Instr::op(Cpu::jsr, Mode::Abs, 6), // 20
+    Instr::op(Cpu::and, Mode::IndX, 6), // 21
+    Instr::kil(2), // 22
+    Instr::op(Cpu::rla, Mode::IndX, 8), // 23
+    Instr::op(Cpu::bit, Mode::ZP, 3), // 24
+    Instr::op(Cpu::and, Mode::ZP, 3), // 25
+    Instr::op(Cpu::rol_addr, Mode::ZP, 5), // 26
+    Instr::op(Cpu::rla, Mode::ZP, 5), // 27
+    Instr::op(Cpu::plp, Mode::Imp, 4), // 28
+    Instr::op(Cpu::and, Mode::Imm, 2), // 29
+    Instr::op(Cpu::rol_acc, Mode::Imp, 2), // 2A
+    Instr::op(Cpu::aac, Mode::Imm, 2), // 2B
+    Instr::op(Cpu::bit, Mode::Abs, 4), // 2C
+    Instr::op(Cpu::and, Mode::Abs, 4), // 2D
+    Instr::op(Cpu::rol_addr, Mode::Abs, 6), // 2E
+    Instr::op(Cpu::rla, Mode::Abs, 6), // 2F
+    Instr::op(Cpu::bmi, Mode::Imm, 2), // 30
+    Instr::op(Cpu::and, Mode::IndY, 5), // 31
+    Instr::kil(2), // 32
+    Instr::op(Cpu::rla, Mode::NoPBIndY, 8), // 33
+    Instr::op(Cpu::ign, Mode::ZPX, 4), // 34
+    Instr::op(Cpu::and, Mode::ZPX, 4), // 35
+    Instr::op(Cpu::rol_addr, Mode::ZPX, 6), // 36
+    Instr::op(Cpu::rla, Mode::ZPX, 6), // 37
+    Instr::op(Cpu::sec, Mode::Imp, 2), // 38
+    Instr::op(Cpu::and, Mode::AbsY, 4), // 39
+    Instr::op(Cpu::nop, Mode::Imp, 2), // 3A
+    Instr::op(Cpu::rla, Mode::NoPBAbsY, 7), // 3B
+    Instr::op(Cpu::ign, Mode::AbsX, 4), // 3C
+    Instr::op(Cpu::and, Mode::AbsX, 4), // 3D
+    Instr::op(Cpu::rol_addr, Mode::NoPBAbsX, 7), // 3E
+    Instr::op(Cpu::rla, Mode::NoPBAbsX, 7), // 3F
+    Instr::op(Cpu::rti, Mode::Imp, 6), // 40
+    Instr::op(Cpu::eor, Mode::IndX, 6), // 41
+    Instr::kil(2), // 42
+    Instr::op(Cpu::sre, Mode::IndX, 8), // 43
+    Instr::op(Cpu::ign, Mode::ZP, 3), // 44
+    Instr::op(Cpu::eor, Mode::ZP, 3), // 45
+    Instr::op(Cpu::lsr_addr, Mode::ZP, 5), // 46
+    Instr::op(Cpu::sre, Mode::ZP, 5), // 47
+    Instr::op(Cpu::pha, Mode::Imp, 3), // 48
+    Instr::op(Cpu::eor, Mode::Imm, 2), // 49
+    Instr::op(Cpu::lsr_acc, Mode::Imp, 2), // 4A
+    Instr::op(Cpu::alr, Mode::Imm, 2), // 4B
+    Instr::op(Cpu::jmp, Mode::Abs, 3), // 4C
+    Instr::op(Cpu::eor, Mode::Abs, 4), // 4D
+    Instr::op(Cpu::lsr_addr, Mode::Abs, 6), // 4E
+    Instr::op(Cpu::sre, Mode::Abs, 6), // 4F
+    Instr::op(Cpu::bvc, Mode::Imm, 2), // 50
+    Instr::op(Cpu::eor, Mode::IndY, 5), // 51
+    Instr::kil(2), // 52
+    Instr::op(Cpu::sre, Mode::NoPBIndY, 8), // 53
+    Instr::op(Cpu::ign, Mode::ZPX, 4), // 54
+    Instr::op(Cpu::eor, Mode::ZPX, 4), // 55
+    Instr::op(Cpu::lsr_addr, Mode::ZPX, 6), // 56
+    Instr::op(Cpu::sre, Mode::ZPX, 6), // 57
+    Instr::op(Cpu::cli, Mode::Imp, 2), // 58
+    Instr::op(Cpu::eor, Mode::AbsY, 4), // 59
+    Instr::op(Cpu::nop, Mode::Imp, 2), // 5A
+    Instr::op(Cpu::sre, Mode::NoPBAbsY, 7), // 5B
+    Instr::op(Cpu::ign, Mode::AbsX, 4), // 5C
+    Instr::op(Cpu::eor, Mode::AbsX, 4), // 5D
+    Instr::op(Cpu::lsr_addr, Mode::NoPBAbsX, 7), // 5E
+    Instr::op(Cpu::sre, Mode::NoPBAbsX, 7), // 5F
+    Instr::op(Cpu::rts, Mode::Imp, 6), // 60
+    Instr::op(Cpu::adc, Mode::IndX, 6), // 61
+    Instr::kil(2), // 62
+    Instr::op(Cpu::rra, Mode::IndX, 8), // 63
+    Instr::op(Cpu::ign, Mode::ZP, 3), // 64
+    Instr::op(Cpu::adc, Mode::ZP, 3), // 65
+    Instr::op(Cpu::ror_addr, Mode::ZP, 5), // 66
+    Instr::op(Cpu::rra, Mode::ZP, 5), // 67
+    Instr::op(Cpu::pla, Mode::Imp, 4), // 68
+    Instr::op(Cpu::adc, Mode::Imm, 2), // 69
+    Instr::op(Cpu::ror_acc, Mode::Imp, 2), // 6A
+    Instr::op(Cpu::arr, Mode::Imm, 2), // 6B
+    Instr::op(Cpu::jmp, Mode::JmpIndir, 5), // 6C
+    Instr::op(Cpu::adc, Mode::Abs, 4), // 6D
+    Instr::op(Cpu::ror_addr, Mode::Abs, 6), // 6E
+    Instr::op(Cpu::rra, Mode::Abs, 6), // 6F
+    Instr::op(Cpu::bvs, Mode::Imm, 2), // 70
+    Instr::op(Cpu::adc, Mode::IndY, 5), // 71
+    Instr::kil(2), // 72
+    Instr::op(Cpu::rra, Mode::NoPBIndY, 8), // 73
+    Instr::op(Cpu::ign, Mode::ZPX, 4), // 74
+    Instr::op(Cpu::adc, Mode::ZPX, 4), // 75
+    Instr::op(Cpu::ror_addr, Mode::ZPX, 6), // 76
+    Instr::op(Cpu::rra, Mode::ZPX, 6), // 77
+    Instr::op(Cpu::sei, Mode::Imp, 2), // 78
+    Instr::op(Cpu::adc, Mode::AbsY, 4), // 79
+    Instr::op(Cpu::nop, Mode::Imp, 2), // 7A
+    Instr::op(Cpu::rra, Mode::NoPBAbsY, 7), // 7B
+    Instr::op(Cpu::ign, Mode::AbsX, 4), // 7C
+    Instr::op(Cpu::adc, Mode::AbsX, 4), // 7D
+    Instr::op(Cpu::ror_addr, Mode::NoPBAbsX, 7), // 7E
+    Instr::op(Cpu::rra, Mode::NoPBAbsX, 7), // 7F
+    Instr::op(Cpu::ign, Mode::Imm, 2), // 80
+    Instr::op(Cpu::sta, Mode::IndX, 6), // 81
+    Instr::op(Cpu::ign, Mode::Imm, 2), // 82
+    Instr::op(Cpu::aax, Mode::IndX, 6), // 83
+    Instr::op(Cpu::sty, Mode::ZP, 3), // 84
+    Instr::op(Cpu::sta, Mode::ZP, 3), // 85
+    Instr::op(Cpu::stx, Mode::ZP, 3), // 86
+    Instr::op(Cpu::aax, Mode::ZP, 3), // 87
+    Instr::op(Cpu::dey, Mode::Imp, 2), // 88
+    Instr::op(Cpu::ign, Mode::Imm, 2), // 89
+    Instr::op(Cpu::txa, Mode::Imp, 2), // 8A
+    Instr::op(Cpu::xaa, Mode::Imm, 2), // 8B
+    Instr::op(Cpu::sty, Mode::Abs, 4), // 8C
+    Instr::op(Cpu::sta, Mode::Abs, 4), // 8D
+    Instr::op(Cpu::stx, Mode::Abs, 4), // 8E
+    Instr::op(Cpu::aax, Mode::Abs, 4), // 8F
+    Instr::op(Cpu::bcc, Mode::Imm, 2), // 90
+    Instr::op(Cpu::sta, Mode::NoPBIndY, 6), // 91
+    Instr::kil(2), // 92
+    Instr::op(Cpu::ahx, Mode::NoPBIndY, 6), // 93
+    Instr::op(Cpu::sty, Mode::ZPX, 4), // 94
+    Instr::op(Cpu::sta, Mode::ZPX, 4), // 95
+    Instr::op(Cpu::stx, Mode::ZPY, 4), // 96
+    Instr::op(Cpu::aax, Mode::ZPY, 4), // 97
+    Instr::op(Cpu::tya, Mode::Imp, 2), // 98
+    Instr::op(Cpu::sta, Mode::NoPBAbsY, 5), // 99
+    Instr::op(Cpu::txs, Mode::Imp, 2), // 9A
+    Instr::op(Cpu::tas, Mode::NoPBAbsY, 5), // 9B
+    Instr::op(Cpu::shy, Mode::NoPBAbsX, 5), // 9C
+    Instr::op(Cpu::sta, Mode::NoPBAbsX, 5), // 9D
+    Instr::op(Cpu::shx, Mode::NoPBAbsY, 5), // 9E
+    Instr::op(Cpu::ahx, Mode::NoPBAbsY, 5), // 9F
+    Instr::op(Cpu::ldy, Mode::Imm, 2), // A0
+    Instr::op(Cpu::lda, Mode::IndX, 6), // A1
+    Instr::op(Cpu::ldx, Mode::Imm, 2), // A2
+    Instr::op(Cpu::lax, Mode::IndX, 6), // A3
+    Instr::op(Cpu::ldy, Mode::ZP, 3), // A4
+    Instr::op(Cpu::lda, Mode::ZP, 3), // A5
+    Instr::op(Cpu::ldx, Mode::ZP, 3), // A6
+    Instr::op(Cpu::lax, Mode::ZP, 3), // A7
+    Instr::op(Cpu::tay, Mode::Imp, 2), // A8
+    Instr::op(Cpu::lda, Mode::Imm, 2), // A9
+    Instr::op(Cpu::tax, Mode::Imp, 2), // AA
+    Instr::op(Cpu::atx, Mode::Imm, 2), // AB
+    Instr::op(Cpu::ldy, Mode::Abs, 4), // AC
+    Instr::op(Cpu::lda, Mode::Abs, 4), // AD
+    Instr::op(Cpu::ldx, Mode::Abs, 4), // AE
+    Instr::op(Cpu::lax, Mode::Abs, 4), // AF
+    Instr::op(Cpu::bcs, Mode::Imm, 2), // B0
+    Instr::op(Cpu::lda, Mode::IndY, 5), // B1
+    Instr::kil(2), // B2
+    Instr::op(Cpu::lax, Mode::IndY, 5), // B3
+    Instr::op(Cpu::ldy, Mode::ZPX, 4), // B4
+    Instr::op(Cpu::lda, Mode::ZPX, 4), // B5
+    Instr::op(Cpu::ldx, Mode::ZPY, 4), // B6
+    Instr::op(Cpu::lax, Mode::ZPY, 4), // B7
+    Instr::op(Cpu::clv, Mode::Imp, 2), // B8
+    Instr::op(Cpu::lda, Mode::AbsY, 4), // B9
+    Instr::op(Cpu::tsx, Mode::Imp, 2), // BA
+    Instr::op(Cpu::las, Mode::AbsY, 4), // BB
+    Instr::op(Cpu::ldy, Mode::AbsX, 4), // BC
+    Instr::op(Cpu::lda, Mode::AbsX, 4), // BD
+    Instr::op(Cpu::ldx, Mode::AbsY, 4), // BE
+    Instr::op(Cpu::lax, Mode::AbsY, 4), // BF
+    Instr::op(Cpu::cpy, Mode::Imm, 2), // C0
+    Instr::op(Cpu::cmp, Mode::IndX, 6), // C1
+    Instr::op(Cpu::ign, Mode::Imm, 2), // C2
+    Instr::op(Cpu::dcp, Mode::IndX, 8), // C3
+    Instr::op(Cpu::cpy, Mode::ZP, 3), // C4
+    Instr::op(Cpu::cmp, Mode::ZP, 3), // C5
+    Instr::op(Cpu::dec, Mode::ZP, 5), // C6
+    Instr::op(Cpu::dcp, Mode::ZP, 5), // C7
+    Instr::op(Cpu::iny, Mode::Imp, 2), // C8
+    Instr::op(Cpu::cmp, Mode::Imm, 2), // C9
+    Instr::op(Cpu::dex, Mode::Imp, 2), // CA
+    Instr::op(Cpu::axs, Mode::Imm, 2), // CB
+    Instr::op(Cpu::cpy, Mode::Abs, 4), // CC
+    Instr::op(Cpu::cmp, Mode::Abs, 4), // CD
+    Instr::op(Cpu::dec, Mode::Abs, 6), // CE
+    Instr::op(Cpu::dcp, Mode::Abs, 6), // CF
+    Instr::op(Cpu::bne, Mode::Imm, 2), // D0
+    Instr::op(Cpu::cmp, Mode::IndY, 5), // D1
+    Instr::kil(2), // D2
+    Instr::op(Cpu::dcp, Mode::NoPBIndY, 8), // D3
+    Instr::op(Cpu::ign, Mode::ZPX, 4), // D4
+    Instr::op(Cpu::cmp, Mode::ZPX, 4), // D5
+    Instr::op(Cpu::dec, Mode::ZPX, 6), // D6
+    Instr::op(Cpu::dcp, Mode::ZPX, 6), // D7
+    Instr::op(Cpu::cld, Mode::Imp, 2), // D8
+    Instr::op(Cpu::cmp, Mode::AbsY, 4), // D9
+    Instr::op(Cpu::nop, Mode::Imp, 2), // DA
+    Instr::op(Cpu::dcp, Mode::NoPBAbsY, 7), // DB
+    Instr::op(Cpu::ign, Mode::AbsX, 4), // DC
+    Instr::op(Cpu::cmp, Mode::AbsX, 4), // DD
+    Instr::op(Cpu::dec, Mode::NoPBAbsX, 7), // DE
+    Instr::op(Cpu::dcp, Mode::NoPBAbsX, 7), // DF
+    Instr::op(Cpu::cpx, Mode::Imm, 2), // E0
+    Instr::op(Cpu::sbc, Mode::IndX, 6), // E1
+    Instr::op(Cpu::ign, Mode::Imm, 2), // E2
+    Instr::op(Cpu::isc, Mode::IndX, 8), // E3
+    Instr::op(Cpu::cpx, Mode::ZP, 3), // E4
+    Instr::op(Cpu::sbc, Mode::ZP, 3), // E5
+    Instr::op(Cpu::inc, Mode::ZP, 5), // E6
+    Instr::op(Cpu::isc, Mode::ZP, 5), // E7
+    Instr::op(Cpu::inx, Mode::Imp, 2), // E8
+    Instr::op(Cpu::sbc, Mode::Imm, 2), // E9
+    Instr::op(Cpu::nop, Mode::Imp, 2), // EA
+    Instr::op(Cpu::sbc, Mode::Imm, 2), // EB
+    Instr::op(Cpu::cpx, Mode::Abs, 4), // EC
+    Instr::op(Cpu::sbc, Mode::Abs, 4), // ED
+    Instr::op(Cpu::inc, Mode::Abs, 6), // EE
+    Instr::op(Cpu::isc, Mode::Abs, 6), // EF
+    Instr::op(Cpu::beq, Mode::Imm, 2), // F0
+    Instr::op(Cpu::sbc, Mode::IndY, 5), // F1
+    Instr::kil(2), // F2
+    Instr::op(Cpu::isc, Mode::NoPBIndY, 8), // F3
+    Instr::op(Cpu::ign, Mode::ZPX, 4), // F4
+    Instr::op(Cpu::sbc, Mode::ZPX, 4), // F5
+    Instr::op(Cpu::inc, Mode::ZPX, 6), // F6
+    Instr::op(Cpu::isc, Mode::ZPX, 6), // F7
+    Instr::op(Cpu::sed, Mode::Imp, 2), // F8
+    Instr::op(Cpu::sbc, Mode::AbsY, 4), // F9
+    Instr::op(Cpu::nop, Mode::Imp, 2), // FA
+    Instr::op(Cpu::isc, Mode::NoPBAbsY, 7), // FB
+    Instr::op(Cpu::ign, Mode::AbsX, 4), // FC
+    Instr::op(Cpu::sbc, Mode::AbsX, 4), // FD
+    Instr::op(Cpu::inc, Mode::NoPBAbsX, 7), // FE
+    Instr::op(Cpu::isc, Mode::NoPBAbsX, 7), // FF
+];
@@ -0,0 +1,219 @@
+use cpu::Cpu;
+use cpu::CpuError;
+use cpu::CpuState;
+use cpu::Exec;
+use cpu::Instr;
+use cpu::DECODE;
+use cpu_const::*;
+use mmu::Mmu;
+use trace::Operand;
+use trace::OPS;
+
+// Blocks end on the first instruction that can jump, or after this many
+const MAX_BLOCK_OPS: usize = 32;
+// PRG ROM mapping is compared in 4KB windows from $8000 up
+const WINDOW_SIZE: u16 = 0x1000;
+const WINDOWS: usize = 8;
+// The low 8 bits are the pages of internal RAM, anything above them means
+// PRG RAM or a mapper register was written
+const RAM_REGIONS: u32 = 0xFF;
+
+// Writes are tracked per 2KB region of the address space, which is fine
+// grained enough to keep writes to RAM away from code in ROM. Internal RAM
+// is split up by page instead, since that's where self modifying code
+// shares space with the stack and zero page. Both fit in the same bits, as
+// $0000-$3FFF is never cached as a 2KB region
+pub fn region_bit(address: u16) -> u32 {
+    match address {
+        0x0000...0x1FFF => 1 << ((address & 0x7FF) >> 8),
+        0x4020...0xFFFF => 1 << (address >> 11),
+        _ => 0,
+    }
+}
+
+struct CachedOp {
+    op: u8,
+    instr: Instr,
+}
+
+struct Block {
+    ops: Vec<CachedOp>,
+    // Regions the block's bytes live in
+    regions: u32,
+}
+
+// Decodes straight line runs of 6502 code once and replays them, skipping
+// the opcode reads, decode and per instruction debugger checks. Every bus
+// access the instructions make still happens on the right cycle. Blocks are
+// thrown away when the code under them is written to or banked out
+pub struct BlockCache {
+    // Indexed by the address the block starts at
+    blocks: Vec<Option<Block>>,
+    len: usize,
+    // Start addresses of the blocks in each region
+    starts: Vec<Vec<u16>>,
+    // What PRG ROM each window was mapped to when the blocks were built
+    banks: [Option<usize>; WINDOWS],
+    hits: u64,
+    misses: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache {
+            blocks: (0..0x10000).map(|_| None).collect(),
+            len: 0,
+            starts: vec![Vec::new(); 32],
+            banks: [None; WINDOWS],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.drop_regions(!0);
+    }
+
+    fn drop_regions(&mut self, regions: u32) {
+        for (region, starts) in self.starts.iter_mut().enumerate() {
+            if regions & (1 << region) == 0 {
+                continue;
+            }
+            // Blocks spanning two regions are listed in both
+            for start in starts.drain(..) {
+                if self.blocks[start as usize].take().is_some() {
+                    self.len -= 1;
+                }
+            }
+        }
+    }
+
+    fn insert(&mut self, pc: u16, block: Block) {
+        for (region, starts) in self.starts.iter_mut().enumerate() {
+            if block.regions & (1 << region) != 0 {
+                starts.push(pc);
+            }
+        }
+        self.blocks[pc as usize] = Some(block);
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Blocks run from the cache and blocks that had to be decoded first
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    fn mapped_banks(mmu: &Mmu) -> [Option<usize>; WINDOWS] {
+        let cartridge = mmu.cartridge.borrow();
+        let mut banks = [None; WINDOWS];
+        for (i, bank) in banks.iter_mut().enumerate() {
+            *bank = cartridge.prg_offset(0x8000 + i as u16 * WINDOW_SIZE);
+        }
+        banks
+    }
+
+    // Drops whatever the writes since the last call made stale
+    fn sync(&mut self, mmu: &mut Mmu) {
+        let written = mmu.take_written();
+        if written & !RAM_REGIONS != 0 {
+            let banks = BlockCache::mapped_banks(mmu);
+            if banks != self.banks {
+                self.banks = banks;
+                self.clear();
+                return;
+            }
+        }
+        self.drop_regions(written);
+    }
+
+    fn compile(mmu: &Mmu, pc: u16) -> Block {
+        let mut ops = Vec::new();
+        let mut addr = pc;
+        let mut last = pc;
+        while ops.len() < MAX_BLOCK_OPS {
+            let op = mmu.peek(addr);
+            let instr = DECODE[op as usize];
+            let operand = OPS[op as usize].1;
+            last = addr.wrapping_add(operand.len() - 1);
+            addr = addr.wrapping_add(operand.len());
+            ops.push(CachedOp { op, instr });
+            let ends = match (op, instr.exec) {
+                (_, Exec::Kil) => true,
+                (JMP_ABS, _) | (JMP_IND, _) | (JSR, _) | (RTS, _)
+                | (RTI, _) | (BRK, _) => true,
+                _ => operand == Operand::Rel,
+            };
+            if ends {
+                break;
+            }
+        }
+        Block {
+            ops,
+            regions: region_bit(pc) | region_bit(last),
+        }
+    }
+}
+
+impl Cpu {
+    // Runs the block starting at PC, or a single instruction through the
+    // normal path when something needs to see every instruction
+    pub(super) fn step_block(
+        &mut self,
+        cache: &mut BlockCache,
+    ) -> Result<u16, CpuError> {
+        let pc = self.regs.pc.get_addr();
+        if self.state != CpuState::Running
+            || self.nmi_pending
+            || self.irq_pending
+            || self.tracer.is_some()
+            || self.profiler.is_some()
+            || !self.mmu.debugger.is_idle()
+            || region_bit(pc) == 0
+        {
+            return self.step_op();
+        }
+        cache.sync(&mut self.mmu);
+        if cache.blocks[pc as usize].is_some() {
+            cache.hits += 1;
+        } else {
+            cache.misses += 1;
+            let block = BlockCache::compile(&self.mmu, pc);
+            cache.insert(pc, block);
+        }
+        let block = match cache.blocks[pc as usize] {
+            Some(ref block) => block,
+            None => unreachable!("Block was just compiled"),
+        };
+        let mut cycles = 0;
+        for cached in &block.ops {
+            let start = self.mmu.cycles;
+            let itr = self.regs.flags.itr();
+            let op_pc = self.regs.pc.get_addr();
+            self.mmu.fetch_cached(op_pc);
+            self.regs.pc.add_unsigned(1);
+            self.cycle_count += cached.instr.cycles as u16;
+            let res = self.execute_instr(cached.op, &cached.instr);
+            cycles += self.finish_cycles(start);
+            self.poll_interrupts(cached.op, itr);
+            res?;
+            // Self modifying code, a bank switch, or an interrupt to take.
+            // Frames end on the same instruction as with the normal core
+            if self.mmu.written() & (block.regions | !RAM_REGIONS) != 0
+                || self.nmi_pending
+                || self.irq_pending
+                || self.mmu.frame_ready()
+            {
+                break;
+            }
+        }
+        Ok(cycles)
+    }
+}
@@ -0,0 +1,312 @@
+use symbols::Symbols;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Breakpoint {
+    // Stops before the instruction at this address executes
+    Exec(u16),
+    // Stops after the instruction that accessed this address finishes, since
+    // the 6502 can't be halted halfway through an instruction
+    Read(u16),
+    Write(u16),
+}
+
+// Watches writes to an inclusive address range that actually change the
+// stored value
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    // Whether emulation should stop when it fires, otherwise it is only
+    // reported through the watch callback
+    pub pause: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub old: u8,
+    pub new: u8,
+    // Address of the instruction that did the write
+    pub pc: u16,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stop {
+    Breakpoint(Breakpoint),
+    Watch(WatchHit),
+    // A step, step over/out or run to cursor finished at this PC
+    Step(u16),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StepTarget {
+    // Stop before the next instruction
+    Into,
+    // Stop once execution is back at pc without being deeper in the call
+    // stack, so a JSR's whole subroutine runs
+    Over { pc: u16, depth: usize },
+    // Stop once the current subroutine or interrupt handler returns
+    Out { depth: usize },
+    Cursor(u16),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    watch_callback: Option<Box<dyn FnMut(WatchHit)>>,
+    hit: Option<Stop>,
+    // Start of the instruction currently executing
+    pc: u16,
+    // Set on resume so the exec breakpoint we stopped on doesn't fire again
+    // before the instruction gets a chance to run
+    resume_pc: Option<u16>,
+    // The pending step and whether its first instruction has run yet
+    target: Option<(StepTarget, bool)>,
+    // Labels used by the disassembler and trace logger
+    pub symbols: Symbols,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            watch_callback: None,
+            hit: None,
+            pc: 0,
+            resume_pc: None,
+            target: None,
+            symbols: Symbols::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| *b != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // Called for every change inside a watched range, pausing or not
+    pub fn set_watch_callback<F: FnMut(WatchHit) + 'static>(&mut self, f: F) {
+        self.watch_callback = Some(Box::new(f));
+    }
+
+    pub fn clear_watch_callback(&mut self) {
+        self.watch_callback = None;
+    }
+
+    // Why emulation is currently stopped, if it is
+    pub fn hit(&self) -> Option<Stop> {
+        self.hit
+    }
+
+    // Nothing armed that needs to look at every instruction or access
+    pub fn is_idle(&self) -> bool {
+        self.breakpoints.is_empty()
+            && self.watchpoints.is_empty()
+            && self.target.is_none()
+            && self.hit.is_none()
+    }
+
+    pub fn resume(&mut self, pc: u16) {
+        match self.hit {
+            Some(Stop::Breakpoint(Breakpoint::Exec(_)))
+            | Some(Stop::Step(_)) => self.resume_pc = Some(pc),
+            _ => (),
+        }
+        self.hit = None;
+    }
+
+    // Arms a step, which takes effect once emulation resumes. Breakpoints
+    // still stop execution early
+    pub fn step(&mut self, target: StepTarget) {
+        self.target = Some((target, false));
+    }
+
+    pub fn cancel_step(&mut self) {
+        self.target = None;
+    }
+
+    // Called by the CPU before fetching an opcode. Returns true if execution
+    // should stop instead
+    pub fn break_on_exec(&mut self, pc: u16, depth: usize) -> bool {
+        self.pc = pc;
+        if self.hit.is_some() {
+            return true;
+        }
+        if let Some((target, started)) = self.target {
+            let reached = started
+                && match target {
+                    StepTarget::Into => true,
+                    StepTarget::Over { pc: ret, depth: d } => {
+                        pc == ret && depth <= d
+                    }
+                    StepTarget::Out { depth: d } => depth < d,
+                    StepTarget::Cursor(cursor) => pc == cursor,
+                };
+            if reached {
+                self.target = None;
+                self.hit = Some(Stop::Step(pc));
+                return true;
+            }
+            self.target = Some((target, true));
+        }
+        if self.resume_pc.take() == Some(pc) {
+            return false;
+        }
+        self.check(Breakpoint::Exec(pc));
+        self.hit.is_some()
+    }
+
+    pub fn on_read(&mut self, address: u16) {
+        self.check(Breakpoint::Read(address));
+    }
+
+    pub fn on_write(&mut self, address: u16) {
+        self.check(Breakpoint::Write(address));
+    }
+
+    pub fn watches(&self, address: u16) -> bool {
+        self.watchpoints
+            .iter()
+            .any(|w| w.start <= address && address <= w.end)
+    }
+
+    pub fn on_watched_write(&mut self, address: u16, old: u8, new: u8) {
+        if old == new {
+            return;
+        }
+        let hit = WatchHit {
+            address,
+            old,
+            new,
+            pc: self.pc,
+        };
+        if let Some(ref mut callback) = self.watch_callback {
+            callback(hit);
+        }
+        let pause = self
+            .watchpoints
+            .iter()
+            .any(|w| w.pause && w.start <= address && address <= w.end);
+        if pause && self.hit.is_none() {
+            self.hit = Some(Stop::Watch(hit));
+        }
+    }
+
+    fn check(&mut self, access: Breakpoint) {
+        if self.hit.is_none() && self.breakpoints.contains(&access) {
+            self.hit = Some(Stop::Breakpoint(access));
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CallKind {
+    Jsr,
+    Brk,
+    Irq,
+    Nmi,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    // Address of the JSR/BRK, or of the instruction an interrupt preempted
+    pub caller: u16,
+    pub target: u16,
+    // Where execution should continue once the frame returns
+    pub ret: u16,
+    // Stack pointer after the return address (and flags) were pushed
+    pub sp: u8,
+}
+
+// Shadow of the 6502 stack that only tracks calls, so debuggers can show how
+// execution got to the current PC. Games don't always return the way they
+// called (stack resets, RTS jump tables), so frames are matched up by stack
+// pointer rather than just pushed and popped
+pub struct CallStack {
+    frames: Vec<CallFrame>,
+    smashed: usize,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack {
+            frames: Vec::new(),
+            smashed: 0,
+        }
+    }
+
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    // Number of returns that came back to a different address than the call
+    // on that stack slot pushed, i.e. the return address was overwritten
+    pub fn smashed(&self) -> usize {
+        self.smashed
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn push(&mut self, frame: CallFrame) {
+        // Frames at or below the new one on the stack were abandoned without
+        // returning
+        self.frames.retain(|f| f.sp > frame.sp);
+        self.frames.push(frame);
+    }
+
+    // Called with the stack pointer from before the return pulled anything
+    // and the address execution continues at
+    pub fn ret(&mut self, sp: u8, ret: u16) {
+        self.frames.retain(|f| f.sp >= sp);
+        match self.frames.last() {
+            Some(frame) if frame.sp == sp => {
+                if frame.ret != ret {
+                    self.smashed += 1;
+                    warn!(
+                        "Return to {:04X} but {:?} from {:04X} expected {:04X}",
+                        ret, frame.kind, frame.caller, frame.ret
+                    );
+                }
+            }
+            // Returning through an address that was pushed by hand, like an
+            // RTS jump table, rather than by a call we saw
+            _ => return,
+        }
+        self.frames.pop();
+    }
+}
@@ -0,0 +1,135 @@
+use failure::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use NesEmulator;
+
+const FRAME_WIDTH: u32 = 256;
+const FRAME_HEIGHT: u32 = 240;
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// Stored deflate blocks hold at most this many bytes each
+const MAX_BLOCK: usize = 0xFFFF;
+
+#[derive(Debug, Fail)]
+pub enum FrameCheckError {
+    #[fail(
+        display = "Frame {} hashed to {:016X} instead of {:016X}, saved to {}",
+        frame, actual, expected, path
+    )]
+    Mismatch {
+        frame: u64,
+        expected: u64,
+        actual: u64,
+        path: String,
+    },
+}
+
+// For regression tests: compares the last finished frame against a hash
+// recorded from a known good run. On a mismatch the frame is saved as a PNG
+// to look at, and the error says where
+pub fn check_frame(
+    nes: &NesEmulator,
+    expected: u64,
+    dump_path: &Path,
+) -> Result<(), Error> {
+    let actual = nes.cpu.mmu.ppu.frame_hash();
+    if actual == expected {
+        return Ok(());
+    }
+    save_frame(nes, dump_path)?;
+    Err(FrameCheckError::Mismatch {
+        frame: nes.clock().frames,
+        expected: expected,
+        actual: actual,
+        path: dump_path.display().to_string(),
+    })?
+}
+
+pub fn save_frame(nes: &NesEmulator, path: &Path) -> Result<(), Error> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let rgb = nes.cpu.mmu.ppu.frame_rgb();
+    write_png(&mut file, FRAME_WIDTH, FRAME_HEIGHT, &rgb)
+}
+
+// An RGB24 image as a PNG. Nothing is compressed, which keeps this short and
+// any image viewer can still open it
+pub fn write_png<W: Write>(
+    writer: &mut W,
+    width: u32,
+    height: u32,
+    rgb: &[u8],
+) -> Result<(), Error> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&be32(width));
+    header.extend_from_slice(&be32(height));
+    // 8 bits per channel, RGB, and the only compression, filter and
+    // interlace methods there are
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // Each row starts with its filter type, 0 for none
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    for row in rgb.chunks(width as usize * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    // zlib header, then deflate's stored blocks, then the adler32 of it all
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(MAX_BLOCK).count();
+    for (i, block) in raw.chunks(MAX_BLOCK).enumerate() {
+        let len = block.len() as u16;
+        zlib.push((i + 1 == blocks) as u8);
+        zlib.extend_from_slice(&[len as u8, (len >> 8) as u8]);
+        zlib.extend_from_slice(&[!len as u8, (!len >> 8) as u8]);
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&be32(adler32(&raw)));
+
+    writer.write_all(&PNG_SIGNATURE)?;
+    write_chunk(writer, b"IHDR", &header)?;
+    write_chunk(writer, b"IDAT", &zlib)?;
+    write_chunk(writer, b"IEND", &[])?;
+    Ok(())
+}
+
+fn write_chunk<W: Write>(
+    writer: &mut W,
+    kind: &[u8; 4],
+    data: &[u8],
+) -> Result<(), Error> {
+    writer.write_all(&be32(data.len() as u32))?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let mut crc = crc32(!0, kind);
+    crc = crc32(crc, data);
+    writer.write_all(&be32(!crc))?;
+    Ok(())
+}
+
+fn be32(val: u32) -> [u8; 4] {
+    [(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]
+}
+
+// Bit at a time, frames are only saved when something has already gone wrong
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
@@ -14,82 +14,252 @@ extern crate failure;
 extern crate log;
 
 pub mod apu;
+pub mod blargg;
+pub mod cartridge;
 pub mod config;
 pub mod controller;
 pub mod cpu;
 pub mod cpu_const;
+pub mod debugger;
+pub mod frame_check;
 pub mod mapper;
 pub mod mmu;
 pub mod ppu;
+pub mod profiler;
 pub mod rom;
 pub mod state;
+pub mod symbols;
+pub mod trace;
 
+use serde::Serialize;
+use serde::Deserialize;
 use state::State;
 use cpu::Cpu;
+use cpu::CpuState;
+use cpu::CpuError;
+use debugger::StepTarget;
 use apu::Apu;
 use ppu::Ppu;
-use ppu::PpuRes;
+use ppu::Timing;
 use rom::Rom;
-use mapper::Mapper;
+use rom::Region;
+use cartridge::Cartridge;
 use mmu::Mmu;
+use mmu::Ram;
+use mmu::RamInit;
+use failure::Error;
 use std::cell::RefCell;
 use std::rc::Rc;
 
+// Everything about the console's power on state that would otherwise vary
+// between runs. Pinning it makes two runs fed the same input produce the same
+// frames, which TAS replays and netplay rely on
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct PowerOn {
+    pub ram: RamInit,
+    // PPU dots run before the CPU starts. Only 0-2 give distinct CPU/PPU
+    // phases, larger values also move the CPU later in the frame
+    pub ppu_dots: u8,
+    // Older config files don't pick a timing, and get NTSC
+    #[serde(default = "default_timing")]
+    pub timing: Timing,
+}
+
+fn default_timing() -> Timing {
+    Timing::Ntsc
+}
+
+impl PowerOn {
+    pub fn new() -> PowerOn {
+        PowerOn {
+            ram: RamInit::Zero,
+            ppu_dots: 0,
+            timing: default_timing(),
+        }
+    }
+}
+
+// Where emulation is, as one consistent timebase for scripts, debuggers and
+// savestates. Cycles and frames count up from power on and survive resets
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct EmulatorClock {
+    // CPU cycles, including the ones spent halted for DMA
+    pub cycles: u64,
+    pub frames: u64,
+    pub scanline: u16,
+    pub dot: u16,
+}
+
 pub struct NesEmulator {
     pub cpu: Cpu,
+    pub power: PowerOn,
 }
 
 impl NesEmulator {
     pub fn new(rom: Rom) -> NesEmulator {
+        NesEmulator::with_power_on(rom, PowerOn::new())
+    }
+
+    pub fn with_power_on(rom: Rom, mut power: PowerOn) -> NesEmulator {
         println!("{:?}", rom);
-        let mapper = Rc::new(RefCell::new(Mapper::from_rom(rom)));
-        let cpu =
-            Cpu::new(Mmu::new(Apu::new(), Ppu::new(mapper.clone()), mapper));
-        NesEmulator { cpu: cpu }
+        // ROMs made for PAL consoles or the Dendy won't run right on
+        // anything else
+        if rom.header.dendy {
+            power.timing = Timing::Dendy;
+        } else if rom.header.region == Region::PAL {
+            power.timing = Timing::Pal;
+        }
+        let cartridge = Rc::new(RefCell::new(Cartridge::from_rom(rom)));
+        let ppu = Ppu::new(cartridge.clone());
+        let cpu = Cpu::new(Mmu::new(Apu::new(), ppu, cartridge));
+        let mut nes = NesEmulator {
+            cpu: cpu,
+            power: power,
+        };
+        nes.power_on();
+        nes
     }
 
+    // Pressing the reset button, RAM and the CPU registers other than SP and
+    // the I flag survive it
     pub fn reset(&mut self) {
-        self.cpu.mmu.mapper.borrow_mut().reset();
+        self.cpu.mmu.cartridge.borrow_mut().reset();
         self.cpu.mmu.ppu.reset();
+        self.cpu.mmu.apu.reset();
         self.cpu.reset();
+        self.resume();
+    }
+
+    // Turning the console off and on again, using the settings in power
+    pub fn power_on(&mut self) {
+        self.cpu.mmu.cartridge.borrow_mut().reset();
+        self.cpu.mmu.ppu.timing = self.power.timing;
+        self.cpu.mmu.ppu.reset();
+        self.cpu.mmu.ppu.offset_dots(self.power.ppu_dots);
+        self.cpu.mmu.apu.set_region(self.power.timing.region());
+        self.cpu.mmu.apu.reset();
+        self.cpu.mmu.ram = Ram::with_init(self.power.ram);
+        self.cpu.power_on();
+        self.resume();
+    }
+
+    pub fn clock(&self) -> EmulatorClock {
+        EmulatorClock {
+            cycles: self.cpu.mmu.cycles,
+            frames: self.cpu.mmu.frames,
+            scanline: self.cpu.mmu.ppu.scanline(),
+            dot: self.cpu.mmu.ppu.dot(),
+        }
     }
 
     pub fn get_state(&self) -> State {
         State {
+            clock: self.clock(),
             ppu_state: self.cpu.mmu.ppu.get_state(),
-            screen_mode: self.cpu.mmu.mapper.borrow().get_mirroring(),
-            chr_ram: self.cpu.mmu.mapper.borrow().rom.chr_ram.clone(),
+            apu_state: self.cpu.mmu.apu.get_state(),
+            chr_ram: self.cpu.mmu.cartridge.borrow().rom.chr_ram.clone(),
+            prg_ram: self.cpu.mmu.cartridge.borrow().rom.prg_ram.clone(),
             cpu_regs: self.cpu.regs.clone(),
-            mapper: self.cpu.mmu.mapper.borrow().mem_type.clone(),
+            mapper: self.cpu.mmu.cartridge.borrow().save_mapper(),
             ram: self.cpu.mmu.ram.clone(),
         }
     }
 
-    pub fn load_state(&mut self, state: State) {
+    // Fails without touching anything if the state is for another board
+    pub fn load_state(&mut self, state: State) -> Result<(), Error> {
+        self.cpu.mmu.cartridge.borrow_mut().load_mapper(&state.mapper)?;
+        self.cpu.mmu.cycles = state.clock.cycles;
+        self.cpu.mmu.frames = state.clock.frames;
         self.cpu.mmu.ppu.set_state(state.ppu_state);
-        self.cpu.mmu.mapper.borrow_mut().rom.header.screen = state.screen_mode;
-        self.cpu.mmu.mapper.borrow_mut().rom.chr_ram = state.chr_ram;
+        self.cpu.mmu.set_apu_state(state.apu_state);
+        self.cpu.mmu.cartridge.borrow_mut().rom.chr_ram = state.chr_ram;
+        self.cpu.mmu.cartridge.borrow_mut().rom.prg_ram = state.prg_ram;
         self.cpu.regs = state.cpu_regs;
-        self.cpu.mmu.mapper.borrow_mut().mem_type = state.mapper;
         self.cpu.mmu.ram = state.ram;
+        #[cfg(feature = "cached-core")]
+        {
+            if let Some(ref mut cache) = self.cpu.block_cache {
+                cache.clear();
+            }
+        }
+        Ok(())
     }
 
-    pub fn step(&mut self) -> bool {
-        let cc = self.cpu.step();
-        match self.cpu.mmu.ppu.emulate_cycles(cc) {
-            Some(r) => match r {
-                PpuRes::Nmi => {
-                    self.cpu.proc_nmi();
-                    false
+    // See Cartridge::reload, shows up from the next thing drawn
+    pub fn reload_rom(&mut self, rom: Rom, prg: bool) -> Result<(), Error> {
+        self.cpu.mmu.cartridge.borrow_mut().reload(rom, prg)?;
+        self.cpu.mmu.ppu.chr_banks_changed();
+        #[cfg(feature = "cached-core")]
+        {
+            if let Some(ref mut cache) = self.cpu.block_cache {
+                if prg {
+                    cache.clear();
                 }
-                PpuRes::Draw => true,
-            },
-            None => false,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn step(&mut self) -> Result<bool, CpuError> {
+        self.cpu.step()?;
+        Ok(self.cpu.mmu.take_frame())
+    }
+
+    pub fn is_jammed(&self) -> bool {
+        self.cpu.state != CpuState::Running
+    }
+
+    // Runs until the PPU finishes a frame, or until a breakpoint is hit in
+    // which case the last complete frame is returned. ppu.capture() has the
+    // one that was being drawn
+    pub fn next_frame(&mut self) -> Result<&[u8], CpuError> {
+        while !self.step()? && self.cpu.mmu.debugger.hit().is_none() {}
+        Ok(self.cpu.mmu.ppu.get_buffer())
+    }
+
+    // Runs until the PPU gets to the given line, which has to exist under
+    // its timing, where capture() shows what has been drawn so far. Whole
+    // instructions are run, so it can stop a few dots into the line
+    pub fn run_to_scanline(&mut self, scanline: u16) -> Result<(), CpuError> {
+        while self.cpu.mmu.ppu.scanline() != scanline {
+            self.step()?;
         }
+        Ok(())
+    }
+
+    pub fn step_into(&mut self) {
+        self.cpu.mmu.debugger.step(StepTarget::Into);
+        self.resume();
+    }
+
+    // Steps over JSRs, anything else is a single step
+    pub fn step_over(&mut self) {
+        let pc = self.cpu.regs.pc.get_addr();
+        let target = if self.cpu.mmu.peek(pc) == cpu_const::JSR {
+            StepTarget::Over {
+                pc: pc.wrapping_add(3),
+                depth: self.cpu.call_stack.depth(),
+            }
+        } else {
+            StepTarget::Into
+        };
+        self.cpu.mmu.debugger.step(target);
+        self.resume();
+    }
+
+    pub fn step_out(&mut self) {
+        let depth = self.cpu.call_stack.depth();
+        self.cpu.mmu.debugger.step(StepTarget::Out { depth });
+        self.resume();
+    }
+
+    pub fn run_to(&mut self, pc: u16) {
+        self.cpu.mmu.debugger.step(StepTarget::Cursor(pc));
+        self.resume();
     }
 
-    pub fn next_frame(&mut self) -> &[u8] {
-        while !self.step() {}
-        self.cpu.mmu.ppu.get_buffer()
+    pub fn resume(&mut self) {
+        let pc = self.cpu.regs.pc.get_addr();
+        self.cpu.mmu.debugger.resume(pc);
     }
 }
@@ -1,127 +1,548 @@
 use serde::Serialize;
-use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use failure::Error;
 use rom::Rom;
 use rom::ScreenMode;
 use mapper::sxrom::*;
 use mapper::unrom::*;
 use mapper::nrom::*;
 use mapper::axrom::*;
+use mapper::txrom::*;
+use mapper::pxrom::*;
+use mapper::fxrom::*;
+use mapper::gxrom::*;
+use mapper::vrc4::*;
+use mapper::vrc7::*;
+use mapper::fme7::*;
+use mapper::n163::*;
+use mapper::bf909x::*;
+use mapper::rambo1::*;
+use mapper::unrom512::*;
 
+pub mod banks;
 pub mod nrom;
 pub mod sxrom;
 pub mod unrom;
 pub mod axrom;
+pub mod txrom;
+pub mod pxrom;
+pub mod fxrom;
+pub mod gxrom;
+pub mod vrc_irq;
+pub mod vrc4;
+pub mod vrc7;
+pub mod fme7;
+pub mod n163;
+pub mod bf909x;
+pub mod rambo1;
+pub mod unrom512;
+#[cfg(feature = "vrc7-audio")]
+pub mod opll;
 
-pub struct Mapper {
-    pub mem_type: MemType,
-    pub rom: Rom,
+// What backs one of the four nametables
+pub enum Nametable {
+    // A 1KB page of the console's VRAM, see ScreenMode::nt_page
+    Vram(u16),
+    // A 1KB bank of CHR ROM, read only
+    Chr(usize),
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub enum MemType {
-    Nrom(Nrom),
-    Sxrom(Sxrom),
-    Unrom(Unrom),
-    Axrom(Axrom),
+#[derive(Debug, Fail)]
+pub enum MapperError {
+    #[fail(display = "Unable to parse mapper state: {}", _0)]
+    StateError(std::boxed::Box<bincode::ErrorKind>),
 }
 
-impl Mapper {
-    pub fn from_rom(mut rom: Rom) -> Mapper {
-        let mem_type = match rom.header.mapper {
-            0 => {
-                let use_chr_ram = rom.chr_ram.len() != 0;
-                MemType::Nrom(Nrom::new(rom.prg_rom.len(), use_chr_ram))
-            }
-            1 => {
-                rom.fill_prg_ram();
-                let use_chr_ram = rom.chr_ram.len() != 0;
-                let last_page_start = rom.prg_rom.len() - 0x4000;
-                MemType::Sxrom(Sxrom::new(use_chr_ram, last_page_start))
-            }
-            2 => {
-                let last_page_start = rom.prg_rom.len() - 0x4000;
-                MemType::Unrom(Unrom::new(last_page_start))
-            }
-            7 => {
-                let last_page_start = rom.prg_rom.len() - 0x8000;
-                MemType::Axrom(Axrom::new(last_page_start))
-            }
-            m => panic!("Mapper {} not supported", m),
-        };
-        Mapper {
-            rom: rom,
-            mem_type: mem_type,
-        }
+// A cartridge board. Boards only hold their own registers, the ROM and RAM
+// chips they switch between are passed in from the cartridge
+pub trait Mapper {
+    fn ld_prg(&self, addr: u16, rom: &Rom) -> u8;
+    fn store_prg(&mut self, addr: u16, val: u8, rom: &mut Rom);
+
+    // A read by the CPU, for boards with registers that change when read.
+    // ld_prg has to stay free of side effects so debuggers can peek
+    fn read_prg(&mut self, addr: u16, rom: &Rom) -> u8 {
+        self.ld_prg(addr, rom)
     }
 
-    pub fn ld_prg(&self, addr: u16) -> u8 {
-        match self.mem_type {
-            MemType::Nrom(ref nrom) => nrom.ld_prg(addr, &self.rom.prg_rom),
-            MemType::Unrom(ref unrom) => unrom.ld_prg(addr, &self.rom.prg_rom),
-            MemType::Sxrom(ref sxrom) => {
-                sxrom.ld_prg(addr, &self.rom.prg_rom, &self.rom.prg_ram)
-            },
-            MemType::Axrom(ref axrom) => axrom.ld_prg(addr, &self.rom.prg_rom),
-        }
+    // Where in PRG ROM a CPU address currently maps to, taking banking into
+    // account. None for anything that isn't PRG ROM
+    fn prg_offset(&self, addr: u16) -> Option<usize>;
+
+    fn ld_chr(&self, addr: u16, rom: &Rom) -> u8;
+    fn store_chr(&mut self, addr: u16, val: u8, rom: &mut Rom);
+
+    // For boards with more CHR RAM than the 8KB an iNES header implies
+    fn chr_ram_size(&self) -> Option<usize> {
+        None
     }
 
-    pub fn ld_chr(&self, addr: u16) -> u8 {
-        match self.mem_type {
-            MemType::Nrom(ref nrom) => {
-                nrom.ld_chr(addr, &self.rom.chr_rom, &self.rom.chr_ram)
-            }
-            MemType::Unrom(ref unrom) => unrom.ld_chr(addr, &self.rom.chr_ram),
-            MemType::Sxrom(ref sxrom) => {
-                sxrom.ld_chr(addr, &self.rom.chr_rom, &self.rom.chr_ram)
-            },
-            MemType::Axrom(ref axrom) => axrom.ld_chr(addr, &self.rom.chr_ram),
-        }
+    // For boards that always have the same PRG RAM, whatever the header
+    // says. Most iNES headers leave the size at 0, which means 8KB
+    fn prg_ram_size(&self) -> Option<usize> {
+        None
     }
 
-    pub fn store_prg(&mut self, addr: u16, val: u8) {
-        match self.mem_type {
-            MemType::Unrom(ref mut unrom) => unrom.store_prg(addr, val),
-            MemType::Sxrom(ref mut sxrom) => {
-                sxrom.store_prg(addr, val, &mut self.rom.prg_ram)
-            }
-            MemType::Nrom(ref nrom) => nrom.store_prg(addr, val),
-            MemType::Axrom(ref mut axrom) => axrom.store_prg(addr, val),
-        }
+    // Mirroring as of right now. The PPU asks on every nametable access, so
+    // mappers just need to return whatever their registers currently select
+    fn get_mirroring(&self) -> ScreenMode;
+
+    // Where each nametable comes from, for boards that can do more than
+    // pick a mirroring
+    fn nametable(&self, table: u16) -> Nametable {
+        Nametable::Vram(self.get_mirroring().nt_page(table))
+    }
+
+    // Boards without any PRG RAM control just have it wired up at
+    // $6000-$7FFF, which is also where test ROMs report their results. The
+    // ones that do get every access there themselves
+    fn controls_prg_ram(&self) -> bool {
+        false
+    }
+
+    // Discrete boards don't stop the ROM driving the data bus while the CPU
+    // writes to their register, so only bits set in both get through
+    fn bus_conflicts(&self) -> bool {
+        false
     }
 
-    pub fn store_chr(&mut self, addr: u16, val: u8) {
-        match self.mem_type {
-            MemType::Unrom(ref mut unrom) => {
-                unrom.store_chr(addr, val, &mut self.rom.chr_ram)
-            }
-            MemType::Sxrom(ref mut sxrom) => {
-                sxrom.store_chr(addr, val, &mut self.rom.chr_ram)
-            }
-            MemType::Nrom(ref mut nrom) => {
-                nrom.store_chr(addr, val, &mut self.rom.chr_ram)
-            },
-            MemType::Axrom(ref mut axrom) => {
-                axrom.store_chr(addr, val, &mut self.rom.chr_ram)
-            }
+    // Whether a CPU write here could change which CHR is mapped in
+    fn switches_chr(&self, addr: u16) -> bool {
+        addr >= 0x8000
+    }
+
+    // Called on every filtered rising edge of PPU address line A12, which
+    // MMC3 style scanline counters count
+    fn a12_rise(&mut self) {}
+
+    // Called after the PPU reads a pattern table address, for boards that
+    // switch CHR on particular tiles being drawn. Returns whether it did
+    fn pattern_fetch(&mut self, _addr: u16) -> bool {
+        false
+    }
+
+    // Called every CPU cycle, for boards with cycle counting IRQs
+    fn clock(&mut self) {}
+
+    // Whether the board is pulling the CPU's IRQ line low
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // What a sound chip on the board is putting out, on the same scale the
+    // APU mixes its own channels to
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    fn reset(&mut self) {}
+
+    // Whatever the board keeps with the power off, for writing to disk.
+    // Usually PRG RAM, when the header says there's a battery for it
+    fn save_data(&self, rom: &Rom) -> Option<Vec<u8>> {
+        if rom.header.save_ram {
+            Some(rom.prg_ram.clone())
+        } else {
+            None
         }
     }
 
-    pub fn get_mirroring(&self) -> ScreenMode {
-        match self.mem_type {
-            MemType::Unrom(_) | MemType::Nrom(_) => {
-                self.rom.header.screen.clone()
-            }
-            MemType::Sxrom(ref sxrom) => sxrom.get_mirroring(),
-            MemType::Axrom(ref axrom) => axrom.get_mirroring(),
+    // Restores what save_data gave on an earlier run
+    fn load_save_data(&mut self, data: &[u8], rom: &mut Rom) {
+        if rom.header.save_ram && data.len() == rom.prg_ram.len() {
+            rom.prg_ram.copy_from_slice(data);
+        } else {
+            warn!("Save doesn't match this ROM, ignoring it");
         }
     }
 
-    pub fn reset(&mut self) {
-        match self.mem_type {
-            MemType::Nrom(_) => (),
-            MemType::Unrom(ref mut unrom) => unrom.reset(),
-            MemType::Sxrom(ref mut sxrom) => sxrom.reset(),
-            MemType::Axrom(ref mut axrom) => axrom.reset(),
+    // The board's registers for save states, see save_board and load_board
+    fn save_state(&self) -> Vec<u8>;
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error>;
+}
+
+// Submapper 1 of the discrete boards means no bus conflicts and 2 means
+// there are, anything else goes with what the board usually does
+fn bus_conflicts(submapper: u8, usual: bool) -> bool {
+    match submapper {
+        1 => false,
+        2 => true,
+        _ => usual,
+    }
+}
+
+// A board's iNES mapper number and what it's usually called
+pub struct Board {
+    pub mapper: u8,
+    pub name: &'static str,
+    pub supported: bool,
+}
+
+// Every board new_mapper can build, along with the better known ones it
+// can't so they can be named when a ROM needs one
+pub const BOARDS: &[Board] = &[
+    Board {
+        mapper: 0,
+        name: "NROM",
+        supported: true,
+    },
+    Board {
+        mapper: 1,
+        name: "Nintendo MMC1, SxROM",
+        supported: true,
+    },
+    Board {
+        mapper: 2,
+        name: "UxROM",
+        supported: true,
+    },
+    Board {
+        mapper: 3,
+        name: "CNROM",
+        supported: true,
+    },
+    Board {
+        mapper: 4,
+        name: "Nintendo MMC3, TxROM",
+        supported: true,
+    },
+    Board {
+        mapper: 5,
+        name: "Nintendo MMC5, ExROM",
+        supported: false,
+    },
+    Board {
+        mapper: 7,
+        name: "AxROM",
+        supported: true,
+    },
+    Board {
+        mapper: 9,
+        name: "Nintendo MMC2, PxROM",
+        supported: true,
+    },
+    Board {
+        mapper: 10,
+        name: "Nintendo MMC4, FxROM",
+        supported: true,
+    },
+    Board {
+        mapper: 11,
+        name: "Color Dreams",
+        supported: true,
+    },
+    Board {
+        mapper: 13,
+        name: "CPROM",
+        supported: false,
+    },
+    Board {
+        mapper: 16,
+        name: "Bandai FCG",
+        supported: false,
+    },
+    Board {
+        mapper: 18,
+        name: "Jaleco SS88006",
+        supported: false,
+    },
+    Board {
+        mapper: 19,
+        name: "Namco 129/163",
+        supported: true,
+    },
+    Board {
+        mapper: 21,
+        name: "Konami VRC4a/VRC4c",
+        supported: true,
+    },
+    Board {
+        mapper: 22,
+        name: "Konami VRC2a",
+        supported: true,
+    },
+    Board {
+        mapper: 23,
+        name: "Konami VRC2b/VRC4e/VRC4f",
+        supported: true,
+    },
+    Board {
+        mapper: 24,
+        name: "Konami VRC6a",
+        supported: false,
+    },
+    Board {
+        mapper: 25,
+        name: "Konami VRC2c/VRC4b/VRC4d",
+        supported: true,
+    },
+    Board {
+        mapper: 26,
+        name: "Konami VRC6b",
+        supported: false,
+    },
+    Board {
+        mapper: 28,
+        name: "Action 53",
+        supported: false,
+    },
+    Board {
+        mapper: 30,
+        name: "UNROM 512",
+        supported: true,
+    },
+    Board {
+        mapper: 32,
+        name: "Irem G-101",
+        supported: false,
+    },
+    Board {
+        mapper: 33,
+        name: "Taito TC0190",
+        supported: false,
+    },
+    Board {
+        mapper: 34,
+        name: "BNROM, NINA-001",
+        supported: false,
+    },
+    Board {
+        mapper: 48,
+        name: "Taito TC0690",
+        supported: false,
+    },
+    Board {
+        mapper: 64,
+        name: "Tengen RAMBO-1",
+        supported: true,
+    },
+    Board {
+        mapper: 65,
+        name: "Irem H3001",
+        supported: false,
+    },
+    Board {
+        mapper: 66,
+        name: "GxROM",
+        supported: true,
+    },
+    Board {
+        mapper: 67,
+        name: "Sunsoft-3",
+        supported: false,
+    },
+    Board {
+        mapper: 68,
+        name: "Sunsoft-4",
+        supported: false,
+    },
+    Board {
+        mapper: 69,
+        name: "Sunsoft FME-7",
+        supported: true,
+    },
+    Board {
+        mapper: 70,
+        name: "Bandai 74161",
+        supported: false,
+    },
+    Board {
+        mapper: 71,
+        name: "Camerica BF9093/BF9097",
+        supported: true,
+    },
+    Board {
+        mapper: 73,
+        name: "Konami VRC3",
+        supported: false,
+    },
+    Board {
+        mapper: 75,
+        name: "Konami VRC1",
+        supported: false,
+    },
+    Board {
+        mapper: 79,
+        name: "AVE NINA-03/06",
+        supported: false,
+    },
+    Board {
+        mapper: 85,
+        name: "Konami VRC7",
+        supported: true,
+    },
+    Board {
+        mapper: 87,
+        name: "Jaleco J87",
+        supported: false,
+    },
+    Board {
+        mapper: 94,
+        name: "UN1ROM",
+        supported: false,
+    },
+    Board {
+        mapper: 105,
+        name: "NES-EVENT",
+        supported: false,
+    },
+    Board {
+        mapper: 118,
+        name: "TxSROM",
+        supported: false,
+    },
+    Board {
+        mapper: 119,
+        name: "TQROM",
+        supported: false,
+    },
+    Board {
+        mapper: 140,
+        name: "Jaleco JF-11/14",
+        supported: false,
+    },
+    Board {
+        mapper: 180,
+        name: "UNROM, Crazy Climber",
+        supported: false,
+    },
+    Board {
+        mapper: 184,
+        name: "Sunsoft-1",
+        supported: false,
+    },
+    Board {
+        mapper: 185,
+        name: "CNROM with copy protection",
+        supported: false,
+    },
+    Board {
+        mapper: 206,
+        name: "Namco 108, DxROM",
+        supported: true,
+    },
+    Board {
+        mapper: 210,
+        name: "Namco 175/340",
+        supported: false,
+    },
+    Board {
+        mapper: 228,
+        name: "Action 52",
+        supported: false,
+    },
+    Board {
+        mapper: 232,
+        name: "Camerica Quattro",
+        supported: false,
+    },
+];
+
+pub fn supported_boards() -> impl Iterator<Item = &'static Board> {
+    BOARDS.iter().filter(|board| board.supported)
+}
+
+// e.g. "mapper 69 (Sunsoft FME-7)"
+pub fn describe_board(mapper: u8) -> String {
+    match BOARDS.iter().find(|board| board.mapper == mapper) {
+        Some(board) => format!("mapper {} ({})", mapper, board.name),
+        None => format!("mapper {}", mapper),
+    }
+}
+
+// Builds the board for a ROM's mapper number, None if it isn't supported
+pub fn new_mapper(rom: &Rom) -> Option<Box<dyn Mapper>> {
+    let chr_size = rom.chr().len();
+    let screen = rom.header.screen.clone();
+    let submapper = rom.header.submapper;
+    let mapper: Box<dyn Mapper> = match rom.header.mapper {
+        0 => Box::new(Nrom::new(rom.prg_rom.len(), screen)),
+        // Submapper 5 is SEROM, SHROM and SH1ROM
+        1 => Box::new(Sxrom::new(rom.prg_rom.len(), submapper == 5)),
+        // Licensed UxROM games avoid conflicting writes anyway, so they're
+        // only on for ROMs that ask
+        2 => {
+            let conflicts = bus_conflicts(submapper, false);
+            Box::new(Unrom::new(rom.prg_rom.len(), conflicts, screen))
+        }
+        // Cybernoid depends on them
+        3 => Box::new(Gxrom::new(
+            Wiring::Cnrom,
+            rom.prg_rom.len(),
+            chr_size,
+            bus_conflicts(submapper, true),
+            screen,
+        )),
+        4 | 206 => {
+            let chip = match (rom.header.mapper, submapper) {
+                (4, 4) => Chip::Mmc3A,
+                (4, _) => Chip::Mmc3,
+                _ => Chip::Namco108,
+            };
+            let prg_size = rom.prg_rom.len();
+            Box::new(Txrom::new(chip, prg_size, screen))
+        }
+        // Most AxROM games are on AOROM
+        7 => {
+            let conflicts = bus_conflicts(submapper, false);
+            Box::new(Axrom::new(rom.prg_rom.len(), conflicts))
+        }
+        9 => Box::new(Pxrom::new(rom.prg_rom.len())),
+        10 => {
+            let last_page_start = rom.prg_rom.len().saturating_sub(0x4000);
+            Box::new(Fxrom::new(last_page_start))
+        }
+        11 | 66 => {
+            let wiring = if rom.header.mapper == 11 {
+                Wiring::ColorDreams
+            } else {
+                Wiring::Gxrom
+            };
+            let prg_size = rom.prg_rom.len();
+            Box::new(Gxrom::new(wiring, prg_size, chr_size, true, screen))
+        }
+        19 => Box::new(N163::new(rom.prg_rom.len(), chr_size)),
+        21 | 22 | 23 | 25 => {
+            let mapper = rom.header.mapper;
+            let pins = Pins::for_mapper(mapper, submapper);
+            // Submapper 3 is the VRC2 boards sharing 23 and 25
+            let vrc2 = mapper == 22 || (submapper == 3 && mapper != 21);
+            let chr_shift = mapper == 22;
+            let prg_size = rom.prg_rom.len();
+            Box::new(Vrc4::new(vrc2, pins, chr_shift, prg_size, chr_size))
+        }
+        30 => Box::new(Unrom512::new(
+            rom.prg_rom.len(),
+            rom.header.save_ram,
+            screen,
+            rom.header.mirroring_bit,
+        )),
+        64 => Box::new(Rambo1::new(rom.prg_rom.len(), screen)),
+        69 => {
+            let prg_size = rom.prg_rom.len();
+            Box::new(Fme7::new(prg_size, rom.prg_ram.len(), chr_size))
+        }
+        71 => Box::new(Bf909x::new(rom.prg_rom.len(), screen)),
+        85 => Box::new(Vrc7::new(rom.prg_rom.len(), chr_size)),
+        _ => return None,
+    };
+    Some(mapper)
+}
+
+// Every board's save_state and load_state is just its serialized self
+pub fn save_board<T: Serialize>(board: &T) -> Vec<u8> {
+    bincode::serialize(board).expect("Mapper state always serializes")
+}
+
+pub fn load_board<T: DeserializeOwned>(
+    board: &mut T,
+    state: &[u8],
+) -> Result<(), Error> {
+    match bincode::deserialize(state) {
+        Ok(loaded) => {
+            *board = loaded;
+            Ok(())
         }
+        Err(e) => Err(Error::from(MapperError::StateError(e))),
     }
 }
@@ -4,30 +4,43 @@
 
 use serde::Serialize;
 use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
 use rom::ScreenMode;
 use rom::ScreenBank;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::banks::Bank;
+use mapper::banks::Banks;
 
 const THIRTY_TWO_KB: usize = 0x8000;
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+// ANROM, AOROM and the like. One 32KB PRG bank and a register that also
+// picks which nametable fills the whole screen, with 8KB of CHR RAM
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Axrom {
-    bank_select: u8,
+    prg: Banks,
     mirror_select: u8,
-    last_page_start: usize,
+    // ANROM has them, AOROM doesn't
+    bus_conflicts: bool,
 }
 
 impl Axrom {
-    pub fn new(last_page_start: usize) -> Axrom {
+    pub fn new(prg_rom_size: usize, bus_conflicts: bool) -> Axrom {
+        let prg = [Bank::Number(0)];
         Axrom {
-            bank_select: 0,
+            prg: Banks::new(0x8000, THIRTY_TWO_KB, prg_rom_size, &prg),
             mirror_select: 0,
-            last_page_start,
+            bus_conflicts: bus_conflicts,
         }
     }
+}
 
-    pub fn store_prg(&mut self, address: u16, val: u8) {
+impl Mapper for Axrom {
+    fn store_prg(&mut self, address: u16, val: u8, _rom: &mut Rom) {
         if address >= 0x8000 {
-            self.bank_select = val & 0b111;
+            self.prg.switch(0, val as usize & 0b111);
             self.mirror_select = (val >> 4) & 1;
         } else {
             info!(
@@ -37,34 +50,68 @@ impl Axrom {
         }
     }
 
-    pub fn ld_prg(&self, address: u16, prg_rom: &Vec<u8>) -> u8 {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match self.prg_offset(address) {
+            Some(offset) => rom.prg_rom[offset],
+            None => {
+                info!("Reading from unmapped prg_rom address: {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
         if address < 0x8000 {
-            info!("Reading from unmapped prg_rom address: {:X}", address);
-            0
-        // Bank switched using 3 bits
+            None
+        // Bank switched using 3 bits, smaller boards just don't connect the
+        // upper ones
         } else {
-            prg_rom[(self.bank_select as usize * THIRTY_TWO_KB)
-                + (address as usize - 0x8000)]
+            Some(self.prg.index(address))
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[address as usize]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        match rom.chr_mut() {
+            Some(chr) => chr[address as usize] = val,
+            None => info!(
+                "Attempt to store to chr rom {:X} val {}",
+                address, val
+            ),
         }
     }
 
-    pub fn ld_chr(&self, address: u16, chr_ram: &Vec<u8>) -> u8 {
-        chr_ram[address as usize]
+    fn bus_conflicts(&self) -> bool {
+        self.bus_conflicts
     }
 
-    pub fn store_chr(&mut self, address: u16, val: u8, chr_ram: &mut Vec<u8>) {
-        chr_ram[address as usize] = val;
+    // Mirroring is looked up on every nametable access and CHR isn't
+    // banked, so nothing the PPU caches ever changes
+    fn switches_chr(&self, _address: u16) -> bool {
+        false
     }
 
-    pub fn reset(&mut self) {
-        self.bank_select = 0;
+    fn reset(&mut self) {
+        self.prg.switch(0, 0);
+        self.mirror_select = 0;
     }
 
-    pub fn get_mirroring(&self) -> ScreenMode {
+    fn get_mirroring(&self) -> ScreenMode {
         match self.mirror_select {
             0 => ScreenMode::OneScreenSwap(ScreenBank::Lower),
             1 => ScreenMode::OneScreenSwap(ScreenBank::Upper),
             _ => panic!("2 bit number can't be greater than 1"),
         }
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
 }
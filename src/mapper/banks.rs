@@ -0,0 +1,76 @@
+use serde::Serialize;
+use serde::Deserialize;
+
+// Which bank a window shows
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Bank {
+    // Counting from the start of the chip
+    Number(usize),
+    // Counting back from the end, 1 being the last bank. Lets boards pin
+    // their fixed banks without knowing how big the chip is
+    FromEnd(usize),
+}
+
+// Eight 1KB CHR windows, starting out in order
+pub const ONE_KB_BANKS: [Bank; 8] = [
+    Bank::Number(0),
+    Bank::Number(1),
+    Bank::Number(2),
+    Bank::Number(3),
+    Bank::Number(4),
+    Bank::Number(5),
+    Bank::Number(6),
+    Bank::Number(7),
+];
+
+// A range of the address space split into equally sized windows, each of
+// which shows one bank of a ROM or RAM chip. Bank numbers past the end of the
+// chip wrap around, like boards that leave the upper bank lines unconnected
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Banks {
+    start: u16,
+    size: usize,
+    // How big the chip is
+    len: usize,
+    windows: Vec<Bank>,
+}
+
+impl Banks {
+    pub fn new(start: u16, size: usize, len: usize, windows: &[Bank]) -> Banks {
+        Banks {
+            start,
+            size,
+            len,
+            windows: windows.to_vec(),
+        }
+    }
+
+    pub fn set(&mut self, window: usize, bank: Bank) {
+        self.windows[window] = bank;
+    }
+
+    pub fn switch(&mut self, window: usize, bank: usize) {
+        self.set(window, Bank::Number(bank));
+    }
+
+    pub fn count(&self) -> usize {
+        (self.len / self.size).max(1)
+    }
+
+    // The bank a window shows, after wrapping
+    pub fn bank(&self, window: usize) -> usize {
+        let count = self.count();
+        match self.windows[window] {
+            Bank::Number(bank) => bank % count,
+            Bank::FromEnd(back) => (count - back % count) % count,
+        }
+    }
+
+    // Where an address inside the windows lands in the chip. Chips smaller
+    // than a window are mirrored across it
+    pub fn index(&self, address: u16) -> usize {
+        let offset = (address - self.start) as usize;
+        let bank = self.bank(offset / self.size);
+        (bank * self.size + offset % self.size) % self.len.max(1)
+    }
+}
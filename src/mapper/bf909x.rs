@@ -0,0 +1,107 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use rom::ScreenBank;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::banks::Bank;
+use mapper::banks::Banks;
+
+const SIXTEEN_KB: usize = 0x4000;
+
+// Camerica's BF9093 and BF9097, mapper 71, on the Codemasters games. UNROM
+// with the bank register moved up to $C000-$FFFF and no bus conflicts. The
+// BF9097 adds a one screen mirroring select at $9000, which only Fire Hawk
+// writes, so mirroring stays as the header says until something does
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Bf909x {
+    prg: Banks,
+    screen: ScreenMode,
+}
+
+impl Bf909x {
+    pub fn new(prg_rom_size: usize, screen: ScreenMode) -> Bf909x {
+        Bf909x {
+            prg: Banks::new(
+                0x8000,
+                SIXTEEN_KB,
+                prg_rom_size,
+                &[Bank::Number(0), Bank::FromEnd(1)],
+            ),
+            screen,
+        }
+    }
+}
+
+impl Mapper for Bf909x {
+    fn store_prg(&mut self, address: u16, val: u8, _rom: &mut Rom) {
+        match address {
+            0x9000...0x9FFF => {
+                self.screen = if val & 0x10 == 0 {
+                    ScreenMode::OneScreenSwap(ScreenBank::Lower)
+                } else {
+                    ScreenMode::OneScreenSwap(ScreenBank::Upper)
+                };
+            }
+            0xC000...0xFFFF => self.prg.switch(0, val as usize & 0b1111),
+            _ => info!(
+                "Writing to unmapped prg_rom address: {:X} val: {}",
+                address, val
+            ),
+        }
+    }
+
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match self.prg_offset(address) {
+            Some(offset) => rom.prg_rom[offset],
+            None => {
+                info!("Reading from unmapped prg_rom address: {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.prg.index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[address as usize]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        match rom.chr_mut() {
+            Some(chr) => chr[address as usize] = val,
+            None => info!(
+                "Attempt to store to chr rom {:X} val {}",
+                address, val
+            ),
+        }
+    }
+
+    fn switches_chr(&self, _addr: u16) -> bool {
+        false
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        self.screen.clone()
+    }
+
+    fn reset(&mut self) {
+        self.prg.switch(0, 0);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
@@ -0,0 +1,208 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use rom::ScreenBank;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::banks::Bank;
+use mapper::banks::Banks;
+use mapper::banks::ONE_KB_BANKS;
+
+const EIGHT_KB: usize = 0x2000;
+const ONE_KB: usize = 0x400;
+
+// Sunsoft's FME-7, mapper 69. Everything goes through a command register at
+// $8000 picking which of 16 internal registers the next write to $A000 sets.
+// Four switchable 8KB PRG banks, one of which can be RAM at $6000, eight 1KB
+// CHR banks and a 16 bit IRQ counter clocked by the CPU
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Fme7 {
+    command: u8,
+    // $6000 to $FFFF, the first window only when RAM isn't selected
+    prg: Banks,
+    ram: Banks,
+    chr: Banks,
+    ram_selected: bool,
+    ram_enabled: bool,
+    mirroring: u8,
+    irq_enabled: bool,
+    counter_enabled: bool,
+    irq_counter: u16,
+    irq: bool,
+}
+
+impl Fme7 {
+    pub fn new(
+        prg_rom_size: usize,
+        prg_ram_size: usize,
+        chr_size: usize,
+    ) -> Fme7 {
+        Fme7 {
+            command: 0,
+            prg: Banks::new(
+                0x6000,
+                EIGHT_KB,
+                prg_rom_size,
+                &[
+                    Bank::Number(0),
+                    Bank::Number(0),
+                    Bank::Number(1),
+                    Bank::Number(2),
+                    Bank::FromEnd(1),
+                ],
+            ),
+            ram: Banks::new(0x6000, EIGHT_KB, prg_ram_size, &[Bank::Number(0)]),
+            chr: Banks::new(0, ONE_KB, chr_size, &ONE_KB_BANKS),
+            ram_selected: false,
+            ram_enabled: false,
+            mirroring: 0,
+            irq_enabled: false,
+            counter_enabled: false,
+            irq_counter: 0,
+            irq: false,
+        }
+    }
+
+    fn write_parameter(&mut self, val: u8) {
+        match self.command {
+            command @ 0x0...0x7 => {
+                self.chr.switch(command as usize, val as usize)
+            }
+            // RAM select and enable, then the bank for $6000 either way
+            0x8 => {
+                self.ram_enabled = val & 0x80 != 0;
+                self.ram_selected = val & 0x40 != 0;
+                self.prg.switch(0, val as usize & 0x3F);
+                self.ram.switch(0, val as usize & 0x3F);
+            }
+            command @ 0x9...0xB => {
+                self.prg.switch(command as usize - 0x8, val as usize & 0x3F);
+            }
+            0xC => self.mirroring = val & 0x03,
+            // Any write here acknowledges the IRQ
+            0xD => {
+                self.irq_enabled = val & 0x01 != 0;
+                self.counter_enabled = val & 0x80 != 0;
+                self.irq = false;
+            }
+            0xE => self.irq_counter = self.irq_counter & 0xFF00 | val as u16,
+            _ => {
+                self.irq_counter = self.irq_counter & 0x00FF | (val as u16) << 8
+            }
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match address {
+            0x6000...0x7FFF if self.ram_selected => {
+                if self.ram_enabled {
+                    // NES 2.0 headers can leave the PRG RAM out
+                    let index = self.ram.index(address);
+                    rom.prg_ram.get(index).cloned().unwrap_or(0)
+                } else {
+                    info!("Reading from disabled prg_ram {:X}", address);
+                    0
+                }
+            }
+            0x6000...0xFFFF => rom.prg_rom[self.prg.index(address)],
+            _ => {
+                info!("Reading from unmapped memory {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        match address {
+            0x6000...0x7FFF => {
+                if self.ram_selected && self.ram_enabled {
+                    let index = self.ram.index(address);
+                    if let Some(byte) = rom.prg_ram.get_mut(index) {
+                        *byte = val;
+                    }
+                }
+            }
+            0x8000...0x9FFF => self.command = val & 0x0F,
+            0xA000...0xBFFF => self.write_parameter(val),
+            _ => info!("Storing to unmapped prg mem {:X}", address),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x6000...0x7FFF if !self.ram_selected => {
+                Some(self.prg.index(address))
+            }
+            0x8000...0xFFFF => Some(self.prg.index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr.index(address)]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.chr.index(address);
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!("Attempting to write to chr rom {:X}", address),
+        }
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        match self.mirroring {
+            0 => ScreenMode::Vertical,
+            1 => ScreenMode::Horizontal,
+            2 => ScreenMode::OneScreenSwap(ScreenBank::Lower),
+            _ => ScreenMode::OneScreenSwap(ScreenBank::Upper),
+        }
+    }
+
+    fn controls_prg_ram(&self) -> bool {
+        true
+    }
+
+    // Only the parameter register changes anything, and even then only for
+    // the CHR commands
+    fn switches_chr(&self, address: u16) -> bool {
+        match address {
+            0xA000...0xBFFF => self.command < 0x8,
+            _ => false,
+        }
+    }
+
+    // Counts down every cycle, and fires on wrapping from 0 to $FFFF
+    fn clock(&mut self) {
+        if !self.counter_enabled {
+            return;
+        }
+        self.irq_counter = self.irq_counter.wrapping_sub(1);
+        if self.irq_counter == 0xFFFF && self.irq_enabled {
+            self.irq = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    fn reset(&mut self) {
+        self.irq_enabled = false;
+        self.counter_enabled = false;
+        self.irq = false;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
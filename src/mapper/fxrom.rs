@@ -0,0 +1,125 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::pxrom::ChrLatch;
+
+const SIXTEEN_KB: usize = 0x4000;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// MMC4, mapper 10, used by the Fire Emblem games and a few other Famicom
+// titles. The same CHR latches as the MMC2, with a 16KB PRG bank at $8000,
+// the last bank fixed at $C000 and 8KB of PRG RAM
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct Fxrom {
+    prg_bank: u8,
+    last_page_start: usize,
+    chr: ChrLatch,
+    horizontal: bool,
+}
+
+impl Fxrom {
+    pub fn new(last_page_start: usize) -> Fxrom {
+        Fxrom {
+            prg_bank: 0,
+            last_page_start: last_page_start,
+            chr: ChrLatch::new(true),
+            horizontal: false,
+        }
+    }
+}
+
+impl Mapper for Fxrom {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match self.prg_offset(address) {
+            Some(offset) => rom.prg_rom[offset],
+            None => {
+                info!("Reading from unmapped prg_rom address: {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, _rom: &mut Rom) {
+        match address {
+            0xA000...0xAFFF => self.prg_bank = val & 0x0F,
+            0xB000...0xBFFF => self.chr.set_bank(0, 0xFD, val),
+            0xC000...0xCFFF => self.chr.set_bank(0, 0xFE, val),
+            0xD000...0xDFFF => self.chr.set_bank(1, 0xFD, val),
+            0xE000...0xEFFF => self.chr.set_bank(1, 0xFE, val),
+            0xF000...0xFFFF => self.horizontal = val & 1 != 0,
+            _ => info!(
+                "Writing to unmapped prg_rom address: {:X} val: {}",
+                address, val
+            ),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        let banks = self.last_page_start / SIXTEEN_KB + 1;
+        match address {
+            0x8000...0xBFFF => {
+                let bank = self.prg_bank as usize % banks;
+                Some(bank * SIXTEEN_KB + (address as usize - 0x8000))
+            }
+            0xC000...0xFFFF => {
+                Some(self.last_page_start + (address as usize - 0xC000))
+            }
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr.chr_index(address, rom.chr().len())]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.chr.chr_index(address, rom.chr().len());
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!(
+                "Attempt to store to chr rom {:X} val {}",
+                address, val
+            ),
+        }
+    }
+
+    fn prg_ram_size(&self) -> Option<usize> {
+        Some(PRG_RAM_SIZE)
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        if self.horizontal {
+            ScreenMode::Horizontal
+        } else {
+            ScreenMode::Vertical
+        }
+    }
+
+    fn switches_chr(&self, address: u16) -> bool {
+        match address {
+            0xB000...0xEFFF => true,
+            _ => false,
+        }
+    }
+
+    fn pattern_fetch(&mut self, address: u16) -> bool {
+        self.chr.fetch(address)
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
@@ -0,0 +1,135 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::banks::Bank;
+use mapper::banks::Banks;
+
+const THIRTY_TWO_KB: usize = 0x8000;
+const EIGHT_KB: usize = 0x2000;
+
+// Which bits of the register pick the PRG and CHR banks
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Wiring {
+    // Mapper 3, CCCC CCCC with PRG fixed. Only 2 bits on the original board,
+    // later ones use more
+    Cnrom,
+    // Mapper 11, CCCC --PP
+    ColorDreams,
+    // Mapper 66, --PP --CC
+    Gxrom,
+}
+
+// Boards with a single latch at $8000-$FFFF that switches an 8KB CHR bank,
+// and on most a 32KB PRG bank along with it. 16KB of PRG is mirrored
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Gxrom {
+    wiring: Wiring,
+    prg: Banks,
+    chr: Banks,
+    bus_conflicts: bool,
+    screen: ScreenMode,
+}
+
+impl Gxrom {
+    pub fn new(
+        wiring: Wiring,
+        prg_rom_size: usize,
+        chr_size: usize,
+        bus_conflicts: bool,
+        screen: ScreenMode,
+    ) -> Gxrom {
+        Gxrom {
+            wiring: wiring,
+            prg: Banks::new(
+                0x8000,
+                THIRTY_TWO_KB,
+                prg_rom_size,
+                &[Bank::Number(0)],
+            ),
+            chr: Banks::new(0, EIGHT_KB, chr_size, &[Bank::Number(0)]),
+            bus_conflicts: bus_conflicts,
+            screen: screen,
+        }
+    }
+}
+
+impl Mapper for Gxrom {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match self.prg_offset(address) {
+            Some(offset) => rom.prg_rom[offset],
+            None => {
+                info!("Reading from unmapped prg_rom address: {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, _rom: &mut Rom) {
+        if address < 0x8000 {
+            info!(
+                "Writing to unmapped prg_rom address: {:X} val: {}",
+                address, val
+            );
+            return;
+        }
+        match self.wiring {
+            Wiring::Cnrom => self.chr.switch(0, val as usize),
+            Wiring::ColorDreams => {
+                self.prg.switch(0, val as usize & 0x03);
+                self.chr.switch(0, val as usize >> 4);
+            }
+            Wiring::Gxrom => {
+                self.prg.switch(0, (val as usize >> 4) & 0x03);
+                self.chr.switch(0, val as usize & 0x03);
+            }
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.prg.index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr.index(address)]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.chr.index(address);
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!(
+                "Attempt to store to chr rom {:X} val {}",
+                address, val
+            ),
+        }
+    }
+
+    fn bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        self.screen.clone()
+    }
+
+    fn reset(&mut self) {
+        self.prg.switch(0, 0);
+        self.chr.switch(0, 0);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
@@ -0,0 +1,237 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use mapper::Mapper;
+use mapper::Nametable;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::banks::Bank;
+use mapper::banks::Banks;
+use mapper::banks::ONE_KB_BANKS;
+
+const EIGHT_KB: usize = 0x2000;
+const ONE_KB: usize = 0x400;
+const INTERNAL_RAM_SIZE: usize = 0x80;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+// Namco 129 and 163, mapper 19. Three switchable 8KB PRG banks, eight 1KB
+// CHR banks and four more for the nametables, which can each be CHR ROM or
+// either page of the console's VRAM. Also has a 15 bit IRQ counter clocked
+// by the CPU and 128 bytes of RAM behind a port at $4800, which the 163's
+// sound channels read their waveforms from. The 8KB of PRG RAM can be write
+// protected 2KB at a time
+#[derive(Serialize, Deserialize, Clone)]
+pub struct N163 {
+    prg: Banks,
+    chr: Banks,
+    // Banks of $E0 and up are VRAM pages instead
+    nt_select: [u8; 4],
+    ram: Vec<u8>,
+    ram_address: u8,
+    auto_increment: bool,
+    // Shares $F800 with the RAM address, the top nibble has to be 0100 for
+    // any writes and each low bit protects one 2KB quarter
+    prg_ram_protect: u8,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq: bool,
+}
+
+impl N163 {
+    pub fn new(prg_rom_size: usize, chr_size: usize) -> N163 {
+        N163 {
+            prg: Banks::new(
+                0x8000,
+                EIGHT_KB,
+                prg_rom_size,
+                &[
+                    Bank::Number(0),
+                    Bank::Number(1),
+                    Bank::Number(2),
+                    Bank::FromEnd(1),
+                ],
+            ),
+            chr: Banks::new(0, ONE_KB, chr_size, &ONE_KB_BANKS),
+            nt_select: [0xE0, 0xE1, 0xE0, 0xE1],
+            ram: vec![0; INTERNAL_RAM_SIZE],
+            ram_address: 0,
+            auto_increment: false,
+            prg_ram_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq: false,
+        }
+    }
+
+    fn ram_port(&mut self) -> &mut u8 {
+        let index = self.ram_address as usize;
+        if self.auto_increment {
+            self.ram_address = (self.ram_address + 1) & 0x7F;
+        }
+        &mut self.ram[index]
+    }
+
+    fn write_register(&mut self, address: u16, val: u8) {
+        match address {
+            0x4800...0x4FFF => *self.ram_port() = val,
+            // Both halves of the counter acknowledge the IRQ
+            0x5000...0x57FF => {
+                self.irq_counter = self.irq_counter & 0x7F00 | val as u16;
+                self.irq = false;
+            }
+            0x5800...0x5FFF => {
+                let high = ((val & 0x7F) as u16) << 8;
+                self.irq_counter = high | self.irq_counter & 0xFF;
+                self.irq_enabled = val & 0x80 != 0;
+                self.irq = false;
+            }
+            0x8000...0xBFFF => {
+                let window = (address as usize - 0x8000) >> 11;
+                self.chr.switch(window, val as usize);
+            }
+            0xC000...0xDFFF => {
+                self.nt_select[(address as usize - 0xC000) >> 11] = val;
+            }
+            // The top bits here turn off the sound and pick whether CHR
+            // banks of $E0 and up are VRAM, neither of which is emulated
+            0xE000...0xF7FF => {
+                let window = (address as usize - 0xE000) >> 11;
+                self.prg.switch(window, val as usize & 0x3F);
+            }
+            0xF800...0xFFFF => {
+                self.auto_increment = val & 0x80 != 0;
+                self.ram_address = val & 0x7F;
+                self.prg_ram_protect = val;
+            }
+            _ => info!(
+                "Writing to unmapped prg_rom address: {:X} val: {}",
+                address, val
+            ),
+        }
+    }
+
+    fn prg_ram_writable(&self, address: u16) -> bool {
+        let quarter = (address as usize - 0x6000) >> 11;
+        self.prg_ram_protect & 0xF0 == 0x40
+            && self.prg_ram_protect & (1 << quarter) == 0
+    }
+}
+
+impl Mapper for N163 {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match address {
+            0x4800...0x4FFF => self.ram[self.ram_address as usize],
+            0x5000...0x57FF => self.irq_counter as u8,
+            0x5800...0x5FFF => {
+                (self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7
+            }
+            0x6000...0x7FFF => rom.prg_ram[address as usize - 0x6000],
+            0x8000...0xFFFF => rom.prg_rom[self.prg.index(address)],
+            _ => {
+                info!("Reading from unmapped memory {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn read_prg(&mut self, address: u16, rom: &Rom) -> u8 {
+        match address {
+            0x4800...0x4FFF => *self.ram_port(),
+            _ => self.ld_prg(address, rom),
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        match address {
+            0x6000...0x7FFF => {
+                if self.prg_ram_writable(address) {
+                    rom.prg_ram[address as usize - 0x6000] = val;
+                }
+            }
+            _ => self.write_register(address, val),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.prg.index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr.index(address)]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.chr.index(address);
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!(
+                "Attempt to store to chr rom {:X} val {}",
+                address, val
+            ),
+        }
+    }
+
+    fn prg_ram_size(&self) -> Option<usize> {
+        Some(PRG_RAM_SIZE)
+    }
+
+    // Only right when the nametables are set up like one, see nametable
+    fn get_mirroring(&self) -> ScreenMode {
+        if self.nt_select[0] == self.nt_select[1] {
+            ScreenMode::Horizontal
+        } else {
+            ScreenMode::Vertical
+        }
+    }
+
+    fn nametable(&self, table: u16) -> Nametable {
+        match self.nt_select[table as usize] {
+            bank @ 0xE0...0xFF => Nametable::Vram((bank & 1) as u16),
+            bank => Nametable::Chr(bank as usize),
+        }
+    }
+
+    fn controls_prg_ram(&self) -> bool {
+        true
+    }
+
+    fn switches_chr(&self, address: u16) -> bool {
+        match address {
+            0x8000...0xBFFF => true,
+            _ => false,
+        }
+    }
+
+    // Counts up every cycle, stopping at $7FFF which fires the IRQ
+    fn clock(&mut self) {
+        if !self.irq_enabled || self.irq_counter == 0x7FFF {
+            return;
+        }
+        self.irq_counter += 1;
+        if self.irq_counter == 0x7FFF {
+            self.irq = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    fn reset(&mut self) {
+        self.irq_enabled = false;
+        self.irq = false;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
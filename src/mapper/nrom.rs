@@ -1,5 +1,11 @@
 use serde::Serialize;
 use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
 
 const UNMIRRORED_MASK: usize = 0x7FFF;
 const MIRRORED_MASK: usize = 0x3FFF;
@@ -7,56 +13,73 @@ const NROM_PRG_ROM_START: u16 = 0x8000;
 
 const SIXTEEN_KB: usize = 0x4000;
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Nrom {
     mirrored: bool,
-    use_chr_ram: bool,
+    // Soldered on the board, straight from the header
+    screen: ScreenMode,
 }
 
 impl Nrom {
-    pub fn new(prg_rom_size: usize, use_chr_ram: bool) -> Nrom {
+    pub fn new(prg_rom_size: usize, screen: ScreenMode) -> Nrom {
         Nrom {
             mirrored: prg_rom_size <= SIXTEEN_KB,
-            use_chr_ram,
+            screen,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match self.prg_offset(address) {
+            Some(offset) => rom.prg_rom[offset],
+            None => {
+                info!("Attempt to read from nrom {:X}", address);
+                0
+            }
         }
     }
 
-    pub fn ld_prg(&self, address: u16, prg_rom: &Vec<u8>) -> u8 {
+    fn prg_offset(&self, address: u16) -> Option<usize> {
         if address < NROM_PRG_ROM_START {
-            info!("Attempt to read from nrom {:X}", address);
-            0
+            None
         } else if self.mirrored {
-            prg_rom[address as usize & MIRRORED_MASK]
+            Some(address as usize & MIRRORED_MASK)
         } else {
-            prg_rom[address as usize & UNMIRRORED_MASK]
+            Some(address as usize & UNMIRRORED_MASK)
         }
     }
 
-    pub fn store_prg(&self, address: u16, val: u8) {
+    fn store_prg(&mut self, address: u16, val: u8, _rom: &mut Rom) {
         info!(
             "Attempt to write to nrom address {:X}, val {}",
             address, val
         );
     }
 
-    pub fn ld_chr(
-        &self,
-        address: u16,
-        chr_rom: &Vec<u8>,
-        chr_ram: &Vec<u8>,
-    ) -> u8 {
-        if self.use_chr_ram {
-            chr_ram[address as usize]
-        } else {
-            chr_rom[address as usize]
-        }
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[address as usize]
     }
 
-    pub fn store_chr(&mut self, address: u16, val: u8, chr_ram: &mut Vec<u8>) {
-        if self.use_chr_ram {
-            chr_ram[address as usize] = val;
-        } else {
-            info!("Attempt to store to nrom address {:X} val {}", address, val);
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        match rom.chr_mut() {
+            Some(chr) => chr[address as usize] = val,
+            None => info!(
+                "Attempt to store to nrom address {:X} val {}",
+                address, val
+            ),
         }
     }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        self.screen.clone()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
 }
@@ -0,0 +1,403 @@
+use std::f32::consts::PI;
+use serde::Serialize;
+use serde::Deserialize;
+
+// The chip runs every one of its channels once per 36 CPU cycles
+const CYCLES_PER_SAMPLE: u8 = 36;
+const SAMPLE_RATE: f32 = 1_789_773.0 / 36.0;
+// An envelope this far down is silent
+const MAX_ATTENUATION: f32 = 48.0;
+// Output of one channel at full volume, about as loud as a pulse channel
+const CHANNEL_GAIN: f32 = 0.15;
+
+const TREMOLO_HZ: f32 = 3.7;
+const TREMOLO_DB: f32 = 4.8;
+const VIBRATO_HZ: f32 = 6.4;
+const VIBRATO_CENTS: f32 = 7.0;
+
+// The 15 instruments baked into the VRC7, laid out like the custom one at
+// registers $00-$07. Dumped from a decapped chip
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+// Frequency multipliers, doubled so the 1/2 at 0 is a whole number too
+const MULTIPLIERS: [u32; 16] =
+    [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+// Key scale attenuation in dB at octave 7 for the top 4 bits of the
+// F-number. Each octave lower takes off 6dB
+const KEY_SCALE: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25,
+    39.0, 39.75, 40.5, 41.25, 42.0,
+];
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq)]
+enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// One of the two halves of a channel, a sine wave with its own envelope.
+// The modulator's output bends the carrier's phase, and the carrier's is
+// what's heard
+#[derive(Serialize, Deserialize, Copy, Clone)]
+struct Operator {
+    // 19 bits to a full wave
+    phase: u32,
+    stage: Stage,
+    // In dB
+    envelope: f32,
+    // The last two outputs, the modulator feeds them back into itself
+    outputs: [f32; 2],
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0,
+            stage: Stage::Release,
+            envelope: MAX_ATTENUATION,
+            outputs: [0.0; 2],
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0;
+        self.stage = Stage::Attack;
+    }
+
+    fn key_off(&mut self) {
+        self.stage = Stage::Release;
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone)]
+struct Channel {
+    f_number: u16,
+    octave: u8,
+    key: bool,
+    // Makes released notes fade out slower
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+    operators: [Operator; 2],
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            f_number: 0,
+            octave: 0,
+            key: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            operators: [Operator::new(); 2],
+        }
+    }
+
+    fn set_key(&mut self, key: bool) {
+        if key && !self.key {
+            self.operators[0].key_on();
+            self.operators[1].key_on();
+        } else if !key && self.key {
+            self.operators[0].key_off();
+            self.operators[1].key_off();
+        }
+        self.key = key;
+    }
+
+    // Higher notes have faster envelopes, by a lot more with KSR set
+    fn key_scale_rate(&self, ksr: bool) -> u8 {
+        let key = self.octave << 1 | (self.f_number >> 8) as u8;
+        if ksr {
+            key
+        } else {
+            key >> 2
+        }
+    }
+
+    fn key_scale_level(&self, ksl: u8) -> f32 {
+        let octaves_down = 7 - self.octave as i32;
+        let base = KEY_SCALE[(self.f_number >> 5) as usize]
+            - 6.0 * octaves_down as f32;
+        match ksl {
+            _ if base <= 0.0 => 0.0,
+            0 => 0.0,
+            1 => base / 4.0,
+            2 => base / 2.0,
+            _ => base,
+        }
+    }
+}
+
+// The 6 channel FM synth in the VRC7, a cut down Yamaha YM2413 (OPLL).
+// Driven through an address port at $9010 and a data port at $9030
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Opll {
+    address: u8,
+    custom: [u8; 8],
+    channels: [Channel; 6],
+    cycles: u8,
+    // Where the tremolo and vibrato are in their waves, 0-1
+    tremolo: f32,
+    vibrato: f32,
+    output: f32,
+    // Held by the VRC7's sound reset bit, which keeps everything quiet
+    pub silenced: bool,
+}
+
+impl Opll {
+    pub fn new() -> Opll {
+        Opll {
+            address: 0,
+            custom: [0; 8],
+            channels: [Channel::new(); 6],
+            cycles: 0,
+            tremolo: 0.0,
+            vibrato: 0.0,
+            output: 0.0,
+            silenced: false,
+        }
+    }
+
+    pub fn select(&mut self, val: u8) {
+        self.address = val;
+    }
+
+    pub fn write(&mut self, val: u8) {
+        let address = self.address;
+        let channel = (address & 0x0F) as usize;
+        match address {
+            0x00...0x07 => self.custom[address as usize] = val,
+            0x10...0x15 => {
+                let high = self.channels[channel].f_number & 0x100;
+                self.channels[channel].f_number = high | val as u16;
+            }
+            0x20...0x25 => {
+                let channel = &mut self.channels[channel];
+                let low = channel.f_number & 0xFF;
+                channel.f_number = ((val & 1) as u16) << 8 | low;
+                channel.octave = (val >> 1) & 0x07;
+                channel.sustain = val & 0x20 != 0;
+                channel.set_key(val & 0x10 != 0);
+            }
+            0x30...0x35 => {
+                self.channels[channel].instrument = val >> 4;
+                self.channels[channel].volume = val & 0x0F;
+            }
+            _ => info!("Writing to unused VRC7 audio register {:X}", address),
+        }
+    }
+
+    // Somewhere around the level the APU mixes to, but signed
+    pub fn output(&self) -> f32 {
+        if self.silenced {
+            0.0
+        } else {
+            self.output
+        }
+    }
+
+    // Every CPU cycle
+    pub fn clock(&mut self) {
+        self.cycles += 1;
+        if self.cycles < CYCLES_PER_SAMPLE {
+            return;
+        }
+        self.cycles = 0;
+        self.tremolo = (self.tremolo + TREMOLO_HZ / SAMPLE_RATE).fract();
+        self.vibrato = (self.vibrato + VIBRATO_HZ / SAMPLE_RATE).fract();
+        let mut output = 0.0;
+        for i in 0..self.channels.len() {
+            output += self.clock_channel(i);
+        }
+        self.output = output * CHANNEL_GAIN;
+    }
+
+    fn patch(&self, channel: &Channel) -> [u8; 8] {
+        match channel.instrument {
+            0 => self.custom,
+            instrument => PATCHES[instrument as usize - 1],
+        }
+    }
+
+    fn clock_channel(&mut self, index: usize) -> f32 {
+        let patch = self.patch(&self.channels[index]);
+        let lfo = Lfo {
+            tremolo: TREMOLO_DB * (1.0 - (2.0 * PI * self.tremolo).cos()) / 2.0,
+            vibrato: 2f32.powf(
+                VIBRATO_CENTS * (2.0 * PI * self.vibrato).sin() / 1200.0,
+            ),
+        };
+        let channel = &mut self.channels[index];
+
+        // The modulator's level comes from the patch, the carrier's from the
+        // channel's volume
+        let feedback = patch[3] & 0x07;
+        let modulation = if feedback == 0 {
+            0.0
+        } else {
+            let outputs = channel.operators[0].outputs;
+            (outputs[0] + outputs[1]) / 2.0 * (1 << (feedback - 1)) as f32
+                / 32.0
+        };
+        let level = (patch[2] & 0x3F) as f32 * 0.75;
+        let modulator =
+            channel.clock_operator(0, &patch, level, modulation, &lfo);
+        // A full swing of the modulator moves the carrier two waves
+        let level = channel.volume as f32 * 3.0;
+        channel.clock_operator(1, &patch, level, modulator * 2.0, &lfo)
+    }
+}
+
+// Where the tremolo and vibrato are this sample, as dB to take off and an
+// amount to multiply the frequency by
+struct Lfo {
+    tremolo: f32,
+    vibrato: f32,
+}
+
+impl Channel {
+    fn clock_operator(
+        &mut self,
+        op: usize,
+        patch: &[u8; 8],
+        level: f32,
+        modulation: f32,
+        lfo: &Lfo,
+    ) -> f32 {
+        let flags = patch[op];
+        let am = flags & 0x80 != 0;
+        let vib = flags & 0x40 != 0;
+        let sustained = flags & 0x20 != 0;
+        let ksr = flags & 0x10 != 0;
+        let multiplier = MULTIPLIERS[(flags & 0x0F) as usize];
+        let ksl = patch[2 + op] >> 6;
+        let rectified = patch[3] & (0x08 << op) != 0;
+        let attack = patch[4 + op] >> 4;
+        let decay = patch[4 + op] & 0x0F;
+        let sustain_level = (patch[6 + op] >> 4) as f32 * 3.0;
+        let release = patch[6 + op] & 0x0F;
+
+        let scale = self.key_scale_rate(ksr);
+        let key_scale_level = self.key_scale_level(ksl);
+        // Once the key's let go percussive patches fade at a fixed rate,
+        // and the channel's sustain slows everything down
+        let release = if self.key || (sustained && !self.sustain) {
+            release
+        } else if self.sustain {
+            5
+        } else {
+            7
+        };
+        let step = ((self.f_number as u32) << self.octave) * multiplier / 2;
+        let operator = &mut self.operators[op];
+        match operator.stage {
+            Stage::Attack => {
+                operator.envelope *= attack_factor(attack, scale);
+                if operator.envelope < 0.1 {
+                    operator.envelope = 0.0;
+                    operator.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                operator.envelope += decay_step(decay, scale);
+                if operator.envelope >= sustain_level {
+                    operator.envelope = sustain_level;
+                    operator.stage = Stage::Sustain;
+                }
+            }
+            // Percussive patches keep fading even with the key held
+            Stage::Sustain if !sustained => {
+                operator.envelope += decay_step(release, scale);
+            }
+            Stage::Sustain => (),
+            Stage::Release => {
+                operator.envelope += decay_step(release, scale);
+            }
+        }
+        operator.envelope = operator.envelope.min(MAX_ATTENUATION);
+
+        let step = if vib {
+            (step as f32 * lfo.vibrato) as u32
+        } else {
+            step
+        };
+        operator.phase = (operator.phase + step) & 0x7FFFF;
+
+        let wave = operator.phase as f32 / 0x80000 as f32 + modulation;
+        let mut sample = (2.0 * PI * wave).sin();
+        // Only the top half of the wave
+        if rectified && sample < 0.0 {
+            sample = 0.0;
+        }
+        let mut attenuation = operator.envelope + level + key_scale_level;
+        if am {
+            attenuation += lfo.tremolo;
+        }
+        let output = if operator.envelope >= MAX_ATTENUATION {
+            0.0
+        } else {
+            sample * 10f32.powf(-attenuation / 20.0)
+        };
+        operator.outputs = [operator.outputs[1], output];
+        output
+    }
+}
+
+// Rates are 4 bits in the patch but 6 once the key scaling is added in
+fn effective_rate(rate: u8, scale: u8) -> u8 {
+    if rate == 0 {
+        0
+    } else {
+        (rate * 4 + scale).min(63)
+    }
+}
+
+// dB added each sample. At rate 1 it takes 39 seconds to fall 96dB, and
+// every rate after that halves it
+fn decay_step(rate: u8, scale: u8) -> f32 {
+    let rate = effective_rate(rate, scale);
+    if rate < 4 {
+        return 0.0;
+    }
+    let fine = 1.0 + (rate & 3) as f32 / 4.0;
+    let coarse = (1u32 << ((rate >> 2) - 1)) as f32;
+    96.0 / (39.28 * SAMPLE_RATE) * coarse * fine
+}
+
+// What the attenuation gets multiplied by each sample, attacks curve
+// towards full volume instead of rising in a straight line. At rate 1 one
+// takes 2.8 seconds and every rate after that halves it, the top rates are
+// instant
+fn attack_factor(rate: u8, scale: u8) -> f32 {
+    let rate = effective_rate(rate, scale);
+    if rate < 4 {
+        return 1.0;
+    }
+    if rate >= 60 {
+        return 0.0;
+    }
+    let fine = 1.0 + (rate & 3) as f32 / 4.0;
+    let coarse = (1u32 << ((rate >> 2) - 1)) as f32;
+    let samples = 2.826 / (coarse * fine) * SAMPLE_RATE;
+    (0.1 / MAX_ATTENUATION).powf(1.0 / samples)
+}
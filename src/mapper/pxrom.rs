@@ -0,0 +1,175 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+
+const EIGHT_KB: usize = 0x2000;
+const FOUR_KB: usize = 0x1000;
+
+// Two latches, one per pattern table, that flip between $FD and $FE when
+// the PPU fetches tile $FD or $FE. Each latch picks which of two 4KB CHR
+// banks that pattern table shows, which lets a game switch graphics partway
+// down the screen just by drawing the right tile
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct ChrLatch {
+    // Bank for each table with the latch at $FD and at $FE
+    banks: [[u8; 2]; 2],
+    latches: [u8; 2],
+    // The MMC4 watches every row of the tiles in both tables
+    all_rows: bool,
+}
+
+impl ChrLatch {
+    pub fn new(all_rows: bool) -> ChrLatch {
+        ChrLatch {
+            banks: [[0; 2]; 2],
+            latches: [0xFE; 2],
+            all_rows: all_rows,
+        }
+    }
+
+    pub fn set_bank(&mut self, table: usize, latch: u8, bank: u8) {
+        self.banks[table][(latch - 0xFD) as usize] = bank & 0x1F;
+    }
+
+    pub fn chr_index(&self, address: u16, chr_len: usize) -> usize {
+        let table = (address >> 12) as usize & 1;
+        let bank = self.banks[table][(self.latches[table] - 0xFD) as usize];
+        (bank as usize * FOUR_KB + (address as usize & 0xFFF)) % chr_len
+    }
+
+    // Only the high bit plane fetches count. The MMC2 only watches the first
+    // row's in the first pattern table, but every row's in the second.
+    // Returns whether a latch changed
+    pub fn fetch(&mut self, address: u16) -> bool {
+        let (table, latch) = match address {
+            0x0FD8 => (0, 0xFD),
+            0x0FE8 => (0, 0xFE),
+            0x0FD9...0x0FDF if self.all_rows => (0, 0xFD),
+            0x0FE9...0x0FEF if self.all_rows => (0, 0xFE),
+            0x1FD8...0x1FDF => (1, 0xFD),
+            0x1FE8...0x1FEF => (1, 0xFE),
+            _ => return false,
+        };
+        let changed = self.latches[table] != latch;
+        self.latches[table] = latch;
+        changed
+    }
+}
+
+// MMC2, mapper 9, only used by Punch-Out!!. An 8KB PRG bank at $8000 with
+// the last three fixed after it, and CHR switched by ChrLatch
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct Pxrom {
+    prg_bank: u8,
+    prg_banks: usize,
+    chr: ChrLatch,
+    horizontal: bool,
+}
+
+impl Pxrom {
+    pub fn new(prg_rom_size: usize) -> Pxrom {
+        Pxrom {
+            prg_bank: 0,
+            prg_banks: prg_rom_size / EIGHT_KB,
+            chr: ChrLatch::new(false),
+            horizontal: false,
+        }
+    }
+
+    fn prg_index(&self, address: u16) -> usize {
+        let bank = match address {
+            0x8000...0x9FFF => self.prg_bank as usize % self.prg_banks,
+            // The last three banks, in order, wrapping on smaller chips
+            _ => {
+                let back = 4 - ((address as usize - 0x8000) >> 13);
+                (self.prg_banks - back % self.prg_banks) % self.prg_banks
+            }
+        };
+        bank * EIGHT_KB + (address as usize & 0x1FFF)
+    }
+}
+
+impl Mapper for Pxrom {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match self.prg_offset(address) {
+            Some(offset) => rom.prg_rom[offset],
+            None => {
+                info!("Reading from unmapped prg_rom address: {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, _rom: &mut Rom) {
+        match address {
+            0xA000...0xAFFF => self.prg_bank = val & 0x0F,
+            0xB000...0xBFFF => self.chr.set_bank(0, 0xFD, val),
+            0xC000...0xCFFF => self.chr.set_bank(0, 0xFE, val),
+            0xD000...0xDFFF => self.chr.set_bank(1, 0xFD, val),
+            0xE000...0xEFFF => self.chr.set_bank(1, 0xFE, val),
+            0xF000...0xFFFF => self.horizontal = val & 1 != 0,
+            _ => info!(
+                "Writing to unmapped prg_rom address: {:X} val: {}",
+                address, val
+            ),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.prg_index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr.chr_index(address, rom.chr().len())]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.chr.chr_index(address, rom.chr().len());
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!(
+                "Attempt to store to chr rom {:X} val {}",
+                address, val
+            ),
+        }
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        if self.horizontal {
+            ScreenMode::Horizontal
+        } else {
+            ScreenMode::Vertical
+        }
+    }
+
+    fn switches_chr(&self, address: u16) -> bool {
+        match address {
+            0xB000...0xEFFF => true,
+            _ => false,
+        }
+    }
+
+    fn pattern_fetch(&mut self, address: u16) -> bool {
+        self.chr.fetch(address)
+    }
+
+    fn reset(&mut self) {
+        self.prg_bank = 0;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
@@ -0,0 +1,234 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+
+const EIGHT_KB: usize = 0x2000;
+const ONE_KB: usize = 0x400;
+
+bitfield! {
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct BankSelect(u8);
+    as_byte,       _ : 7, 0;
+    chr_inversion, _ : 7;
+    prg_mode,      _ : 6;
+    // Splits the two 2KB CHR banks into 1KB ones, the second halves coming
+    // from R8 and R9
+    chr_1k,        _ : 5;
+    register,      _ : 3, 0;
+}
+
+// Tengen's RAMBO-1, mapper 64. The MMC3 with a third switchable PRG bank,
+// an option for all 1KB CHR banks, and an IRQ counter that can also be
+// clocked every 4 CPU cycles instead of by A12
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Rambo1 {
+    bank_select: BankSelect,
+    // R0-R7 like the MMC3, R8 and R9 the extra 1KB CHR banks and RF the
+    // third PRG bank
+    registers: [u8; 16],
+    mirroring: ScreenMode,
+    four_screen: bool,
+    prg_banks: usize,
+    irq_latch: u8,
+    // One wider than the latch, a reload can put it at $101
+    irq_counter: u16,
+    irq_reload: bool,
+    irq_enabled: bool,
+    cycle_mode: bool,
+    prescaler: u8,
+    irq: bool,
+}
+
+impl Rambo1 {
+    pub fn new(prg_rom_size: usize, screen: ScreenMode) -> Rambo1 {
+        let four_screen = match screen {
+            ScreenMode::FourScreen => true,
+            _ => false,
+        };
+        Rambo1 {
+            bank_select: BankSelect(0),
+            registers: [0, 2, 4, 5, 6, 7, 0, 1, 1, 3, 0, 0, 0, 0, 0, 2],
+            mirroring: screen,
+            four_screen: four_screen,
+            prg_banks: prg_rom_size / EIGHT_KB,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            cycle_mode: false,
+            prescaler: 0,
+            irq: false,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, val: u8) {
+        let even = address & 1 == 0;
+        match (address, even) {
+            (0x8000...0x9FFF, true) => self.bank_select = BankSelect(val),
+            (0x8000...0x9FFF, false) => {
+                let register = self.bank_select.register() as usize;
+                self.registers[register] = val;
+            }
+            (0xA000...0xBFFF, true) => {
+                if !self.four_screen {
+                    self.mirroring = if val & 1 == 0 {
+                        ScreenMode::Vertical
+                    } else {
+                        ScreenMode::Horizontal
+                    };
+                }
+            }
+            (0xA000...0xBFFF, false) => (),
+            (0xC000...0xDFFF, true) => self.irq_latch = val,
+            (0xC000...0xDFFF, false) => {
+                self.cycle_mode = val & 1 != 0;
+                self.prescaler = 0;
+                self.irq_reload = true;
+            }
+            (0xE000...0xFFFF, true) => {
+                self.irq_enabled = false;
+                self.irq = false;
+            }
+            (0xE000...0xFFFF, false) => self.irq_enabled = true,
+            _ => panic!("Registers are only $8000-$FFFF {:X}", address),
+        }
+    }
+
+    fn prg_index(&self, address: u16) -> usize {
+        let registers = &self.registers;
+        let bank = match (address, self.bank_select.prg_mode()) {
+            (0x8000...0x9FFF, false) | (0xA000...0xBFFF, true) => registers[6],
+            (0xA000...0xBFFF, false) | (0xC000...0xDFFF, true) => registers[7],
+            (0x8000...0x9FFF, true) | (0xC000...0xDFFF, false) => {
+                registers[15]
+            }
+            _ => (self.prg_banks - 1) as u8,
+        } as usize;
+        (bank % self.prg_banks) * EIGHT_KB + (address as usize & 0x1FFF)
+    }
+
+    fn chr_index(&self, address: u16, chr_len: usize) -> usize {
+        let flip = if self.bank_select.chr_inversion() { 0x1000 } else { 0 };
+        let address = (address ^ flip) as usize;
+        let second_half = (address >> 10) & 1 != 0;
+        let bank = match (address, self.bank_select.chr_1k()) {
+            (0x0000...0x07FF, true) if second_half => self.registers[8],
+            (0x0800...0x0FFF, true) if second_half => self.registers[9],
+            (0x0000...0x07FF, true) => self.registers[0],
+            (0x0800...0x0FFF, true) => self.registers[1],
+            (0x0000...0x07FF, false) => {
+                self.registers[0] & 0xFE | second_half as u8
+            }
+            (0x0800...0x0FFF, false) => {
+                self.registers[1] & 0xFE | second_half as u8
+            }
+            _ => self.registers[2 + ((address - 0x1000) >> 10)],
+        } as usize;
+        (bank * ONE_KB + (address & 0x3FF)) % chr_len
+    }
+
+    // Fires when it's decremented to 0. A reload lands one or two past the
+    // latch, which Hard Drivin' depends on
+    fn clock_irq(&mut self) {
+        if self.irq_reload {
+            let extra = if self.irq_latch <= 1 { 1 } else { 2 };
+            self.irq_counter = self.irq_latch as u16 + extra;
+            self.irq_reload = false;
+        } else if self.irq_counter == 0 {
+            self.irq_counter = self.irq_latch as u16 + 1;
+        }
+        self.irq_counter -= 1;
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq = true;
+        }
+    }
+}
+
+impl Mapper for Rambo1 {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match address {
+            0x8000...0xFFFF => rom.prg_rom[self.prg_index(address)],
+            _ => {
+                info!("Reading from unmapped memory {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, _rom: &mut Rom) {
+        match address {
+            0x8000...0xFFFF => self.write_register(address, val),
+            _ => info!("Storing to unmapped prg mem {:X}", address),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.prg_index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr_index(address, rom.chr().len())]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.chr_index(address, rom.chr().len());
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!("Attempting to write to chr rom {:X}", address),
+        }
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        self.mirroring.clone()
+    }
+
+    fn switches_chr(&self, address: u16) -> bool {
+        match address {
+            0x8000...0x9FFF => true,
+            _ => false,
+        }
+    }
+
+    fn a12_rise(&mut self) {
+        if !self.cycle_mode {
+            self.clock_irq();
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.cycle_mode {
+            self.prescaler = (self.prescaler + 1) & 3;
+            if self.prescaler == 0 {
+                self.clock_irq();
+            }
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    fn reset(&mut self) {
+        self.bank_select = BankSelect(0);
+        self.irq_counter = 0;
+        self.irq_reload = false;
+        self.irq_enabled = false;
+        self.irq = false;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
@@ -7,8 +7,18 @@
 
 use serde::Serialize;
 use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
 use rom::ScreenMode;
 use rom::ScreenBank;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+
+const SIXTEEN_KB: usize = 0x4000;
+const EIGHT_KB: usize = 0x2000;
+// What the MMC1's own PRG lines reach, bigger boards add a line of their own
+const PRG_HALF: usize = 0x40000;
 
 #[derive(Serialize, Deserialize, Copy, Clone)]
 struct Shift {
@@ -35,6 +45,8 @@ impl Shift {
     }
 }
 
+// Loaded a bit at a time from bit 0 of writes to $8000-$FFFF, the fifth
+// write copies it into the register its address selects
 bitfield! {
     #[derive(Clone, Debug ,Serialize, Deserialize)]
     struct Ctrl(u8);
@@ -44,6 +56,11 @@ bitfield! {
     mirroring,    _ : 1, 0;
 }
 
+// Nintendo's MMC1 boards. The biggest ones only have CHR RAM and reuse the
+// upper CHR bank lines: SUROM and SXROM take their 512KB of PRG a 256KB half
+// at a time with bit 4, and SOROM and SXROM bank their 16KB or 32KB of PRG
+// RAM with bit 3 or bits 2-3. Only the first CHR register is looked at for
+// these, games keep both the same in 4KB mode anyway
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Sxrom {
     shift: Shift,
@@ -52,97 +69,49 @@ pub struct Sxrom {
     chr_bank_1: usize,
     prg_bank: usize,
     prg_ram_enabled: bool,
-    use_chr_ram: bool,
-    last_page_start: usize,
+    prg_len: usize,
+    // SEROM, SHROM and SH1ROM only have 32KB of PRG and don't connect the
+    // MMC1's PRG lines at all
+    fixed_prg: bool,
+    // The MMC1 ignores a write on the cycle right after another one, which
+    // read-modify-write instructions rely on since they write twice
+    cycles_since_write: u8,
 }
 
 impl Sxrom {
-    pub fn new(use_chr_ram: bool, last_page_start: usize) -> Sxrom {
+    pub fn new(prg_rom_size: usize, fixed_prg: bool) -> Sxrom {
         Sxrom {
-            shift: Shift {
-                val: 0x10,
-                index: 0,
-            },
+            shift: Shift { val: 0, index: 0 },
             ctrl: Ctrl(0x0C),
             chr_bank_0: 0,
             chr_bank_1: 0,
             prg_bank: 0,
-            use_chr_ram: use_chr_ram,
-            last_page_start: last_page_start,
+            prg_len: prg_rom_size,
+            fixed_prg: fixed_prg,
             prg_ram_enabled: true, //Default state is 0 = true
+            cycles_since_write: 2,
         }
     }
 
-    pub fn store_prg(&mut self, address: u16, val: u8, prg_ram: &mut Vec<u8>) {
-        if address < 0x6000 {
-            info!("Storing to unmapped prg mem {:X}", address);
-        } else if address < 0x8000 {
-            prg_ram[address as usize - 0x6000] = val;
-        } else {
-            if (val & 0x80) != 0 {
-                self.reset();
-                self.ctrl = Ctrl(self.ctrl.as_byte() | 0x0C);
-            } else {
-                if let Some(val) = self.shift.push(val) {
-                    match address {
-                        0x8000...0x9FFF => self.ctrl = Ctrl(val),
-                        0xA000...0xBFFF => {
-                            self.chr_bank_0 = (val & 0b11111) as usize % 8;
-                        }
-                        0xC000...0xDFFF => {
-                            self.chr_bank_1 = (val & 0b11111) as usize % 8;
-                        }
-                        0xE000...0xFFFF => {
-                            self.prg_bank = (val & 0b1111) as usize;
-                            self.prg_ram_enabled = (val & 0b10000) == 0;
-                        }
-                        _ => panic!("Impossible to get here"),
-                    }
-                }
-            }
-        }
-    }
-
-    pub fn ld_prg(
-        &self,
-        address: u16,
-        prg_rom: &Vec<u8>,
-        prg_ram: &Vec<u8>,
-    ) -> u8 {
+    fn write_register(&mut self, address: u16, val: u8) {
         match address {
-            0x6000...0x7FFF => prg_ram[address as usize - 0x6000],
-            0x8000...0xFFFF => prg_rom[self.get_prg_index(address)],
-            addr => {
-                info!("Reading from unmapped memory {:X}", addr);
-                0
+            0x8000...0x9FFF => self.ctrl = Ctrl(val),
+            0xA000...0xBFFF => self.chr_bank_0 = (val & 0b11111) as usize,
+            0xC000...0xDFFF => self.chr_bank_1 = (val & 0b11111) as usize,
+            0xE000...0xFFFF => {
+                self.prg_bank = (val & 0b1111) as usize;
+                self.prg_ram_enabled = (val & 0b10000) == 0;
             }
+            _ => panic!("Impossible to get here"),
         }
     }
 
-    pub fn ld_chr(
-        &self,
-        address: u16,
-        chr_rom: &Vec<u8>,
-        chr_ram: &Vec<u8>,
-    ) -> u8 {
-        if self.use_chr_ram {
-            chr_ram[self.get_chr_index(address)]
-        } else {
-            chr_rom[self.get_chr_index(address)]
-        }
-    }
-
-    pub fn store_chr(&mut self, address: u16, val: u8, chr_ram: &mut Vec<u8>) {
-        if self.use_chr_ram {
-            chr_ram[self.get_chr_index(address)] = val;
-        } else {
-            info!("Attempting to write to chr rom {:X}", address);
-        }
-    }
-
-    fn get_chr_index(&self, addr: u16) -> usize {
-        match self.ctrl.chr_rom_mode() as u8 {
-            0 => (((self.chr_bank_0 & 0xFE) * 0x1000) + addr as usize),
+    // Banks past the end of CHR wrap around, boards with less than 128KB
+    // just don't connect the upper bank bits
+    fn get_chr_index(&self, addr: u16, chr_len: usize) -> usize {
+        let index = match self.ctrl.chr_rom_mode() as u8 {
+            // 8KB mode ignores the low bit of the bank
+            0 => ((self.chr_bank_0 & 0x1E) * 0x1000) + addr as usize,
             1 => match addr {
                 0x0000...0x0FFF => (self.chr_bank_0 * 0x1000) + addr as usize,
                 0x1000...0x1FFF => {
@@ -151,12 +120,29 @@ impl Sxrom {
                 c => panic!("Chr indices are only 0000-1FFFF {:X}", c),
             },
             _ => panic!("only one bit used here"),
-        }
+        };
+        index % chr_len
     }
 
     fn get_prg_index(&self, addr: u16) -> usize {
-        match self.ctrl.prg_rom_mode() {
-            0 | 1 => ((self.prg_bank >> 1) * 0x4000) + (addr as usize - 0x8000),
+        if self.fixed_prg {
+            return (addr as usize - 0x8000) % self.prg_len;
+        }
+        // The banking below happens within the current half, including the
+        // fixed last bank
+        let half_len = self.prg_len.min(PRG_HALF);
+        let half = if self.prg_len > PRG_HALF {
+            (self.chr_bank_0 >> 4 & 1) * PRG_HALF
+        } else {
+            0
+        };
+        let last_page_start = half_len.saturating_sub(SIXTEEN_KB);
+        let index = match self.ctrl.prg_rom_mode() {
+            // 32KB mode ignores the low bit of the bank
+            0 | 1 => {
+                ((self.prg_bank & 0xE) * 0x4000) + (addr as usize - 0x8000)
+            }
+            // First bank fixed at $8000, switchable one at $C000
             2 => match addr {
                 0x8000...0xBFFF => addr as usize - 0x8000,
                 0xC000...0xFFFF => {
@@ -164,35 +150,122 @@ impl Sxrom {
                 }
                 _ => panic!("addr can't be anything else"),
             },
+            // Switchable bank at $8000, last bank fixed at $C000
             3 => match addr {
                 0x8000...0xBFFF => {
                     (self.prg_bank * 0x4000) + (addr as usize - 0x8000)
                 }
-                0xC000...0xFFFF => {
-                    self.last_page_start + addr as usize - 0xC000
-                }
+                0xC000...0xFFFF => last_page_start + addr as usize - 0xC000,
                 a => panic!("addr can't be anything else {:X}", a),
             },
             b => panic!("Can't get anything else {:b}", b),
+        };
+        (half + index % half_len) % self.prg_len
+    }
+
+    fn prg_ram_index(&self, addr: u16, prg_ram_len: usize) -> usize {
+        let bank = match prg_ram_len {
+            0x4000 => self.chr_bank_0 >> 3 & 1,
+            _ => self.chr_bank_0 >> 2 & 3,
+        };
+        (bank * EIGHT_KB + addr as usize - 0x6000) % prg_ram_len
+    }
+}
+
+impl Mapper for Sxrom {
+    fn store_prg(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        if address < 0x6000 {
+            info!("Storing to unmapped prg mem {:X}", address);
+        } else if address < 0x8000 {
+            if self.prg_ram_enabled && !rom.prg_ram.is_empty() {
+                let index = self.prg_ram_index(address, rom.prg_ram.len());
+                rom.prg_ram[index] = val;
+            }
+        } else {
+            let consecutive = self.cycles_since_write < 2;
+            self.cycles_since_write = 0;
+            if consecutive {
+                return;
+            }
+            if (val & 0x80) != 0 {
+                // Only clears the shift register and goes back to the fixed
+                // last bank, the other registers are left alone
+                self.shift.reset();
+                self.ctrl = Ctrl(self.ctrl.as_byte() | 0x0C);
+            } else if let Some(val) = self.shift.push(val) {
+                self.write_register(address, val);
+            }
+        }
+    }
+
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match address {
+            0x6000...0x7FFF if self.prg_ram_enabled => {
+                match rom.prg_ram.len() {
+                    0 => 0,
+                    len => rom.prg_ram[self.prg_ram_index(address, len)],
+                }
+            }
+            0x8000...0xFFFF => rom.prg_rom[self.get_prg_index(address)],
+            addr => {
+                info!("Reading from unmapped memory {:X}", addr);
+                0
+            }
         }
     }
 
-    pub fn reset(&mut self) {
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.get_chr_index(address, rom.chr().len())]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.get_chr_index(address, rom.chr().len());
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!("Attempting to write to chr rom {:X}", address),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.get_prg_index(address)),
+            _ => None,
+        }
+    }
+
+    fn reset(&mut self) {
         self.shift.reset();
         self.ctrl = Ctrl(0x0C);
         self.chr_bank_0 = 0;
         self.chr_bank_1 = 0;
         self.prg_bank = 0;
         self.prg_ram_enabled = true;
+        self.cycles_since_write = 2;
     }
 
-    pub fn get_mirroring(&self) -> ScreenMode {
+    fn get_mirroring(&self) -> ScreenMode {
         match self.ctrl.mirroring() {
-            0 => ScreenMode::OneScreenSwap(ScreenBank::Upper),
-            1 => ScreenMode::OneScreenSwap(ScreenBank::Lower),
+            0 => ScreenMode::OneScreenSwap(ScreenBank::Lower),
+            1 => ScreenMode::OneScreenSwap(ScreenBank::Upper),
             2 => ScreenMode::Vertical,
             3 => ScreenMode::Horizontal,
             _ => panic!("2 bit number can't be greater than 3"),
         }
     }
+
+    fn controls_prg_ram(&self) -> bool {
+        true
+    }
+
+    fn clock(&mut self) {
+        self.cycles_since_write = self.cycles_since_write.saturating_add(1);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
 }
@@ -0,0 +1,261 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+
+const EIGHT_KB: usize = 0x2000;
+const ONE_KB: usize = 0x400;
+
+bitfield! {
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct BankSelect(u8);
+    as_byte,       _ : 7, 0;
+    // Swaps the 2KB and 1KB CHR banks between the two pattern tables
+    chr_inversion, _ : 7;
+    // Swaps $8000 and $C000, where R6 and the second to last bank go
+    prg_mode,      _ : 6;
+    register,      _ : 2, 0;
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Chip {
+    // Mapper 4
+    Mmc3,
+    // Mapper 4 submapper 4, the MMC3A. Its IRQ only fires when the counter
+    // is decremented to 0 or reloaded by a write to $C001, so a latch of 0
+    // gives a single IRQ rather than one every line
+    Mmc3A,
+    // Mapper 206, on DxROM boards. The chip the MMC3 grew out of, with just
+    // the bank registers, fixed PRG and CHR modes and no IRQ
+    Namco108,
+}
+
+// MMC3, mapper 4. Two switchable 8KB PRG banks and six CHR banks picked
+// through eight registers, with a scanline counter clocked off PPU A12
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Txrom {
+    chip: Chip,
+    bank_select: BankSelect,
+    // R0 and R1 are the 2KB CHR banks, R2-R5 the 1KB ones and R6 and R7
+    // the PRG banks
+    registers: [u8; 8],
+    mirroring: ScreenMode,
+    // Boards with extra nametable RAM ignore the mirroring register
+    four_screen: bool,
+    prg_ram_enabled: bool,
+    prg_ram_protect: bool,
+    prg_banks: usize,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq: bool,
+}
+
+impl Txrom {
+    pub fn new(chip: Chip, prg_rom_size: usize, screen: ScreenMode) -> Txrom {
+        let four_screen = match screen {
+            ScreenMode::FourScreen => true,
+            _ => false,
+        };
+        Txrom {
+            chip: chip,
+            bank_select: BankSelect(0),
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring: screen,
+            four_screen: four_screen,
+            prg_ram_enabled: true,
+            prg_ram_protect: false,
+            prg_banks: prg_rom_size / EIGHT_KB,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq: false,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, val: u8) {
+        let even = address & 1 == 0;
+        match (address, even) {
+            // The Namco 108 only has the register number
+            (0x8000...0x9FFF, true) => {
+                self.bank_select = match self.chip {
+                    Chip::Namco108 => BankSelect(val & 0x07),
+                    _ => BankSelect(val),
+                };
+            }
+            (0x8000...0x9FFF, false) => {
+                let register = self.bank_select.register() as usize;
+                self.registers[register] = val;
+            }
+            (0xA000...0xFFFF, _) if self.chip == Chip::Namco108 => info!(
+                "Writing to unmapped prg_rom address: {:X} val: {}",
+                address, val
+            ),
+            (0xA000...0xBFFF, true) => {
+                if !self.four_screen {
+                    self.mirroring = if val & 1 == 0 {
+                        ScreenMode::Vertical
+                    } else {
+                        ScreenMode::Horizontal
+                    };
+                }
+            }
+            (0xA000...0xBFFF, false) => {
+                self.prg_ram_enabled = val & 0x80 != 0;
+                self.prg_ram_protect = val & 0x40 != 0;
+            }
+            (0xC000...0xDFFF, true) => self.irq_latch = val,
+            // Reloads the counter from the latch on the next A12 rise
+            (0xC000...0xDFFF, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            // Disabling also acknowledges a pending IRQ
+            (0xE000...0xFFFF, true) => {
+                self.irq_enabled = false;
+                self.irq = false;
+            }
+            (0xE000...0xFFFF, false) => self.irq_enabled = true,
+            _ => panic!("Registers are only $8000-$FFFF {:X}", address),
+        }
+    }
+
+    fn prg_index(&self, address: u16) -> usize {
+        let second_last = self.prg_banks.saturating_sub(2);
+        let r6 = (self.registers[6] & 0x3F) as usize;
+        let r7 = (self.registers[7] & 0x3F) as usize;
+        let bank = match (address, self.bank_select.prg_mode()) {
+            (0x8000...0x9FFF, false) | (0xC000...0xDFFF, true) => r6,
+            (0x8000...0x9FFF, true) | (0xC000...0xDFFF, false) => second_last,
+            (0xA000...0xBFFF, _) => r7,
+            _ => self.prg_banks - 1,
+        };
+        (bank % self.prg_banks) * EIGHT_KB + (address as usize & 0x1FFF)
+    }
+
+    fn chr_index(&self, address: u16, chr_len: usize) -> usize {
+        // Inversion just flips which pattern table each bank lands in
+        let flip = if self.bank_select.chr_inversion() { 0x1000 } else { 0 };
+        let address = (address ^ flip) as usize;
+        let bank = match address {
+            // The 2KB banks ignore their low bit
+            0x0000...0x07FF => self.registers[0] & 0xFE | (address >> 10) as u8,
+            0x0800...0x0FFF => {
+                self.registers[1] & 0xFE | ((address >> 10) & 1) as u8
+            }
+            _ => self.registers[2 + ((address - 0x1000) >> 10)],
+        } as usize;
+        (bank * ONE_KB + (address & 0x3FF)) % chr_len
+    }
+}
+
+impl Mapper for Txrom {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match address {
+            // NES 2.0 headers can leave the PRG RAM out
+            0x6000...0x7FFF if self.prg_ram_enabled => {
+                let index = address as usize - 0x6000;
+                rom.prg_ram.get(index).cloned().unwrap_or(0)
+            }
+            0x8000...0xFFFF => rom.prg_rom[self.prg_index(address)],
+            _ => {
+                info!("Reading from unmapped memory {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        match address {
+            0x6000...0x7FFF => {
+                if self.prg_ram_enabled && !self.prg_ram_protect {
+                    let index = address as usize - 0x6000;
+                    if let Some(byte) = rom.prg_ram.get_mut(index) {
+                        *byte = val;
+                    }
+                }
+            }
+            0x8000...0xFFFF => self.write_register(address, val),
+            _ => info!("Storing to unmapped prg mem {:X}", address),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.prg_index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr_index(address, rom.chr().len())]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.chr_index(address, rom.chr().len());
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!("Attempting to write to chr rom {:X}", address),
+        }
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        self.mirroring.clone()
+    }
+
+    fn controls_prg_ram(&self) -> bool {
+        true
+    }
+
+    // Only the bank select and bank data registers move CHR around
+    fn switches_chr(&self, address: u16) -> bool {
+        match address {
+            0x8000...0x9FFF => true,
+            _ => false,
+        }
+    }
+
+    // Once per scanline while rendering, when the PPU goes from background
+    // to sprite pattern fetches. The IRQ fires when the counter gets to 0,
+    // whether it was decremented or reloaded there, except on the MMC3A
+    fn a12_rise(&mut self) {
+        let was_counting = self.irq_counter != 0 || self.irq_reload;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        let fires = self.chip != Chip::Mmc3A || was_counting;
+        if self.irq_counter == 0 && self.irq_enabled && fires {
+            self.irq = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    fn reset(&mut self) {
+        self.bank_select = BankSelect(0);
+        self.registers = [0, 2, 4, 5, 6, 7, 0, 1];
+        self.irq_counter = 0;
+        self.irq_reload = false;
+        self.irq_enabled = false;
+        self.irq = false;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
@@ -1,25 +1,51 @@
 use serde::Serialize;
 use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::banks::Bank;
+use mapper::banks::Banks;
 
 const SIXTEEN_KB: usize = 0x4000;
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+// UNROM, UOROM and the like. A 16KB bank switched at $8000 with the last
+// bank fixed at $C000, and almost always CHR RAM
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Unrom {
-    bank_select: u8,
-    last_page_start: usize,
+    prg: Banks,
+    bus_conflicts: bool,
+    // Soldered on the board, straight from the header
+    screen: ScreenMode,
 }
 
 impl Unrom {
-    pub fn new(last_page_start: usize) -> Unrom {
+    pub fn new(
+        prg_rom_size: usize,
+        bus_conflicts: bool,
+        screen: ScreenMode,
+    ) -> Unrom {
         Unrom {
-            bank_select: 0,
-            last_page_start,
+            prg: Banks::new(
+                0x8000,
+                SIXTEEN_KB,
+                prg_rom_size,
+                &[Bank::Number(0), Bank::FromEnd(1)],
+            ),
+            bus_conflicts,
+            screen,
         }
     }
+}
 
-    pub fn store_prg(&mut self, address: u16, val: u8) {
+impl Mapper for Unrom {
+    fn store_prg(&mut self, address: u16, val: u8, _rom: &mut Rom) {
         if address >= 0x8000 {
-            self.bank_select = val & 0b111;
+            // UNROM only connects 3 bits and UOROM 4, boards just leave the
+            // ones past the end of PRG unconnected
+            self.prg.switch(0, val as usize & 0b1111);
         } else {
             info!(
                 "Writing to unmapped prg_rom address: {:X} val: {}",
@@ -28,29 +54,60 @@ impl Unrom {
         }
     }
 
-    pub fn ld_prg(&self, address: u16, prg_rom: &Vec<u8>) -> u8 {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match self.prg_offset(address) {
+            Some(offset) => rom.prg_rom[offset],
+            None => {
+                info!("Reading from unmapped prg_rom address: {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
         if address < 0x8000 {
-            info!("Reading from unmapped prg_rom address: {:X}", address);
-            0
-        // Bank switched using 3 bits
-        } else if address < 0xC000 {
-            prg_rom[(self.bank_select as usize * SIXTEEN_KB)
-                + (address as usize - 0x8000)]
-        // Hard wired to last 16KB
+            None
         } else {
-            prg_rom[(7 * SIXTEEN_KB) + (address as usize - 0xC000)]
+            Some(self.prg.index(address))
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[address as usize]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        match rom.chr_mut() {
+            Some(chr) => chr[address as usize] = val,
+            None => info!(
+                "Attempt to store to chr rom {:X} val {}",
+                address, val
+            ),
         }
     }
 
-    pub fn ld_chr(&self, address: u16, chr_ram: &Vec<u8>) -> u8 {
-        chr_ram[address as usize]
+    fn bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    // Only PRG is banked
+    fn switches_chr(&self, _addr: u16) -> bool {
+        false
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        self.screen.clone()
+    }
+
+    fn reset(&mut self) {
+        self.prg.switch(0, 0);
     }
 
-    pub fn store_chr(&mut self, address: u16, val: u8, chr_ram: &mut Vec<u8>) {
-        chr_ram[address as usize] = val;
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
     }
 
-    pub fn reset(&mut self) {
-        self.bank_select = 0;
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
     }
 }
@@ -0,0 +1,236 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use rom::ScreenBank;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::banks::Bank;
+use mapper::banks::Banks;
+
+const SIXTEEN_KB: usize = 0x4000;
+const EIGHT_KB: usize = 0x2000;
+const FOUR_KB: usize = 0x1000;
+const CHR_RAM_SIZE: usize = 0x8000;
+// What the SST39SF040 answers with in software ID mode
+const FLASH_ID: [u8; 2] = [0xBF, 0xB7];
+
+// Where the flash chip is in its command sequence. Every command starts by
+// writing $AA to $5555 and $55 to $2AAA, addresses in the flash rather than
+// the CPU's
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+enum Flash {
+    Ready,
+    Unlocking,
+    Unlocked,
+    // The next write is programmed in
+    Program,
+    // Erasing takes a second unlock
+    EraseReady,
+    EraseUnlocking,
+    EraseUnlocked,
+}
+
+// UNROM 512, mapper 30, the homebrew board. UNROM with up to 512KB of PRG,
+// four 8KB banks of CHR RAM and a one screen mirroring option. Boards with
+// the header's battery bit set are self-flashable, the game can rewrite its
+// own PRG to save, and the register moves up to $C000-$FFFF to make room
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Unrom512 {
+    prg: Banks,
+    chr: Banks,
+    // Used when the header picks one screen mirroring
+    upper_screen: bool,
+    screen: ScreenMode,
+    one_screen: bool,
+    flashable: bool,
+    flash: Flash,
+    id_mode: bool,
+    // Whether anything's been flashed since power on
+    flashed: bool,
+}
+
+impl Unrom512 {
+    pub fn new(
+        prg_rom_size: usize,
+        flashable: bool,
+        screen: ScreenMode,
+        mirroring_bit: bool,
+    ) -> Unrom512 {
+        // Four screen without the mirroring bit means one screen instead
+        let one_screen = match screen {
+            ScreenMode::FourScreen => !mirroring_bit,
+            _ => false,
+        };
+        Unrom512 {
+            prg: Banks::new(
+                0x8000,
+                SIXTEEN_KB,
+                prg_rom_size,
+                &[Bank::Number(0), Bank::FromEnd(1)],
+            ),
+            chr: Banks::new(0, EIGHT_KB, CHR_RAM_SIZE, &[Bank::Number(0)]),
+            upper_screen: false,
+            screen: screen,
+            one_screen: one_screen,
+            flashable: flashable,
+            flash: Flash::Ready,
+            id_mode: false,
+            flashed: false,
+        }
+    }
+
+    fn write_register(&mut self, val: u8) {
+        self.prg.switch(0, val as usize & 0x1F);
+        self.chr.switch(0, (val as usize >> 5) & 0x03);
+        self.upper_screen = val & 0x80 != 0;
+    }
+
+    // Writes to $8000-$BFFF land in the bank currently at $8000
+    fn write_flash(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let offset = self.prg_offset(address).expect("Only $8000-$BFFF");
+        let command = offset & 0x7FFF;
+        self.flash = match (self.flash, command, val) {
+            // Flash can only clear bits, erasing is what sets them
+            (Flash::Program, _, _) => {
+                rom.prg_rom[offset] &= val;
+                self.flashed = true;
+                Flash::Ready
+            }
+            // Anything else with $F0 leaves ID mode and cancels the command
+            (_, _, 0xF0) => {
+                self.id_mode = false;
+                Flash::Ready
+            }
+            (Flash::Ready, 0x5555, 0xAA) => Flash::Unlocking,
+            (Flash::Unlocking, 0x2AAA, 0x55) => Flash::Unlocked,
+            (Flash::Unlocked, 0x5555, 0xA0) => Flash::Program,
+            (Flash::Unlocked, 0x5555, 0x80) => Flash::EraseReady,
+            (Flash::Unlocked, 0x5555, 0x90) => {
+                self.id_mode = true;
+                Flash::Ready
+            }
+            (Flash::EraseReady, 0x5555, 0xAA) => Flash::EraseUnlocking,
+            (Flash::EraseUnlocking, 0x2AAA, 0x55) => Flash::EraseUnlocked,
+            (Flash::EraseUnlocked, 0x5555, 0x10) => {
+                for byte in rom.prg_rom.iter_mut() {
+                    *byte = 0xFF;
+                }
+                self.flashed = true;
+                Flash::Ready
+            }
+            (Flash::EraseUnlocked, _, 0x30) => {
+                let sector = offset & !(FOUR_KB - 1);
+                for byte in rom.prg_rom[sector..sector + FOUR_KB].iter_mut() {
+                    *byte = 0xFF;
+                }
+                self.flashed = true;
+                Flash::Ready
+            }
+            _ => {
+                info!("Unexpected flash write {:X} val: {:X}", address, val);
+                Flash::Ready
+            }
+        };
+    }
+}
+
+impl Mapper for Unrom512 {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match self.prg_offset(address) {
+            Some(offset) if self.id_mode => FLASH_ID[offset & 1],
+            Some(offset) => rom.prg_rom[offset],
+            None => {
+                info!("Reading from unmapped prg_rom address: {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        match address {
+            0x8000...0xBFFF if self.flashable => {
+                self.write_flash(address, val, rom)
+            }
+            0x8000...0xFFFF => self.write_register(val),
+            _ => info!(
+                "Writing to unmapped prg_rom address: {:X} val: {}",
+                address, val
+            ),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.prg.index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr.index(address)]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        rom.chr_ram[self.chr.index(address)] = val;
+    }
+
+    fn chr_ram_size(&self) -> Option<usize> {
+        Some(CHR_RAM_SIZE)
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        match (self.one_screen, self.upper_screen) {
+            (true, false) => ScreenMode::OneScreenSwap(ScreenBank::Lower),
+            (true, true) => ScreenMode::OneScreenSwap(ScreenBank::Upper),
+            _ => self.screen.clone(),
+        }
+    }
+
+    // Only on boards that can't flash
+    fn bus_conflicts(&self) -> bool {
+        !self.flashable
+    }
+
+    fn switches_chr(&self, address: u16) -> bool {
+        match address {
+            0x8000...0xBFFF => !self.flashable,
+            0xC000...0xFFFF => true,
+            _ => false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.prg.switch(0, 0);
+        self.chr.switch(0, 0);
+        self.flash = Flash::Ready;
+        self.id_mode = false;
+    }
+
+    // The whole flash, once something's been written to it
+    fn save_data(&self, rom: &Rom) -> Option<Vec<u8>> {
+        if self.flashed {
+            Some(rom.prg_rom.clone())
+        } else {
+            None
+        }
+    }
+
+    fn load_save_data(&mut self, data: &[u8], rom: &mut Rom) {
+        if self.flashable && data.len() == rom.prg_rom.len() {
+            rom.prg_rom.copy_from_slice(data);
+        } else {
+            warn!("Flash save doesn't match this ROM, ignoring it");
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
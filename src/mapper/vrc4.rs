@@ -0,0 +1,237 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use rom::ScreenBank;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::banks::Bank;
+use mapper::banks::Banks;
+use mapper::banks::ONE_KB_BANKS;
+use mapper::vrc_irq::VrcIrq;
+
+const EIGHT_KB: usize = 0x2000;
+const ONE_KB: usize = 0x400;
+
+// Which CPU address lines the board wires to the chip's two register select
+// pins. Each mapper number covers a couple of boards with different wiring,
+// the submapper says which one, without it both are ORed into one mask and
+// games only ever use the lines they have
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct Pins {
+    pub a0: u16,
+    pub a1: u16,
+}
+
+impl Pins {
+    pub fn for_mapper(mapper: u8, submapper: u8) -> Pins {
+        match (mapper, submapper) {
+            // VRC4a
+            (21, 1) => Pins { a0: 0x02, a1: 0x04 },
+            // VRC4c
+            (21, 2) => Pins { a0: 0x40, a1: 0x80 },
+            (21, _) => Pins { a0: 0x02 | 0x40, a1: 0x04 | 0x80 },
+            // VRC2a
+            (22, _) => Pins { a0: 0x02, a1: 0x01 },
+            // VRC4f and VRC2b
+            (23, 1) | (23, 3) => Pins { a0: 0x01, a1: 0x02 },
+            // VRC4e
+            (23, 2) => Pins { a0: 0x04, a1: 0x08 },
+            (23, _) => Pins { a0: 0x01 | 0x04, a1: 0x02 | 0x08 },
+            // VRC4b and VRC2c
+            (25, 1) | (25, 3) => Pins { a0: 0x02, a1: 0x01 },
+            // VRC4d
+            (25, 2) => Pins { a0: 0x08, a1: 0x04 },
+            (25, _) => Pins { a0: 0x02 | 0x08, a1: 0x01 | 0x04 },
+            _ => panic!("Mapper {} isn't a VRC2 or VRC4", mapper),
+        }
+    }
+
+    fn register(&self, address: u16) -> u16 {
+        let a0 = (address & self.a0 != 0) as u16;
+        let a1 = (address & self.a1 != 0) as u16;
+        address & 0xF000 | a1 << 1 | a0
+    }
+}
+
+// Konami's VRC2 and VRC4, mappers 21, 22, 23 and 25. Two switchable 8KB
+// PRG banks, eight 1KB CHR banks each written a nibble at a time, and on
+// the VRC4 a PRG swap mode, single screen mirroring and the VRC IRQ
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Vrc4 {
+    vrc2: bool,
+    pins: Pins,
+    // VRC2a only has the top 7 bits of the CHR bank wired up
+    chr_shift: bool,
+    prg: Banks,
+    // Goes at $8000, or at $C000 in swap mode
+    prg_select: u8,
+    // Swaps $8000 and $C000, where the first bank and the second to last go
+    prg_swap: bool,
+    chr: Banks,
+    // Built up a nibble at a time
+    chr_select: [u16; 8],
+    mirroring: u8,
+    irq: VrcIrq,
+}
+
+impl Vrc4 {
+    pub fn new(
+        vrc2: bool,
+        pins: Pins,
+        chr_shift: bool,
+        prg_rom_size: usize,
+        chr_size: usize,
+    ) -> Vrc4 {
+        Vrc4 {
+            vrc2: vrc2,
+            pins: pins,
+            chr_shift: chr_shift,
+            prg: Banks::new(
+                0x8000,
+                EIGHT_KB,
+                prg_rom_size,
+                &[
+                    Bank::Number(0),
+                    Bank::Number(1),
+                    Bank::FromEnd(2),
+                    Bank::FromEnd(1),
+                ],
+            ),
+            prg_select: 0,
+            prg_swap: false,
+            chr: Banks::new(0, ONE_KB, chr_size, &ONE_KB_BANKS),
+            chr_select: [0, 1, 2, 3, 4, 5, 6, 7],
+            mirroring: 0,
+            irq: VrcIrq::new(),
+        }
+    }
+
+    fn write_register(&mut self, address: u16, val: u8) {
+        match self.pins.register(address) {
+            0x8000...0x8003 => {
+                self.prg_select = val & 0x1F;
+                self.update_prg();
+            }
+            // The VRC2 has nothing else there
+            0x9000...0x9003 if self.vrc2 => self.mirroring = val & 0x01,
+            0x9000 | 0x9001 => self.mirroring = val & 0x03,
+            0x9002 | 0x9003 => {
+                self.prg_swap = val & 0x02 != 0;
+                self.update_prg();
+            }
+            0xA000...0xA003 => self.prg.switch(1, val as usize & 0x1F),
+            // $B000-$E003, two banks a register, low nibble then high
+            register @ 0xB000...0xE003 => {
+                let bank = ((register - 0xB000) >> 11) | ((register >> 1) & 1);
+                let bank = bank as usize;
+                let high_mask = if self.vrc2 { 0x0F } else { 0x1F };
+                let old = self.chr_select[bank];
+                self.chr_select[bank] = if register & 1 == 0 {
+                    old & 0x1F0 | (val & 0x0F) as u16
+                } else {
+                    old & 0x00F | ((val & high_mask) as u16) << 4
+                };
+                let shift = self.chr_shift as usize;
+                self.chr.switch(bank, self.chr_select[bank] as usize >> shift);
+            }
+            0xF000 if !self.vrc2 => self.irq.set_latch_low(val),
+            0xF001 if !self.vrc2 => self.irq.set_latch_high(val),
+            0xF002 if !self.vrc2 => self.irq.set_control(val),
+            0xF003 if !self.vrc2 => self.irq.acknowledge(),
+            _ => info!(
+                "Writing to unmapped prg_rom address: {:X} val: {}",
+                address, val
+            ),
+        }
+    }
+
+    fn update_prg(&mut self) {
+        let select = Bank::Number(self.prg_select as usize);
+        let (first, third) = if self.prg_swap {
+            (Bank::FromEnd(2), select)
+        } else {
+            (select, Bank::FromEnd(2))
+        };
+        self.prg.set(0, first);
+        self.prg.set(2, third);
+    }
+}
+
+impl Mapper for Vrc4 {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match self.prg_offset(address) {
+            Some(offset) => rom.prg_rom[offset],
+            None => {
+                info!("Reading from unmapped prg_rom address: {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, _rom: &mut Rom) {
+        match address {
+            0x8000...0xFFFF => self.write_register(address, val),
+            _ => info!("Storing to unmapped prg mem {:X}", address),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.prg.index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr.index(address)]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.chr.index(address);
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!("Attempting to write to chr rom {:X}", address),
+        }
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        match self.mirroring {
+            0 => ScreenMode::Vertical,
+            1 => ScreenMode::Horizontal,
+            2 => ScreenMode::OneScreenSwap(ScreenBank::Lower),
+            _ => ScreenMode::OneScreenSwap(ScreenBank::Upper),
+        }
+    }
+
+    fn switches_chr(&self, address: u16) -> bool {
+        match address {
+            0xB000...0xEFFF => true,
+            _ => false,
+        }
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn reset(&mut self) {
+        self.prg_swap = false;
+        self.update_prg();
+        self.irq = VrcIrq::new();
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
@@ -0,0 +1,212 @@
+use serde::Serialize;
+use serde::Deserialize;
+use failure::Error;
+use rom::Rom;
+use rom::ScreenMode;
+use rom::ScreenBank;
+use mapper::Mapper;
+use mapper::save_board;
+use mapper::load_board;
+use mapper::banks::Bank;
+use mapper::banks::Banks;
+use mapper::banks::ONE_KB_BANKS;
+use mapper::vrc_irq::VrcIrq;
+#[cfg(feature = "vrc7-audio")]
+use mapper::opll::Opll;
+
+const EIGHT_KB: usize = 0x2000;
+const ONE_KB: usize = 0x400;
+
+// Konami's VRC7, mapper 85. Three switchable 8KB PRG banks with the last
+// one fixed, eight 1KB CHR banks, the VRC IRQ and an FM synth, which only
+// Lagrange Point uses
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Vrc7 {
+    prg: Banks,
+    chr: Banks,
+    mirroring: u8,
+    prg_ram_enabled: bool,
+    irq: VrcIrq,
+    #[cfg(feature = "vrc7-audio")]
+    audio: Opll,
+}
+
+impl Vrc7 {
+    pub fn new(prg_rom_size: usize, chr_size: usize) -> Vrc7 {
+        Vrc7 {
+            prg: Banks::new(
+                0x8000,
+                EIGHT_KB,
+                prg_rom_size,
+                &[
+                    Bank::Number(0),
+                    Bank::Number(1),
+                    Bank::Number(2),
+                    Bank::FromEnd(1),
+                ],
+            ),
+            chr: Banks::new(0, ONE_KB, chr_size, &ONE_KB_BANKS),
+            mirroring: 0,
+            prg_ram_enabled: false,
+            irq: VrcIrq::new(),
+            #[cfg(feature = "vrc7-audio")]
+            audio: Opll::new(),
+        }
+    }
+
+    fn write_register(&mut self, address: u16, val: u8) {
+        // The two boards wire the second register in each range to A4 and
+        // A3, only the sound ports need A4 and A5 both
+        let second = address & 0x18 != 0;
+        match (address & 0xF000, second) {
+            (0x9000, _) if address & 0x30 == 0x10 => self.select_audio(val),
+            (0x9000, _) if address & 0x30 == 0x30 => self.write_audio(val),
+            (0x8000, false) => self.prg.switch(0, val as usize & 0x3F),
+            (0x8000, true) => self.prg.switch(1, val as usize & 0x3F),
+            (0x9000, false) => self.prg.switch(2, val as usize & 0x3F),
+            (bank @ 0xA000...0xD000, second) => {
+                let window = ((bank - 0xA000) >> 11) as usize;
+                self.chr.switch(window + second as usize, val as usize);
+            }
+            (0xE000, false) => {
+                self.mirroring = val & 0x03;
+                self.prg_ram_enabled = val & 0x40 != 0;
+                self.silence_audio(val & 0x80 != 0);
+            }
+            (0xE000, true) => self.irq.set_latch(val),
+            (0xF000, false) => self.irq.set_control(val),
+            (0xF000, true) => self.irq.acknowledge(),
+            _ => info!(
+                "Writing to unmapped prg_rom address: {:X} val: {}",
+                address, val
+            ),
+        }
+    }
+
+    #[cfg(feature = "vrc7-audio")]
+    fn select_audio(&mut self, val: u8) {
+        self.audio.select(val);
+    }
+
+    #[cfg(feature = "vrc7-audio")]
+    fn write_audio(&mut self, val: u8) {
+        self.audio.write(val);
+    }
+
+    #[cfg(feature = "vrc7-audio")]
+    fn silence_audio(&mut self, silenced: bool) {
+        self.audio.silenced = silenced;
+    }
+
+    // Without the synth the sound ports go nowhere
+    #[cfg(not(feature = "vrc7-audio"))]
+    fn select_audio(&mut self, _val: u8) {}
+
+    #[cfg(not(feature = "vrc7-audio"))]
+    fn write_audio(&mut self, _val: u8) {}
+
+    #[cfg(not(feature = "vrc7-audio"))]
+    fn silence_audio(&mut self, _silenced: bool) {}
+}
+
+impl Mapper for Vrc7 {
+    fn ld_prg(&self, address: u16, rom: &Rom) -> u8 {
+        match address {
+            // NES 2.0 headers can leave the PRG RAM out
+            0x6000...0x7FFF if self.prg_ram_enabled => {
+                let index = address as usize - 0x6000;
+                rom.prg_ram.get(index).cloned().unwrap_or(0)
+            }
+            0x8000...0xFFFF => rom.prg_rom[self.prg.index(address)],
+            _ => {
+                info!("Reading from unmapped memory {:X}", address);
+                0
+            }
+        }
+    }
+
+    fn store_prg(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        match address {
+            0x6000...0x7FFF => {
+                if self.prg_ram_enabled {
+                    let index = address as usize - 0x6000;
+                    if let Some(byte) = rom.prg_ram.get_mut(index) {
+                        *byte = val;
+                    }
+                }
+            }
+            0x8000...0xFFFF => self.write_register(address, val),
+            _ => info!("Storing to unmapped prg mem {:X}", address),
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000...0xFFFF => Some(self.prg.index(address)),
+            _ => None,
+        }
+    }
+
+    fn ld_chr(&self, address: u16, rom: &Rom) -> u8 {
+        rom.chr()[self.chr.index(address)]
+    }
+
+    fn store_chr(&mut self, address: u16, val: u8, rom: &mut Rom) {
+        let index = self.chr.index(address);
+        match rom.chr_mut() {
+            Some(chr) => chr[index] = val,
+            None => info!("Attempting to write to chr rom {:X}", address),
+        }
+    }
+
+    fn get_mirroring(&self) -> ScreenMode {
+        match self.mirroring {
+            0 => ScreenMode::Vertical,
+            1 => ScreenMode::Horizontal,
+            2 => ScreenMode::OneScreenSwap(ScreenBank::Lower),
+            _ => ScreenMode::OneScreenSwap(ScreenBank::Upper),
+        }
+    }
+
+    fn controls_prg_ram(&self) -> bool {
+        true
+    }
+
+    fn switches_chr(&self, address: u16) -> bool {
+        match address {
+            0xA000...0xDFFF => true,
+            _ => false,
+        }
+    }
+
+    fn clock(&mut self) {
+        self.irq.clock();
+        #[cfg(feature = "vrc7-audio")]
+        self.audio.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending
+    }
+
+    #[cfg(feature = "vrc7-audio")]
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn reset(&mut self) {
+        self.irq = VrcIrq::new();
+        #[cfg(feature = "vrc7-audio")]
+        {
+            self.audio = Opll::new();
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_board(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        load_board(self, state)
+    }
+}
@@ -0,0 +1,90 @@
+use serde::Serialize;
+use serde::Deserialize;
+
+// CPU cycles per scanline times 3, the prescaler counts down 3 a cycle so
+// it lands on the same 113.667 cycle average as the PPU
+const PRESCALER_PERIOD: i16 = 341;
+
+// The IRQ counter in Konami's VRC4, VRC6 and VRC7. An 8 bit counter that
+// counts up to $FF and then reloads from the latch, either every CPU cycle
+// or once per scanline's worth of cycles. It doesn't watch the PPU at all
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    // Whether acknowledging turns the counter back on
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pub pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> VrcIrq {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: PRESCALER_PERIOD,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn set_latch(&mut self, val: u8) {
+        self.latch = val;
+    }
+
+    // The VRC4 takes the latch 4 bits at a time
+    pub fn set_latch_low(&mut self, val: u8) {
+        self.latch = self.latch & 0xF0 | val & 0x0F;
+    }
+
+    pub fn set_latch_high(&mut self, val: u8) {
+        self.latch = self.latch & 0x0F | val << 4;
+    }
+
+    // ---- -MEA. Enabling reloads the counter and the prescaler
+    pub fn set_control(&mut self, val: u8) {
+        self.enable_after_ack = val & 0x01 != 0;
+        self.enabled = val & 0x02 != 0;
+        self.cycle_mode = val & 0x04 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    // Every CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += PRESCALER_PERIOD;
+                self.clock_counter();
+            }
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
@@ -1,11 +1,19 @@
 use serde::Serialize;
 use serde::Deserialize;
 use ppu::Ppu;
+use ppu::PpuRes;
 use apu::Apu;
-use mapper::Mapper;
+use apu::ApuState;
+use cpu::IrqLine;
+use cpu::IrqSource;
+use debugger::Debugger;
+use cartridge::Cartridge;
 use std::cell::RefCell;
 use std::rc::Rc;
 use controller::Controller;
+use cpu_const::OAM_DATA;
+#[cfg(feature = "cached-core")]
+use cpu::block_cache::region_bit;
 
 const WRAM_START: u16 = 0x0000;
 const WRAM_END: u16 = 0x1FFF;
@@ -18,15 +26,48 @@ pub struct Mmu {
     pub ppu: Ppu,
     pub apu: Apu,
     pub ram: Ram,
-    pub mapper: Rc<RefCell<Mapper>>,
+    pub cartridge: Rc<RefCell<Cartridge>>,
     pub ctrl0: Controller,
     pub ctrl1: Controller,
+    pub irq: IrqLine,
+    pub debugger: Debugger,
+    // Total number of CPU cycles the bus has been clocked for
+    pub cycles: u64,
+    // Frames the PPU has finished since power on
+    pub frames: u64,
+    // Latched when the PPU raises an NMI, cleared once the CPU services it
+    nmi: bool,
+    // Interrupt lines as they were going into the most recent cycle. The CPU
+    // polls during the last cycle of an instruction, so anything raised on
+    // that very cycle is only noticed after the following instruction
+    nmi_poll: bool,
+    irq_poll: bool,
+    // Latched when the PPU finishes a frame
+    frame_ready: bool,
+    // Sample address the DMC wants fetched, serviced on the next CPU read
+    dmc_dma: Option<u16>,
+    // Cycles DMC DMA stole from the current instruction
+    stalled: u16,
+    // The last byte on the CPU's data bus, which is what reads of addresses
+    // nothing answers to return
     open_bus: u8,
+    // Regions written since the block cache last looked, see region_bit
+    #[cfg(feature = "cached-core")]
+    written: u32,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Ram(Box<[u8]>);
 
+// What internal RAM holds at power on. Real consoles come up with a mostly
+// random pattern, a seed keeps that reproducible between runs
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum RamInit {
+    Zero,
+    Fill(u8),
+    Random(u64),
+}
+
 impl Ram {
     pub fn new() -> Ram {
         Ram {
@@ -34,6 +75,30 @@ impl Ram {
         }
     }
 
+    pub fn with_init(init: RamInit) -> Ram {
+        let mut ram = Ram::new();
+        match init {
+            RamInit::Zero => (),
+            RamInit::Fill(val) => {
+                for byte in ram.0.iter_mut() {
+                    *byte = val;
+                }
+            }
+            // splitmix64, so any seed including 0 gives a usable sequence
+            RamInit::Random(seed) => {
+                let mut state = seed;
+                for byte in ram.0.iter_mut() {
+                    state = state.wrapping_add(0x9E3779B97F4A7C15);
+                    let mut z = state;
+                    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+                    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+                    *byte = (z ^ (z >> 31)) as u8;
+                }
+            }
+        }
+        ram
+    }
+
     fn load(&self, address: u16) -> u8 {
         self.0[address as usize]
     }
@@ -44,23 +109,156 @@ impl Ram {
 }
 
 impl Mmu {
-    pub fn new(apu: Apu, ppu: Ppu, mapper: Rc<RefCell<Mapper>>) -> Mmu {
+    pub fn new(apu: Apu, ppu: Ppu, cartridge: Rc<RefCell<Cartridge>>) -> Mmu {
         Mmu {
             ppu: ppu,
             apu: apu,
             ram: Ram::new(),
-            mapper: mapper,
+            cartridge: cartridge,
             ctrl0: Controller::new(),
             ctrl1: Controller::new(),
+            irq: IrqLine::new(),
+            debugger: Debugger::new(),
+            cycles: 0,
+            frames: 0,
+            nmi: false,
+            nmi_poll: false,
+            irq_poll: false,
+            frame_ready: false,
+            dmc_dma: None,
+            stalled: 0,
             open_bus: 0,
+            #[cfg(feature = "cached-core")]
+            written: 0,
         }
     }
 
+    // Advances every other component on the bus by one CPU cycle. Every load
+    // and store the CPU makes goes through here first, so register accesses
+    // land on the right PPU dot instead of after the whole instruction
+    pub fn tick(&mut self) {
+        self.nmi_poll = self.nmi;
+        self.irq_poll = self.irq.asserted();
+        self.cycles += 1;
+        if let Some(addr) = self.apu.tick(&mut self.irq) {
+            self.request_dmc_dma(addr);
+        }
+        match self.ppu.emulate_cycles(1) {
+            Some(PpuRes::Nmi) => self.nmi = true,
+            Some(PpuRes::Draw) => {
+                self.frame_ready = true;
+                self.frames += 1;
+            }
+            None => (),
+        }
+        // After the PPU, so an IRQ from its A12 edges shows up on the line
+        // the same cycle
+        let mut cartridge = self.cartridge.borrow_mut();
+        cartridge.clock();
+        self.irq.set(IrqSource::Mapper, cartridge.irq_pending());
+        self.apu.set_expansion(cartridge.audio_output());
+    }
+
+    // The CPU alternates between get (read) and put (write) cycles, DMA units
+    // can only read on a get cycle
+    fn next_is_get(&self) -> bool {
+        self.cycles % 2 == 0
+    }
+
+    // OAM DMA halts the CPU for one cycle, waits another if that left it on
+    // a put cycle, then copies the page a get/put pair at a time. Everything
+    // else keeps being clocked throughout. Returns the cycles it took, 513
+    // or 514
+    pub fn oam_dma(&mut self, page: u8) -> u16 {
+        let start = self.cycles;
+        let stalled = self.stalled;
+        self.tick();
+        if !self.next_is_get() {
+            self.tick();
+        }
+        let base = (page as u16) << 8;
+        for offset in 0..=0xFF {
+            let val = self.ld8(base | offset);
+            self.store(OAM_DATA, val);
+        }
+        // A DMC fetch in the middle is accounted as a stall of its own
+        (self.cycles - start) as u16 - (self.stalled - stalled)
+    }
+
+    pub fn request_dmc_dma(&mut self, addr: u16) {
+        self.dmc_dma = Some(addr);
+    }
+
+    // A fetch the DMC was waiting on in the state has to be made again
+    pub fn set_apu_state(&mut self, apu_state: ApuState) {
+        self.apu.set_state(apu_state);
+        self.dmc_dma = self.apu.pending_fetch();
+    }
+
+    // DMC DMA can only halt the CPU on a read. The halted CPU repeats the
+    // read it was about to make, so if that was $4016/$4017 the controller
+    // gets clocked an extra time and drops a bit. Takes 3 or 4 cycles
+    // depending on how the get cycle lines up
+    fn dmc_dma(&mut self, cpu_addr: u16, sample_addr: u16) {
+        let start = self.cycles;
+        self.tick();
+        let _ = self.read(cpu_addr);
+        self.tick();
+        if !self.next_is_get() {
+            self.tick();
+        }
+        self.tick();
+        let val = self.read(sample_addr);
+        self.apu.dmc_fill(val);
+        self.stalled += (self.cycles - start) as u16;
+    }
+
+    pub fn take_stalled(&mut self) -> u16 {
+        let stalled = self.stalled;
+        self.stalled = 0;
+        stalled
+    }
+
+    // NMI is edge triggered, so seeing it acknowledges the latch
+    pub fn poll_nmi(&mut self) -> bool {
+        if self.nmi_poll {
+            self.nmi = false;
+            self.nmi_poll = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn poll_irq(&self) -> bool {
+        self.irq_poll
+    }
+
+    pub fn frame_ready(&self) -> bool {
+        self.frame_ready
+    }
+
+    pub fn take_frame(&mut self) -> bool {
+        let frame_ready = self.frame_ready;
+        self.frame_ready = false;
+        frame_ready
+    }
+
     pub fn store(&mut self, address: u16, val: u8) {
+        self.tick();
+        #[cfg(feature = "cached-core")]
+        {
+            self.written |= region_bit(address);
+        }
+        self.debugger.on_write(address);
+        if self.debugger.watches(address) {
+            let old = self.peek(address);
+            self.debugger.on_watched_write(address, old, val);
+        }
+        self.open_bus = val;
         match address {
             WRAM_START...WRAM_END => self.ram.store(address & 0x7FF, val),
             PPU_START...PPU_END => {
-                self.open_bus = val;
                 self.ppu.store((address - 0x2000) & 7, val);
             }
             0x4016 => {
@@ -68,41 +266,81 @@ impl Mmu {
                 self.ctrl1.store(val);
             }
             0x4000...0x4017 => self.apu.store(address - 0x4000, val),
-            0x4018...0x401F => println!("disabled normally"),
+            // The CPU test mode registers, disabled on retail consoles
+            0x4018...0x401F => trace!("Write to disabled {:X}", address),
             ROM_START...ROM_END => {
-                self.mapper.borrow_mut().store_prg(address, val)
+                self.cartridge.borrow_mut().store_prg(address, val);
+                if self.cartridge.borrow().switches_chr(address) {
+                    self.ppu.chr_banks_changed();
+                }
             }
         }
     }
 
-    fn update_bus(&mut self, ppu_reg: u16, val: u8) {
-        match ppu_reg {
-            2 => self.open_bus = (self.open_bus & 0b11100000) | val,
-            7 => self.open_bus = val,
-            _ => (),
+    pub fn ld8(&mut self, address: u16) -> u8 {
+        if let Some(sample_addr) = self.dmc_dma.take() {
+            self.dmc_dma(address, sample_addr);
         }
+        self.tick();
+        self.debugger.on_read(address);
+        self.read(address)
     }
 
-    pub fn ld8(&mut self, address: u16) -> u8 {
-        match address {
+    // Opcode fetch for code the block cache already holds. The bus is still
+    // clocked, the mapper just isn't asked for a byte we know
+    #[cfg(feature = "cached-core")]
+    pub fn fetch_cached(&mut self, address: u16) {
+        if let Some(sample_addr) = self.dmc_dma.take() {
+            self.dmc_dma(address, sample_addr);
+        }
+        self.tick();
+    }
+
+    #[cfg(feature = "cached-core")]
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    #[cfg(feature = "cached-core")]
+    pub fn take_written(&mut self) -> u32 {
+        let written = self.written;
+        self.written = 0;
+        written
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        let val = match address {
             WRAM_START...WRAM_END => self.ram.load(address & 0x7FF),
             PPU_START...PPU_END => {
                 let ppu_reg = (address - 0x2000) & 7;
-                let val = self.ppu.ld(ppu_reg, self.open_bus);
-                self.update_bus(ppu_reg, val);
+                let val = self.ppu.ld(ppu_reg);
+                if self.ppu.take_cancelled_nmi() {
+                    self.nmi = false;
+                }
                 val
             }
             0x4015 => self.apu.load(address - 0x4000),
             0x4016 => self.ctrl0.ld8(),
             0x4017 => self.ctrl1.ld8(),
-            0x4000...0x4014 | 0x4018...0x401F => {
-                println!("disabled normally");
-                0
-            }
+            // Write only APU registers and the disabled test mode ones.
+            // Indexed stores and DMC DMA read these all the time
+            0x4000...0x4014 | 0x4018...0x401F => self.open_bus,
             ROM_START...ROM_END => {
-                let mapper = self.mapper.borrow();
-                mapper.ld_prg(address)
+                self.cartridge.borrow_mut().read_prg(address)
             }
+        };
+        self.open_bus = val;
+        val
+    }
+
+    // Reads without clocking the bus or triggering read side effects, for
+    // debuggers and tracing. Registers just show the open bus value
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            WRAM_START...WRAM_END => self.ram.load(address & 0x7FF),
+            PPU_START...PPU_END => self.ppu.open_bus(),
+            ROM_START...ROM_END => self.cartridge.borrow().ld_prg(address),
+            _ => self.open_bus,
         }
     }
 
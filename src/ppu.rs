@@ -1,8 +1,10 @@
 use serde::Serialize;
 use serde::Deserialize;
-use mapper::Mapper;
+use cartridge::Cartridge;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::mem;
+use rom::Region;
 
 use ppu::pregisters::PRegisters;
 use ppu::pregisters::VramAddr;
@@ -10,49 +12,120 @@ use ppu::pregisters::Ctrl;
 use ppu::sprite::Sprite;
 use ppu::sprite::Priority;
 use ppu::vram::*;
+use ppu::tile_cache::TileCache;
+use ppu::palette::COLORS;
+use ppu::palette::EMPHASIZED_COLORS;
 
 pub mod pregisters;
 pub mod sprite;
 pub mod vram;
+pub mod palette;
+pub mod viewer;
+pub mod tile_cache;
 
 const SPRITE_NUM: usize = 64;
-const SCREEN_WIDTH: usize = 256;
-const SCREEN_HEIGHT: usize = 240;
-const PRERENDER: u16 = 261;
-
-pub const PALETTE: [u32; 64] = [
-    0x808080, 0x003DA6, 0x0012B0, 0x440096, 0xA1005E, 0xC70028, 0xBA0600,
-    0x8C1700, 0x5C2F00, 0x104500, 0x054A00, 0x00472E, 0x004166, 0x000000,
-    0x050505, 0x050505, 0xC7C7C7, 0x0077FF, 0x2155FF, 0x8237FA, 0xEB2FB5,
-    0xFF2950, 0xFF2200, 0xD63200, 0xC46200, 0x358000, 0x058F00, 0x008A55,
-    0x0099CC, 0x212121, 0x090909, 0x090909, 0xFFFFFF, 0x0FD7FF, 0x69A2FF,
-    0xD480FF, 0xFF45F3, 0xFF618B, 0xFF8833, 0xFF9C12, 0xFABC20, 0x9FE30E,
-    0x2BF035, 0x0CF0A4, 0x05FBFF, 0x5E5E5E, 0x0D0D0D, 0x0D0D0D, 0xFFFFFF,
-    0xA6FCFF, 0xB3ECFF, 0xDAABEB, 0xFFA8F9, 0xFFABB3, 0xFFD2B0, 0xFFEFA6,
-    0xFFF79C, 0xD7E895, 0xA6EDAF, 0xA2F2DA, 0x99FFFC, 0xDDDDDD, 0x111111,
-    0x111111,
-];
-
-//TODO: Add loadable palettes. This palette here is more accurate to the og NES
-//colors but in my opinion looks works.
-//pub const PALETTE: [u32; 64] = [
-//    0x666666, 0x002A88, 0x1412A7, 0x3B00A4, 0x5C007E, 0x6E0040, 0x6C0600,
-//    0x561D00, 0x333500, 0x0B4800, 0x005200, 0x004F08, 0x00404D, 0x000000,
-//    0x000000, 0x000000, 0xADADAD, 0x155FD9, 0x4240FF, 0x7527FE, 0xA01ACC,
-//    0xB71E7B, 0xB53120, 0x994E00, 0x6B6D00, 0x388700, 0x0C9300, 0x008F32,
-//    0x007C8D, 0x000000, 0x000000, 0x000000, 0xFFFEFF, 0x64B0FF, 0x9290FF,
-//    0xC676FF, 0xF36AFF, 0xFE6ECC, 0xFE8170, 0xEA9E22, 0xBCBE00, 0x88D800,
-//    0x5CE430, 0x45E082, 0x48CDDE, 0x4F4F4F, 0x000000, 0x000000, 0xFFFEFF,
-//    0xC0DFFF, 0xD3D2FF, 0xE8C8FF, 0xFBC2FF, 0xFEC4EA, 0xFECCC5, 0xF7D8A5,
-//    0xE4E594, 0xCFEF96, 0xBDF4AB, 0xB3F3CC, 0xB5EBF2, 0xB8B8B8, 0x000000,
-//    0x000000,
-//];
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+// Bits of the open bus latch fade to 0 roughly 600ms after they were last
+// driven
+const OPEN_BUS_DECAY_FRAMES: u8 = 36;
+// OAM is dynamic RAM that rendering keeps refreshed. A row left alone for
+// about 3000 CPU cycles loses its contents
+const OAM_DECAY_DOTS: u64 = 9000;
+const OAM_DECAYED: u8 = 0x10;
+// A12 has to stay low for a bit over 3 CPU cycles before a rise counts. The
+// 9 dot dip between the last fetches of one line and the first pattern fetch
+// of the next doesn't
+const A12_FILTER_DOTS: u64 = 10;
+// 64 bit FNV-1a, run over each pixel's colour index as it's drawn
+const FNV_OFFSET: u64 = 0xCBF29CE484222325;
+const FNV_PRIME: u64 = 0x100000001B3;
+
+// Which console's frame timing the PPU follows
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    // Famiclones like the Dendy draw PAL's 312 lines, but keep NTSC's 3 dots
+    // per CPU cycle and only start vblank 51 lines after the picture ends,
+    // so the NMI handler gets about as much time as it would on NTSC
+    Dendy,
+    // 312 lines with vblank starting right after the picture, and 3.2 dots
+    // per CPU cycle
+    Pal,
+}
+
+impl Timing {
+    fn prerender(&self) -> u16 {
+        match *self {
+            Timing::Ntsc => 261,
+            Timing::Dendy | Timing::Pal => 311,
+        }
+    }
+
+    fn vblank_line(&self) -> u16 {
+        match *self {
+            Timing::Ntsc | Timing::Pal => 241,
+            Timing::Dendy => 291,
+        }
+    }
+
+    // For frontends pacing frames themselves
+    pub fn frames_per_second(&self) -> f64 {
+        match *self {
+            Timing::Ntsc => 60.0988,
+            Timing::Dendy => 50.0,
+            Timing::Pal => 50.007,
+        }
+    }
+
+    // The APU only knows about NTSC and PAL, the Dendy's sounds like NTSC
+    pub fn region(&self) -> Region {
+        match *self {
+            Timing::Pal => Region::PAL,
+            Timing::Ntsc | Timing::Dendy => Region::NTSC,
+        }
+    }
+
+    // Only NTSC drops a dot on odd frames
+    fn skips_odd_dot(&self) -> bool {
+        *self == Timing::Ntsc
+    }
+}
+
+// How get_buffer lays out each pixel
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum PixelFormat {
+    Rgb24,
+    // R, G, B and then an opaque alpha byte
+    Rgba8888,
+    // B, G, R and then an opaque alpha byte, which is ARGB8888 read as
+    // little endian words
+    Bgra8888,
+    // Little endian 16 bit entries with the colour in the low 6 bits and the
+    // emphasis bits above it. That is the entry's index in Ppu::colors
+    Indexed,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Rgba8888 | PixelFormat::Bgra8888 => 4,
+            PixelFormat::Indexed => 2,
+        }
+    }
+}
 
 #[derive(Copy, Clone)]
 struct Rgb {
     data: [u8; 3],
 }
 
+fn screen_buffer(format: PixelFormat) -> Box<[u8]> {
+    vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * format.bytes_per_pixel()]
+        .into_boxed_slice()
+}
+
 #[derive(Debug)]
 pub enum PpuRes {
     Nmi,
@@ -133,6 +206,37 @@ impl InternalRegs {
     }
 }
 
+// Where sprite evaluation is in primary OAM, and what it has found so far
+#[derive(Copy, Clone)]
+struct SpriteEval {
+    n: u8,
+    m: u8,
+    // Bytes written to secondary OAM
+    copied: u8,
+    // Whether sprite 0 is among the sprites found
+    sprite0: bool,
+    done: bool,
+}
+
+impl SpriteEval {
+    fn new() -> SpriteEval {
+        SpriteEval {
+            n: 0,
+            m: 0,
+            copied: 0,
+            sprite0: false,
+            done: false,
+        }
+    }
+
+    fn next_sprite(&mut self) {
+        self.n += 1;
+        if self.n as usize == SPRITE_NUM {
+            self.done = true;
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PpuState {
     vram: Box<[u8]>,
@@ -143,61 +247,146 @@ pub struct PpuState {
     scanline: u16,
     write_latch: bool,
     t_addr: VramAddr,
+    fine_x: u8,
     trip_nmi: bool,
     vblank_off: bool,
+    nt_entry: u8,
     at_entry: u8,
+    odd_frame: bool,
+    open_bus: u8,
+    open_bus_decay: [u8; 8],
+    timing: Timing,
+    warming_up: bool,
+    pal_cycle: u8,
 }
 
 pub struct Ppu {
     pub regs: PRegisters,
     vram: Vram,
-    // multiply by 3 to account for r g b
+    // The last finished frame, laid out as pixel_format says. Pixels are
+    // drawn into back_buff, and the two swap once the picture is done, so
+    // whatever reads the frame never sees one that's half drawn
     screen_buff: Box<[u8]>,
+    back_buff: Box<[u8]>,
+    // Hashes of the colour indices in screen_buff and of what's been drawn
+    // into back_buff so far. They don't depend on the palette or pixel format
+    frame_hash: u64,
+    back_hash: u64,
+    pixel_format: PixelFormat,
     oam: [u8; 256],
-    tmp_oam: Vec<Sprite>,
+    // RGB for each colour under each combination of emphasis bits
+    colors: Vec<u32>,
+    // The up to 8 sprites found for the next line
+    secondary_oam: [u8; 32],
+    eval: SpriteEval,
+    // Last byte sprite evaluation read from OAM, which $2004 returns
+    oam_latch: u8,
+    // The sprites being drawn on this line, with their patterns fetched
     main_oam: Vec<Sprite>,
+    // Decoded sprite patterns
+    tiles: TileCache,
+    // Sprites drawn per line. The hardware draws 8, anything higher is an
+    // enhancement that avoids flicker
+    pub sprite_limit: usize,
+    // Whether OAM rows decay when rendering is off for too long. Off by
+    // default, since games that hit this on hardware are buggy anyway
+    pub oam_decay: bool,
+    // Frame timing, only safe to change before a reset since the current
+    // line might not exist under the new timing
+    pub timing: Timing,
+    // Whether $2000, $2001, $2005 and $2006 ignore writes until the end of
+    // the first frame after power on or reset, as on hardware. Off by
+    // default, well behaved games wait that long anyway
+    pub warm_up: bool,
+    // Set by a reset and cleared on the prerender line
+    warming_up: bool,
+    // CPU cycles into PAL's 5 cycle, 16 dot pattern
+    pal_cycle: u8,
+    // Dot each 8 byte OAM row was last refreshed on
+    oam_refreshed: [u64; 32],
+    // Dots run since power on
+    dots: u64,
+    // Dot PPU address line A12 went low on, None while it's high
+    a12_low_since: Option<u64>,
     cc: u16,
     scanline: u16,
-    // Internal registers
+    // Internal registers, known as v, t, x and w on the nesdev wiki. v is
+    // regs.addr, the address rendering fetches from and $2007 accesses
     //
     // Write latch is another 1 bit latch that stores data on which write we
-    // are on
+    // are on (w). It is shared by $2005 and $2006 and reset by reading $2002
     write_latch: bool,
-    // Data buff is used for the 1 byte delay when reading data from port 7
     // Temporary address used to reload x and y scroll values and also for
-    // intermediary storage for writes to port 5
+    // intermediary storage for writes to port 5 (t)
     t_addr: VramAddr,
     // Fine x scrolling is not part of the 16 bit internal v_addr, so the NES
-    // has a separate fine x register for inner tile scrolling
+    // has a separate fine x register for inner tile scrolling (x)
     fine_x: u8,
     // Used to force an nmi when in vblank and a write to CTRL enables NMI
     trip_nmi: bool,
     // Used to correctly emulate the race condition when reading from STATUS
     // disables NMI for that frame
     vblank_off: bool,
-    // Contains the attribute table data for the NEXT tile
+    // Set when STATUS is read right as vblank starts. The flag still reads as
+    // set, but the NMI it raised gets pulled back before the CPU sees it
+    cancel_nmi: bool,
+    // Nametable and attribute table bytes for the tile being fetched
+    nt_entry: u8,
     at_entry: u8,
+    // Palette entry of the last pixel drawn while rendering
+    pixel: u8,
+    // Frames alternate between even and odd, odd ones can be a dot shorter
+    odd_frame: bool,
+    // The PPU's data bus to the CPU holds on to the last value driven onto
+    // it. Write only registers read back as this latch, as do the bits of
+    // $2002 and palette reads that aren't driven
+    open_bus: u8,
+    // Frames until each bit of the latch decays
+    open_bus_decay: [u8; 8],
     // Contains the shift and latch registers the NES uses for rendering
     internal_regs: InternalRegs,
 }
 
 impl Ppu {
-    pub fn new(mapper: Rc<RefCell<Mapper>>) -> Ppu {
+    pub fn new(cartridge: Rc<RefCell<Cartridge>>) -> Ppu {
         Ppu {
             trip_nmi: false,
             vblank_off: false,
+            cancel_nmi: false,
             regs: PRegisters::new(),
-            vram: Vram::new(mapper),
-            screen_buff: Box::new([0; SCREEN_WIDTH * 3 * SCREEN_HEIGHT]),
+            vram: Vram::new(cartridge),
+            screen_buff: screen_buffer(PixelFormat::Rgb24),
+            back_buff: screen_buffer(PixelFormat::Rgb24),
+            frame_hash: FNV_OFFSET,
+            back_hash: FNV_OFFSET,
+            pixel_format: PixelFormat::Rgb24,
             oam: [0; 256],
-            tmp_oam: Vec::with_capacity(8),
+            colors: palette::emphasize(&palette::CLASSIC),
+            secondary_oam: [0xFF; 32],
+            eval: SpriteEval::new(),
+            oam_latch: 0,
             main_oam: Vec::with_capacity(8),
+            tiles: TileCache::new(),
+            sprite_limit: 8,
+            oam_decay: false,
+            timing: Timing::Ntsc,
+            warm_up: false,
+            warming_up: true,
+            pal_cycle: 0,
+            oam_refreshed: [0; 32],
+            dots: 0,
+            a12_low_since: Some(0),
             cc: 0,
             scanline: 0,
             write_latch: false,
             fine_x: 0,
             t_addr: VramAddr(0),
+            nt_entry: 0,
             at_entry: 0,
+            pixel: 0,
+            odd_frame: false,
+            open_bus: 0,
+            open_bus_decay: [0; 8],
             internal_regs: InternalRegs::new(),
         }
     }
@@ -212,9 +401,17 @@ impl Ppu {
             scanline: self.scanline,
             write_latch: self.write_latch,
             t_addr: self.t_addr,
+            fine_x: self.fine_x,
             trip_nmi: self.trip_nmi,
             vblank_off: self.vblank_off,
+            nt_entry: self.nt_entry,
             at_entry: self.at_entry,
+            odd_frame: self.odd_frame,
+            open_bus: self.open_bus,
+            open_bus_decay: self.open_bus_decay,
+            timing: self.timing,
+            warming_up: self.warming_up,
+            pal_cycle: self.pal_cycle,
         }
     }
 
@@ -227,26 +424,49 @@ impl Ppu {
         self.scanline = ppu_state.scanline;
         self.write_latch = ppu_state.write_latch;
         self.t_addr = ppu_state.t_addr;
+        self.fine_x = ppu_state.fine_x;
         self.trip_nmi = ppu_state.trip_nmi;
         self.vblank_off = ppu_state.vblank_off;
+        self.nt_entry = ppu_state.nt_entry;
         self.at_entry = ppu_state.at_entry;
+        self.odd_frame = ppu_state.odd_frame;
+        self.open_bus = ppu_state.open_bus;
+        self.open_bus_decay = ppu_state.open_bus_decay;
+        self.timing = ppu_state.timing;
+        self.warming_up = ppu_state.warming_up;
+        self.pal_cycle = ppu_state.pal_cycle;
+        self.tiles.clear();
     }
 
     pub fn reset(&mut self) {
         self.trip_nmi = false;
         self.vblank_off = false;
+        self.cancel_nmi = false;
         self.regs = PRegisters::new();
         self.vram.reset();
-        self.screen_buff = Box::new([0; SCREEN_WIDTH * 3 * SCREEN_HEIGHT]);
+        self.screen_buff = screen_buffer(self.pixel_format);
+        self.back_buff = screen_buffer(self.pixel_format);
+        self.frame_hash = FNV_OFFSET;
+        self.back_hash = FNV_OFFSET;
         self.oam = [0; 256];
-        self.tmp_oam = Vec::with_capacity(8);
+        self.secondary_oam = [0xFF; 32];
+        self.eval = SpriteEval::new();
+        self.oam_latch = 0;
         self.main_oam = Vec::with_capacity(8);
+        self.tiles.clear();
+        self.warming_up = true;
+        self.pal_cycle = 0;
         self.cc = 0;
         self.scanline = 0;
         self.write_latch = false;
         self.fine_x = 0;
         self.t_addr = VramAddr(0);
+        self.nt_entry = 0;
         self.at_entry = 0;
+        self.odd_frame = false;
+        self.a12_low_since = Some(self.dots);
+        self.open_bus = 0;
+        self.open_bus_decay = [0; 8];
         self.internal_regs = InternalRegs::new();
     }
 
@@ -262,9 +482,16 @@ impl Ppu {
         }
     }
 
+    // Index into colors of what a palette RAM entry shows as right now
+    fn color_index(&self, vram_offset: u8) -> usize {
+        let pal_index =
+            self.grayscale(self.vram.ld8(0x3F00 + vram_offset as u16)) & 0x3F;
+        let emphasis = self.regs.mask.emphasis() as usize;
+        emphasis * COLORS + pal_index as usize
+    }
+
     fn get_palette_color(&self, vram_offset: u8) -> Rgb {
-        let pal_index = (self.vram.ld8(0x3F00 + vram_offset as u16)) & 0x3F;
-        let num = PALETTE[pal_index as usize];
+        let num = self.colors[self.color_index(vram_offset)];
         Rgb {
             data: [
                 ((num & 0xFF0000) >> 16) as u8,
@@ -274,37 +501,146 @@ impl Ppu {
         }
     }
 
-    pub fn ld(&mut self, address: u16, open_bus: u8) -> u8 {
+    pub fn ld(&mut self, address: u16) -> u8 {
         match address {
-            0 => open_bus,
-            1 => open_bus,
-            2 => self.read_ppustatus(),
-            3 => open_bus,
-            4 => self.oam[self.regs.oam_addr as usize],
-            5 => open_bus,
-            6 => open_bus,
-            7 => self.read_ppudata(),
+            0 | 1 | 3 | 5 | 6 => self.open_bus,
+            // Only the 3 flags are driven
+            2 => {
+                let status = self.read_ppustatus();
+                self.drive_open_bus(status, 0xE0);
+                self.open_bus
+            }
+            4 => {
+                let row = self.regs.oam_addr as usize / 8;
+                self.refresh_oam_row(row);
+                let val = self.read_oamdata();
+                self.drive_open_bus(val, 0xFF);
+                val
+            }
+            // Palette entries are 6 bits, the top 2 come from the latch
+            7 => {
+                let mask = if self.regs.addr.addr() >= 0x3F00 {
+                    0x3F
+                } else {
+                    0xFF
+                };
+                let val = self.read_ppudata();
+                self.drive_open_bus(val, mask);
+                self.open_bus
+            }
             _ => panic!("Somehow got to invalid register"),
         }
     }
 
+    // What a read of a write only register would return
+    pub fn open_bus(&self) -> u8 {
+        self.open_bus
+    }
+
+    fn drive_open_bus(&mut self, val: u8, mask: u8) {
+        self.open_bus = (self.open_bus & !mask) | (val & mask);
+        for (bit, decay) in self.open_bus_decay.iter_mut().enumerate() {
+            if mask & (1 << bit) != 0 {
+                *decay = OPEN_BUS_DECAY_FRAMES;
+            }
+        }
+    }
+
+    fn decay_open_bus(&mut self) {
+        for (bit, decay) in self.open_bus_decay.iter_mut().enumerate() {
+            if *decay > 0 {
+                *decay -= 1;
+                if *decay == 0 {
+                    self.open_bus &= !(1 << bit);
+                }
+            }
+        }
+    }
+
     fn read_ppustatus(&mut self) -> u8 {
         self.write_latch = false;
         let tmp = self.regs.status.load();
         self.regs.status.set_vblank(false);
         self.vblank_off = true;
+        // The flag was set on dot 1, so cc is already past it. Reading on
+        // that dot or the next one suppresses the NMI for the frame, a read
+        // one dot earlier is caught by vblank_off instead
+        if self.scanline == self.timing.vblank_line()
+            && (self.cc == 2 || self.cc == 3)
+        {
+            self.cancel_nmi = true;
+        }
         tmp
     }
 
+    pub fn take_cancelled_nmi(&mut self) -> bool {
+        let cancel_nmi = self.cancel_nmi;
+        self.cancel_nmi = false;
+        cancel_nmi
+    }
+
+    // While sprites are being evaluated $2004 shows what the PPU is doing
+    // with OAM rather than what OAMADDR points at
+    fn read_oamdata(&self) -> u8 {
+        if self.rendering_enabled() && self.scanline < 240 {
+            match self.cc {
+                1...64 => return 0xFF,
+                65...256 => return self.oam_latch,
+                _ => (),
+            }
+        }
+        let val = self.oam[self.regs.oam_addr as usize];
+        // Bits 2-4 of the attribute byte don't exist
+        if self.regs.oam_addr % 4 == 2 {
+            val & 0xE3
+        } else {
+            val
+        }
+    }
+
     fn read_ppudata(&mut self) -> u8 {
         let addr = self.regs.addr.addr();
-        let val = self.vram.buffered_ld8(addr);
-        self.regs.addr.add_offset(self.regs.ctrl.vram_incr());
+        self.watch_a12(addr);
+        let mut val = self.vram.buffered_ld8(addr);
+        self.pattern_fetched(addr);
+        // Palette reads come from the same lookup that feeds the video
+        // output, so they see grayscale too. While rendering that lookup is
+        // addressed by the pixel being drawn rather than v
+        if addr >= 0x3F00 {
+            if self.rendering_active() {
+                val = self.vram.ld8(0x3F00 + self.pixel as u16);
+            }
+            val = self.grayscale(val);
+        }
+        self.incr_vram_addr();
         val
     }
 
+    // Grayscale drops the hue bits, leaving the grey of each brightness row
+    fn grayscale(&self, color: u8) -> u8 {
+        if self.regs.mask.is_grey_scale() {
+            color & 0x30
+        } else {
+            color
+        }
+    }
+
+    // Outside of rendering $2007 moves v on by 1 or 32. While rendering it
+    // instead bumps coarse X and Y together, since the increment goes through
+    // the same logic the tile fetches use
+    fn incr_vram_addr(&mut self) {
+        if self.rendering_active() {
+            self.regs.addr.scroll_x();
+            self.regs.addr.scroll_y();
+        } else {
+            self.regs.addr.add_offset(self.regs.ctrl.vram_incr());
+        }
+    }
+
     pub fn store(&mut self, address: u16, val: u8) {
+        self.drive_open_bus(val, 0xFF);
         match address {
+            0 | 1 | 5 | 6 if self.warm_up && self.warming_up => (),
             0 => {
                 self.write_ctrl(val);
             }
@@ -336,6 +672,8 @@ impl Ppu {
     }
 
     fn write_oamdata(&mut self, val: u8) {
+        let row = self.regs.oam_addr as usize / 8;
+        self.refresh_oam_row(row);
         self.oam[self.regs.oam_addr as usize] = val;
         self.regs.oam_addr = self.regs.oam_addr.wrapping_add(1);
     }
@@ -355,6 +693,8 @@ impl Ppu {
         if self.write_latch {
             self.t_addr.set_l_byte(val);
             self.regs.addr = self.t_addr.clone();
+            let addr = self.regs.addr.addr();
+            self.watch_a12(addr);
         } else {
             self.t_addr.set_h_byte_clear_bit(val);
         }
@@ -363,100 +703,289 @@ impl Ppu {
 
     fn write_ppudata(&mut self, val: u8) {
         let addr = self.regs.addr.addr();
+        self.watch_a12(addr);
         self.vram.store(addr, val);
-        self.regs.addr.add_offset(self.regs.ctrl.vram_incr());
+        if addr < 0x2000 {
+            self.tiles.invalidate(addr);
+        }
+        self.incr_vram_addr();
     }
 
-    fn put_pixel(&mut self, x: usize, y: usize, color: Rgb) {
-        self.screen_buff[(y * SCREEN_WIDTH + x) * 3..][..3]
-            .copy_from_slice(&color.data);
+    // Has to be called whenever the mapper might have changed what CHR is
+    // mapped in, so sprites aren't drawn from stale tiles
+    pub fn chr_banks_changed(&mut self) {
+        self.tiles.clear();
+    }
+
+    fn put_pixel(&mut self, x: usize, y: usize, vram_offset: u8) {
+        let size = self.pixel_format.bytes_per_pixel();
+        let index = self.color_index(vram_offset);
+        self.back_hash = (self.back_hash ^ index as u64).wrapping_mul(FNV_PRIME);
+        let rgb = self.colors[index];
+        let (r, g, b) = ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+        let pixel = &mut self.back_buff[(y * SCREEN_WIDTH + x) * size..];
+        match self.pixel_format {
+            PixelFormat::Rgb24 => pixel[..3].copy_from_slice(&[r, g, b]),
+            PixelFormat::Rgba8888 => {
+                pixel[..4].copy_from_slice(&[r, g, b, 0xFF])
+            }
+            PixelFormat::Bgra8888 => {
+                pixel[..4].copy_from_slice(&[b, g, r, 0xFF])
+            }
+            PixelFormat::Indexed => {
+                pixel[..2].copy_from_slice(&[index as u8, (index >> 8) as u8])
+            }
+        }
     }
 
+    // Sprites for the next line are found while this one is drawn: dots
+    // 1-64 fill secondary OAM with $FF, 65-256 copy the sprites in range
+    // into it (reading on odd dots and writing on even ones), and 257-320
+    // fetch the patterns for each of its 8 slots
     fn step_sprites(&mut self) {
+        if self.cc == 1 && self.is_prerender() {
+            self.regs.status.set_sprite_o_f(false);
+            self.regs.status.set_sprite_0_hit(false);
+        }
+        if !self.rendering_enabled() {
+            return;
+        }
         match self.cc {
-            1 => {
-                self.tmp_oam.clear();
-                if self.is_prerender() {
-                    self.regs.status.set_sprite_o_f(false);
-                    self.regs.status.set_sprite_0_hit(false);
+            1...64 if !self.is_prerender() => {
+                if self.cc % 2 == 0 {
+                    self.secondary_oam[(self.cc / 2 - 1) as usize] = 0xFF;
                 }
             }
-            257 => {
-                self.get_sprites();
-                self.regs.oam_addr = 0;
+            65...256 if !self.is_prerender() => {
+                if self.cc == 65 {
+                    self.eval = SpriteEval::new();
+                    // Evaluation reads through all of OAM each line
+                    for row in 0..self.oam_refreshed.len() {
+                        self.refresh_oam_row(row);
+                    }
+                }
+                // Once all 64 sprites are checked n wraps and the reads go on
+                if self.cc % 2 == 1 {
+                    let n = self.eval.n as usize % SPRITE_NUM;
+                    self.oam_latch = self.oam[n * 4 + self.eval.m as usize];
+                } else {
+                    self.evaluate_sprite();
+                }
             }
-            258...320 => {
+            // Each slot takes 8 dots: two nametable fetches that go unused,
+            // then the pattern
+            257...320 => {
                 self.regs.oam_addr = 0;
+                match self.cc % 8 {
+                    1 | 3 => {
+                        let addr = self.regs.addr.nt_addr();
+                        self.fetch(addr);
+                    }
+                    5 => self.fetch_sprite(((self.cc - 257) / 8) as usize),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Any access refreshes the row, but if it had already decayed what's
+    // left is garbage
+    fn refresh_oam_row(&mut self, row: usize) {
+        if !self.oam_decay {
+            return;
+        }
+        if self.dots - self.oam_refreshed[row] > OAM_DECAY_DOTS {
+            for byte in self.oam[row * 8..row * 8 + 8].iter_mut() {
+                *byte = OAM_DECAYED;
             }
-            321 => {
-                self.main_oam = self.tmp_oam.clone();
-                for sprite in self.main_oam.iter_mut() {
-                    let address =
-                        sprite.get_pt_address(&self.regs.ctrl, self.scanline);
-                    sprite.low_byte = self.vram.ld8(address);
-                    sprite.high_byte = self.vram.ld8(address + 8);
+        }
+        self.oam_refreshed[row] = self.dots;
+    }
+
+    fn evaluate_sprite(&mut self) {
+        if self.eval.done {
+            return;
+        }
+        let val = self.oam_latch;
+        if self.eval.copied < 32 {
+            // Y is written whether or not the sprite turns out to be in range
+            self.secondary_oam[self.eval.copied as usize] = val;
+            if self.eval.m == 0 {
+                if !self.in_range(val) {
+                    self.eval.next_sprite();
+                    return;
+                }
+                if self.eval.n == 0 {
+                    self.eval.sprite0 = true;
                 }
             }
+            self.eval.copied += 1;
+            self.eval.m += 1;
+            if self.eval.m == 4 {
+                self.eval.m = 0;
+                self.eval.next_sprite();
+            }
+        } else if self.in_range(val) {
+            self.regs.status.set_sprite_o_f(true);
+            self.eval.done = true;
+        } else {
+            // With 8 sprites found the PPU keeps looking for a 9th to set the
+            // overflow flag. A bug makes it step to the next byte of each
+            // sprite along with the next sprite whenever one is out of range,
+            // so tile numbers, attributes and X positions get compared as if
+            // they were Y. Games relying on the flag see both false positives
+            // and misses
+            self.eval.m = (self.eval.m + 1) % 4;
+            self.eval.next_sprite();
+        }
+    }
+
+    // Slots past the sprites found still fetch, using tile $FF, which
+    // mappers watching the pattern table address can see
+    fn fetch_sprite(&mut self, slot: usize) {
+        if slot == 0 {
+            self.main_oam.clear();
+        }
+        // Nothing is evaluated on the prerender line, so no sprites are drawn
+        // on the first line of the frame
+        if self.is_prerender() || slot * 4 >= self.eval.copied as usize {
+            let tile = match self.regs.ctrl.sprite_size() {
+                8 => self.regs.ctrl.sprite_pt_addr() + 0xFF * 16,
+                _ => 0x1000 + 0xFE * 16,
+            };
+            self.fetch(tile);
+            self.fetch(tile + 8);
+        } else {
+            let sprite0 = slot == 0 && self.eval.sprite0;
+            let bytes = &self.secondary_oam[slot * 4..slot * 4 + 4];
+            let sprite = Sprite::from_bytes(sprite0, bytes);
+            self.load_sprite(sprite);
+        }
+        if slot == 7 && self.sprite_limit > 8 && !self.is_prerender() {
+            self.fetch_extra_sprites();
+        }
+    }
+
+    // The pattern comes from the tile cache, but both fetches still go on
+    // the bus for the mapper to see
+    fn load_sprite(&mut self, sprite: Sprite) {
+        let address = sprite.get_pt_address(&self.regs.ctrl, self.scanline);
+        self.watch_a12(address);
+        self.watch_a12(address + 8);
+        self.push_sprite(sprite, address);
+        self.pattern_fetched(address);
+        self.pattern_fetched(address + 8);
+    }
+
+    fn push_sprite(&mut self, mut sprite: Sprite, address: u16) {
+        sprite.pixels = self.tiles.row(&self.vram, address);
+        if sprite.attributes.flip_x() {
+            sprite.pixels.reverse();
+        }
+        self.main_oam.push(sprite);
+    }
+
+    // Every address rendering puts on the PPU bus goes through here, so
+    // mappers see the same pattern of accesses as on hardware
+    fn fetch(&mut self, addr: u16) -> u8 {
+        self.watch_a12(addr);
+        let val = self.vram.ld8(addr);
+        self.pattern_fetched(addr);
+        val
+    }
+
+    // Mappers like MMC2 switch CHR banks when certain tiles are read, which
+    // only affects the fetches after it
+    fn pattern_fetched(&mut self, addr: u16) {
+        if addr < 0x2000 && self.vram.pattern_fetch(addr) {
+            self.tiles.clear();
+        }
+    }
+
+    // A12 picks the pattern table half. MMC3 style scanline counters are
+    // clocked by it rising, but only after it has been low for a while,
+    // which filters out the short dips between sprite fetches
+    fn watch_a12(&mut self, addr: u16) {
+        let high = addr & 0x1000 != 0;
+        match (self.a12_low_since, high) {
+            (Some(since), true) => {
+                if self.dots - since >= A12_FILTER_DOTS {
+                    self.vram.a12_rise();
+                }
+                self.a12_low_since = None;
+            }
+            (None, false) => self.a12_low_since = Some(self.dots),
             _ => (),
         }
     }
 
-    fn get_sprites(&mut self) {
-        self.tmp_oam.clear();
-        for sprite_index in 0..SPRITE_NUM {
-            if self.tmp_oam.len() == 8 {
-                self.regs.status.set_sprite_o_f(true);
+    // Goes past the hardware's 8 sprites per line, which removes flicker in
+    // games that cycle sprites to get around the limit. The overflow flag
+    // still only reflects what the real PPU would see
+    fn fetch_extra_sprites(&mut self) {
+        let mut found = 0;
+        for n in 0..SPRITE_NUM {
+            if self.main_oam.len() >= self.sprite_limit {
                 return;
             }
-            let sprite_y = self.oam[sprite_index * 4] as u16;
-            if sprite_y <= self.scanline
-                && sprite_y + self.regs.ctrl.sprite_size() > self.scanline
-            {
-                self.tmp_oam.push(Sprite::new(sprite_index, &self.oam));
+            if self.in_range(self.oam[n * 4]) {
+                found += 1;
+                // There's no fetch for these on hardware, so they stay off
+                // the bus mappers watch
+                if found > 8 {
+                    let sprite =
+                        Sprite::from_bytes(n == 0, &self.oam[n * 4..]);
+                    let address = sprite
+                        .get_pt_address(&self.regs.ctrl, self.scanline);
+                    self.push_sprite(sprite, address);
+                }
             }
         }
     }
 
+    fn in_range(&self, y: u8) -> bool {
+        let y = y as u16;
+        y <= self.scanline && y + self.regs.ctrl.sprite_size() > self.scanline
+    }
+
     fn sprite_pixel(
         &mut self,
         x: u8,
         bg_opaque: bool,
     ) -> (u8, Option<Priority>) {
+        if !self.regs.mask.show_sprites() {
+            return (0, None);
+        }
+        // Clipping the left column hides sprites there and, with them, any
+        // sprite 0 hit
+        let clipped = x < 8
+            && (!self.regs.mask.left8_sprite() || !self.regs.mask.left8_bg());
+        if x < 8 && !self.regs.mask.left8_sprite() {
+            return (0, None);
+        }
+        // The first opaque sprite is the one drawn, and only its priority is
+        // weighed against the background. A behind sprite earlier in OAM
+        // hides the ones after it wherever the background is opaque, which
+        // games use to clip sprites
         for sprite in self.main_oam.iter() {
-            if !self.regs.mask.show_sprites() {
-                return (0, None);
-            }
-
-            if !sprite.in_bounding_box(x, self.scanline as u8) {
+            if !sprite.covers(x) {
                 continue;
             }
 
-            let x_offset = if sprite.attributes.flip_x() {
-                7 - (x.wrapping_sub(sprite.x))
-            } else {
-                x.wrapping_sub(sprite.x)
-            };
-
-            let l_bit = ((sprite.low_byte << x_offset & 0x80) != 0) as u8;
-            let r_bit = ((sprite.high_byte << x_offset & 0x80) != 0) as u8;
-            let tile_color = r_bit << 1 | l_bit;
+            let tile_color = sprite.pixels[(x - sprite.x) as usize];
 
             if tile_color == 0 {
                 continue;
             }
 
-            if sprite.index == 0
-                && bg_opaque
-                && x != 255
-                && !((sprite.x == 0) && (!self.regs.mask.left8_sprite()))
-            {
+            // Both pixels have to be opaque, on the dot the overlap is drawn.
+            // Nothing hits at x=255, or in the left column if either layer
+            // is clipped there
+            if sprite.sprite0 && bg_opaque && x != 255 && !clipped {
                 self.regs.status.set_sprite_0_hit(true);
             }
 
-            if sprite.x < 8 && !self.regs.mask.left8_sprite() {
-                continue;
-            }
-
             let sprite_color =
                 (sprite.attributes.palette()) + 4 << 2 | tile_color;
             return (
@@ -467,62 +996,94 @@ impl Ppu {
         (0, None)
     }
 
+    // Each tile takes 8 dots to fetch, two dots for each of the nametable
+    // byte, attribute byte and the two pattern bytes. The shifters move one
+    // pixel per dot and take on the fetched tile every 8th dot, so the tile
+    // fetched now is drawn starting 16 dots later
+    // With rendering off nothing is fetched or shifted and v holds still, so
+    // turning it off mid-frame freezes the scroll where it was
     fn step_bg_regs(&mut self) {
+        if !self.rendering_enabled() {
+            return;
+        }
+        match self.cc {
+            2...257 | 322...337 => self.internal_regs.shift(),
+            _ => (),
+        }
         match self.cc {
-            2...256 | 322...337 => match self.cc % 8 {
-                1 => {
-                    self.internal_regs.reload(self.at_entry);
+            9...257 | 329...337 if self.cc % 8 == 1 => {
+                self.internal_regs.reload(self.at_entry);
+            }
+            _ => (),
+        }
+        match self.cc {
+            1...256 | 321...336 => match self.cc % 8 {
+                2 => {
+                    let addr = self.regs.addr.nt_addr();
+                    self.nt_entry = self.fetch(addr);
                 }
-                //This is over simplified to make it faster. If there are
-                //games that write to vram during rendering and the emulator
-                //does not work, check this part out first.
-                0 => {
-                    let nt_entry = self.vram.ld8(self.regs.addr.nt_addr());
-                    self.at_entry = self.vram.ld8(self.regs.addr.at_addr());
-
+                4 => {
+                    let addr = self.regs.addr.at_addr();
+                    self.at_entry = self.fetch(addr);
                     if self.regs.addr.coarse_y() % 4 >= 2 {
                         self.at_entry >>= 4;
                     }
-
                     if self.regs.addr.coarse_x() % 4 >= 2 {
                         self.at_entry >>= 2;
                     }
-                    let pt_index = self.regs.ctrl.nt_pt_addr()
-                        + (nt_entry as u16 * 16)
-                        + self.regs.addr.fine_y() as u16;
-                    self.internal_regs.bg_latch.low_tile =
-                        self.vram.ld8(pt_index);
-                    self.internal_regs.bg_latch.high_tile =
-                        self.vram.ld8(pt_index + 8);
-                    if self.regs.mask.show_bg() {
-                        if self.cc == 256 {
-                            self.regs.addr.scroll_y();
-                        } else {
-                            self.regs.addr.scroll_x();
-                        }
-                    }
+                }
+                6 => {
+                    let addr = self.bg_pt_addr();
+                    self.internal_regs.bg_latch.low_tile = self.fetch(addr);
+                }
+                0 => {
+                    let addr = self.bg_pt_addr() + 8;
+                    self.internal_regs.bg_latch.high_tile = self.fetch(addr);
                 }
                 _ => (),
             },
-            257 => {
-                self.internal_regs.reload(self.at_entry);
-                if self.regs.mask.show_bg() {
-                    self.regs.addr.pull_x(self.t_addr);
-                }
+            // Two more nametable fetches that go unused, though mappers
+            // watching the bus can still see them
+            338 | 340 => {
+                let addr = self.regs.addr.nt_addr();
+                self.fetch(addr);
             }
-
-            280...304 => {
-                if self.is_prerender() && self.regs.mask.show_bg() {
-                    self.regs.addr.pull_y(self.t_addr);
-                }
+            _ => (),
+        }
+        match self.cc {
+            256 => {
+                self.regs.addr.scroll_x();
+                self.regs.addr.scroll_y();
+            }
+            8...255 | 328 | 336 if self.cc % 8 == 0 => {
+                self.regs.addr.scroll_x();
+            }
+            257 => self.regs.addr.pull_x(self.t_addr),
+            280...304 if self.is_prerender() => {
+                self.regs.addr.pull_y(self.t_addr);
             }
-
             _ => (),
         }
     }
 
+    // Takes all 512 colours, as from palette::PaletteChoice::load
+    pub fn set_colors(&mut self, colors: Vec<u32>) {
+        assert_eq!(colors.len(), EMPHASIZED_COLORS, "Palette needs 512 colours");
+        self.colors = colors;
+    }
+
+    fn bg_pt_addr(&self) -> u16 {
+        self.regs.ctrl.nt_pt_addr()
+            + (self.nt_entry as u16 * 16)
+            + self.regs.addr.fine_y() as u16
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.regs.mask.show_bg() || self.regs.mask.show_sprites()
+    }
+
     fn bg_pixel(&self, x: u8) -> u8 {
-        if (x <= 8 && !self.regs.mask.left8_bg()) || !self.regs.mask.show_bg() {
+        if (x < 8 && !self.regs.mask.left8_bg()) || !self.regs.mask.show_bg() {
             return 0;
         }
         let bg_off = 15 - self.fine_x;
@@ -543,55 +1104,162 @@ impl Ppu {
     }
 
     fn is_prerender(&self) -> bool {
-        self.scanline == PRERENDER
+        self.scanline == self.timing.prerender()
     }
 
-    //TODO: Split this logic into 2 functions to separate out the shift since
-    //it's not part of rendering pixels
+    // Pixels come out on dots 1-256, from the shifters as they are after
+    // this dot's shift
     fn render_pixel(&mut self) {
-        match self.cc {
-            2...257 | 322...337 => {
-                let x = self.cc - 2;
-                if x < 256 && !self.is_prerender() {
-                    let bg_color = self.bg_pixel(x as u8);
-                    let (spr_color, priority) =
-                        self.sprite_pixel(x as u8, bg_color != 0);
-
-                    let color = match (bg_color, spr_color) {
-                        (0, 0) => 0,
-                        (bg_c, 0) => bg_c,
-                        (0, spr_c) => spr_c,
-                        (bg_c, spr_c) => {
-                            match priority.expect("Cannot get none here") {
-                                Priority::Foreground => spr_c,
-                                Priority::Background => bg_c,
-                            }
-                        }
-                    };
-
-                    self.put_pixel(
-                        x as usize,
-                        self.scanline as usize,
-                        self.get_palette_color(color),
-                    );
-                }
-                self.internal_regs.shift();
-            }
-            _ => (),
+        if self.is_prerender() || self.cc < 1 || self.cc > 256 {
+            return;
+        }
+        let x = self.cc - 1;
+        if !self.rendering_enabled() {
+            let color = self.blank_color();
+            self.put_pixel(x as usize, self.scanline as usize, color);
+            return;
+        }
+        let bg_color = self.bg_pixel(x as u8);
+        let (spr_color, priority) = self.sprite_pixel(x as u8, bg_color != 0);
+
+        let color = match (bg_color, spr_color) {
+            (0, 0) => 0,
+            (bg_c, 0) => bg_c,
+            (0, spr_c) => spr_c,
+            (bg_c, spr_c) => match priority.expect("Cannot get none here") {
+                Priority::Foreground => spr_c,
+                Priority::Background => bg_c,
+            },
+        };
+
+        self.pixel = color;
+        self.put_pixel(x as usize, self.scanline as usize, color);
+    }
+
+    // Rendering is on and the PPU is on a line it fetches for
+    fn rendering_active(&self) -> bool {
+        self.rendering_enabled()
+            && (self.scanline < 240 || self.is_prerender())
+    }
+
+    // Without rendering the backdrop colour is shown, unless v points into
+    // palette RAM. Then the entry it points at is shown instead, which some
+    // games use to draw with the palette during forced blanking. With
+    // rendering on the backdrop is always entry 0, wherever v points
+    fn blank_color(&self) -> u8 {
+        let addr = self.regs.addr.addr();
+        if addr >= 0x3F00 {
+            (addr & 0x1F) as u8
+        } else {
+            0
         }
     }
 
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.cc
+    }
+
+    // The last complete frame, which stays the same until the next one is
+    // finished at the start of line 240. Its number is the frames count the
+    // mmu keeps
     pub fn get_buffer(&self) -> &[u8] {
         &self.screen_buff
     }
 
+    // Pixels of the current frame that are in back_buff so far
+    pub fn pixels_drawn(&self) -> usize {
+        match self.scanline {
+            0...239 => {
+                let on_line = (self.cc as usize).saturating_sub(1);
+                self.scanline as usize * SCREEN_WIDTH
+                    + on_line.min(SCREEN_WIDTH)
+            }
+            // Finished, but not swapped in yet
+            240 if self.cc == 0 => SCREEN_WIDTH * SCREEN_HEIGHT,
+            _ => 0,
+        }
+    }
+
+    // The screen as a TV would show it right now: the current frame as far
+    // as it's been drawn, and the last frame below that. Laid out the same
+    // as get_buffer, for looking at raster effects mid-frame
+    pub fn capture(&self) -> Vec<u8> {
+        let split = self.pixels_drawn() * self.pixel_format.bytes_per_pixel();
+        let mut screen = self.back_buff[..split].to_vec();
+        screen.extend_from_slice(&self.screen_buff[split..]);
+        screen
+    }
+
+    // Cheap to compare against a hash recorded earlier, for regression tests
+    // that don't want to keep whole frames around
+    pub fn frame_hash(&self) -> u64 {
+        self.frame_hash
+    }
+
+    // The last complete frame as RGB24, whatever format it's kept in
+    pub fn frame_rgb(&self) -> Vec<u8> {
+        let size = self.pixel_format.bytes_per_pixel();
+        let mut rgb = Vec::with_capacity(SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        for pixel in self.screen_buff.chunks(size) {
+            match self.pixel_format {
+                PixelFormat::Rgb24 | PixelFormat::Rgba8888 => {
+                    rgb.extend_from_slice(&pixel[..3])
+                }
+                PixelFormat::Bgra8888 => {
+                    rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]])
+                }
+                PixelFormat::Indexed => {
+                    let index = pixel[0] as usize | (pixel[1] as usize) << 8;
+                    let color = self.colors[index];
+                    rgb.push((color >> 16) as u8);
+                    rgb.push((color >> 8) as u8);
+                    rgb.push(color as u8);
+                }
+            }
+        }
+        rgb
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    // Pixels already drawn are lost, both buffers start over blank in the
+    // new format
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.pixel_format = format;
+        self.screen_buff = screen_buffer(format);
+        self.back_buff = screen_buffer(format);
+    }
+
+    // RGB for each PixelFormat::Indexed entry, as 0xRRGGBB
+    pub fn colors(&self) -> &[u32] {
+        &self.colors
+    }
+
     fn step_cc(&mut self) {
         self.cc += 1;
+        self.dots += 1;
+        // With rendering on, odd frames drop the last dot of the prerender
+        // line, making them one dot shorter
+        if self.cc == 340
+            && self.is_prerender()
+            && self.odd_frame
+            && self.timing.skips_odd_dot()
+            && self.rendering_enabled()
+        {
+            self.cc = 341;
+        }
         if self.cc >= 341 {
             self.cc %= 341;
             self.scanline += 1;
-            if self.scanline > PRERENDER {
+            if self.scanline > self.timing.prerender() {
                 self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
             }
         }
     }
@@ -600,18 +1268,22 @@ impl Ppu {
         let mut res = match self.scanline {
             0...239 => {
                 self.step_sprites();
-                self.render_pixel();
                 self.step_bg_regs();
+                self.render_pixel();
                 None
             }
             240 => {
                 if self.cc == 0 {
+                    self.decay_open_bus();
+                    mem::swap(&mut self.screen_buff, &mut self.back_buff);
+                    self.frame_hash = self.back_hash;
+                    self.back_hash = FNV_OFFSET;
                     Some(PpuRes::Draw)
                 } else {
                     None
                 }
             }
-            241 => {
+            line if line == self.timing.vblank_line() => {
                 if self.cc == 1 && !self.vblank_off {
                     self.regs.status.set_vblank(true);
                     if self.regs.ctrl.nmi_on() {
@@ -623,16 +1295,16 @@ impl Ppu {
                     None
                 }
             }
-            242...260 => None,
-            PRERENDER => {
+            line if line == self.timing.prerender() => {
                 if self.cc == 1 {
                     self.regs.status.set_vblank(false);
+                    self.warming_up = false;
                 }
                 self.step_sprites();
-                self.render_pixel();
                 self.step_bg_regs();
                 None
             }
+            line if line < self.timing.prerender() => None,
             _ => panic!(
                 "Scanline can't get here {}. Check emulate_cycles",
                 self.scanline
@@ -656,13 +1328,36 @@ impl Ppu {
         res
     }
 
+    // Runs the PPU on its own for a few dots, shifting where the CPU's
+    // cycles fall relative to the PPU's from then on
+    pub fn offset_dots(&mut self, dots: u8) {
+        for _ in 0..dots {
+            self.step();
+        }
+    }
+
     pub fn emulate_cycles(&mut self, cyc_elapsed: u16) -> Option<PpuRes> {
         let mut ppu_res = None;
-        for _ in 0..(cyc_elapsed * 3) {
-            if let Some(res) = self.step() {
-                ppu_res = Some(res);
+        for _ in 0..cyc_elapsed {
+            for _ in 0..self.cycle_dots() {
+                if let Some(res) = self.step() {
+                    ppu_res = Some(res);
+                }
             }
         }
         ppu_res
     }
+
+    // 3 dots a CPU cycle, with PAL's extra dot every 5th cycle
+    fn cycle_dots(&mut self) -> u8 {
+        if self.timing != Timing::Pal {
+            return 3;
+        }
+        self.pal_cycle = (self.pal_cycle + 1) % 5;
+        if self.pal_cycle == 0 {
+            4
+        } else {
+            3
+        }
+    }
 }
@@ -0,0 +1,108 @@
+use serde::Serialize;
+use serde::Deserialize;
+use std::fs;
+use failure::Error;
+
+// Colours the PPU can output, and then every one of those under each of the
+// 8 combinations of emphasis bits
+pub const COLORS: usize = 64;
+pub const EMPHASIZED_COLORS: usize = 8 * COLORS;
+
+// Each emphasis bit darkens the two colour channels it doesn't name. This
+// factor is roughly what the NTSC PPU's attenuation comes out to in RGB
+const EMPHASIS_ATTENUATION: f32 = 0.75;
+
+#[derive(Debug, Fail)]
+pub enum PaletteError {
+    #[fail(display = "Palette files hold 64 or 512 colours, got {} bytes", _0)]
+    BadSize(usize),
+}
+
+// Which colours the PPU's output gets turned into
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PaletteChoice {
+    // What the emulator has always used, brighter than a real console
+    Classic,
+    // Closer to what a real NTSC console puts out
+    Ntsc,
+    // A .pal file
+    File(String),
+}
+
+impl PaletteChoice {
+    // All 512 colours, for Ppu::set_colors
+    pub fn load(&self) -> Result<Vec<u32>, Error> {
+        match *self {
+            PaletteChoice::Classic => Ok(emphasize(&CLASSIC)),
+            PaletteChoice::Ntsc => Ok(emphasize(&NTSC)),
+            PaletteChoice::File(ref path) => from_pal(&fs::read(path)?),
+        }
+    }
+}
+
+// .pal files are raw RGB triples. 64 colour files get emphasis applied like
+// the built in palettes, 512 colour ones already have each combination of
+// emphasis bits in turn
+pub fn from_pal(bytes: &[u8]) -> Result<Vec<u32>, Error> {
+    if bytes.len() != COLORS * 3 && bytes.len() != EMPHASIZED_COLORS * 3 {
+        Err(PaletteError::BadSize(bytes.len()))?
+    }
+    let colors: Vec<u32> = bytes
+        .chunks(3)
+        .map(|rgb| (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32)
+        .collect();
+    if colors.len() == COLORS {
+        Ok(emphasize(&colors))
+    } else {
+        Ok(colors)
+    }
+}
+
+// The 64 colours with every combination of the emphasis bits applied,
+// indexed by the bits (red, green, blue from low to high) times 64 plus the
+// colour
+pub fn emphasize(palette: &[u32]) -> Vec<u32> {
+    let mut colors = Vec::with_capacity(EMPHASIZED_COLORS);
+    for emphasis in 0..8u32 {
+        for &color in palette.iter() {
+            let mut rgb = 0;
+            for channel in 0..3 {
+                // Channels from red down to blue, matching the emphasis bits
+                let shift = 16 - channel * 8;
+                let val = ((color >> shift) & 0xFF) as f32;
+                let others = emphasis & !(1 << channel);
+                let dimmed =
+                    val * EMPHASIS_ATTENUATION.powi(others.count_ones() as i32);
+                rgb |= (dimmed as u32) << shift;
+            }
+            colors.push(rgb);
+        }
+    }
+    colors
+}
+
+pub const CLASSIC: [u32; COLORS] = [
+    0x808080, 0x003DA6, 0x0012B0, 0x440096, 0xA1005E, 0xC70028, 0xBA0600,
+    0x8C1700, 0x5C2F00, 0x104500, 0x054A00, 0x00472E, 0x004166, 0x000000,
+    0x050505, 0x050505, 0xC7C7C7, 0x0077FF, 0x2155FF, 0x8237FA, 0xEB2FB5,
+    0xFF2950, 0xFF2200, 0xD63200, 0xC46200, 0x358000, 0x058F00, 0x008A55,
+    0x0099CC, 0x212121, 0x090909, 0x090909, 0xFFFFFF, 0x0FD7FF, 0x69A2FF,
+    0xD480FF, 0xFF45F3, 0xFF618B, 0xFF8833, 0xFF9C12, 0xFABC20, 0x9FE30E,
+    0x2BF035, 0x0CF0A4, 0x05FBFF, 0x5E5E5E, 0x0D0D0D, 0x0D0D0D, 0xFFFFFF,
+    0xA6FCFF, 0xB3ECFF, 0xDAABEB, 0xFFA8F9, 0xFFABB3, 0xFFD2B0, 0xFFEFA6,
+    0xFFF79C, 0xD7E895, 0xA6EDAF, 0xA2F2DA, 0x99FFFC, 0xDDDDDD, 0x111111,
+    0x111111,
+];
+
+pub const NTSC: [u32; COLORS] = [
+    0x666666, 0x002A88, 0x1412A7, 0x3B00A4, 0x5C007E, 0x6E0040, 0x6C0600,
+    0x561D00, 0x333500, 0x0B4800, 0x005200, 0x004F08, 0x00404D, 0x000000,
+    0x000000, 0x000000, 0xADADAD, 0x155FD9, 0x4240FF, 0x7527FE, 0xA01ACC,
+    0xB71E7B, 0xB53120, 0x994E00, 0x6B6D00, 0x388700, 0x0C9300, 0x008F32,
+    0x007C8D, 0x000000, 0x000000, 0x000000, 0xFFFEFF, 0x64B0FF, 0x9290FF,
+    0xC676FF, 0xF36AFF, 0xFE6ECC, 0xFE8170, 0xEA9E22, 0xBCBE00, 0x88D800,
+    0x5CE430, 0x45E082, 0x48CDDE, 0x4F4F4F, 0x000000, 0x000000, 0xFFFEFF,
+    0xC0DFFF, 0xD3D2FF, 0xE8C8FF, 0xFBC2FF, 0xFEC4EA, 0xFECCC5, 0xF7D8A5,
+    0xE4E594, 0xCFEF96, 0xBDF4AB, 0xB3F3CC, 0xB5EBF2, 0xB8B8B8, 0x000000,
+    0x000000,
+];
@@ -82,8 +82,9 @@ impl VramAddr {
         self.set_last_bit(false);
     }
 
+    // v is only 15 bits wide
     pub fn add_offset(&mut self, offset: u8) {
-        self.0 = self.0.wrapping_add(offset as u16);
+        self.0 = self.0.wrapping_add(offset as u16) & 0x7FFF;
     }
 
     //Strip the fine y out, and add the base name table value of 0x2000
@@ -182,6 +183,7 @@ bitfield! {
     pub emphasize_r,   _ : 5;
     pub emphasize_g,   _ : 6;
     pub emphasize_b,   _ : 7;
+    pub emphasis,      _ : 7, 5;
 }
 
 impl Mask {
@@ -1,21 +1,22 @@
 use super::pregisters::Ctrl;
+use super::tile_cache::TileRow;
 
-const SPRITE_ATTR: usize = 4;
-
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Sprite {
     pub x: u8,
     pub y: u8,
     pub pt_index: u8,
     pub attributes: SpriteAttr,
-    pub index: u8,
-    pub low_byte: u8,
-    pub high_byte: u8,
+    // Sprite 0 is the one that sets the sprite 0 hit flag
+    pub sprite0: bool,
+    // The row of the pattern on this line, already mirrored for flip_x
+    pub pixels: TileRow,
 }
 
 bitfield! {
     #[derive(Copy, Clone)]
     pub struct SpriteAttr(u8);
+    impl Debug;
     pub palette,  _: 1, 0;
     pub priority, _:    5;
     pub flip_x,   _:    6;
@@ -23,24 +24,22 @@ bitfield! {
 }
 
 impl Sprite {
-    pub fn new(index: usize, oam: &[u8; 256]) -> Sprite {
+    // Takes the 4 OAM bytes of a sprite, from primary or secondary OAM
+    pub fn from_bytes(sprite0: bool, bytes: &[u8]) -> Sprite {
         Sprite {
-            index: index as u8,
-            x: oam[(index * SPRITE_ATTR) + 3],
-            y: oam[(index * SPRITE_ATTR)],
-            pt_index: oam[(index * SPRITE_ATTR) + 1],
-            attributes: SpriteAttr(oam[(index * SPRITE_ATTR) + 2]),
-            low_byte: 0,
-            high_byte: 0,
+            sprite0: sprite0,
+            y: bytes[0],
+            pt_index: bytes[1],
+            attributes: SpriteAttr(bytes[2]),
+            x: bytes[3],
+            pixels: [0; 8],
         }
     }
 
-    pub fn in_bounding_box(&self, x: u8, y: u8) -> bool {
-        !(self.x > x || self.x + 8 <= x
-        // These last 2 cases are for accounting for overscan
-        || y <= 8
-        // This is screenheight - 8
-        || y >= 240 - 8)
+    // Only sprites in range of the current scanline get this far, so just x
+    // needs checking
+    pub fn covers(&self, x: u8) -> bool {
+        x >= self.x && x - self.x < 8
     }
 
     pub fn get_pt_address(&self, ctrl: &Ctrl, y: u16) -> u16 {
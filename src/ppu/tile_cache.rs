@@ -0,0 +1,59 @@
+use ppu::vram::Vram;
+
+// 512 tiles of 16 bytes fill the $0000-$1FFF pattern tables
+const TILES: usize = 0x2000 / 16;
+
+// One row of a tile as 2 bit colour indices, leftmost pixel first
+pub type TileRow = [u8; 8];
+
+// CHR tiles decoded into colour indices the first time they're drawn, so
+// every line a tile is on doesn't read and unpack its bit planes again.
+// Tiles are cached by their PPU address, so anything that changes what's
+// mapped there, a CHR RAM write or a bank switch, has to clear them
+pub struct TileCache {
+    tiles: Box<[[TileRow; 8]]>,
+    valid: Box<[bool]>,
+}
+
+impl TileCache {
+    pub fn new() -> TileCache {
+        TileCache {
+            tiles: vec![[[0; 8]; 8]; TILES].into_boxed_slice(),
+            valid: vec![false; TILES].into_boxed_slice(),
+        }
+    }
+
+    // The row at addr, which is a tile's address plus the row within it
+    pub fn row(&mut self, vram: &Vram, addr: u16) -> TileRow {
+        let tile = (addr as usize >> 4) % TILES;
+        if !self.valid[tile] {
+            let base = addr & !0xF;
+            for (y, row) in self.tiles[tile].iter_mut().enumerate() {
+                let low = vram.ld8(base + y as u16);
+                let high = vram.ld8(base + y as u16 + 8);
+                *row = decode_row(low, high);
+            }
+            self.valid[tile] = true;
+        }
+        self.tiles[tile][addr as usize & 7]
+    }
+
+    // A write to one byte of CHR RAM only changes the tile it's in
+    pub fn invalidate(&mut self, addr: u16) {
+        self.valid[(addr as usize >> 4) % TILES] = false;
+    }
+
+    pub fn clear(&mut self) {
+        for valid in self.valid.iter_mut() {
+            *valid = false;
+        }
+    }
+}
+
+pub fn decode_row(low: u8, high: u8) -> TileRow {
+    let mut row = [0; 8];
+    for (px, color) in row.iter_mut().enumerate() {
+        *color = ((high >> (7 - px)) & 1) << 1 | ((low >> (7 - px)) & 1);
+    }
+    row
+}
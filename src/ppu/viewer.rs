@@ -0,0 +1,176 @@
+use ppu::Ppu;
+use ppu::sprite::Sprite;
+use ppu::tile_cache::decode_row;
+
+// The four nametables laid out 2x2, the way they sit in the PPU's address
+// space
+pub const NT_VIEW_WIDTH: usize = 512;
+pub const NT_VIEW_HEIGHT: usize = 480;
+// Both pattern tables side by side, each 16x16 tiles
+pub const PT_VIEW_WIDTH: usize = 256;
+pub const PT_VIEW_HEIGHT: usize = 128;
+
+impl Ppu {
+    // Draws all four nametables through the current mirroring, background
+    // pattern table and palettes into an RGB24 buffer. The screen the next
+    // frame starts scrolled to is outlined, wrapping around the edges like
+    // the scroll itself does
+    pub fn render_nametables(&self) -> Vec<u8> {
+        let mut buff = vec![0; NT_VIEW_WIDTH * NT_VIEW_HEIGHT * 3];
+        let pt_base = self.regs.ctrl.nt_pt_addr();
+        for nt in 0..4 {
+            let nt_base = 0x2000 + nt * 0x400;
+            let left = (nt as usize & 1) * 256;
+            let top = (nt as usize >> 1) * 240;
+            for tile_y in 0..30 {
+                for tile_x in 0..32 {
+                    let tile = self.vram.ld8(nt_base + tile_y * 32 + tile_x);
+                    let attr = self
+                        .vram
+                        .ld8(nt_base + 0x3C0 + (tile_y / 4) * 8 + tile_x / 4);
+                    // Each attribute byte covers 4x4 tiles, 2 bits per 2x2
+                    let shift = (tile_y % 4 / 2) * 4 + (tile_x % 4 / 2) * 2;
+                    let palette = (attr >> shift) & 3;
+                    for row in 0..8 {
+                        let addr = pt_base + tile as u16 * 16 + row;
+                        self.draw_tile_row(
+                            &mut buff,
+                            NT_VIEW_WIDTH,
+                            left + tile_x as usize * 8,
+                            top + (tile_y * 8 + row) as usize,
+                            self.tile_row(addr),
+                            palette,
+                        );
+                    }
+                }
+            }
+        }
+        self.outline_scroll(&mut buff);
+        buff
+    }
+
+    // Draws both pattern tables into an RGB24 buffer, $0000 on the left and
+    // $1000 on the right, using one of the 8 palettes (0-3 background, 4-7
+    // sprites). Tiles are read as they are right now, so CHR RAM and bank
+    // switches show up on the next call
+    pub fn render_pattern_tables(&self, palette: u8) -> Vec<u8> {
+        let mut buff = vec![0; PT_VIEW_WIDTH * PT_VIEW_HEIGHT * 3];
+        for table in 0..2 {
+            for tile in 0..256 {
+                let x = table * 128 + (tile % 16) * 8;
+                let y = (tile / 16) * 8;
+                let addr = (table * 0x1000 + tile * 16) as u16;
+                for row in 0..8 {
+                    self.draw_tile_row(
+                        &mut buff,
+                        PT_VIEW_WIDTH,
+                        x,
+                        y + row,
+                        self.tile_row(addr + row as u16),
+                        palette & 7,
+                    );
+                }
+            }
+        }
+        buff
+    }
+
+    // The 32 palette RAM entries as the screen would show them right now,
+    // with grayscale and emphasis applied. $3F10, $3F14, $3F18 and $3F1C
+    // mirror the background entries below them
+    pub fn palette_colors(&self) -> [[u8; 3]; 32] {
+        let mut colors = [[0; 3]; 32];
+        for (i, color) in colors.iter_mut().enumerate() {
+            *color = self.get_palette_color(i as u8).data;
+        }
+        colors
+    }
+
+    // OAM decoded, in OAM order
+    pub fn oam_sprites(&self) -> Vec<Sprite> {
+        self.oam
+            .chunks(4)
+            .enumerate()
+            .map(|(i, bytes)| Sprite::from_bytes(i == 0, bytes))
+            .collect()
+    }
+
+    // One sprite on its own as an RGB24 buffer, 8 pixels wide and as tall as
+    // the current sprite size, flipped the way it would be on screen.
+    // Transparent pixels show the backdrop colour
+    pub fn render_sprite(&self, index: usize) -> Vec<u8> {
+        let sprite = Sprite::from_bytes(index == 0, &self.oam[index * 4..]);
+        let height = self.regs.ctrl.sprite_size();
+        let palette = sprite.attributes.palette() + 4;
+        let mut buff = vec![0; 8 * height as usize * 3];
+        for row in 0..height {
+            let addr =
+                sprite.get_pt_address(&self.regs.ctrl, sprite.y as u16 + row);
+            let (mut low, mut high) = self.tile_row(addr);
+            if sprite.attributes.flip_x() {
+                low = low.reverse_bits();
+                high = high.reverse_bits();
+            }
+            self.draw_tile_row(
+                &mut buff,
+                8,
+                0,
+                row as usize,
+                (low, high),
+                palette,
+            );
+        }
+        buff
+    }
+
+    // The two bit planes of one row of a tile
+    fn tile_row(&self, addr: u16) -> (u8, u8) {
+        (self.vram.ld8(addr), self.vram.ld8(addr + 8))
+    }
+
+    fn draw_tile_row(
+        &self,
+        buff: &mut [u8],
+        width: usize,
+        x: usize,
+        y: usize,
+        (low, high): (u8, u8),
+        palette: u8,
+    ) {
+        for (px, &c) in decode_row(low, high).iter().enumerate() {
+            let color = if c == 0 { 0 } else { palette << 2 | c };
+            let rgb = self.get_palette_color(color);
+            buff[(y * width + x + px) * 3..][..3].copy_from_slice(&rgb.data);
+        }
+    }
+
+    // Where the frame starts comes from t, which gets copied to v during
+    // the prerender line
+    fn outline_scroll(&self, buff: &mut [u8]) {
+        let t = self.t_addr;
+        let scroll_x = (t.nt() as usize & 1) * 256
+            + t.coarse_x() as usize * 8
+            + self.fine_x as usize;
+        let scroll_y = (t.nt() as usize >> 1) * 240
+            + t.coarse_y() as usize * 8
+            + t.fine_y() as usize;
+        for i in 0..256 {
+            invert(buff, scroll_x + i, scroll_y);
+            invert(buff, scroll_x + i, scroll_y + 239);
+        }
+        for i in 1..239 {
+            invert(buff, scroll_x, scroll_y + i);
+            invert(buff, scroll_x + 255, scroll_y + i);
+        }
+    }
+}
+
+// Inverting rather than drawing in a fixed colour keeps the outline visible
+// over anything
+fn invert(buff: &mut [u8], x: usize, y: usize) {
+    let x = x % NT_VIEW_WIDTH;
+    let y = y % NT_VIEW_HEIGHT;
+    for byte in buff[(y * NT_VIEW_WIDTH + x) * 3..][..3].iter_mut() {
+        *byte = !*byte;
+    }
+}
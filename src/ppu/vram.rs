@@ -1,32 +1,23 @@
-use mapper::Mapper;
+use cartridge::Cartridge;
+use mapper::Nametable;
 use std::cell::RefCell;
 use std::rc::Rc;
-use rom::ScreenMode;
-use rom::ScreenBank;
 
-const VRAM_SIZE: usize = 0x800;
-
-const NT_0: u16 = 0x000;
-const NT_0_END: u16 = 0x3FF;
-const NT_1: u16 = 0x400;
-const NT_1_END: u16 = 0x7FF;
-const NT_2: u16 = 0x800;
-const NT_2_END: u16 = 0xBFF;
-const NT_3: u16 = 0xC00;
-const NT_3_END: u16 = 0xFFF;
+// Enough for four screen boards, everything else only uses the first 2KB
+const VRAM_SIZE: usize = 0x1000;
 
 pub struct Vram {
     pub vram: Box<[u8]>,
-    mapper: Rc<RefCell<Mapper>>,
+    cartridge: Rc<RefCell<Cartridge>>,
     pub palette: [u8; 0x20],
     ppudata_buff: u8,
 }
 
 impl Vram {
-    pub fn new(mapper: Rc<RefCell<Mapper>>) -> Vram {
+    pub fn new(cartridge: Rc<RefCell<Cartridge>>) -> Vram {
         Vram {
             vram: Box::new([0; VRAM_SIZE]),
-            mapper: mapper,
+            cartridge: cartridge,
             palette: [0; 0x20],
             ppudata_buff: 0,
         }
@@ -43,53 +34,56 @@ impl Vram {
             self.ppudata_buff = self.ld8(addr);
             val
         } else {
-            self.ppudata_buff = self.vram[self.nt_mirror(addr & 0xFFF)];
+            self.ppudata_buff = self.ld_nametable(addr);
             self.ld8(addr)
         }
     }
 
     pub fn ld8(&self, addr: u16) -> u8 {
         match addr {
-            0x0000...0x1FFF => self.mapper.borrow_mut().ld_chr(addr),
-            0x2000...0x3EFF => self.vram[self.nt_mirror(addr & 0xFFF)],
+            0x0000...0x1FFF => self.cartridge.borrow_mut().ld_chr(addr),
+            0x2000...0x3EFF => self.ld_nametable(addr),
             0x3F00...0x3FFF => self.palette[self.palette_mirror(addr)],
             _ => panic!(),
         }
     }
 
+    // Passes a filtered rise of PPU address line A12 on to the cartridge
+    pub fn a12_rise(&self) {
+        self.cartridge.borrow_mut().a12_rise();
+    }
+
+    // Tells the cartridge a pattern table address was read, returns whether
+    // that switched CHR banks
+    pub fn pattern_fetch(&self, addr: u16) -> bool {
+        self.cartridge.borrow_mut().pattern_fetch(addr)
+    }
+
     pub fn store(&mut self, addr: u16, val: u8) {
         match addr {
-            0x0000...0x1FFF => self.mapper.borrow_mut().store_chr(addr, val),
-            0x2000...0x3EFF => self.vram[self.nt_mirror(addr & 0xFFF)] = val,
+            0x0000...0x1FFF => self.cartridge.borrow_mut().store_chr(addr, val),
+            0x2000...0x3EFF => self.store_nametable(addr, val),
             0x3F00...0x3FFF => self.palette[self.palette_mirror(addr)] = val,
             _ => panic!(),
         }
     }
 
-    // Helper function that resolves the nametable mirroring and returns an
-    // index usable for VRAM array indexing
-    fn nt_mirror(&self, addr: u16) -> usize {
-        match self.mapper.borrow().get_mirroring() {
-            ScreenMode::Horizontal => match addr {
-                NT_0...NT_0_END => addr as usize,
-                NT_1...NT_2_END => (addr - 0x400) as usize,
-                NT_3...NT_3_END => (addr - 0x800) as usize,
-                _ => panic!("Horizontal: addr outside of nt passed"),
-            },
-            ScreenMode::Vertical => match addr {
-                NT_0...NT_1_END => addr as usize,
-                NT_2...NT_3_END => (addr - 0x800) as usize,
-                _ => panic!("Vertical: addr outside of nt passed"),
-            },
-            ScreenMode::OneScreenSwap(bank) => {
-                let addr = addr & 0x3FF;
-                match bank {
-                    ScreenBank::Lower => addr as usize,
-                    ScreenBank::Upper => addr as usize + 0x400,
-                }
-            }
-            ScreenMode::FourScreen => {
-                unimplemented!("Four Screen mode not supported yet")
+    // The mapper is asked on every access, so a mapper switching mirroring
+    // mid-frame affects the very next fetch
+    fn ld_nametable(&self, addr: u16) -> u8 {
+        let cartridge = self.cartridge.borrow();
+        match cartridge.nametable(addr) {
+            Nametable::Vram(page) => self.vram[vram_index(page, addr)],
+            Nametable::Chr(bank) => cartridge.ld_chr_nametable(bank, addr),
+        }
+    }
+
+    fn store_nametable(&mut self, addr: u16, val: u8) {
+        let nametable = self.cartridge.borrow().nametable(addr);
+        match nametable {
+            Nametable::Vram(page) => self.vram[vram_index(page, addr)] = val,
+            Nametable::Chr(_) => {
+                info!("Attempt to store to chr rom nametable {:X}", addr)
             }
         }
     }
@@ -102,3 +96,7 @@ impl Vram {
         }
     }
 }
+
+fn vram_index(page: u16, addr: u16) -> usize {
+    (page * 0x400 + (addr & 0x3FF)) as usize
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use trace::OPS;
+
+// Banks are reported in 16KB units regardless of the mapper's own bank size,
+// so reports from different boards line up
+pub const BANK_SIZE: usize = 0x4000;
+
+// Counts what the CPU spends its time on. Attach one to Cpu::profiler to
+// start recording, take it back off to stop
+pub struct Profiler {
+    opcodes: Box<[u64; 256]>,
+    opcode_cycles: Box<[u64; 256]>,
+    pcs: Box<[u64]>,
+    banks: BTreeMap<usize, u64>,
+    // Cycles spent running code from RAM or anywhere else outside PRG ROM
+    outside_rom: u64,
+    instructions: u64,
+    cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            opcodes: Box::new([0; 256]),
+            opcode_cycles: Box::new([0; 256]),
+            pcs: vec![0; 0x10000].into_boxed_slice(),
+            banks: BTreeMap::new(),
+            outside_rom: 0,
+            instructions: 0,
+            cycles: 0,
+        }
+    }
+
+    // prg_offset is where pc mapped to in PRG ROM when the opcode was fetched
+    pub fn record(
+        &mut self,
+        op: u8,
+        pc: u16,
+        prg_offset: Option<usize>,
+        cycles: u16,
+    ) {
+        let cycles = cycles as u64;
+        self.opcodes[op as usize] += 1;
+        self.opcode_cycles[op as usize] += cycles;
+        self.pcs[pc as usize] += 1;
+        match prg_offset {
+            Some(offset) => {
+                *self.banks.entry(offset / BANK_SIZE).or_insert(0) += cycles
+            }
+            None => self.outside_rom += cycles,
+        }
+        self.instructions += 1;
+        self.cycles += cycles;
+    }
+
+    pub fn opcode_count(&self, op: u8) -> u64 {
+        self.opcodes[op as usize]
+    }
+
+    pub fn pc_hits(&self, pc: u16) -> u64 {
+        self.pcs[pc as usize]
+    }
+
+    // Most executed addresses first
+    pub fn hotspots(&self, count: usize) -> Vec<(u16, u64)> {
+        let mut hits = self
+            .pcs
+            .iter()
+            .enumerate()
+            .filter(|&(_, &hits)| hits != 0)
+            .map(|(pc, &hits)| (pc as u16, hits))
+            .collect::<Vec<(u16, u64)>>();
+        hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hits.truncate(count);
+        hits
+    }
+
+    pub fn bank_cycles(&self) -> &BTreeMap<usize, u64> {
+        &self.banks
+    }
+
+    pub fn outside_rom_cycles(&self) -> u64 {
+        self.outside_rom
+    }
+
+    pub fn report(&self, hotspots: usize) -> String {
+        let percent = |cycles: u64| {
+            if self.cycles == 0 {
+                0.0
+            } else {
+                cycles as f64 * 100.0 / self.cycles as f64
+            }
+        };
+        let mut out = String::new();
+        // Writing to a String can't fail
+        let _ = writeln!(
+            out,
+            "{} instructions, {} cycles",
+            self.instructions, self.cycles
+        );
+
+        let _ = writeln!(out, "\nOpcodes:");
+        let mut ops = (0..256)
+            .filter(|&op| self.opcodes[op] != 0)
+            .collect::<Vec<usize>>();
+        ops.sort_by(|&a, &b| self.opcode_cycles[b].cmp(&self.opcode_cycles[a]));
+        for op in ops {
+            let _ = writeln!(
+                out,
+                "  {:02X} {}  {:>12} runs {:>14} cycles {:>6.2}%",
+                op,
+                OPS[op].0,
+                self.opcodes[op],
+                self.opcode_cycles[op],
+                percent(self.opcode_cycles[op])
+            );
+        }
+
+        let _ = writeln!(out, "\nHotspots:");
+        for (pc, hits) in self.hotspots(hotspots) {
+            let _ = writeln!(out, "  {:04X} {:>12}", pc, hits);
+        }
+
+        let _ = writeln!(out, "\nPRG banks (16KB):");
+        for (bank, &cycles) in &self.banks {
+            let _ = writeln!(
+                out,
+                "  {:>3} {:>14} cycles {:>6.2}%",
+                bank,
+                cycles,
+                percent(cycles)
+            );
+        }
+        if self.outside_rom != 0 {
+            let _ = writeln!(
+                out,
+                "  RAM {:>14} cycles {:>6.2}%",
+                self.outside_rom,
+                percent(self.outside_rom)
+            );
+        }
+        out
+    }
+}
@@ -1,15 +1,19 @@
 use serde::Serialize;
 use serde::Deserialize;
-use nom::be_u8;
 use nom::IResult;
 use std::fmt;
 use failure::Error;
+use mapper::describe_board;
+use mapper::supported_boards;
 
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const PRG_RAM_PAGE_SIZE: usize = 8192;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 const CHR_RAM_PAGE_SIZE: usize = 8192;
 const TRAINER_LEN: usize = 512;
+// Where $7000 is in PRG RAM
+const TRAINER_START: usize = 0x1000;
+const HEADER_LEN: usize = 16;
 
 #[derive(Debug, Fail)]
 pub enum LoadRomError {
@@ -22,62 +26,48 @@ pub enum LoadRomError {
 fn parse_rom(src: &[u8]) -> IResult<&[u8], Rom> {
     do_parse!(
         src,
-        tag!(b"NES\x1A")
-            >> prg_pgs: be_u8
-            >> chr_pgs: be_u8
-            >> flag6: be_u8
-            >> flag7: be_u8
-            >> prg_ram_pgs: be_u8
-            >> flag9: be_u8
-            >> flag10: be_u8
-            >> take!(5)
-            >> cond!((flag6 & 0b100) == 1, take!(TRAINER_LEN))
-            >> prg_rom: take!(prg_pgs as usize * PRG_ROM_PAGE_SIZE)
-            >> chr_rom: take!(chr_pgs as usize * CHR_ROM_PAGE_SIZE)
-            >> (Rom {
-                header: Header {
-                    mapper: flag7 & 0xF0 | ((flag6 & 0xF0) >> 4),
-                    screen: if flag6 & 0b1000 == 1 {
-                        ScreenMode::FourScreen
-                    } else {
-                        if flag6 & 0b01 == 1 {
-                            ScreenMode::Vertical
-                        } else {
-                            ScreenMode::Horizontal
-                        }
-                    },
-                    save_ram: flag6 & 0b10 == 1,
-                    vs_unisystem: flag7 & 0b01 == 1,
-                    playchoice10: flag7 & 0b10 == 1,
-                    region: if flag9 & 0b01 == 1 {
-                        Region::PAL
-                    } else {
-                        Region::NTSC
-                    },
-                    flag10: flag10,
-                    rom_type: if flag7 & 0b1100 == 0b1000 {
-                        RomType::Nes2
-                    } else {
-                        RomType::INes
-                    },
-                },
-                prg_rom: prg_rom.into(),
-                chr_rom: chr_rom.into(),
-                prg_ram_size: if prg_ram_pgs != 0 {
-                    PRG_RAM_PAGE_SIZE * prg_ram_pgs as usize
-                } else {
-                    PRG_RAM_PAGE_SIZE as usize
-                },
-                prg_ram: Vec::new(),
-                chr_ram: if chr_pgs == 0 {
-                    vec![0; CHR_RAM_PAGE_SIZE]
-                } else {
-                    Vec::new()
-                },
-            })
+        header: peek!(take!(HEADER_LEN))
+            >> tag!(b"NES\x1A")
+            >> take!(HEADER_LEN - 4)
+            >> trainer: cond!(header[6] & 0b100 != 0, take!(TRAINER_LEN))
+            >> prg_rom: take!(rom_size(header, 4, PRG_ROM_PAGE_SIZE))
+            >> chr_rom: take!(rom_size(header, 5, CHR_ROM_PAGE_SIZE))
+            >> (Rom::from_parts(header, trainer, prg_rom, chr_rom))
     )
 }
 
+// Bits 2-3 of byte 7 are 10 in NES 2.0 headers
+fn is_nes2(header: &[u8]) -> bool {
+    header[7] & 0b1100 == 0b1000
+}
+
+// The page count at byte 4 (PRG) or 5 (CHR). NES 2.0 adds a high nibble
+// from byte 9, or with $F there the count is instead an exponent and a
+// multiplier for sizes that aren't whole pages
+fn rom_size(header: &[u8], byte: usize, page_size: usize) -> usize {
+    let count = header[byte];
+    if !is_nes2(header) {
+        return count as usize * page_size;
+    }
+    match header[9] >> ((byte - 4) * 4) & 0x0F {
+        0x0F => {
+            let multiplier = (count & 0b11) as usize * 2 + 1;
+            1usize
+                .checked_shl((count >> 2) as u32)
+                .and_then(|size| size.checked_mul(multiplier))
+                .unwrap_or(usize::max_value())
+        }
+        high => ((high as usize) << 8 | count as usize) * page_size,
+    }
+}
+
+// NES 2.0 gives the volatile and battery backed RAM sizes as shift counts
+// in the low and high nibbles, 64 << n bytes or none for 0
+fn ram_size(shifts: u8) -> usize {
+    let size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+    size(shifts & 0x0F) + size(shifts >> 4)
+}
+
 pub fn load_rom(rom_bytes: &[u8]) -> Result<Rom, Error> {
     let rom = match parse_rom(rom_bytes) {
         Ok((_, rom)) => rom,
@@ -95,11 +85,20 @@ pub fn load_rom(rom_bytes: &[u8]) -> Result<Rom, Error> {
 pub struct Header {
     pub rom_type: RomType,
     pub mapper: u8,
+    // NES 2.0's bits 8-11 of the mapper number, none of those are supported
+    mapper_plane: u8,
+    // NES 2.0 only, picks between variants of a board. 0 for iNES
+    pub submapper: u8,
     pub screen: ScreenMode,
+    // The vertical mirroring bit on its own, some boards give it another
+    // meaning alongside the four screen bit
+    pub mirroring_bit: bool,
     pub save_ram: bool,
-    vs_unisystem: bool,
-    playchoice10: bool,
+    pub console: Console,
+    // ROMs that work on both NTSC and PAL consoles are NTSC here
     pub region: Region,
+    // NES 2.0 only, for the Dendy famiclone's timing
+    pub dendy: bool,
     flag10: u8,
 }
 
@@ -108,21 +107,25 @@ impl fmt::Debug for Header {
         write!(
             f,
             "Header:\n\
-             Type-{:?}, Mapper-{}, ScreenMode-{:?}, SRAM-{}\n\
-             VS Unisystem-{}, Playchoice10-{}, Region-{:?}, flag10-{}\n",
+             Type-{:?}, Mapper-{}, Submapper-{}, ScreenMode-{:?}, SRAM-{}\n\
+             Console-{:?}, Region-{:?}, Dendy-{}, flag10-{}\n",
             self.rom_type,
             self.mapper,
+            self.submapper,
             self.screen,
             self.save_ram,
-            self.vs_unisystem,
-            self.playchoice10,
+            self.console,
             self.region,
+            self.dendy,
             self.flag10
         )
     }
 }
 
 pub struct Rom {
+    // 512 bytes some hacked dumps have between the header and PRG, which
+    // go in PRG RAM at $7000
+    pub trainer: Option<Vec<u8>>,
     pub prg_rom: Vec<u8>,
     pub prg_ram: Vec<u8>,
     pub chr_rom: Vec<u8>,
@@ -132,23 +135,97 @@ pub struct Rom {
 }
 
 impl Rom {
+    fn from_parts(
+        header: &[u8],
+        trainer: Option<&[u8]>,
+        prg_rom: &[u8],
+        chr_rom: &[u8],
+    ) -> Rom {
+        let nes2 = is_nes2(header);
+        let (flag6, flag7) = (header[6], header[7]);
+        let clean_tail = header[12..16].iter().all(|&byte| byte == 0);
+        // iNES counts 8KB pages of PRG RAM, with 0 meaning one
+        let prg_ram_size = match (nes2, header[8]) {
+            (true, _) => ram_size(header[10]),
+            (false, 0) => PRG_RAM_PAGE_SIZE,
+            (false, pages) => PRG_RAM_PAGE_SIZE * pages as usize,
+        };
+        // Boards with both CHR ROM and RAM aren't supported, so RAM is only
+        // given to ROMs without CHR ROM
+        let chr_ram_size = match (chr_rom.is_empty(), nes2) {
+            (false, _) => 0,
+            (true, true) if ram_size(header[11]) != 0 => ram_size(header[11]),
+            (true, _) => CHR_RAM_PAGE_SIZE,
+        };
+        Rom {
+            header: Header {
+                mapper: flag7 & 0xF0 | ((flag6 & 0xF0) >> 4),
+                mapper_plane: if nes2 { header[8] & 0x0F } else { 0 },
+                submapper: if nes2 { header[8] >> 4 } else { 0 },
+                screen: if flag6 & 0b1000 != 0 {
+                    ScreenMode::FourScreen
+                } else {
+                    if flag6 & 0b01 == 1 {
+                        ScreenMode::Vertical
+                    } else {
+                        ScreenMode::Horizontal
+                    }
+                },
+                mirroring_bit: flag6 & 0b01 != 0,
+                save_ram: flag6 & 0b10 != 0,
+                console: match (flag7 & 0b11, nes2) {
+                    (1, _) => Console::VsSystem,
+                    (2, _) => Console::Playchoice10,
+                    (3, true) => Console::Extended(header[13] & 0x0F),
+                    _ => Console::Nes,
+                },
+                // NES 2.0 has 0 for NTSC, 1 for PAL, 2 for either and 3 for
+                // Dendy. In iNES it's bit 0 of byte 9, which NES 2.0 uses
+                // for ROM sizes. Old dumps often have junk like "DiskDude!"
+                // past byte 7, so byte 9 only counts when 12-15 are clear
+                region: match (nes2, header[12] & 0b11, header[9] & 1) {
+                    (true, 1, _) => Region::PAL,
+                    (false, _, 1) if clean_tail => Region::PAL,
+                    _ => Region::NTSC,
+                },
+                dendy: nes2 && header[12] & 0b11 == 3,
+                flag10: header[10],
+                rom_type: if nes2 { RomType::Nes2 } else { RomType::INes },
+            },
+            trainer: trainer.map(|trainer| trainer.into()),
+            prg_rom: prg_rom.into(),
+            chr_rom: chr_rom.into(),
+            prg_ram_size: prg_ram_size,
+            prg_ram: Vec::new(),
+            chr_ram: vec![0; chr_ram_size],
+        }
+    }
+
     fn check_invalid(&self) -> Result<(), LoadRomError> {
-        match self.header.rom_type {
-            RomType::Nes2 => {
-                return Err(LoadRomError::Unsupported(
-                    "Unsupported rom type NES2.0!".to_string(),
-                ));
-            }
-            _ => (),
+        // NES 2.0's exponent sizes can be anything, the boards all assume
+        // at least 16KB made of whole 8KB banks
+        let prg_len = self.prg_rom.len();
+        if prg_len < 0x4000 || prg_len % 0x2000 != 0 {
+            return Err(LoadRomError::Unsupported(format!(
+                "{} bytes of PRG ROM",
+                prg_len
+            )));
         }
 
-        match self.header.region {
-            Region::PAL => {
-                return Err(LoadRomError::Unsupported(
-                    "Unsupported region PAL!".to_string(),
-                ));
-            }
-            _ => (),
+        if self.header.mapper_plane != 0 {
+            let plane = (self.header.mapper_plane as u16) << 8;
+            return Err(LoadRomError::Unsupported(format!(
+                "mapper {} not implemented",
+                plane | self.header.mapper as u16
+            )));
+        }
+
+        let mapper = self.header.mapper;
+        if !supported_boards().any(|board| board.mapper == mapper) {
+            return Err(LoadRomError::Unsupported(format!(
+                "{} not implemented",
+                describe_board(mapper)
+            )));
         }
 
         Ok(())
@@ -157,6 +234,35 @@ impl Rom {
     pub fn fill_prg_ram(&mut self) {
         self.prg_ram = vec![0u8; self.prg_ram_size];
     }
+
+    pub fn load_trainer(&mut self) {
+        if let Some(ref trainer) = self.trainer {
+            match self.prg_ram.get_mut(TRAINER_START..) {
+                Some(ram) if ram.len() >= TRAINER_LEN => {
+                    ram[..TRAINER_LEN].copy_from_slice(trainer);
+                }
+                _ => warn!("No PRG RAM at $7000 for the trainer"),
+            }
+        }
+    }
+
+    // The CHR chip on the board, RAM when the header has no CHR ROM
+    pub fn chr(&self) -> &[u8] {
+        if self.chr_ram.is_empty() {
+            &self.chr_rom
+        } else {
+            &self.chr_ram
+        }
+    }
+
+    // Only RAM can be written to
+    pub fn chr_mut(&mut self) -> Option<&mut [u8]> {
+        if self.chr_ram.is_empty() {
+            None
+        } else {
+            Some(&mut self.chr_ram)
+        }
+    }
 }
 
 impl fmt::Debug for Rom {
@@ -191,13 +297,39 @@ pub enum ScreenBank {
     Upper,
 }
 
+impl ScreenMode {
+    // Which 1KB page of nametable memory backs each of the four nametables
+    // at $2000, $2400, $2800 and $2C00. Pages 0 and 1 are the console's own
+    // 2KB, four screen boards add pages 2 and 3 on the cartridge
+    pub fn nt_page(&self, table: u16) -> u16 {
+        match *self {
+            ScreenMode::Horizontal => table >> 1,
+            ScreenMode::Vertical => table & 1,
+            ScreenMode::OneScreenSwap(ScreenBank::Lower) => 0,
+            ScreenMode::OneScreenSwap(ScreenBank::Upper) => 1,
+            ScreenMode::FourScreen => table,
+        }
+    }
+}
+
+// What the ROM was made for, from the low bits of byte 7
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Console {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    // NES 2.0's extended console type from byte 13, e.g. famiclones with
+    // decimal mode or VT0x chips
+    Extended(u8),
+}
+
 #[derive(Debug)]
 pub enum RomType {
     INes,
     Nes2,
 }
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub enum Region {
     NTSC,
     PAL,
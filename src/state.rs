@@ -2,20 +2,25 @@ use std::io::Read;
 use std::io::Write;
 use mmu::Ram;
 use cpu::Registers;
-use rom::ScreenMode;
-use mapper::MemType;
 use failure::Error;
 use serde::Serialize;
 use serde::Deserialize;
 use ppu::PpuState;
+use apu::ApuState;
+use EmulatorClock;
 
 #[derive(Serialize, Deserialize)]
 pub struct State {
+    // The scanline and dot are restored along with the rest of the PPU
+    pub clock: EmulatorClock,
     pub ppu_state: PpuState,
-    pub screen_mode: ScreenMode,
+    pub apu_state: ApuState,
     pub chr_ram: Vec<u8>,
+    // The cartridge's work RAM, battery backed or not
+    pub prg_ram: Vec<u8>,
     pub cpu_regs: Registers,
-    pub mapper: MemType,
+    // Whatever the board's own save_state gave
+    pub mapper: Vec<u8>,
     pub ram: Ram,
 }
 
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use failure::Error;
+use cartridge::Cartridge;
+
+// FCEUX .nl files cover one 16KB bank each
+const NL_BANK_SIZE: usize = 0x4000;
+const PRG_RAM_START: u16 = 0x6000;
+
+#[derive(Debug, Fail)]
+pub enum SymbolError {
+    #[fail(display = "Bad label on line {}: {}", _0, _1)]
+    BadLine(usize, String),
+    #[fail(display = "Unknown symbol file type: {}", _0)]
+    UnknownFormat(String),
+}
+
+// Where a label points. Labels inside PRG ROM are kept as ROM offsets, so
+// they only resolve while their bank is actually mapped in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    Cpu(u16),
+    Prg(usize),
+}
+
+pub struct Symbols {
+    labels: HashMap<Location, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            labels: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn insert(&mut self, location: Location, label: &str) {
+        self.labels.insert(location, label.to_string());
+    }
+
+    // Picks the parser from the file name: game.nes.0.nl and game.nes.ram.nl
+    // for FCEUX, game.mlb for Mesen
+    pub fn load(&mut self, path: &Path) -> Result<(), Error> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("")
+            .to_string();
+        let text = fs::read_to_string(path)?;
+        if name.ends_with(".mlb") {
+            self.parse_mlb(&text)
+        } else if name.ends_with(".ram.nl") {
+            self.parse_nl(&text, None)
+        } else if name.ends_with(".nl") {
+            let bank = name
+                .trim_end_matches(".nl")
+                .rsplit('.')
+                .next()
+                .and_then(|bank| usize::from_str_radix(bank, 16).ok())
+                .ok_or_else(|| SymbolError::UnknownFormat(name.clone()))?;
+            self.parse_nl(&text, Some(bank))
+        } else {
+            Err(SymbolError::UnknownFormat(name))?
+        }
+    }
+
+    // Lines look like $C000#reset#Comment. Bank files give CPU addresses
+    // within that bank, the .ram.nl file plain CPU addresses
+    pub fn parse_nl(
+        &mut self,
+        text: &str,
+        bank: Option<usize>,
+    ) -> Result<(), Error> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(3, '#');
+            let addr = fields
+                .next()
+                .filter(|addr| addr.starts_with('$'))
+                .and_then(|addr| u16::from_str_radix(&addr[1..], 16).ok())
+                .ok_or_else(|| SymbolError::BadLine(i + 1, line.to_string()))?;
+            let label = fields.next().unwrap_or("").trim();
+            if label.is_empty() {
+                continue;
+            }
+            let location = match bank {
+                Some(bank) if addr >= 0x8000 => Location::Prg(
+                    bank * NL_BANK_SIZE + (addr as usize & (NL_BANK_SIZE - 1)),
+                ),
+                _ => Location::Cpu(addr),
+            };
+            self.insert(location, label);
+        }
+        Ok(())
+    }
+
+    // Lines look like P:1F00:label:comment, where the prefix says what the
+    // address is relative to. Both Mesen's single letter prefixes and
+    // Mesen 2's long names are accepted
+    pub fn parse_mlb(&mut self, text: &str) -> Result<(), Error> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let bad_line = || SymbolError::BadLine(i + 1, line.to_string());
+            let mut fields = line.splitn(4, ':');
+            let kind = fields.next().unwrap_or("");
+            let addr = fields
+                .next()
+                .and_then(|range| range.split('-').next())
+                .and_then(|addr| usize::from_str_radix(addr, 16).ok())
+                .ok_or_else(bad_line)?;
+            let label = fields.next().unwrap_or("").trim();
+            if label.is_empty() {
+                continue;
+            }
+            let location = match kind {
+                "P" | "NesPrgRom" => Location::Prg(addr),
+                "R" | "NesInternalRam" | "G" | "NesMemory" => {
+                    Location::Cpu(addr as u16)
+                }
+                "S" | "W" | "NesSaveRam" | "NesWorkRam" => {
+                    Location::Cpu(PRG_RAM_START.wrapping_add(addr as u16))
+                }
+                _ => Err(bad_line())?,
+            };
+            self.insert(location, label);
+        }
+        Ok(())
+    }
+
+    // Resolves a CPU address through the mapper's current banking
+    pub fn label(&self, cartridge: &Cartridge, addr: u16) -> Option<&str> {
+        let location = match cartridge.prg_offset(addr) {
+            Some(offset) => Location::Prg(offset),
+            None => Location::Cpu(addr),
+        };
+        self.labels.get(&location).map(|label| label.as_str())
+    }
+
+    pub fn find(&self, label: &str) -> Option<Location> {
+        self.labels
+            .iter()
+            .find(|&(_, name)| name == label)
+            .map(|(&location, _)| location)
+    }
+}
@@ -0,0 +1,534 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use failure::Error;
+use failure::Fail;
+use cpu::Cpu;
+use mmu::Mmu;
+
+// Operand formats as the disassembler prints them. These are separate from
+// cpu::Mode since that one also encodes page crossing behaviour
+#[derive(Copy, Clone, PartialEq)]
+pub enum Operand {
+    Imp,
+    Acc,
+    Imm,
+    ZP,
+    ZPX,
+    ZPY,
+    Abs,
+    AbsX,
+    AbsY,
+    Ind,
+    IndX,
+    IndY,
+    Rel,
+}
+
+impl Operand {
+    pub fn len(&self) -> u16 {
+        match *self {
+            Operand::Imp | Operand::Acc => 1,
+            Operand::Abs
+            | Operand::AbsX
+            | Operand::AbsY
+            | Operand::Ind => 3,
+            _ => 2,
+        }
+    }
+}
+
+// Mnemonic, operand format and whether the opcode is official. Unofficial
+// opcodes are marked with a * in the trace, like nestest.log does
+#[derive(Copy, Clone)]
+pub struct Op(pub &'static str, pub Operand, pub bool);
+
+pub static OPS: [Op; 256] = [
+    Op("BRK", Operand::Imp, true),
+    Op("ORA", Operand::IndX, true),
+    Op("KIL", Operand::Imp, false),
+    Op("SLO", Operand::IndX, false),
+    Op("NOP", Operand::ZP, false),
+    Op("ORA", Operand::ZP, true),
+    Op("ASL", Operand::ZP, true),
+    Op("SLO", Operand::ZP, false),
+    Op("PHP", Operand::Imp, true),
+    Op("ORA", Operand::Imm, true),
+    Op("ASL", Operand::Acc, true),
+    Op("ANC", Operand::Imm, false),
+    Op("NOP", Operand::Abs, false),
+    Op("ORA", Operand::Abs, true),
+    Op("ASL", Operand::Abs, true),
+    Op("SLO", Operand::Abs, false),
+    Op("BPL", Operand::Rel, true),
+    Op("ORA", Operand::IndY, true),
+    Op("KIL", Operand::Imp, false),
+    Op("SLO", Operand::IndY, false),
+    Op("NOP", Operand::ZPX, false),
+    Op("ORA", Operand::ZPX, true),
+    Op("ASL", Operand::ZPX, true),
+    Op("SLO", Operand::ZPX, false),
+    Op("CLC", Operand::Imp, true),
+    Op("ORA", Operand::AbsY, true),
+    Op("NOP", Operand::Imp, false),
+    Op("SLO", Operand::AbsY, false),
+    Op("NOP", Operand::AbsX, false),
+    Op("ORA", Operand::AbsX, true),
+    Op("ASL", Operand::AbsX, true),
+    Op("SLO", Operand::AbsX, false),
+    Op("JSR", Operand::Abs, true),
+    Op("AND", Operand::IndX, true),
+    Op("KIL", Operand::Imp, false),
+    Op("RLA", Operand::IndX, false),
+    Op("BIT", Operand::ZP, true),
+    Op("AND", Operand::ZP, true),
+    Op("ROL", Operand::ZP, true),
+    Op("RLA", Operand::ZP, false),
+    Op("PLP", Operand::Imp, true),
+    Op("AND", Operand::Imm, true),
+    Op("ROL", Operand::Acc, true),
+    Op("ANC", Operand::Imm, false),
+    Op("BIT", Operand::Abs, true),
+    Op("AND", Operand::Abs, true),
+    Op("ROL", Operand::Abs, true),
+    Op("RLA", Operand::Abs, false),
+    Op("BMI", Operand::Rel, true),
+    Op("AND", Operand::IndY, true),
+    Op("KIL", Operand::Imp, false),
+    Op("RLA", Operand::IndY, false),
+    Op("NOP", Operand::ZPX, false),
+    Op("AND", Operand::ZPX, true),
+    Op("ROL", Operand::ZPX, true),
+    Op("RLA", Operand::ZPX, false),
+    Op("SEC", Operand::Imp, true),
+    Op("AND", Operand::AbsY, true),
+    Op("NOP", Operand::Imp, false),
+    Op("RLA", Operand::AbsY, false),
+    Op("NOP", Operand::AbsX, false),
+    Op("AND", Operand::AbsX, true),
+    Op("ROL", Operand::AbsX, true),
+    Op("RLA", Operand::AbsX, false),
+    Op("RTI", Operand::Imp, true),
+    Op("EOR", Operand::IndX, true),
+    Op("KIL", Operand::Imp, false),
+    Op("SRE", Operand::IndX, false),
+    Op("NOP", Operand::ZP, false),
+    Op("EOR", Operand::ZP, true),
+    Op("LSR", Operand::ZP, true),
+    Op("SRE", Operand::ZP, false),
+    Op("PHA", Operand::Imp, true),
+    Op("EOR", Operand::Imm, true),
+    Op("LSR", Operand::Acc, true),
+    Op("ALR", Operand::Imm, false),
+    Op("JMP", Operand::Abs, true),
+    Op("EOR", Operand::Abs, true),
+    Op("LSR", Operand::Abs, true),
+    Op("SRE", Operand::Abs, false),
+    Op("BVC", Operand::Rel, true),
+    Op("EOR", Operand::IndY, true),
+    Op("KIL", Operand::Imp, false),
+    Op("SRE", Operand::IndY, false),
+    Op("NOP", Operand::ZPX, false),
+    Op("EOR", Operand::ZPX, true),
+    Op("LSR", Operand::ZPX, true),
+    Op("SRE", Operand::ZPX, false),
+    Op("CLI", Operand::Imp, true),
+    Op("EOR", Operand::AbsY, true),
+    Op("NOP", Operand::Imp, false),
+    Op("SRE", Operand::AbsY, false),
+    Op("NOP", Operand::AbsX, false),
+    Op("EOR", Operand::AbsX, true),
+    Op("LSR", Operand::AbsX, true),
+    Op("SRE", Operand::AbsX, false),
+    Op("RTS", Operand::Imp, true),
+    Op("ADC", Operand::IndX, true),
+    Op("KIL", Operand::Imp, false),
+    Op("RRA", Operand::IndX, false),
+    Op("NOP", Operand::ZP, false),
+    Op("ADC", Operand::ZP, true),
+    Op("ROR", Operand::ZP, true),
+    Op("RRA", Operand::ZP, false),
+    Op("PLA", Operand::Imp, true),
+    Op("ADC", Operand::Imm, true),
+    Op("ROR", Operand::Acc, true),
+    Op("ARR", Operand::Imm, false),
+    Op("JMP", Operand::Ind, true),
+    Op("ADC", Operand::Abs, true),
+    Op("ROR", Operand::Abs, true),
+    Op("RRA", Operand::Abs, false),
+    Op("BVS", Operand::Rel, true),
+    Op("ADC", Operand::IndY, true),
+    Op("KIL", Operand::Imp, false),
+    Op("RRA", Operand::IndY, false),
+    Op("NOP", Operand::ZPX, false),
+    Op("ADC", Operand::ZPX, true),
+    Op("ROR", Operand::ZPX, true),
+    Op("RRA", Operand::ZPX, false),
+    Op("SEI", Operand::Imp, true),
+    Op("ADC", Operand::AbsY, true),
+    Op("NOP", Operand::Imp, false),
+    Op("RRA", Operand::AbsY, false),
+    Op("NOP", Operand::AbsX, false),
+    Op("ADC", Operand::AbsX, true),
+    Op("ROR", Operand::AbsX, true),
+    Op("RRA", Operand::AbsX, false),
+    Op("NOP", Operand::Imm, false),
+    Op("STA", Operand::IndX, true),
+    Op("NOP", Operand::Imm, false),
+    Op("SAX", Operand::IndX, false),
+    Op("STY", Operand::ZP, true),
+    Op("STA", Operand::ZP, true),
+    Op("STX", Operand::ZP, true),
+    Op("SAX", Operand::ZP, false),
+    Op("DEY", Operand::Imp, true),
+    Op("NOP", Operand::Imm, false),
+    Op("TXA", Operand::Imp, true),
+    Op("XAA", Operand::Imm, false),
+    Op("STY", Operand::Abs, true),
+    Op("STA", Operand::Abs, true),
+    Op("STX", Operand::Abs, true),
+    Op("SAX", Operand::Abs, false),
+    Op("BCC", Operand::Rel, true),
+    Op("STA", Operand::IndY, true),
+    Op("KIL", Operand::Imp, false),
+    Op("AHX", Operand::IndY, false),
+    Op("STY", Operand::ZPX, true),
+    Op("STA", Operand::ZPX, true),
+    Op("STX", Operand::ZPY, true),
+    Op("SAX", Operand::ZPY, false),
+    Op("TYA", Operand::Imp, true),
+    Op("STA", Operand::AbsY, true),
+    Op("TXS", Operand::Imp, true),
+    Op("TAS", Operand::AbsY, false),
+    Op("SHY", Operand::AbsX, false),
+    Op("STA", Operand::AbsX, true),
+    Op("SHX", Operand::AbsY, false),
+    Op("AHX", Operand::AbsY, false),
+    Op("LDY", Operand::Imm, true),
+    Op("LDA", Operand::IndX, true),
+    Op("LDX", Operand::Imm, true),
+    Op("LAX", Operand::IndX, false),
+    Op("LDY", Operand::ZP, true),
+    Op("LDA", Operand::ZP, true),
+    Op("LDX", Operand::ZP, true),
+    Op("LAX", Operand::ZP, false),
+    Op("TAY", Operand::Imp, true),
+    Op("LDA", Operand::Imm, true),
+    Op("TAX", Operand::Imp, true),
+    Op("LAX", Operand::Imm, false),
+    Op("LDY", Operand::Abs, true),
+    Op("LDA", Operand::Abs, true),
+    Op("LDX", Operand::Abs, true),
+    Op("LAX", Operand::Abs, false),
+    Op("BCS", Operand::Rel, true),
+    Op("LDA", Operand::IndY, true),
+    Op("KIL", Operand::Imp, false),
+    Op("LAX", Operand::IndY, false),
+    Op("LDY", Operand::ZPX, true),
+    Op("LDA", Operand::ZPX, true),
+    Op("LDX", Operand::ZPY, true),
+    Op("LAX", Operand::ZPY, false),
+    Op("CLV", Operand::Imp, true),
+    Op("LDA", Operand::AbsY, true),
+    Op("TSX", Operand::Imp, true),
+    Op("LAS", Operand::AbsY, false),
+    Op("LDY", Operand::AbsX, true),
+    Op("LDA", Operand::AbsX, true),
+    Op("LDX", Operand::AbsY, true),
+    Op("LAX", Operand::AbsY, false),
+    Op("CPY", Operand::Imm, true),
+    Op("CMP", Operand::IndX, true),
+    Op("NOP", Operand::Imm, false),
+    Op("DCP", Operand::IndX, false),
+    Op("CPY", Operand::ZP, true),
+    Op("CMP", Operand::ZP, true),
+    Op("DEC", Operand::ZP, true),
+    Op("DCP", Operand::ZP, false),
+    Op("INY", Operand::Imp, true),
+    Op("CMP", Operand::Imm, true),
+    Op("DEX", Operand::Imp, true),
+    Op("AXS", Operand::Imm, false),
+    Op("CPY", Operand::Abs, true),
+    Op("CMP", Operand::Abs, true),
+    Op("DEC", Operand::Abs, true),
+    Op("DCP", Operand::Abs, false),
+    Op("BNE", Operand::Rel, true),
+    Op("CMP", Operand::IndY, true),
+    Op("KIL", Operand::Imp, false),
+    Op("DCP", Operand::IndY, false),
+    Op("NOP", Operand::ZPX, false),
+    Op("CMP", Operand::ZPX, true),
+    Op("DEC", Operand::ZPX, true),
+    Op("DCP", Operand::ZPX, false),
+    Op("CLD", Operand::Imp, true),
+    Op("CMP", Operand::AbsY, true),
+    Op("NOP", Operand::Imp, false),
+    Op("DCP", Operand::AbsY, false),
+    Op("NOP", Operand::AbsX, false),
+    Op("CMP", Operand::AbsX, true),
+    Op("DEC", Operand::AbsX, true),
+    Op("DCP", Operand::AbsX, false),
+    Op("CPX", Operand::Imm, true),
+    Op("SBC", Operand::IndX, true),
+    Op("NOP", Operand::Imm, false),
+    Op("ISB", Operand::IndX, false),
+    Op("CPX", Operand::ZP, true),
+    Op("SBC", Operand::ZP, true),
+    Op("INC", Operand::ZP, true),
+    Op("ISB", Operand::ZP, false),
+    Op("INX", Operand::Imp, true),
+    Op("SBC", Operand::Imm, true),
+    Op("NOP", Operand::Imp, true),
+    Op("SBC", Operand::Imm, false),
+    Op("CPX", Operand::Abs, true),
+    Op("SBC", Operand::Abs, true),
+    Op("INC", Operand::Abs, true),
+    Op("ISB", Operand::Abs, false),
+    Op("BEQ", Operand::Rel, true),
+    Op("SBC", Operand::IndY, true),
+    Op("KIL", Operand::Imp, false),
+    Op("ISB", Operand::IndY, false),
+    Op("NOP", Operand::ZPX, false),
+    Op("SBC", Operand::ZPX, true),
+    Op("INC", Operand::ZPX, true),
+    Op("ISB", Operand::ZPX, false),
+    Op("SED", Operand::Imp, true),
+    Op("SBC", Operand::AbsY, true),
+    Op("NOP", Operand::Imp, false),
+    Op("ISB", Operand::AbsY, false),
+    Op("NOP", Operand::AbsX, false),
+    Op("SBC", Operand::AbsX, true),
+    Op("INC", Operand::AbsX, true),
+    Op("ISB", Operand::AbsX, false),
+];
+
+// Writes one line per instruction in the format Nintendulator and Mesen use
+// (and that nestest.log is published in), so traces can be diffed directly
+pub struct Tracer {
+    sink: Box<dyn Write>,
+}
+
+impl Tracer {
+    pub fn new<W: Write + 'static>(sink: W) -> Tracer {
+        Tracer {
+            sink: Box::new(sink),
+        }
+    }
+
+    pub fn to_file(path: &Path) -> Result<Tracer, Error> {
+        let file = File::create(path)?;
+        Ok(Tracer::new(BufWriter::new(file)))
+    }
+
+    pub fn trace(&mut self, cpu: &Cpu) -> Result<(), Error> {
+        let pc = cpu.regs.pc.get_addr();
+        let cartridge = cpu.mmu.cartridge.borrow();
+        if let Some(label) = cpu.mmu.debugger.symbols.label(&cartridge, pc) {
+            writeln!(self.sink, "{}:", label)?;
+        }
+        writeln!(self.sink, "{}", trace_line(cpu))?;
+        Ok(())
+    }
+}
+
+// Lines of matching trace shown before a divergence
+const CONTEXT_LINES: usize = 5;
+
+// First point where a trace stopped matching its reference log
+#[derive(Debug)]
+pub struct Divergence {
+    // 1 based, like an editor would show it
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+    pub context: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Trace diverged from the log at line {}", self.line)?;
+        for line in &self.context {
+            writeln!(f, "           {}", line)?;
+        }
+        writeln!(f, "expected:  {}", self.expected)?;
+        write!(f, "actual:    {}", self.actual)
+    }
+}
+
+impl Fail for Divergence {}
+
+// Runs nestest in its automated mode, starting at $C000 instead of the reset
+// vector, and compares every trace line with the canonical nestest.log.
+// Returns how many lines matched
+pub fn verify_nestest(cpu: &mut Cpu, log: &str) -> Result<usize, Error> {
+    cpu.regs.pc.set_addr(0xC000);
+    let mut context = VecDeque::with_capacity(CONTEXT_LINES);
+    let mut matched = 0;
+    for (i, expected) in log.lines().enumerate() {
+        let actual = trace_line(cpu);
+        if actual != expected.trim_end() {
+            return Err(Divergence {
+                line: i + 1,
+                expected: expected.to_string(),
+                actual,
+                context: context.into_iter().collect(),
+            }
+            .into());
+        }
+        if context.len() == CONTEXT_LINES {
+            context.pop_front();
+        }
+        context.push_back(actual);
+        matched += 1;
+        cpu.step()?;
+    }
+    Ok(matched)
+}
+
+// Formats the instruction at the current PC along with the CPU and PPU state
+// before it executes, e.g.
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+pub fn trace_line(cpu: &Cpu) -> String {
+    let pc = cpu.regs.pc.get_addr();
+    let op = OPS[cpu.mmu.peek(pc) as usize];
+    let bytes = (0..op.1.len())
+        .map(|i| format!("{:02X}", cpu.mmu.peek(pc.wrapping_add(i))))
+        .collect::<Vec<String>>()
+        .join(" ");
+    format!(
+        "{:04X}  {:<8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} \
+         PPU:{:>3},{:>3} CYC:{}",
+        pc,
+        bytes,
+        if op.2 { ' ' } else { '*' },
+        disassemble(&cpu.mmu, pc, cpu.regs.x, cpu.regs.y),
+        cpu.regs.acc,
+        cpu.regs.x,
+        cpu.regs.y,
+        cpu.regs.flags.as_byte(),
+        cpu.regs.sp,
+        cpu.mmu.ppu.scanline(),
+        cpu.mmu.ppu.dot(),
+        cpu.mmu.cycles,
+    )
+}
+
+// Disassembles the instruction at pc. Memory operands are annotated with the
+// effective address and the value currently stored there, read with peek so
+// tracing never disturbs registers with read side effects. Operands that
+// have a label in the debugger's symbols are printed by name
+pub fn disassemble(mmu: &Mmu, pc: u16, x: u8, y: u8) -> String {
+    let Op(name, operand, _) = OPS[mmu.peek(pc) as usize];
+    let lo = mmu.peek(pc.wrapping_add(1));
+    let hi = mmu.peek(pc.wrapping_add(2));
+    let abs = (hi as u16) << 8 | lo as u16;
+    let peek16_zp = |addr: u8| {
+        (mmu.peek(addr.wrapping_add(1) as u16) as u16) << 8
+            | mmu.peek(addr as u16) as u16
+    };
+    let symbols = &mmu.debugger.symbols;
+    let cartridge = mmu.cartridge.borrow();
+    let label = |addr: u16| {
+        if symbols.is_empty() {
+            None
+        } else {
+            symbols.label(&cartridge, addr).map(|label| label.to_string())
+        }
+    };
+    let zp = |addr: u8| {
+        label(addr as u16).unwrap_or_else(|| format!("${:02X}", addr))
+    };
+    let word = |addr: u16| label(addr).unwrap_or_else(|| format!("${:04X}", addr));
+    // Jumps print their target rather than the byte stored there
+    let is_jump = name == "JMP" || name == "JSR";
+    match operand {
+        Operand::Imp => name.to_string(),
+        Operand::Acc => format!("{} A", name),
+        Operand::Imm => format!("{} #${:02X}", name, lo),
+        Operand::ZP => {
+            format!("{} {} = {:02X}", name, zp(lo), mmu.peek(lo as u16))
+        }
+        Operand::ZPX => {
+            let addr = lo.wrapping_add(x);
+            format!(
+                "{} {},X @ {:02X} = {:02X}",
+                name,
+                zp(lo),
+                addr,
+                mmu.peek(addr as u16)
+            )
+        }
+        Operand::ZPY => {
+            let addr = lo.wrapping_add(y);
+            format!(
+                "{} {},Y @ {:02X} = {:02X}",
+                name,
+                zp(lo),
+                addr,
+                mmu.peek(addr as u16)
+            )
+        }
+        Operand::Abs if is_jump => format!("{} {}", name, word(abs)),
+        Operand::Abs => {
+            format!("{} {} = {:02X}", name, word(abs), mmu.peek(abs))
+        }
+        Operand::AbsX => {
+            let addr = abs.wrapping_add(x as u16);
+            format!(
+                "{} {},X @ {:04X} = {:02X}",
+                name,
+                word(abs),
+                addr,
+                mmu.peek(addr)
+            )
+        }
+        Operand::AbsY => {
+            let addr = abs.wrapping_add(y as u16);
+            format!(
+                "{} {},Y @ {:04X} = {:02X}",
+                name,
+                word(abs),
+                addr,
+                mmu.peek(addr)
+            )
+        }
+        Operand::Ind => {
+            // Same page wrapping bug as the real JMP ($xxFF)
+            let hi_addr = (abs & 0xFF00) | (abs.wrapping_add(1) & 0x00FF);
+            let target =
+                (mmu.peek(hi_addr) as u16) << 8 | mmu.peek(abs) as u16;
+            format!("{} ({}) = {:04X}", name, word(abs), target)
+        }
+        Operand::IndX => {
+            let ptr = lo.wrapping_add(x);
+            let addr = peek16_zp(ptr);
+            format!(
+                "{} ({},X) @ {:02X} = {:04X} = {:02X}",
+                name,
+                zp(lo),
+                ptr,
+                addr,
+                mmu.peek(addr)
+            )
+        }
+        Operand::IndY => {
+            let base = peek16_zp(lo);
+            let addr = base.wrapping_add(y as u16);
+            format!(
+                "{} ({}),Y = {:04X} @ {:04X} = {:02X}",
+                name,
+                zp(lo),
+                base,
+                addr,
+                mmu.peek(addr)
+            )
+        }
+        Operand::Rel => {
+            let target = pc.wrapping_add(2).wrapping_add(lo as i8 as u16);
+            format!("{} {}", name, word(target))
+        }
+    }
+}
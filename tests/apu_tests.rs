@@ -0,0 +1,82 @@
+extern crate nes_emu;
+
+use nes_emu::blargg::run_test_rom;
+use nes_emu::blargg::Outcome;
+use nes_emu::rom::load_rom;
+use std::fs::File;
+use std::io::Read;
+
+// Each of these ROMs takes a few seconds at most
+const MAX_FRAMES: usize = 60 * 30;
+
+// Runs every ROM of one of blargg's suites from ./nes_test_roms, and lists
+// the ones that didn't pass along with what they reported
+fn run_suite(dir: &str, roms: &[&str]) {
+    let mut failures = Vec::new();
+    for rom_name in roms {
+        let path = format!("./nes_test_roms/{}/{}", dir, rom_name);
+        let mut raw_bytes = Vec::new();
+        File::open(&path)
+            .expect("This path is correct")
+            .read_to_end(&mut raw_bytes)
+            .expect("Should be able to read to end");
+        let rom = load_rom(&raw_bytes).expect("This is a good rom");
+        let report = run_test_rom(rom, MAX_FRAMES)
+            .expect("Test roms shouldn't hit a bad opcode");
+        if report.outcome != Outcome::Passed {
+            failures.push(format!(
+                "{} {:?}: {}",
+                rom_name,
+                report.outcome,
+                report.text.trim()
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn apu_test() {
+    run_suite(
+        "apu_test/rom_singles",
+        &[
+            "1-len_ctr.nes",
+            "2-len_table.nes",
+            "3-irq_flag.nes",
+            "4-jitter.nes",
+            "5-len_timing.nes",
+            "6-irq_flag_timing.nes",
+            "7-dmc_basics.nes",
+            "8-dmc_rates.nes",
+        ],
+    );
+}
+
+#[test]
+fn apu_reset() {
+    run_suite(
+        "apu_reset",
+        &[
+            "4015_cleared.nes",
+            "4017_timing.nes",
+            "4017_written.nes",
+            "irq_flag_cleared.nes",
+            "len_ctrs_enabled.nes",
+            "works_immediately.nes",
+        ],
+    );
+}
+
+#[test]
+fn dmc_dma_during_read4() {
+    run_suite(
+        "dmc_dma_during_read4",
+        &[
+            "dma_2007_read.nes",
+            "dma_2007_write.nes",
+            "dma_4016_read.nes",
+            "double_2007_read.nes",
+            "read_write_2007.nes",
+        ],
+    );
+}
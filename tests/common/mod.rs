@@ -0,0 +1,34 @@
+// Fixtures shared by the integration tests, not every file uses all of them
+#![allow(dead_code)]
+
+use nes_emu::NesEmulator;
+
+// An iNES ROM for a mapper with 32KB of PRG that just spins at $8000. CHR is
+// the given CHR ROM, or CHR RAM when it's empty
+pub fn spinning_rom(mapper: u8, chr: &[u8]) -> Vec<u8> {
+    let chr_banks = (chr.len() / 0x2000) as u8;
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 2, chr_banks];
+    raw.push(mapper << 4);
+    raw.push(mapper & 0xF0);
+    raw.resize(16, 0);
+    let mut prg = vec![0; 0x8000];
+    prg[0..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+    prg[0x7FFA..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+    raw.extend(prg);
+    raw.extend_from_slice(chr);
+    raw
+}
+
+// Through $2006, the high byte first
+pub fn set_vram_addr(nes: &mut NesEmulator, addr: u16) {
+    nes.cpu.mmu.ppu.store(6, (addr >> 8) as u8);
+    nes.cpu.mmu.ppu.store(6, addr as u8);
+}
+
+// Through $2007, starting at addr
+pub fn write_vram(nes: &mut NesEmulator, addr: u16, data: &[u8]) {
+    set_vram_addr(nes, addr);
+    for &byte in data {
+        nes.cpu.mmu.ppu.store(7, byte);
+    }
+}
@@ -1,16 +1,22 @@
 extern crate nes_emu;
 
+mod common;
+
 use nes_emu::apu::Apu;
 use nes_emu::cpu::Cpu;
-use nes_emu::mapper::Mapper;
+use nes_emu::cartridge::Cartridge;
+use nes_emu::controller::Button;
 use nes_emu::mmu::Mmu;
-use nes_emu::mmu::Ram;
 use nes_emu::ppu::Ppu;
 use nes_emu::rom::load_rom;
+use nes_emu::trace::verify_nestest;
+use nes_emu::NesEmulator;
 use std::cell::RefCell;
+use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::rc::Rc;
+use common::spinning_rom;
 
 #[test]
 
@@ -22,19 +28,18 @@ fn run_nestest() {
         .expect("Should be able to read to end");
     let rom = load_rom(&raw_bytes).expect("This is a good rom");
 
-    let mapper = Rc::new(RefCell::new(Mapper::from_rom(rom)));
+    let cartridge = Rc::new(RefCell::new(Cartridge::from_rom(rom)));
     let mut cpu = Cpu::new(Mmu::new(
         Apu::new(),
-        Ram::new(),
-        Ppu::new(mapper.clone()),
-        mapper,
+        Ppu::new(cartridge.clone()),
+        cartridge,
     ));
     cpu.regs.pc.set_addr(0xC000);
     let mut cycle_count: usize = 7;
     // TODO: Need to implement cycle checking and assertions per instruction
     // instead of just at the end
     loop {
-        let cc = cpu.step();
+        let cc = cpu.step().expect("nestest shouldn't hit a bad opcode");
         cycle_count += cc as usize;
         if cpu.regs.pc.get_addr() == 0xF7A5 {
             assert_eq!(cpu.regs.pc.get_addr(), 0xF7A5);
@@ -48,3 +53,75 @@ fn run_nestest() {
         }
     }
 }
+
+#[test]
+fn nestest_matches_log() {
+    let mut raw_bytes = Vec::new();
+    File::open("./nes_test_roms/other/nestest.nes")
+        .expect("This path is correct")
+        .read_to_end(&mut raw_bytes)
+        .expect("Should be able to read to end");
+    let rom = load_rom(&raw_bytes).expect("This is a good rom");
+    let log = fs::read_to_string("./nes_test_roms/other/nestest.log")
+        .expect("nestest.log sits next to the rom");
+
+    let cartridge = Rc::new(RefCell::new(Cartridge::from_rom(rom)));
+    let mut cpu = Cpu::new(Mmu::new(
+        Apu::new(),
+        Ppu::new(cartridge.clone()),
+        cartridge,
+    ));
+    if let Err(e) = verify_nestest(&mut cpu, &log) {
+        panic!("{}", e);
+    }
+}
+
+#[test]
+fn inc_abs_x_reads_before_the_carry() {
+    // LDX #$17, INC $40FF,X then spin. The read before the carry is fixed
+    // up goes to $4016
+    let mut raw = spinning_rom(0, &[0; 0x2000]);
+    let program = [0xA2, 0x17, 0xFE, 0xFF, 0x40, 0x4C, 0x05, 0x80];
+    raw[16..24].copy_from_slice(&program);
+    let mut nes = NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    // With A held the controller's first bit is 1
+    nes.cpu.mmu.ctrl0.set_button_state(Button::A, true);
+    nes.cpu.mmu.store(0x4016, 1);
+    nes.cpu.mmu.store(0x4016, 0);
+
+    nes.step().expect("LDX");
+    let start = nes.cpu.mmu.cycles;
+    nes.step().expect("INC");
+    // Always 7, crossing a page or not
+    assert_eq!(nes.cpu.mmu.cycles - start, 7);
+    // The dummy read already shifted A out
+    assert_eq!(nes.cpu.mmu.ld8(0x4016) & 1, 0);
+}
+
+#[test]
+fn unmapped_reads_see_the_cpu_bus() {
+    // LDA $4000 then spin. The last byte on the bus is the operand's $40
+    let mut raw = spinning_rom(0, &[0; 0x2000]);
+    let program = [0xAD, 0x00, 0x40, 0x4C, 0x03, 0x80];
+    raw[16..22].copy_from_slice(&program);
+    let mut nes = NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    // Something else in the PPU's latch
+    nes.cpu.mmu.store(0x2003, 0x99);
+
+    nes.step().expect("LDA");
+    assert_eq!(nes.cpu.regs.acc, 0x40);
+    assert_eq!(nes.cpu.mmu.peek(0x4018), 0x40);
+}
+
+#[test]
+fn nop_imm_takes_2_cycles() {
+    for &op in [0x80, 0x82, 0x89, 0xC2, 0xE2].iter() {
+        let mut raw = spinning_rom(0, &[0; 0x2000]);
+        raw[16..21].copy_from_slice(&[op, 0x00, 0x4C, 0x02, 0x80]);
+        let rom = load_rom(&raw).expect("This is a good rom");
+        let mut nes = NesEmulator::new(rom);
+        let start = nes.cpu.mmu.cycles;
+        nes.step().expect("NOP");
+        assert_eq!(nes.cpu.mmu.cycles - start, 2, "{:02X}", op);
+    }
+}
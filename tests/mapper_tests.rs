@@ -0,0 +1,710 @@
+extern crate nes_emu;
+
+use nes_emu::cpu::IrqSource;
+use nes_emu::mapper::banks::Bank;
+use nes_emu::mapper::banks::Banks;
+use nes_emu::mapper::supported_boards;
+use nes_emu::mmu::Mmu;
+use nes_emu::rom::load_rom;
+use nes_emu::rom::LoadRomError;
+use nes_emu::rom::Region;
+use nes_emu::state::State;
+use nes_emu::NesEmulator;
+use nes_emu::ppu::Timing;
+
+// Where each 16KB PRG bank keeps its own number, for telling banks apart.
+// CHR ROM has the number of each 1KB bank in its first byte instead
+const MARKER: u16 = 0x100;
+// Holds $FF in every bank, so writes there pass through bus conflicts
+const OPEN: u16 = 0x200;
+// The MMC2 and MMC4 switch CHR in 4KB banks, four of the 1KB ones each
+const CHR_4KB: u8 = 4;
+
+// An iNES ROM for a mapper, with the given number of 16KB PRG banks and CHR
+// RAM. The last bank spins at $C000, which is where every vector points
+fn rom(mapper: u8, prg_banks: u8) -> NesEmulator {
+    rom_with_chr(mapper, prg_banks, 0)
+}
+
+// Same, with 8KB banks of CHR ROM
+fn rom_with_chr(mapper: u8, prg_banks: u8, chr_banks: u8) -> NesEmulator {
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, prg_banks, chr_banks];
+    raw.push(mapper << 4);
+    raw.push(mapper & 0xF0);
+    raw.resize(16, 0);
+    for bank in 0..prg_banks {
+        let mut prg = vec![0; 0x4000];
+        prg[MARKER as usize] = bank;
+        prg[OPEN as usize] = 0xFF;
+        if bank == prg_banks - 1 {
+            prg[0..3].copy_from_slice(&[0x4C, 0x00, 0xC0]);
+            let vectors = [0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0];
+            prg[0x3FFA..].copy_from_slice(&vectors);
+        }
+        raw.extend(prg);
+    }
+    for bank in 0..chr_banks as usize * 8 {
+        let mut chr = vec![0; 0x400];
+        chr[0] = bank as u8;
+        raw.extend(chr);
+    }
+    NesEmulator::new(load_rom(&raw).expect("This is a good rom"))
+}
+
+fn banks(mmu: &Mmu) -> (u8, u8) {
+    (mmu.peek(0x8000 + MARKER), mmu.peek(0xC000 + MARKER))
+}
+
+// Five single bit writes, each a cycle apart from the last
+fn mmc1_write(mmu: &mut Mmu, addr: u16, val: u8) {
+    for i in 0..5 {
+        mmu.store(addr, val >> i & 1);
+        mmu.tick();
+    }
+}
+
+#[test]
+fn mmc1_prg_banking() {
+    let mut nes = rom(1, 8);
+    let mmu = &mut nes.cpu.mmu;
+    assert_eq!(banks(mmu), (0, 7));
+
+    mmc1_write(mmu, 0xE000, 3);
+    assert_eq!(banks(mmu), (3, 7));
+    // Fixed first bank
+    mmc1_write(mmu, 0x8000, 0x08);
+    assert_eq!(banks(mmu), (0, 3));
+    // 32KB, the low bit of the bank is ignored
+    mmc1_write(mmu, 0x8000, 0x00);
+    assert_eq!(banks(mmu), (2, 3));
+}
+
+#[test]
+fn mmc1_ignores_consecutive_writes() {
+    let mut nes = rom(1, 8);
+    let mmu = &mut nes.cpu.mmu;
+    mmc1_write(mmu, 0xE000, 5);
+
+    // INC where the ROM holds $FF writes $FF then $00 on the next cycle,
+    // only the reset gets through
+    mmu.store(0xE000, 0xFF);
+    mmu.store(0xE000, 0x00);
+    mmu.tick();
+    mmc1_write(mmu, 0xE000, 2);
+    assert_eq!(banks(mmu), (2, 7));
+}
+
+#[test]
+fn mmc1_512k_prg() {
+    let mut nes = rom(1, 32);
+    let mmu = &mut nes.cpu.mmu;
+    mmc1_write(mmu, 0xE000, 3);
+    assert_eq!(banks(mmu), (3, 15));
+    // The upper half, last bank included
+    mmc1_write(mmu, 0xA000, 0x10);
+    assert_eq!(banks(mmu), (19, 31));
+}
+
+#[test]
+fn mmc1_banked_prg_ram() {
+    // SXROM's 32KB of PRG RAM
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 2, 0, 0x10, 0, 4];
+    raw.resize(16 + 0x8000, 0);
+    let mut nes = NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    let mmu = &mut nes.cpu.mmu;
+    for bank in 0..4 {
+        mmc1_write(mmu, 0xA000, bank << 2);
+        mmu.store(0x6000, bank);
+    }
+    mmc1_write(mmu, 0xA000, 2 << 2);
+    assert_eq!(mmu.peek(0x6000), 2);
+}
+
+#[test]
+fn uxrom_fixes_the_last_bank() {
+    let mut nes = rom(2, 16);
+    let mmu = &mut nes.cpu.mmu;
+    assert_eq!(banks(mmu), (0, 15));
+    mmu.store(0x8000, 9);
+    assert_eq!(banks(mmu), (9, 15));
+
+    let mut nes = rom(2, 4);
+    let mmu = &mut nes.cpu.mmu;
+    assert_eq!(banks(mmu), (0, 3));
+    // Bits past the end of PRG aren't connected
+    mmu.store(0x8000, 6);
+    assert_eq!(banks(mmu), (2, 3));
+}
+
+#[test]
+fn mmc3_prg_modes() {
+    let mut nes = rom(4, 4);
+    let mmu = &mut nes.cpu.mmu;
+    // 8KB bank 2 is the first half of 16KB bank 1
+    mmu.store(0x8000, 6);
+    mmu.store(0x8001, 2);
+    assert_eq!(banks(mmu), (1, 3));
+    // Swaps R6 with the second to last bank
+    mmu.store(0x8000, 0x46);
+    assert_eq!(banks(mmu), (3, 1));
+}
+
+#[test]
+fn mmc3_scanline_irq() {
+    let mut nes = rom(4, 4);
+    nes.next_frame().expect("Spinning can't fail");
+    {
+        let mmu = &mut nes.cpu.mmu;
+        // Background from $0000 and sprites from $1000, so A12 rises once
+        // a line
+        mmu.store(0x2000, 0x08);
+        mmu.store(0x2001, 0x18);
+        mmu.store(0xC000, 20);
+        mmu.store(0xC001, 0);
+        mmu.store(0xE001, 0);
+    }
+    while !nes.cpu.mmu.irq.is_asserted_by(IrqSource::Mapper) {
+        nes.step().expect("Spinning can't fail");
+    }
+    // Reloaded on the pre-render line, then down to 0 twenty lines later
+    assert_eq!(nes.cpu.mmu.ppu.scanline(), 19);
+}
+
+#[test]
+fn axrom_wraps_banks() {
+    // 128KB, so only 2 of the 3 bank bits are connected
+    let mut nes = rom(7, 8);
+    let mmu = &mut nes.cpu.mmu;
+    mmu.store(0x8000, 0x12);
+    assert_eq!(banks(mmu), (4, 5));
+    mmu.store(0x8000, 0x05);
+    assert_eq!(banks(mmu), (2, 3));
+}
+
+#[test]
+fn mmc2_chr_latches() {
+    let nes = rom_with_chr(9, 8, 4);
+    let mut cartridge = nes.cpu.mmu.cartridge.borrow_mut();
+    for (i, &addr) in [0xB000, 0xC000, 0xD000, 0xE000].iter().enumerate() {
+        cartridge.store_prg(addr, i as u8 + 1);
+    }
+    // Both latches start out at $FE
+    assert_eq!(cartridge.ld_chr(0x0000), 2 * CHR_4KB);
+    assert_eq!(cartridge.ld_chr(0x1000), 4 * CHR_4KB);
+
+    assert!(cartridge.pattern_fetch(0x0FD8));
+    assert_eq!(cartridge.ld_chr(0x0000), CHR_4KB);
+    // Any row of the tile works for the second table, only the first row
+    // for the first
+    assert!(cartridge.pattern_fetch(0x1FDB));
+    assert_eq!(cartridge.ld_chr(0x1000), 3 * CHR_4KB);
+    assert!(!cartridge.pattern_fetch(0x0FEB));
+    assert_eq!(cartridge.ld_chr(0x0000), CHR_4KB);
+}
+
+#[test]
+fn mmc4_banking() {
+    let mut nes = rom_with_chr(10, 8, 4);
+    nes.cpu.mmu.store(0xA000, 5);
+    assert_eq!(banks(&nes.cpu.mmu), (5, 7));
+
+    let mut cartridge = nes.cpu.mmu.cartridge.borrow_mut();
+    cartridge.store_prg(0xB000, 1);
+    cartridge.store_prg(0xC000, 2);
+    assert_eq!(cartridge.ld_chr(0x0000), 2 * CHR_4KB);
+    // Unlike the MMC2, any row of the tile flips the first table's latch
+    assert!(cartridge.pattern_fetch(0x0FDB));
+    assert_eq!(cartridge.ld_chr(0x0000), CHR_4KB);
+}
+
+#[test]
+fn smallest_prg() {
+    // 16KB is the least load_rom lets through, less than the fixed banks
+    // some of these boards have
+    for &mapper in [1, 4, 9, 10].iter() {
+        let nes = rom(mapper, 1);
+        for address in (0x8000..0x10000).step_by(0x1000) {
+            nes.cpu.mmu.peek(address as u16);
+        }
+        assert_eq!(nes.cpu.mmu.peek(0xE000 + MARKER), 0, "{}", mapper);
+    }
+}
+
+// Boards that only ever shipped with CHR ROM still get RAM from a header
+// without any
+#[test]
+fn mmc2_chr_ram() {
+    let nes = rom(9, 8);
+    let mut cartridge = nes.cpu.mmu.cartridge.borrow_mut();
+    cartridge.store_chr(0x1234, 0x56);
+    assert_eq!(cartridge.ld_chr(0x1234), 0x56);
+}
+
+#[test]
+fn gxrom_bus_conflicts() {
+    let mut nes = rom_with_chr(66, 8, 4);
+    nes.cpu.mmu.store(0x8000 + OPEN, 0x21);
+    assert_eq!(banks(&nes.cpu.mmu), (4, 5));
+    assert_eq!(nes.cpu.mmu.cartridge.borrow().ld_chr(0), 8);
+
+    // The ROM holds 4 here, which has none of the bits being written
+    nes.cpu.mmu.store(0x8000 + MARKER, 0x13);
+    assert_eq!(banks(&nes.cpu.mmu), (0, 1));
+    assert_eq!(nes.cpu.mmu.cartridge.borrow().ld_chr(0), 0);
+}
+
+#[test]
+fn cnrom_bus_conflicts() {
+    let mut nes = rom_with_chr(3, 2, 4);
+    nes.cpu.mmu.store(0x8000 + OPEN, 3);
+    assert_eq!(nes.cpu.mmu.cartridge.borrow().ld_chr(0), 3 * 8);
+    // PRG isn't switched, and bank 0's marker is 0
+    nes.cpu.mmu.store(0x8000 + MARKER, 3);
+    assert_eq!(banks(&nes.cpu.mmu), (0, 1));
+    assert_eq!(nes.cpu.mmu.cartridge.borrow().ld_chr(0), 0);
+}
+
+#[test]
+fn color_dreams_banking() {
+    let mut nes = rom_with_chr(11, 8, 4);
+    nes.cpu.mmu.store(0x8000 + OPEN, 0x31);
+    assert_eq!(banks(&nes.cpu.mmu), (2, 3));
+    assert_eq!(nes.cpu.mmu.cartridge.borrow().ld_chr(0), 3 * 8);
+}
+
+#[test]
+fn vrc4_prg_swap_mode() {
+    let mut nes = rom(23, 8);
+    let mmu = &mut nes.cpu.mmu;
+    // 8KB banks, so 4 is the first half of 16KB bank 2
+    mmu.store(0x8000, 4);
+    assert_eq!(banks(mmu), (2, 7));
+    mmu.store(0x9002, 0x02);
+    assert_eq!(banks(mmu), (7, 2));
+}
+
+#[test]
+fn vrc4_prescaled_irq() {
+    let mut nes = rom(23, 8);
+    let mmu = &mut nes.cpu.mmu;
+    mmu.store(0xF000, 0x0E);
+    mmu.store(0xF001, 0x0F);
+    mmu.store(0xF002, 0x02);
+    let mut cycles = 0;
+    while !mmu.irq.is_asserted_by(IrqSource::Mapper) {
+        mmu.tick();
+        cycles += 1;
+    }
+    // Two scanlines from $FE, at 341 / 3 cycles each
+    assert_eq!(cycles, 228);
+    mmu.store(0xF003, 0);
+    mmu.tick();
+    assert!(!mmu.irq.is_asserted_by(IrqSource::Mapper));
+}
+
+#[test]
+fn vrc7_banking() {
+    let mut nes = rom(85, 8);
+    let mmu = &mut nes.cpu.mmu;
+    assert_eq!(banks(mmu), (0, 1));
+    mmu.store(0x8000, 4);
+    mmu.store(0x9000, 6);
+    assert_eq!(banks(mmu), (2, 3));
+}
+
+#[cfg(feature = "vrc7-audio")]
+#[test]
+fn vrc7_audio() {
+    let mut nes = rom(85, 8);
+    let mmu = &mut nes.cpu.mmu;
+    // Channel 0 on the flute at full volume, keyed on
+    for &(register, val) in [(0x30, 0x40), (0x10, 0x80), (0x20, 0x19)].iter() {
+        mmu.store(0x9010, register);
+        mmu.store(0x9030, val);
+    }
+    let mut loudest: f32 = 0.0;
+    // A fifth of a second, long enough for the attack
+    for _ in 0..36 * 10_000 {
+        mmu.tick();
+        loudest = loudest.max(mmu.cartridge.borrow().audio_output().abs());
+    }
+    assert!(loudest > 0.05);
+
+    // The sound reset bit holds it quiet
+    mmu.store(0xE000, 0x80);
+    mmu.tick();
+    assert_eq!(mmu.cartridge.borrow().audio_output(), 0.0);
+}
+
+#[test]
+fn fme7_prg_ram_select() {
+    let mut nes = rom(69, 8);
+    let mmu = &mut nes.cpu.mmu;
+    mmu.store(0x8000, 0x9);
+    mmu.store(0xA000, 4);
+    mmu.store(0x8000, 0xB);
+    mmu.store(0xA000, 6);
+    assert_eq!(banks(mmu), (2, 3));
+
+    // ROM bank 12 at $6000, then enabled RAM in its place
+    mmu.store(0x8000, 0x8);
+    mmu.store(0xA000, 12);
+    assert_eq!(mmu.peek(0x6000 + MARKER), 6);
+    mmu.store(0xA000, 0xC0);
+    mmu.store(0x6000 + MARKER, 0x42);
+    assert_eq!(mmu.peek(0x6000 + MARKER), 0x42);
+}
+
+#[test]
+fn fme7_cycle_irq() {
+    let mut nes = rom(69, 8);
+    let mmu = &mut nes.cpu.mmu;
+    mmu.store(0x8000, 0xE);
+    mmu.store(0xA000, 100);
+    mmu.store(0x8000, 0xF);
+    mmu.store(0xA000, 0);
+    mmu.store(0x8000, 0xD);
+    mmu.store(0xA000, 0x81);
+    let mut cycles = 0;
+    while !mmu.irq.is_asserted_by(IrqSource::Mapper) {
+        mmu.tick();
+        cycles += 1;
+    }
+    // Down to 0 and then past it
+    assert_eq!(cycles, 101);
+    mmu.store(0xA000, 0x81);
+    mmu.tick();
+    assert!(!mmu.irq.is_asserted_by(IrqSource::Mapper));
+}
+
+#[test]
+fn n163_chr_nametables() {
+    let mut nes = rom_with_chr(19, 8, 4);
+    let mmu = &mut nes.cpu.mmu;
+    mmu.store(0xC000, 5);
+    mmu.store(0x2006, 0x20);
+    mmu.store(0x2006, 0x00);
+    mmu.ld8(0x2007);
+    assert_eq!(mmu.ld8(0x2007), 5);
+
+    // Back to VRAM, where the write sticks
+    mmu.store(0xC000, 0xE1);
+    mmu.store(0x2006, 0x20);
+    mmu.store(0x2006, 0x00);
+    mmu.store(0x2007, 0x42);
+    mmu.store(0x2006, 0x24);
+    mmu.store(0x2006, 0x00);
+    mmu.ld8(0x2007);
+    assert_eq!(mmu.ld8(0x2007), 0x42);
+}
+
+#[test]
+fn n163_internal_ram() {
+    let mut nes = rom(19, 8);
+    let mmu = &mut nes.cpu.mmu;
+    mmu.store(0xF800, 0x90);
+    for val in 1..4 {
+        mmu.store(0x4800, val);
+    }
+    mmu.store(0xF800, 0x90);
+    assert_eq!(mmu.peek(0x4800), 1);
+    let read: Vec<u8> = (0..3).map(|_| mmu.ld8(0x4800)).collect();
+    assert_eq!(read, [1, 2, 3]);
+}
+
+#[test]
+fn banks_wrap_and_pin_from_the_end() {
+    // 40KB of 8KB banks, so 5 of them
+    let windows = [Bank::Number(0), Bank::FromEnd(2), Bank::FromEnd(1)];
+    let mut banks = Banks::new(0x8000, 0x2000, 5 * 0x2000, &windows);
+    banks.switch(0, 7);
+    assert_eq!(banks.index(0x8010), 2 * 0x2000 + 0x10);
+    assert_eq!(banks.index(0xA000), 3 * 0x2000);
+    assert_eq!(banks.index(0xDFFF), 5 * 0x2000 - 1);
+}
+
+#[test]
+fn n163_prg_ram_protect() {
+    let mut nes = rom(19, 8);
+    let mmu = &mut nes.cpu.mmu;
+    mmu.store(0x6000, 1);
+    assert_eq!(mmu.peek(0x6000), 0);
+    // Writes allowed, apart from $6800-$6FFF
+    mmu.store(0xF800, 0x42);
+    mmu.store(0x6000, 1);
+    mmu.store(0x6800, 2);
+    assert_eq!(mmu.peek(0x6000), 1);
+    assert_eq!(mmu.peek(0x6800), 0);
+}
+
+#[test]
+fn bf909x_banking() {
+    let mut nes = rom(71, 8);
+    let mmu = &mut nes.cpu.mmu;
+    // Nothing there on the BF9093
+    mmu.store(0x8000, 3);
+    assert_eq!(banks(mmu), (0, 7));
+    mmu.store(0xC000, 3);
+    assert_eq!(banks(mmu), (3, 7));
+}
+
+#[test]
+fn rambo1_cycle_irq() {
+    let mut nes = rom(64, 8);
+    let mmu = &mut nes.cpu.mmu;
+    mmu.store(0xC000, 10);
+    mmu.store(0xC001, 1);
+    mmu.store(0xE001, 0);
+    let mut cycles = 0;
+    while !mmu.irq.is_asserted_by(IrqSource::Mapper) {
+        mmu.tick();
+        cycles += 1;
+    }
+    // Reloaded to 12, then once every 4 cycles from the $C001 write. The
+    // $E001 write took one of them
+    assert_eq!(cycles, 12 * 4 - 1);
+}
+
+#[test]
+fn namco108_has_no_modes() {
+    let mut nes = rom(206, 8);
+    let mmu = &mut nes.cpu.mmu;
+    // The PRG mode bit doesn't exist, so this still switches $8000
+    mmu.store(0x8000, 0x46);
+    mmu.store(0x8001, 4);
+    assert_eq!(banks(mmu), (2, 7));
+}
+
+#[test]
+fn battery_ram_save_data() {
+    // NROM with the battery bit set
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 1, 0, 0x02, 0];
+    raw.resize(16 + 0x4000, 0);
+    let load = || NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    let mut nes = load();
+    nes.cpu.mmu.store(0x6123, 0x42);
+    let save = nes.cpu.mmu.cartridge.borrow().save_data();
+    let save = save.expect("Battery RAM is saved");
+
+    let nes = load();
+    nes.cpu.mmu.cartridge.borrow_mut().load_save_data(&save);
+    assert_eq!(nes.cpu.mmu.peek(0x6123), 0x42);
+}
+
+// Why load_rom turned a ROM down
+fn unsupported(raw: &[u8]) -> String {
+    match load_rom(raw).unwrap_err().downcast::<LoadRomError>() {
+        Ok(LoadRomError::Unsupported(reason)) => reason,
+        other => panic!("Expected an unsupported rom, got {:?}", other),
+    }
+}
+
+// Commands go to addresses in the flash chip, picked with the bank at $8000
+fn flash_command(mmu: &mut Mmu, flash_addr: usize, val: u8) {
+    mmu.store(0xC000, (flash_addr >> 14) as u8);
+    mmu.store(0x8000 | (flash_addr & 0x3FFF) as u16, val);
+}
+
+#[test]
+fn unrom512_flash_saves() {
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 4, 0, 0xE2, 0x10];
+    raw.resize(16, 0);
+    // Erased flash
+    raw.resize(16 + 4 * 0x4000, 0xFF);
+    raw[16 + 0xFFFC..16 + 0xFFFE].copy_from_slice(&[0x00, 0xC0]);
+    let mut nes = NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    {
+        let mmu = &mut nes.cpu.mmu;
+        for &(addr, val) in [(0x5555, 0xAA), (0x2AAA, 0x55), (0x5555, 0xA0)]
+            .iter()
+        {
+            flash_command(mmu, addr, val);
+        }
+        mmu.store(0xC000, 2);
+        mmu.store(0x8123, 0x42);
+        assert_eq!(mmu.peek(0x8123), 0x42);
+    }
+    let cartridge = nes.cpu.mmu.cartridge.borrow();
+    let flash = cartridge.save_data().expect("Something was flashed");
+    assert_eq!(flash[2 * 0x4000 + 0x123], 0x42);
+}
+
+#[test]
+fn unrom512_flash_programs_f0() {
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 4, 0, 0xE2, 0x10];
+    raw.resize(16, 0);
+    raw.resize(16 + 4 * 0x4000, 0xFF);
+    raw[16 + 0xFFFC..16 + 0xFFFE].copy_from_slice(&[0x00, 0xC0]);
+    let mut nes = NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    let mmu = &mut nes.cpu.mmu;
+    for &(addr, val) in [(0x5555, 0xAA), (0x2AAA, 0x55), (0x5555, 0xA0)]
+        .iter()
+    {
+        flash_command(mmu, addr, val);
+    }
+    // $F0 is the reset command everywhere but as the byte to program
+    mmu.store(0xC000, 2);
+    mmu.store(0x8123, 0xF0);
+    assert_eq!(mmu.peek(0x8123), 0xF0);
+    assert_eq!(mmu.peek(0x8124), 0xFF);
+}
+
+#[test]
+fn reload_swaps_chr_rom() {
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0];
+    raw.resize(16 + 0x4000 + 0x2000, 0);
+    let load = |raw: &[u8]| load_rom(raw).expect("This is a good rom");
+    let mut nes = NesEmulator::new(load(&raw));
+    nes.cpu.mmu.store(0x0300, 0x42);
+    raw[16 + 0x4000 + 0x10] = 0x24;
+    nes.reload_rom(load(&raw), false).expect("Same board");
+    assert_eq!(nes.cpu.mmu.cartridge.borrow().ld_chr(0x10), 0x24);
+    assert_eq!(nes.cpu.mmu.peek(0x0300), 0x42);
+
+    // Banks depend on the chip sizes, so they have to match
+    raw[5] = 2;
+    raw.resize(16 + 0x4000 + 0x4000, 0);
+    assert!(nes.reload_rom(load(&raw), false).is_err());
+}
+
+#[test]
+fn every_supported_board_builds() {
+    for board in supported_boards() {
+        rom_with_chr(board.mapper, 2, 1);
+    }
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 1, 0, 0x50, 0];
+    raw.resize(16 + 0x4000, 0);
+    let reason = "mapper 5 (Nintendo MMC5, ExROM) not implemented";
+    assert_eq!(unsupported(&raw), reason);
+}
+
+#[test]
+fn save_states_restore_the_board() {
+    for board in supported_boards() {
+        let mut nes = rom_with_chr(board.mapper, 8, 4);
+        let mut saved = Vec::new();
+        nes.get_state().save(&mut saved).expect("States always save");
+        // Every register and all of PRG RAM
+        for addr in 0x4800..=0xFFFF {
+            nes.cpu.mmu.store(addr, 0x55);
+        }
+        let state = State::load(&mut &saved[..]).expect("Just saved");
+        nes.load_state(state).expect("Same board");
+        let mut loaded = Vec::new();
+        nes.get_state().save(&mut loaded).expect("States always save");
+        assert!(saved == loaded, "{} wasn't restored", board.name);
+    }
+}
+
+#[test]
+fn nes2_header() {
+    // MMC1 on SEROM, 8KB of PRG RAM, 32KB of CHR RAM and Dendy timing
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 2, 0, 0x10, 0x08, 0x50, 0];
+    raw.extend(&[0x07, 0x09, 0x03, 0, 0, 0]);
+    for bank in 0..2 {
+        let mut prg = vec![0; 0x4000];
+        prg[MARKER as usize] = bank;
+        raw.extend(prg);
+    }
+    let mut nes = NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    assert!(nes.power.timing == Timing::Dendy);
+    assert_eq!(nes.cpu.mmu.cartridge.borrow().rom.prg_ram.len(), 0x2000);
+    assert_eq!(nes.cpu.mmu.cartridge.borrow().rom.chr_ram.len(), 0x8000);
+    let mmu = &mut nes.cpu.mmu;
+    mmc1_write(mmu, 0xE000, 1);
+    assert_eq!(banks(mmu), (0, 1));
+}
+
+#[test]
+fn nes2_without_prg_ram() {
+    // MMC3, VRC7 and FME7 with the RAM enabled through their registers
+    let enable: [(u8, &[(u16, u8)]); 3] = [
+        (4, &[]),
+        (85, &[(0xE000, 0x40)]),
+        (69, &[(0x8000, 0x08), (0xA000, 0xC0)]),
+    ];
+    for &(mapper, writes) in enable.iter() {
+        let mut raw = vec![b'N', b'E', b'S', 0x1A, 2, 0, mapper << 4];
+        raw.push(mapper & 0xF0 | 0x08);
+        raw.extend(&[0, 0, 0, 0x07, 0, 0, 0, 0]);
+        raw.resize(16 + 2 * 0x4000, 0);
+        let rom = load_rom(&raw).expect("This is a good rom");
+        assert!(rom.prg_ram.is_empty());
+        let mut nes = NesEmulator::new(rom);
+        let mmu = &mut nes.cpu.mmu;
+        for &(address, val) in writes {
+            mmu.store(address, val);
+        }
+        mmu.store(0x6000, 0x42);
+        assert_eq!(mmu.ld8(0x6000), 0, "mapper {}", mapper);
+    }
+}
+
+#[test]
+fn nes2_odd_prg_sizes() {
+    // Exponent and multiplier sizes, 2^12 * 1, 2^12 * 5 and 2^13 * 3
+    let sizes = [
+        (12 << 2, 0x1000),
+        (12 << 2 | 2, 0x5000),
+        (13 << 2 | 1, 0x6000),
+    ];
+    for &(size, len) in sizes.iter() {
+        let mut raw = vec![b'N', b'E', b'S', 0x1A, size, 0, 0, 0x08, 0];
+        raw.extend(&[0x0F, 0, 0x07, 0, 0, 0, 0]);
+        raw.resize(16 + len, 0);
+        if len % 0x2000 == 0 {
+            load_rom(&raw).expect("Whole 8KB banks");
+        } else {
+            let reason = format!("{} bytes of PRG ROM", len);
+            assert_eq!(unsupported(&raw), reason);
+        }
+    }
+}
+
+#[test]
+fn pal_rom_timing() {
+    // NROM with the PAL bit in byte 9, spinning at $C000
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 1, 0, 0, 0, 0, 1];
+    raw.resize(16 + 0x4000, 0);
+    raw[16..19].copy_from_slice(&[0x4C, 0x00, 0xC0]);
+    raw[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+    let mut nes = NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    assert!(nes.power.timing == Timing::Pal);
+    assert!(nes.cpu.mmu.apu.region() == Region::PAL);
+    nes.next_frame().expect("Just spins");
+    let start = nes.cpu.mmu.cycles;
+    for _ in 0..5 {
+        nes.next_frame().expect("Just spins");
+    }
+    // 312 lines of 341 dots at 3.2 dots a cycle, give or take an
+    // instruction
+    let cycles = nes.cpu.mmu.cycles - start;
+    assert!(cycles > 166230 && cycles < 166245, "{} cycles", cycles);
+}
+
+#[test]
+fn junk_header_stays_ntsc() {
+    // Byte 9's PAL bit is set, but so are the bytes after it
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 1, 0, 0, 0, 0];
+    raw.extend(b"iNESfix");
+    raw.resize(16 + 0x4000, 0);
+    let nes = NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    assert!(nes.power.timing == Timing::Ntsc);
+    assert!(nes.cpu.mmu.apu.region() == Region::NTSC);
+}
+
+#[test]
+fn trainer_goes_to_7000() {
+    let mut raw = vec![b'N', b'E', b'S', 0x1A, 1, 0, 0x04, 0];
+    raw.resize(16, 0);
+    raw.extend(vec![0xAB; 512]);
+    let mut prg = vec![0; 0x4000];
+    prg[MARKER as usize] = 0x42;
+    raw.extend(prg);
+    let nes = NesEmulator::new(load_rom(&raw).expect("This is a good rom"));
+    assert_eq!(nes.cpu.mmu.peek(0x7000), 0xAB);
+    assert_eq!(nes.cpu.mmu.peek(0x71FF), 0xAB);
+    assert_eq!(nes.cpu.mmu.peek(0x7200), 0);
+    // PRG starts after it
+    assert_eq!(nes.cpu.mmu.peek(0x8000 + MARKER), 0x42);
+}
@@ -0,0 +1,73 @@
+extern crate nes_emu;
+
+mod common;
+
+use nes_emu::cartridge::Cartridge;
+use nes_emu::ppu::vram::Vram;
+use nes_emu::rom::load_rom;
+use nes_emu::NesEmulator;
+use std::cell::RefCell;
+use std::rc::Rc;
+use common::spinning_rom;
+use common::set_vram_addr;
+use common::write_vram;
+
+// AxROM with CHR RAM
+fn axrom() -> Vec<u8> {
+    spinning_rom(7, &[])
+}
+
+fn select_screen(cartridge: &Rc<RefCell<Cartridge>>, upper: bool) {
+    let val = if upper { 0x10 } else { 0x00 };
+    cartridge.borrow_mut().store_prg(0x8000, val);
+}
+
+#[test]
+fn mirroring_switch_applies_immediately() {
+    let rom = load_rom(&axrom()).expect("This is a good rom");
+    let cartridge = Rc::new(RefCell::new(Cartridge::from_rom(rom)));
+    let mut vram = Vram::new(cartridge.clone());
+
+    select_screen(&cartridge, false);
+    vram.store(0x2000, 1);
+    select_screen(&cartridge, true);
+    vram.store(0x2000, 2);
+
+    assert_eq!(vram.ld8(0x2C00), 2);
+    select_screen(&cartridge, false);
+    assert_eq!(vram.ld8(0x2400), 1);
+}
+
+#[test]
+fn mirroring_switch_mid_frame() {
+    let rom = load_rom(&axrom()).expect("This is a good rom");
+    let mut nes = NesEmulator::new(rom);
+    nes.next_frame().expect("Spinning can't fail");
+
+    // Tile 1 is solid, the lower screen is all tile 0 and the upper one is
+    // all tile 1, with colour 3 of the first palette white
+    let writes = [
+        (0x0010, vec![0xFF; 16]),
+        (0x2000, vec![0x00; 0x400]),
+        (0x2000, vec![0x01; 0x400]),
+        (0x3F00, vec![0x0F, 0x0F, 0x0F, 0x30]),
+    ];
+    for (i, &(addr, ref data)) in writes.iter().enumerate() {
+        select_screen(&nes.cpu.mmu.cartridge, i == 2);
+        write_vram(&mut nes, addr, data);
+    }
+    select_screen(&nes.cpu.mmu.cartridge, false);
+    set_vram_addr(&mut nes, 0);
+    nes.cpu.mmu.ppu.store(1, 0x0A);
+
+    nes.next_frame().expect("Spinning can't fail");
+    while nes.cpu.mmu.ppu.scanline() != 120 {
+        nes.step().expect("Spinning can't fail");
+    }
+    select_screen(&nes.cpu.mmu.cartridge, true);
+    let frame = nes.next_frame().expect("Spinning can't fail").to_vec();
+
+    let pixel = |x: usize, y: usize| frame[(y * 256 + x) * 3];
+    assert!(pixel(100, 60) < 0x80);
+    assert!(pixel(100, 180) > 0x80);
+}
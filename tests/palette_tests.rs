@@ -0,0 +1,102 @@
+extern crate nes_emu;
+
+mod common;
+
+use nes_emu::ppu::PixelFormat;
+use nes_emu::rom::load_rom;
+use nes_emu::NesEmulator;
+use common::spinning_rom;
+use common::set_vram_addr;
+
+// NROM with blank CHR
+fn nrom() -> Vec<u8> {
+    spinning_rom(0, &[0; 0x2000])
+}
+
+#[test]
+fn rainbow_backdrop_follows_vram_addr() {
+    let rom = load_rom(&nrom()).expect("This is a good rom");
+    let mut nes = NesEmulator::new(rom);
+    nes.cpu.mmu.ppu.set_pixel_format(PixelFormat::Indexed);
+    nes.next_frame().expect("Spinning can't fail");
+    nes.next_frame().expect("Spinning can't fail");
+
+    // A different colour in each of the first 16 palette entries
+    set_vram_addr(&mut nes, 0x3F00);
+    for i in 0..16 {
+        nes.cpu.mmu.ppu.store(7, 0x11 + i);
+    }
+
+    // With rendering off, pointing v at a palette entry shows that entry
+    // instead of the backdrop. Move it on every 16 lines
+    for line in 0..240 {
+        nes.run_to_scanline(line).expect("Spinning can't fail");
+        if line % 16 == 0 {
+            set_vram_addr(&mut nes, 0x3F00 + line / 16);
+        }
+    }
+    let frame = nes.next_frame().expect("Spinning can't fail").to_vec();
+
+    for line in 0..240 {
+        let pixel = (line * 256 + 200) * 2;
+        assert_eq!(frame[pixel], 0x11 + (line / 16) as u8, "line {}", line);
+    }
+}
+
+#[test]
+fn palette_reads_skip_the_read_buffer() {
+    let rom = load_rom(&nrom()).expect("This is a good rom");
+    let mut nes = NesEmulator::new(rom);
+    nes.next_frame().expect("Spinning can't fail");
+
+    set_vram_addr(&mut nes, 0x3F05);
+    nes.cpu.mmu.ppu.store(7, 0x2A);
+    set_vram_addr(&mut nes, 0x3F05);
+    assert_eq!(nes.cpu.mmu.ppu.ld(7), 0x2A);
+}
+
+#[test]
+fn rendering_backdrop_ignores_vram_addr() {
+    let rom = load_rom(&nrom()).expect("This is a good rom");
+    let mut nes = NesEmulator::new(rom);
+    nes.cpu.mmu.ppu.set_pixel_format(PixelFormat::Indexed);
+    nes.next_frame().expect("Spinning can't fail");
+    nes.next_frame().expect("Spinning can't fail");
+
+    set_vram_addr(&mut nes, 0x3F00);
+    for i in 0..16 {
+        nes.cpu.mmu.ppu.store(7, 0x11 + i);
+    }
+    // Background on, everything it draws is transparent with blank CHR
+    nes.cpu.mmu.ppu.store(1, 0x0A);
+    nes.next_frame().expect("Spinning can't fail");
+
+    // With rendering on, the rainbow trick does nothing
+    nes.run_to_scanline(100).expect("Spinning can't fail");
+    set_vram_addr(&mut nes, 0x3F05);
+    let frame = nes.next_frame().expect("Spinning can't fail").to_vec();
+
+    for line in 0..240 {
+        let pixel = (line * 256 + 200) * 2;
+        assert_eq!(frame[pixel], 0x11, "line {}", line);
+    }
+}
+
+#[test]
+fn rendering_palette_reads_see_the_current_pixel() {
+    let rom = load_rom(&nrom()).expect("This is a good rom");
+    let mut nes = NesEmulator::new(rom);
+    nes.next_frame().expect("Spinning can't fail");
+
+    set_vram_addr(&mut nes, 0x3F00);
+    nes.cpu.mmu.ppu.store(7, 0x21);
+    set_vram_addr(&mut nes, 0x3F05);
+    nes.cpu.mmu.ppu.store(7, 0x2A);
+    nes.cpu.mmu.ppu.store(1, 0x0A);
+    nes.next_frame().expect("Spinning can't fail");
+
+    // The palette is addressed by the backdrop pixel being drawn, not v
+    nes.run_to_scanline(100).expect("Spinning can't fail");
+    set_vram_addr(&mut nes, 0x3F05);
+    assert_eq!(nes.cpu.mmu.ppu.ld(7), 0x21);
+}
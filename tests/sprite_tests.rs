@@ -0,0 +1,66 @@
+extern crate nes_emu;
+
+mod common;
+
+use nes_emu::ppu::PixelFormat;
+use nes_emu::rom::load_rom;
+use nes_emu::NesEmulator;
+use common::spinning_rom;
+use common::write_vram;
+
+const BG_COLOR: u8 = 0x16;
+const BEHIND_COLOR: u8 = 0x2A;
+const FRONT_COLOR: u8 = 0x12;
+
+// NROM with tile 1 solid in colour 1
+fn nrom() -> Vec<u8> {
+    let mut chr = vec![0; 0x2000];
+    for byte in chr[0x10..0x18].iter_mut() {
+        *byte = 0xFF;
+    }
+    spinning_rom(0, &chr)
+}
+
+#[test]
+fn behind_sprite_masks_later_sprites() {
+    let rom = load_rom(&nrom()).expect("This is a good rom");
+    let mut nes = NesEmulator::new(rom);
+    nes.cpu.mmu.ppu.set_pixel_format(PixelFormat::Indexed);
+    nes.next_frame().expect("Spinning can't fail");
+
+    // Solid background on the left half of the screen only
+    let mut row = vec![1; 16];
+    row.extend(vec![0; 16]);
+    let nametable: Vec<u8> = row.iter().cycle().take(960).cloned().collect();
+    write_vram(&mut nes, 0x2000, &nametable);
+    write_vram(&mut nes, 0x3F00, &[0x0F, BG_COLOR]);
+    write_vram(&mut nes, 0x3F11, &[BEHIND_COLOR]);
+    write_vram(&mut nes, 0x3F15, &[FRONT_COLOR]);
+    write_vram(&mut nes, 0x2000, &[]);
+
+    // Each pair is a behind background sprite with a front one after it in
+    // OAM, once over the background and once over nothing
+    let mut oam = vec![];
+    for &x in &[64, 192] {
+        oam.extend_from_slice(&[100, 1, 0x20, x]);
+        oam.extend_from_slice(&[100, 1, 0x01, x]);
+    }
+    oam.resize(256, 0xF8);
+    nes.cpu.mmu.ppu.store(3, 0);
+    for byte in oam {
+        nes.cpu.mmu.ppu.store(4, byte);
+    }
+    nes.cpu.mmu.ppu.store(1, 0x1E);
+
+    nes.next_frame().expect("Spinning can't fail");
+    let frame = nes.next_frame().expect("Spinning can't fail").to_vec();
+    let pixel = |x: usize, y: usize| frame[(y * 256 + x) * 2];
+
+    // The first opaque sprite wins before priority is looked at, so the
+    // front sprite is hidden even where the background shows through
+    assert_eq!(pixel(68, 104), BG_COLOR);
+    assert_eq!(pixel(196, 104), BEHIND_COLOR);
+    // Outside the sprites nothing changes
+    assert_eq!(pixel(40, 104), BG_COLOR);
+    assert_eq!(pixel(160, 104), 0x0F);
+}